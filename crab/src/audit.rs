@@ -0,0 +1,97 @@
+use crate::tools::{CommandOutput, FsChanges};
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// AUDIT_LOG gets one JSON line for every command crab ran or refused to run,
+// apart from the conversation history. Only metadata is kept: output sizes,
+// never the output itself.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub started_at_ms: u64,
+    pub command: String,
+    // "ran", "failed" when it never produced an exit code, "blocked" or "declined"
+    pub status: String,
+    pub exit_code: Option<i32>,
+    pub stdout_bytes: usize,
+    pub stderr_bytes: usize,
+    pub duration_ms: u64,
+    pub reason: Option<String>,
+    // Paths the command touched, with WATCH_CHANGES on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub changes: Option<FsChanges>,
+}
+
+fn millis_since_epoch(at: SystemTime) -> u64 {
+    at.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+impl AuditEntry {
+    pub fn ran(
+        command: &str,
+        started: SystemTime,
+        duration: Duration,
+        output: &CommandOutput,
+    ) -> Self {
+        Self {
+            started_at_ms: millis_since_epoch(started),
+            command: command.to_string(),
+            status: "ran".to_string(),
+            exit_code: Some(output.exit_code),
+            stdout_bytes: output.stdout.len(),
+            stderr_bytes: output.stderr.len(),
+            duration_ms: duration.as_millis() as u64,
+            reason: None,
+            changes: None,
+        }
+    }
+
+    pub fn failed(command: &str, started: SystemTime, duration: Duration, error: &str) -> Self {
+        Self {
+            started_at_ms: millis_since_epoch(started),
+            command: command.to_string(),
+            status: "failed".to_string(),
+            exit_code: None,
+            stdout_bytes: 0,
+            stderr_bytes: 0,
+            duration_ms: duration.as_millis() as u64,
+            reason: Some(error.to_string()),
+            changes: None,
+        }
+    }
+
+    // Blocked by a policy or declined by the operator before anything ran
+    pub fn refused(command: &str, status: &str, reason: &str) -> Self {
+        Self {
+            started_at_ms: millis_since_epoch(SystemTime::now()),
+            command: command.to_string(),
+            status: status.to_string(),
+            exit_code: None,
+            stdout_bytes: 0,
+            stderr_bytes: 0,
+            duration_ms: 0,
+            reason: Some(reason.to_string()),
+            changes: None,
+        }
+    }
+}
+
+// A failed write is warned about rather than stopping the run, like LLM_RECORD
+pub fn append(path: &str, entry: &AuditEntry) {
+    let written = serde_json::to_string(entry)
+        .map_err(|e| e.to_string())
+        .and_then(|line| {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{}", line))
+                .map_err(|e| e.to_string())
+        });
+    if let Err(e) = written {
+        eprintln!("Warning: Could not write AUDIT_LOG {}: {}", path, e);
+    }
+}
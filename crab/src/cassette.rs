@@ -0,0 +1,191 @@
+use crate::error::CrabError;
+use crate::llm::{Message, TokenUsage};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// One request/response pair, stored as a line of JSON
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Interaction {
+    pub request: Vec<Message>,
+    pub response: Message,
+    #[serde(default)]
+    pub usage: TokenUsage,
+}
+
+// LLM_RECORD appends every completion to a file; LLM_REPLAY answers from one
+// instead of the network, matching calls by their position in the run
+pub enum Cassette {
+    Record(PathBuf),
+    Replay {
+        path: PathBuf,
+        interactions: Vec<Interaction>,
+        next: AtomicUsize,
+    },
+}
+
+impl Cassette {
+    pub fn from_config(record: &str, replay: &str) -> Result<Option<Cassette>, CrabError> {
+        match (record.is_empty(), replay.is_empty()) {
+            (true, true) => Ok(None),
+            (false, true) => Ok(Some(Cassette::Record(PathBuf::from(record)))),
+            (true, false) => Cassette::replay(replay).map(Some),
+            (false, false) => Err(CrabError::Config(
+                "LLM_RECORD and LLM_REPLAY cannot be used together".to_string(),
+            )),
+        }
+    }
+
+    pub fn replay(path: &str) -> Result<Cassette, CrabError> {
+        let invalid = |why: String| CrabError::Config(format!("LLM_REPLAY {}: {}", path, why));
+        let content = fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+        let interactions = content
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| {
+                serde_json::from_str(line).map_err(|e| invalid(format!("line {}: {}", i + 1, e)))
+            })
+            .collect::<Result<Vec<Interaction>, CrabError>>()?;
+        Ok(Cassette::Replay {
+            path: PathBuf::from(path),
+            interactions,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn is_replay(&self) -> bool {
+        matches!(self, Cassette::Replay { .. })
+    }
+
+    // None while recording; running past the end of a replay is an error, since
+    // the run has diverged from the one that was recorded
+    pub fn next_response(&self) -> Option<Result<(Message, TokenUsage), CrabError>> {
+        let Cassette::Replay {
+            path,
+            interactions,
+            next,
+        } = self
+        else {
+            return None;
+        };
+        let index = next.fetch_add(1, Ordering::SeqCst);
+        Some(match interactions.get(index) {
+            Some(interaction) => {
+                log::debug!("Replaying LLM call {} from {}", index + 1, path.display());
+                Ok((interaction.response.clone(), interaction.usage.clone()))
+            }
+            None => Err(CrabError::Config(format!(
+                "LLM_REPLAY {} has {} recorded call(s), but call {} was made",
+                path.display(),
+                interactions.len(),
+                index + 1
+            ))),
+        })
+    }
+
+    // A failed write loses the recording, not the run
+    pub fn record(&self, request: &[Message], response: &Message, usage: TokenUsage) {
+        let Cassette::Record(path) = self else {
+            return;
+        };
+        let interaction = Interaction {
+            request: request.to_vec(),
+            response: response.clone(),
+            usage,
+        };
+        let written = serde_json::to_string(&interaction)
+            .map_err(|e| e.to_string())
+            .and_then(|line| {
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .and_then(|mut file| writeln!(file, "{}", line))
+                    .map_err(|e| e.to_string())
+            });
+        if let Err(e) = written {
+            eprintln!(
+                "Warning: Could not record LLM call to {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::test_support::{http_response, mock_server};
+    use crate::llm::{LLMClient, Provider};
+    use std::sync::Arc;
+
+    fn user(content: &str) -> Vec<Message> {
+        vec![Message {
+            role: "user".to_string(),
+            content: content.to_string(),
+            ..Default::default()
+        }]
+    }
+
+    #[test]
+    fn recorded_calls_replay_in_order_without_the_network() {
+        let path = std::env::temp_dir().join(format!("crab-cassette-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let path_str = path.to_str().unwrap();
+        let (url, server) = mock_server(vec![
+            http_response(
+                "200 OK",
+                r#"{"choices":[{"message":{"content":"first"}}],"usage":{"total_tokens":5}}"#,
+            ),
+            http_response(
+                "200 OK",
+                r#"{"choices":[{"message":{"content":"second"}}]}"#,
+            ),
+        ]);
+        let recorder = LLMClient::new(Provider::OpenAI, String::new())
+            .with_base_url(&url)
+            .with_cassette(Cassette::from_config(path_str, "").unwrap().map(Arc::new));
+        let recorded = vec![
+            recorder.complete(&user("one"), 10).unwrap(),
+            recorder.complete(&user("two"), 10).unwrap(),
+        ];
+        server.join().unwrap();
+
+        // Nothing listens here; every answer has to come from the file
+        let replayer = LLMClient::new(Provider::OpenAI, String::new())
+            .with_base_url("http://127.0.0.1:9/v1")
+            .with_cassette(Cassette::from_config("", path_str).unwrap().map(Arc::new));
+        let mut streamed = String::new();
+        let replayed = vec![
+            replayer.complete(&user("one"), 10).unwrap(),
+            replayer
+                .complete_stream(&user("two"), 10, &mut |delta| streamed.push_str(delta))
+                .unwrap(),
+        ];
+        let overrun = replayer.complete(&user("three"), 10);
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(recorded, replayed);
+        assert_eq!(replayed[0].0, "first");
+        assert_eq!(replayed[0].1.total, 5);
+        assert_eq!(streamed, "second");
+        assert!(overrun.unwrap_err().to_string().contains("call 3 was made"));
+    }
+
+    #[test]
+    fn record_and_replay_are_exclusive() {
+        assert!(Cassette::from_config("", "").unwrap().is_none());
+        assert!(matches!(
+            Cassette::from_config("a.jsonl", "b.jsonl"),
+            Err(CrabError::Config(_))
+        ));
+        assert!(matches!(
+            Cassette::from_config("", "/nonexistent/crab.jsonl"),
+            Err(CrabError::Config(_))
+        ));
+    }
+}
@@ -0,0 +1,95 @@
+use crate::config::Config;
+use crate::error::CrabError;
+use crate::llm::Message;
+use crate::tools::sha256_hex;
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+// CHECKPOINT_FILE is rewritten before every iteration with everything the loop
+// needs to carry on, so `--resume` can pick up a run that crashed or was stopped.
+// Unlike HISTORY_FILE it keeps the system messages and the loop's position.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub fingerprint: String,
+    pub iterations: u32,
+    pub messages: Vec<Message>,
+}
+
+// The settings that decide what the rest of the run would do; resuming under
+// different ones would continue someone else's session
+pub fn fingerprint(config: &Config) -> String {
+    let settings = serde_json::json!({
+        "provider": config.provider.name(),
+        "model": config.model,
+        "docker_image": config.docker_image,
+        "system_prompt_file": config.system_prompt_file,
+        "dry_run": config.dry_run,
+        "readonly": config.readonly,
+        "allowed_commands": config.allowed_commands,
+        "allow_sudo": config.allow_sudo,
+        "tool_calling": config.tool_calling,
+    });
+    sha256_hex(settings.to_string().as_bytes())
+}
+
+// Written to a sibling temp file first so a crash can't leave half a checkpoint
+pub fn save(path: &str, checkpoint: &Checkpoint) -> Result<(), CrabError> {
+    let failed = |e: String| CrabError::Exec(format!("Could not save checkpoint {}: {}", path, e));
+    let json = serde_json::to_string(checkpoint).map_err(|e| failed(e.to_string()))?;
+    let tmp_path = format!("{}.tmp", path);
+    fs::write(&tmp_path, json)
+        .and_then(|_| fs::rename(&tmp_path, path))
+        .map_err(|e| failed(e.to_string()))
+}
+
+pub fn resume(path: &str, config: &Config) -> Result<Checkpoint, CrabError> {
+    let invalid = |why: String| CrabError::Config(format!("--resume {}: {}", path, why));
+    let content = fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+    let checkpoint: Checkpoint =
+        serde_json::from_str(&content).map_err(|e| invalid(e.to_string()))?;
+    if checkpoint.fingerprint != fingerprint(config) {
+        return Err(invalid(
+            "it was written under a different configuration (provider, model, image or command policy), refusing to resume".to_string(),
+        ));
+    }
+    Ok(checkpoint)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resume_refuses_a_checkpoint_from_another_configuration() {
+        let path =
+            std::env::temp_dir().join(format!("crab-checkpoint-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let config = Config::default();
+        let checkpoint = Checkpoint {
+            fingerprint: fingerprint(&config),
+            iterations: 2,
+            messages: vec![Message {
+                role: "user".to_string(),
+                content: "list files".to_string(),
+                ..Default::default()
+            }],
+        };
+        save(path, &checkpoint).unwrap();
+
+        let same = resume(path, &config);
+        let other = resume(
+            path,
+            &Config {
+                model: "another-model".to_string(),
+                ..Config::default()
+            },
+        );
+        let _ = fs::remove_file(path);
+
+        assert_eq!(same.unwrap(), checkpoint);
+        assert!(other
+            .unwrap_err()
+            .to_string()
+            .contains("different configuration"));
+    }
+}
@@ -0,0 +1,2234 @@
+use crate::cost::{self, ModelPrice};
+use crate::error::CrabError;
+use crate::llm::{
+    default_model, max_feedback_bytes, parse_extra_headers, parse_proxy, supports_vision,
+    AzureDeployment, CommandDelimiters, Message, Provider, ReasoningEffort, DEFAULT_COMMAND_END,
+    DEFAULT_COMMAND_START, DEFAULT_MAX_RESPONSE_BYTES,
+};
+use crate::notes::DEFAULT_MEMORY_MAX_BYTES;
+use crate::parser::PARSER_NAMES;
+use crate::redact::DEFAULT_REDACT_PATTERNS;
+use crate::structured;
+use crate::tools::{
+    output_encoding, parse_shell_cmd, running_in_container, CommandOutput, DockerOptions, HostUser,
+    Sandbox, SshRunner, DEFAULT_ERROR_TEMPLATE, DEFAULT_FORWARD_ENV, DEFAULT_MAX_COMMAND_LEN,
+    DEFAULT_OUTPUT_TEMPLATE, DEFAULT_PROBE_TOOLS, DEFAULT_READONLY_DENYLIST,
+    DEFAULT_READ_FILE_MAX_BYTES, DEFAULT_SCRIPT_INTERPRETERS, DEFAULT_SHELL_CMD,
+};
+use clap::{Parser, Subcommand, ValueEnum};
+use regex::Regex;
+use reqwest::header::HeaderMap;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+const DEFAULT_CONFIG_FILE: &str = "crab.toml";
+pub const VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    " (",
+    env!("CRAB_GIT_HASH"),
+    " ",
+    env!("CRAB_BUILD_DATE"),
+    ")"
+);
+pub const DOTENV_FILE: &str = ".env";
+
+// Fills in variables the real environment leaves unset; those always win.
+// A missing file is the normal case and stays silent.
+pub fn load_dotenv(path: &Path) {
+    match dotenvy::from_path(path) {
+        Ok(()) => {}
+        Err(dotenvy::Error::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => eprintln!("Warning: Ignoring {}: {}", path.display(), e),
+    }
+}
+
+// DNS hiccups, dropped connections and apt/dpkg lock contention
+pub const DEFAULT_COMMAND_RETRY_PATTERN: &str =
+    "Temporary failure|Could not get lock|Connection reset by peer|Connection timed out";
+
+// Every setting lives here. Values are layered: built-in defaults, then crab.toml,
+// then environment variables, so the orchestrator's env always wins.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub agent_name: String,
+    pub agent_role: String,
+    pub agent_id: i32,
+    // One of PERSONAS, applied before the rest of the environment; see Persona
+    pub persona: String,
+    // Where commands run; `auto` is the DOCKER_IMAGE container, or the host when
+    // that's empty (also `--executor`)
+    pub executor: ExecutorKind,
+    pub docker_image: String,
+    // Pull DOCKER_IMAGE at startup when it isn't present locally
+    pub docker_auto_pull: bool,
+    // Leave the container running when the run fails, and print how to get into it
+    pub keep_container_on_error: bool,
+    // Program and arguments each command is appended to, on the host and in docker
+    pub shell_cmd: String,
+    // Commands main runs on the host with SHELL_CMD before the agent's first
+    // command and after the run ends, however it ends; a failing pre-hook aborts
+    pub pre_hook: String,
+    pub post_hook: String,
+    // Container caps as docker spells them ("512m", "1.5", "100"); empty leaves them off.
+    // Checked at startup by docker_options().
+    pub docker_memory: String,
+    pub docker_cpus: String,
+    pub docker_pids_limit: String,
+    // Passed as --network, e.g. "none" for no outbound access; empty keeps docker's default
+    pub docker_network: String,
+    // Passed as --user, e.g. "1000:1000" so files written to WORKDIR_MOUNT are
+    // yours; empty keeps the image's user, usually root
+    pub docker_user: String,
+    // Host directory bind-mounted at /workspace, optionally with a `:ro` suffix
+    pub workdir_mount: String,
+    // firejail or bwrap around host commands, see tools::Sandbox; empty runs them bare
+    pub sandbox: String,
+    // Account host commands run as rather than crab's own; needs crab to be root
+    pub exec_as_user: String,
+    // For EXECUTOR=ssh; user and key may be left to ~/.ssh/config
+    pub ssh_host: String,
+    pub ssh_user: String,
+    pub ssh_key: String,
+    pub user_msg: String,
+    // Read when user_msg is empty; piped stdin is the last resort
+    pub user_msg_file: String,
+    // Image files or data: URLs attached to the task (`--image`)
+    pub images: Vec<String>,
+    // TOML table of name = "text", spliced into the task as {{macro:name}}
+    pub macros_file: String,
+    // Fill $NAME placeholders in the task (`--var NAME=value`)
+    pub prompt_vars: HashMap<String, String>,
+    // Replaces the built-in system prompt; see llm::DEFAULT_SYSTEM_PROMPT for placeholders
+    pub system_prompt_file: String,
+    // Markers around commands in free-form replies, also quoted in the system prompt
+    pub command_start: String,
+    pub command_end: String,
+    // Fence tags run as scripts, mapped to their interpreter, e.g. "node" = "node -e".
+    // SCRIPT_INTERPRETERS replaces the map with comma-separated `tag=command` pairs.
+    pub script_interpreters: HashMap<String, String>,
+    pub history: Vec<Message>,
+    pub history_file: String,
+    // Gzip the saved history; always on for a HISTORY_FILE ending in .gz
+    pub compress_history: bool,
+    // Rewritten before each iteration so `--resume` can continue the run; see checkpoint.rs
+    pub checkpoint_file: String,
+    pub max_tokens: u32,
+    // Budgets for turns that run commands and for the final answer; 0 uses
+    // max_tokens. When they differ, the final answer costs one extra call.
+    pub max_tokens_step: u32,
+    pub max_tokens_final: u32,
+    pub provider: Provider,
+    pub model: String,
+    pub base_url: String,
+    // Run through /bin/sh when the key's variable and <VAR>_FILE are both unset;
+    // its stdout is the key, e.g. `pass show openai` (also `--key-command`)
+    pub key_command: String,
+    // Proxy URL for LLM requests only; empty follows HTTP_PROXY/HTTPS_PROXY/NO_PROXY
+    pub llm_proxy: String,
+    // JSONL files of LLM calls: one is appended to, the other answers in place of the API
+    pub llm_record: String,
+    pub llm_replay: String,
+    // Reply script for PROVIDER=mock, see mock.rs
+    pub mock_responses: String,
+    // Comma-separated `Name:Value` pairs added to every LLM request
+    pub extra_headers: String,
+    // Comma-separated `provider[:model]` list tried in order when the main provider
+    // is unreachable or returns 5xx, e.g. "openai:gpt-4o-mini,groq"
+    pub fallback_providers: String,
+    // Model to retry once with when a request overflows the context window, e.g.
+    // "gpt-4o-mini" = "gpt-4o". CONTEXT_FALLBACKS takes comma-separated `from=to` pairs.
+    pub context_fallbacks: HashMap<String, String>,
+    // Only read by the azure provider; an empty api version picks the default
+    pub azure_endpoint: String,
+    pub azure_deployment: String,
+    pub azure_api_version: String,
+    // temperature: 0.0-2.0, top_p: 0.0-1.0; None leaves the provider default
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    // low, medium or high for reasoning models; dropped for models without it
+    pub reasoning_effort: Option<ReasoningEffort>,
+    // Sampling seed for reproducible runs; only OpenAI and Azure are sent it
+    pub seed: Option<u64>,
+    // Sequences that end generation early; empty leaves the provider default
+    pub stop: Vec<String>,
+    // Marks the stable prompt prefix as cacheable where the provider needs asking
+    pub prompt_cache: bool,
+    pub stream: bool,
+    pub llm_max_retries: u32,
+    pub llm_max_retry_after_secs: u64,
+    pub llm_timeout_secs: u64,
+    pub command_timeout_secs: u64,
+    // Reruns of a command that failed transiently, before its result goes back to
+    // the model; 0 disables. A failure is transient when its exit code is listed in
+    // command_retry_exit_codes or its output matches command_retry_pattern.
+    pub command_retries: u32,
+    pub command_retry_exit_codes: Vec<i32>,
+    pub command_retry_pattern: String,
+    pub command_retry_delay_ms: u64,
+    pub max_output_bytes: usize,
+    // Cap on each command-results message as a whole; 0 uses llm::max_feedback_bytes
+    // for the provider and model
+    pub max_feedback_bytes: usize,
+    // Cap on a model response as received; 0 disables it
+    pub max_response_bytes: usize,
+    // How command results and failures are worded for the model; see
+    // tools::DEFAULT_OUTPUT_TEMPLATE for the placeholders. A literal `\n` in the
+    // env var stands for a newline.
+    pub output_template: String,
+    pub error_template: String,
+    // Pass color codes and other escape sequences through to the model untouched
+    pub keep_ansi: bool,
+    // Host variables set for commands; everything else is cleared (passed with -e in docker)
+    pub forward_env: Vec<String>,
+    pub hitl_enabled: bool,
+    pub dry_run: bool,
+    pub approval: ApprovalMode,
+    pub readonly: bool,
+    pub readonly_denylist: Vec<String>,
+    // When set, only commands whose every pipeline stage starts with one of these may
+    // run, readonly or not; empty allows everything
+    pub allowed_commands: Vec<String>,
+    // sudo, doas and su are refused unless this is set
+    pub allow_sudo: bool,
+    // Commands longer than this many bytes are refused; 0 disables the check
+    pub max_command_len: usize,
+    // JSONL file recording every command run or refused, see audit.rs; empty disables
+    pub audit_log: String,
+    // JSONL file getting every LLM request and response body with credentials
+    // masked, see wiretrace.rs; empty disables
+    pub trace_file: String,
+    // Tell the model, and AUDIT_LOG, which files each command added, changed or
+    // deleted: `docker diff` in a container, an mtime scan of the workspace on the host
+    pub watch_changes: bool,
+    // Parse each command with the shell's `-n` first and send a syntax error back
+    // instead of running it; costs a process per command
+    pub syntax_check: bool,
+    // OTLP/HTTP collector that spans are exported to, see telemetry.rs; empty disables
+    pub otel_endpoint: String,
+    // Regexes masked out of command output before it reaches the model. Only set
+    // from crab.toml; API keys are masked even when the list is empty.
+    pub redact_patterns: Vec<String>,
+    // Exit with the last command's non-zero code when the agent finishes
+    pub propagate_exit: bool,
+    // Abort after the same command is requested this many times in a row; 0 disables
+    pub max_repeated_commands: u32,
+    // LLM calls per run. 0 means unbounded: the loop then only ends on a final
+    // answer, a repeat or an error, so a confused model can burn tokens indefinitely.
+    pub max_iterations: u32,
+    // Wall-clock budget for the whole run, in-flight commands included; 0 disables
+    pub session_timeout_secs: u64,
+    // Pause between iterations, before the next LLM call, to pace free-tier keys
+    pub loop_delay_ms: u64,
+    // Estimated prompt tokens to keep under; older turns are dropped. 0 disables.
+    pub context_limit: usize,
+    // Estimated prompt tokens past which older turns are replaced by a model-written
+    // summary, keeping the latest `summarize_keep` messages verbatim. 0 disables;
+    // set it below context_limit so trimming rarely has to kick in.
+    pub summarize_at: usize,
+    pub summarize_keep: usize,
+    pub summary_max_tokens: u32,
+    // Bytes of stdout or stderr past which a command's output is summarized by the
+    // model rather than cut, its full text saved to SCROLLBACK_DIR; 0 disables
+    pub summarize_output_over: usize,
+    // Tools from structured::KNOWN_TOOLS whose stdout the model gets as compact
+    // JSON rather than columns; empty leaves all output as text
+    pub structured_tools: Vec<String>,
+    // Command results older than the latest this many are cut to a one-line
+    // placeholder before each request; 0 keeps them all
+    pub elide_outputs_after: usize,
+    // Print the end-of-run token summary as JSON (also `--json-stats`)
+    pub json_stats: bool,
+    pub output: OutputFormat,
+    // Keep stdout to the final answer: no COMMAND/FILE markers, dry-run lines or
+    // streamed step replies (also `--quiet`)
+    pub quiet: bool,
+    // Colors for commands, answers and errors; see style.rs (also `--color`)
+    pub color: ColorChoice,
+    // Offer commands as an OpenAI-style `run_command` tool instead of parsing them out
+    // of the reply text; providers without tool calling fall back to text
+    pub tool_calling: bool,
+    // Shape of the final answer; command turns are always plain text
+    pub response_format: ResponseFormat,
+    // Which of parser::PARSER_NAMES reads commands and answers out of replies
+    pub response_parser: String,
+    // Which of a command's streams go back to the model; the exit code always does
+    pub feedback_streams: FeedbackStreams,
+    pub safety_level: SafetyLevel,
+    // Echo command output to stderr line by line while it runs
+    pub stream_output: bool,
+    // Charset commands print in, for hosts with a non-UTF-8 locale; transcoded
+    // to UTF-8 before the model sees it
+    pub output_encoding: String,
+    // READ_FILE and WRITE_FILE directives may only reach files under this
+    // directory; relative to the workspace, which is also the default
+    pub read_file_root: String,
+    pub read_file_max_bytes: usize,
+    // Truncated command output is kept whole here for READ_OUTPUT, see
+    // scrollback.rs; empty disables it
+    pub scrollback_dir: String,
+    // Notes kept between runs and shown ahead of the system prompt; empty disables
+    pub memory_file: String,
+    pub memory_max_bytes: usize,
+    // Tell the model its working directory, OS/arch and which of `probe_tools`
+    // are installed. Host commands only; an image is described by its name.
+    pub report_environment: bool,
+    pub probe_tools: Vec<String>,
+    // How many `# parallel` commands from one reply may run at once; 1 keeps
+    // everything sequential
+    pub max_parallel: usize,
+    // Per-model pricing that overrides or extends the built-in table, e.g.
+    // [model_prices."my-local-model"] input_per_1k = 0.0, output_per_1k = 0.0
+    pub model_prices: HashMap<String, ModelPrice>,
+    // The loop stops before a call that could take the estimated spend past
+    // this many USD; 0 disables it
+    pub max_cost_usd: f64,
+    pub interactive: bool,
+    // One task per line, or a JSON array of strings; each runs as its own agent loop
+    pub batch_file: String,
+    // Let batch tasks see the earlier tasks' conversation instead of starting fresh
+    pub batch_shared_history: bool,
+    // Ask for a plan first and run nothing until the operator accepts it
+    pub plan: bool,
+    // Answer from the first reply alone: nothing it asks for is run or fed back
+    pub no_exec: bool,
+    // Values from the environment or flags that couldn't be used; reported by validate
+    #[serde(skip)]
+    pub rejected: Vec<String>,
+    // Settings crab.toml spelled out, which a PERSONA preset leaves alone
+    #[serde(skip)]
+    pub file_keys: Vec<String>,
+}
+
+// `json` prints one RunReport object on stdout at the end and nothing else there
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+// `auto` colors a terminal unless NO_COLOR is set; `always` ignores both
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorChoice {
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ExecutorKind {
+    #[default]
+    Auto,
+    Local,
+    Docker,
+    Ssh,
+}
+
+// `json_object` asks the provider for a JSON final answer and retries once with
+// a correction when the answer doesn't parse
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseFormat {
+    #[default]
+    Text,
+    JsonObject,
+}
+
+// How much safety guidance goes ahead of the system prompt, see llm::SAFETY_PREAMBLE
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SafetyLevel {
+    None,
+    #[default]
+    Default,
+    Strict,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FeedbackStreams {
+    Stdout,
+    Stderr,
+    #[default]
+    Both,
+}
+
+impl FeedbackStreams {
+    // Empties the stream the model isn't sent
+    pub fn keep(self, output: CommandOutput) -> CommandOutput {
+        match self {
+            FeedbackStreams::Stdout => CommandOutput {
+                stderr: String::new(),
+                ..output
+            },
+            FeedbackStreams::Stderr => CommandOutput {
+                stdout: String::new(),
+                ..output
+            },
+            FeedbackStreams::Both => output,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ApprovalMode {
+    #[default]
+    Auto,
+    Manual,
+}
+
+// Settings that go together for one way of working, picked with PERSONA. A preset
+// is applied ahead of the other environment variables, so each of those still
+// overrides its part; a setting crab.toml spells out is kept. None leaves a
+// setting as it was.
+pub struct Persona {
+    pub name: &'static str,
+    pub summary: &'static str,
+    pub temperature: Option<f32>,
+    pub approval: Option<ApprovalMode>,
+    pub readonly: Option<bool>,
+    pub max_tokens_step: Option<u32>,
+}
+
+pub const PERSONAS: &[Persona] = &[
+    Persona {
+        name: "careful",
+        summary: "an auditor: steady replies, short steps, nothing changed or run unasked",
+        temperature: Some(0.1),
+        approval: Some(ApprovalMode::Manual),
+        readonly: Some(true),
+        max_tokens_step: Some(400),
+    },
+    Persona {
+        name: "explorer",
+        summary: "moves fast: livelier replies and commands run without asking",
+        temperature: Some(0.9),
+        approval: Some(ApprovalMode::Auto),
+        readonly: None,
+        max_tokens_step: None,
+    },
+];
+
+impl Persona {
+    pub fn find(name: &str) -> Option<&'static Persona> {
+        PERSONAS.iter().find(|p| p.name == name)
+    }
+
+    // As the environment variables that would set the same things
+    fn settings(&self) -> Vec<String> {
+        let mut settings = Vec::new();
+        if let Some(t) = self.temperature {
+            settings.push(format!("TEMPERATURE={}", t));
+        }
+        if let Some(approval) = self.approval {
+            settings.push(format!(
+                "APPROVAL={}",
+                match approval {
+                    ApprovalMode::Auto => "auto",
+                    ApprovalMode::Manual => "manual",
+                }
+            ));
+        }
+        if let Some(readonly) = self.readonly {
+            settings.push(format!("READONLY={}", readonly));
+        }
+        if let Some(tokens) = self.max_tokens_step {
+            settings.push(format!("MAX_TOKENS_STEP={}", tokens));
+        }
+        settings
+    }
+
+    fn apply(&self, config: &mut Config) {
+        let unset = |key: &str| !config.file_keys.iter().any(|k| k == key);
+        let temperature = self.temperature.filter(|_| unset("temperature"));
+        let approval = self.approval.filter(|_| unset("approval"));
+        let readonly = self.readonly.filter(|_| unset("readonly"));
+        let tokens = self.max_tokens_step.filter(|_| unset("max_tokens_step"));
+        if let Some(t) = temperature {
+            config.temperature = Some(t);
+        }
+        if let Some(approval) = approval {
+            config.approval = approval;
+        }
+        if let Some(readonly) = readonly {
+            config.readonly = readonly;
+        }
+        if let Some(tokens) = tokens {
+            config.max_tokens_step = tokens;
+        }
+    }
+}
+
+// For --list-personas
+pub fn list_personas() -> String {
+    PERSONAS
+        .iter()
+        .map(|p| {
+            format!(
+                "{:<10} {}\n{:<10} {}\n",
+                p.name,
+                p.summary,
+                "",
+                p.settings().join(" ")
+            )
+        })
+        .collect()
+}
+
+// When and how often a failed command is run again, from Config::retry_policy
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub delay: Duration,
+    exit_codes: Vec<i32>,
+    pattern: Option<Regex>,
+}
+
+impl RetryPolicy {
+    // A failed command worth running again, see command_retries
+    pub fn is_transient(&self, output: &CommandOutput) -> bool {
+        output.exit_code != 0
+            && (self.exit_codes.contains(&output.exit_code)
+                || self
+                    .pattern
+                    .as_ref()
+                    .is_some_and(|re| re.is_match(&output.stdout) || re.is_match(&output.stderr)))
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            agent_name: "CrabShell".to_string(),
+            agent_role: "General Assistant".to_string(),
+            agent_id: 0,
+            persona: String::new(),
+            executor: ExecutorKind::Auto,
+            docker_image: "hermit/base".to_string(),
+            docker_auto_pull: true,
+            keep_container_on_error: false,
+            shell_cmd: DEFAULT_SHELL_CMD.join(" "),
+            pre_hook: String::new(),
+            post_hook: String::new(),
+            docker_memory: String::new(),
+            docker_cpus: String::new(),
+            docker_pids_limit: String::new(),
+            docker_network: String::new(),
+            docker_user: String::new(),
+            workdir_mount: String::new(),
+            sandbox: String::new(),
+            exec_as_user: String::new(),
+            ssh_host: String::new(),
+            ssh_user: String::new(),
+            ssh_key: String::new(),
+            user_msg: String::new(),
+            user_msg_file: String::new(),
+            images: Vec::new(),
+            macros_file: String::new(),
+            prompt_vars: HashMap::new(),
+            system_prompt_file: String::new(),
+            command_start: DEFAULT_COMMAND_START.to_string(),
+            command_end: DEFAULT_COMMAND_END.to_string(),
+            script_interpreters: DEFAULT_SCRIPT_INTERPRETERS
+                .iter()
+                .map(|(tag, interpreter)| (tag.to_string(), interpreter.to_string()))
+                .collect(),
+            history: Vec::new(),
+            history_file: String::new(),
+            compress_history: false,
+            checkpoint_file: String::new(),
+            max_tokens: 1000,
+            max_tokens_step: 0,
+            max_tokens_final: 0,
+            provider: Provider::default(),
+            model: String::new(),
+            base_url: String::new(),
+            key_command: String::new(),
+            llm_proxy: String::new(),
+            llm_record: String::new(),
+            llm_replay: String::new(),
+            mock_responses: String::new(),
+            extra_headers: String::new(),
+            fallback_providers: String::new(),
+            context_fallbacks: HashMap::new(),
+            azure_endpoint: String::new(),
+            azure_deployment: String::new(),
+            azure_api_version: String::new(),
+            temperature: None,
+            top_p: None,
+            reasoning_effort: None,
+            seed: None,
+            stop: Vec::new(),
+            prompt_cache: false,
+            stream: false,
+            llm_max_retries: 3,
+            llm_max_retry_after_secs: 60,
+            llm_timeout_secs: 60,
+            command_timeout_secs: 30,
+            command_retries: 0,
+            command_retry_exit_codes: Vec::new(),
+            command_retry_pattern: DEFAULT_COMMAND_RETRY_PATTERN.to_string(),
+            command_retry_delay_ms: 1000,
+            max_output_bytes: 8 * 1024,
+            max_feedback_bytes: 0,
+            max_response_bytes: DEFAULT_MAX_RESPONSE_BYTES,
+            output_template: DEFAULT_OUTPUT_TEMPLATE.to_string(),
+            error_template: DEFAULT_ERROR_TEMPLATE.to_string(),
+            keep_ansi: false,
+            forward_env: DEFAULT_FORWARD_ENV.iter().map(|s| s.to_string()).collect(),
+            hitl_enabled: false,
+            dry_run: false,
+            approval: ApprovalMode::Auto,
+            readonly: false,
+            readonly_denylist: DEFAULT_READONLY_DENYLIST
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            allowed_commands: Vec::new(),
+            allow_sudo: false,
+            max_command_len: DEFAULT_MAX_COMMAND_LEN,
+            audit_log: String::new(),
+            trace_file: String::new(),
+            watch_changes: false,
+            syntax_check: false,
+            otel_endpoint: String::new(),
+            redact_patterns: DEFAULT_REDACT_PATTERNS
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            propagate_exit: false,
+            max_repeated_commands: 3,
+            max_iterations: 5,
+            session_timeout_secs: 0,
+            loop_delay_ms: 0,
+            context_limit: 100_000,
+            summarize_at: 0,
+            elide_outputs_after: 0,
+            summarize_keep: 6,
+            summary_max_tokens: 400,
+            summarize_output_over: 0,
+            structured_tools: Vec::new(),
+            json_stats: false,
+            output: OutputFormat::Text,
+            quiet: false,
+            color: ColorChoice::Auto,
+            tool_calling: false,
+            response_format: ResponseFormat::Text,
+            response_parser: "default".to_string(),
+            feedback_streams: FeedbackStreams::Both,
+            safety_level: SafetyLevel::Default,
+            stream_output: false,
+            output_encoding: "utf-8".to_string(),
+            read_file_root: ".".to_string(),
+            read_file_max_bytes: DEFAULT_READ_FILE_MAX_BYTES,
+            scrollback_dir: String::new(),
+            memory_file: String::new(),
+            memory_max_bytes: DEFAULT_MEMORY_MAX_BYTES,
+            report_environment: false,
+            probe_tools: DEFAULT_PROBE_TOOLS.iter().map(|s| s.to_string()).collect(),
+            max_parallel: 1,
+            model_prices: HashMap::new(),
+            max_cost_usd: 0.0,
+            interactive: false,
+            batch_file: String::new(),
+            batch_shared_history: false,
+            plan: false,
+            no_exec: false,
+            rejected: Vec::new(),
+            file_keys: Vec::new(),
+        }
+    }
+}
+
+// Flags sit on top of everything else: defaults, then crab.toml, then env, then flags.
+// Every setting stays reachable through env alone, which is how the orchestrator runs us.
+#[derive(Debug, Parser)]
+#[command(
+    name = "crab",
+    version = VERSION,
+    about = "An autonomous shell agent for the CrabShell cubicle"
+)]
+pub struct Cli {
+    #[arg(
+        long,
+        env = "CRAB_CONFIG",
+        help = "Path to a crab.toml; ./crab.toml is used when present"
+    )]
+    pub config: Option<String>,
+
+    #[arg(
+        long,
+        env = "PROVIDER",
+        help = "LLM provider (openai, openrouter, anthropic, ...)"
+    )]
+    pub provider: Option<String>,
+
+    #[arg(
+        long,
+        env = "MODEL",
+        help = "Model name; defaults to the provider's default"
+    )]
+    pub model: Option<String>,
+
+    #[arg(long, env = "MAX_TOKENS", help = "Max tokens per completion")]
+    pub max_tokens: Option<u32>,
+
+    #[arg(
+        long,
+        env = "DOCKER_IMAGE",
+        help = "Image to run commands in; empty runs on the host"
+    )]
+    pub docker_image: Option<String>,
+
+    #[arg(
+        long,
+        value_enum,
+        help = "Where commands run: auto, local, docker or ssh (to SSH_HOST)"
+    )]
+    pub executor: Option<ExecutorKind>,
+
+    #[arg(
+        long,
+        env = "MAX_ITERATIONS",
+        help = "LLM calls per run, 0 for unbounded"
+    )]
+    pub max_iterations: Option<u32>,
+
+    #[arg(long, help = "Print commands instead of running them")]
+    pub dry_run: bool,
+
+    #[arg(long, help = "Refuse commands that modify the system")]
+    pub readonly: bool,
+
+    #[arg(long, help = "Report the files each command added, changed or deleted")]
+    pub watch_changes: bool,
+
+    #[arg(
+        long,
+        help = "Check each command's syntax with the shell's -n before running it"
+    )]
+    pub syntax_check: bool,
+
+    #[arg(long, help = "Stream the model's reply as it arrives")]
+    pub stream: bool,
+
+    #[arg(long, help = "Print the end-of-run token summary as JSON")]
+    pub json_stats: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        help = "Output format; json prints a single report object"
+    )]
+    pub output: Option<OutputFormat>,
+
+    #[arg(
+        short,
+        long,
+        help = "Print only the final answer to stdout; progress markers are left out"
+    )]
+    pub quiet: bool,
+
+    #[arg(long, value_enum, help = "Color the output: auto, always or never")]
+    pub color: Option<ColorChoice>,
+
+    #[arg(
+        short,
+        long,
+        help = "Read tasks line by line, keeping the conversation between them"
+    )]
+    pub interactive: bool,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with = "interactive",
+        help = "Run each task in FILE (one per line or a JSON array) and report a summary"
+    )]
+    pub batch: Option<String>,
+
+    #[arg(
+        long,
+        requires = "batch",
+        help = "Carry the conversation from one batch task to the next"
+    )]
+    pub shared_history: bool,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["interactive", "batch"],
+        help = "Show the model's plan and wait for approval before running anything"
+    )]
+    pub plan: bool,
+
+    #[arg(
+        long,
+        value_name = "CHECKPOINT",
+        conflicts_with_all = ["interactive", "batch", "plan", "no_exec", "estimate"],
+        help = "Continue the run saved in CHECKPOINT (see CHECKPOINT_FILE) instead of starting a task"
+    )]
+    pub resume: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "COMMAND",
+        help = "Shell command printing the API key, used when neither its variable nor <VAR>_FILE is set"
+    )]
+    pub key_command: Option<String>,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["interactive", "batch", "plan"],
+        help = "Ask one question and print the answer; commands in it are never run"
+    )]
+    pub no_exec: bool,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["interactive", "batch", "plan", "no_exec"],
+        help = "Print the opening prompt's token count and projected cost, then exit without calling the model"
+    )]
+    pub estimate: bool,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["interactive", "batch", "plan", "no_exec", "estimate"],
+        help = "Print the messages that would be sent to the model, then exit without calling it"
+    )]
+    pub print_prompt: bool,
+
+    #[arg(
+        long = "image",
+        value_name = "PATH",
+        conflicts_with_all = ["interactive", "batch"],
+        help = "Attach an image (png, jpeg, gif, webp) to the task; needs a vision model"
+    )]
+    pub images: Vec<String>,
+
+    #[arg(
+        long = "var",
+        value_name = "NAME=VALUE",
+        help = "Fill $NAME in the task; repeatable"
+    )]
+    pub vars: Vec<String>,
+
+    // For bug reports: prints the build, provider and resolved config, then exits
+    #[arg(long, hide = true)]
+    pub debug_info: bool,
+
+    #[arg(
+        long,
+        help = "Print the PERSONA presets and what each one sets, then exit"
+    )]
+    pub list_personas: bool,
+
+    #[arg(help = "The task for the agent; falls back to USER_MSG, USER_MSG_FILE or stdin")]
+    pub task: Vec<String>,
+
+    #[command(subcommand)]
+    pub command: Option<CliCommand>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum CliCommand {
+    #[command(about = "List the model ids the provider's key can use, then exit")]
+    Models {
+        #[arg(long, help = "Print the ids as a JSON array")]
+        json: bool,
+    },
+}
+
+impl Cli {
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(name) = &self.provider {
+            config.provider = config.parse_provider(name);
+        }
+        if let Some(model) = &self.model {
+            config.model = model.clone();
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            config.max_tokens = max_tokens;
+        }
+        if let Some(image) = &self.docker_image {
+            config.docker_image = image.clone();
+        }
+        if let Some(executor) = self.executor {
+            config.executor = executor;
+        }
+        if let Some(max_iterations) = self.max_iterations {
+            config.max_iterations = max_iterations;
+        }
+        config.dry_run |= self.dry_run;
+        config.readonly |= self.readonly;
+        config.watch_changes |= self.watch_changes;
+        config.syntax_check |= self.syntax_check;
+        config.stream |= self.stream;
+        config.json_stats |= self.json_stats;
+        if let Some(output) = self.output {
+            config.output = output;
+        }
+        config.quiet |= self.quiet;
+        config.images.extend(self.images.iter().cloned());
+        for pair in &self.vars {
+            match pair.split_once('=') {
+                Some((name, value)) if !name.trim().is_empty() => {
+                    config
+                        .prompt_vars
+                        .insert(name.trim().to_string(), value.to_string());
+                }
+                _ => config
+                    .rejected
+                    .push(format!("--var '{}' should be NAME=VALUE", pair)),
+            }
+        }
+        if let Some(color) = self.color {
+            config.color = color;
+        }
+        config.interactive |= self.interactive;
+        if let Some(command) = &self.key_command {
+            config.key_command = command.clone();
+        }
+        if let Some(batch) = &self.batch {
+            config.batch_file = batch.clone();
+        }
+        config.batch_shared_history |= self.shared_history;
+        config.plan |= self.plan;
+        config.no_exec |= self.no_exec;
+        if !self.task.is_empty() {
+            config.user_msg = self.task.join(" ");
+        }
+    }
+}
+
+impl Config {
+    // `--config <path>` beats CRAB_CONFIG, which beats ./crab.toml if present
+    pub fn load(cli: &Cli) -> Result<Self, CrabError> {
+        let mut config = match cli.config.clone() {
+            Some(path) => Self::from_file(&path)?,
+            // An unreadable ./crab.toml is as good as none; a named file isn't
+            None => match fs::read_to_string(DEFAULT_CONFIG_FILE) {
+                Ok(contents) => Self::from_toml(&contents, DEFAULT_CONFIG_FILE)?,
+                Err(_) => Self::default(),
+            },
+        };
+
+        config.apply_env(|key| env::var(key).ok());
+        cli.apply(&mut config);
+        Ok(config)
+    }
+
+    pub fn docker_options(&self) -> Result<DockerOptions, CrabError> {
+        DockerOptions::default()
+            .limits(
+                &self.docker_memory,
+                &self.docker_cpus,
+                &self.docker_pids_limit,
+            )?
+            .network(&self.docker_network)?
+            .user(&self.docker_user)?
+            .mount(&self.workdir_mount)
+    }
+
+    // Rooted at the current directory, which is the one host commands may write to
+    pub fn sandbox_prefix(&self) -> Result<Vec<String>, CrabError> {
+        if self.sandbox.trim().is_empty() {
+            return Ok(Vec::new());
+        }
+        let sandbox = Sandbox::from_name(&self.sandbox).ok_or_else(|| {
+            CrabError::Config(format!(
+                "unknown SANDBOX '{}'; use firejail or bwrap",
+                self.sandbox.trim()
+            ))
+        })?;
+        let cwd = env::current_dir()
+            .map_err(|e| CrabError::Config(format!("SANDBOX needs a working directory: {}", e)))?;
+        sandbox.prefix(&cwd)
+    }
+
+    pub fn host_user(&self) -> Result<Option<HostUser>, CrabError> {
+        if self.exec_as_user.trim().is_empty() {
+            return Ok(None);
+        }
+        HostUser::lookup(&self.exec_as_user).map(Some)
+    }
+
+    pub fn ssh_runner(&self, shell: Vec<String>) -> SshRunner {
+        SshRunner {
+            host: self.ssh_host.clone(),
+            user: self.ssh_user.clone(),
+            key: self.ssh_key.clone(),
+            shell,
+            stream_output: self.stream_output,
+        }
+    }
+
+    pub fn azure_deployment(&self) -> Result<AzureDeployment, CrabError> {
+        AzureDeployment::new(
+            &self.azure_endpoint,
+            &self.azure_deployment,
+            &self.azure_api_version,
+        )
+    }
+
+    pub fn step_max_tokens(&self) -> u32 {
+        if self.max_tokens_step > 0 {
+            self.max_tokens_step
+        } else {
+            self.max_tokens
+        }
+    }
+
+    // command_retries with its pattern compiled, made once per batch
+    pub fn retry_policy(&self) -> RetryPolicy {
+        let pattern = self.command_retry_pattern.trim();
+        RetryPolicy {
+            retries: self.command_retries,
+            delay: Duration::from_millis(self.command_retry_delay_ms),
+            exit_codes: self.command_retry_exit_codes.clone(),
+            pattern: (!pattern.is_empty())
+                .then(|| Regex::new(pattern).ok())
+                .flatten(),
+        }
+    }
+
+    pub fn feedback_limit(&self) -> usize {
+        match self.max_feedback_bytes {
+            0 => max_feedback_bytes(self.provider, self.resolved_model()),
+            limit => limit,
+        }
+    }
+
+    pub fn final_max_tokens(&self) -> u32 {
+        if self.max_tokens_final > 0 {
+            self.max_tokens_final
+        } else {
+            self.max_tokens
+        }
+    }
+
+    pub fn command_delimiters(&self) -> CommandDelimiters {
+        CommandDelimiters::new(&self.command_start, &self.command_end)
+    }
+
+    pub fn extra_headers(&self) -> Result<HeaderMap, CrabError> {
+        parse_extra_headers(&self.extra_headers)
+    }
+
+    // Each fallback's key comes from that provider's usual variable
+    pub fn fallbacks(&self) -> Result<Vec<(Provider, String)>, CrabError> {
+        parse_list(&self.fallback_providers)
+            .iter()
+            .map(|entry| {
+                let (name, model) = entry.split_once(':').unwrap_or((entry, ""));
+                let provider = Provider::from_name(name).ok_or_else(|| {
+                    CrabError::Config(format!("unknown fallback provider '{}'", name))
+                })?;
+                Ok((provider, model.trim().to_string()))
+            })
+            .collect()
+    }
+
+    // MODEL, or the provider's default when it's left unset
+    pub fn resolved_model(&self) -> &str {
+        match self.model.trim() {
+            "" => default_model(self.provider),
+            model => model,
+        }
+    }
+
+    // Where commands will actually run: never Auto, and Local for a dry run (nothing
+    // runs) or when crab is already inside a container
+    pub fn executor_kind(&self) -> ExecutorKind {
+        match self.executor {
+            _ if self.dry_run => ExecutorKind::Local,
+            ExecutorKind::Ssh => ExecutorKind::Ssh,
+            ExecutorKind::Local => ExecutorKind::Local,
+            ExecutorKind::Auto | ExecutorKind::Docker
+                if self.docker_image.is_empty() || running_in_container() =>
+            {
+                ExecutorKind::Local
+            }
+            ExecutorKind::Auto | ExecutorKind::Docker => ExecutorKind::Docker,
+        }
+    }
+
+    // Everything that would stop the run, collected in one pass so a broken
+    // environment can be fixed in one go rather than one error per attempt
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut problems = self.rejected.clone();
+        let mut positive = |key: &str, value: u64| {
+            if value == 0 {
+                problems.push(format!("{} must be greater than 0", key));
+            }
+        };
+        positive("MAX_TOKENS", self.max_tokens.into());
+        positive("LLM_TIMEOUT_SECS", self.llm_timeout_secs);
+        positive("COMMAND_TIMEOUT_SECS", self.command_timeout_secs);
+        positive("MAX_PARALLEL", self.max_parallel as u64);
+        if self.summarize_at > 0 || self.summarize_output_over > 0 {
+            positive("SUMMARY_MAX_TOKENS", self.summary_max_tokens.into());
+        }
+
+        if !self.images.is_empty() && !supports_vision(self.provider, self.resolved_model()) {
+            problems.push(format!(
+                "--image needs a vision model on a chat-format provider; {} model '{}' can't take images",
+                self.provider.name(),
+                self.resolved_model()
+            ));
+        }
+        if !self.sandbox.trim().is_empty() && Sandbox::from_name(&self.sandbox).is_none() {
+            problems.push(format!(
+                "unknown SANDBOX '{}'; use firejail or bwrap",
+                self.sandbox.trim()
+            ));
+        }
+        for tool in &self.structured_tools {
+            if !structured::KNOWN_TOOLS.contains(&tool.as_str()) {
+                problems.push(format!(
+                    "unknown STRUCTURED_TOOLS entry '{}'; use {}",
+                    tool,
+                    structured::KNOWN_TOOLS.join(", ")
+                ));
+            }
+        }
+        if output_encoding(&self.output_encoding).is_none() {
+            problems.push(format!(
+                "unknown OUTPUT_ENCODING '{}'; use a label like utf-8, latin1 or shift_jis",
+                self.output_encoding.trim()
+            ));
+        }
+        for (key, marker) in [
+            ("COMMAND_START", &self.command_start),
+            ("COMMAND_END", &self.command_end),
+        ] {
+            if marker.trim().is_empty() {
+                problems.push(format!("{} must not be empty", key));
+            }
+        }
+        if let Err(e) = Regex::new(&self.command_retry_pattern) {
+            problems.push(format!("invalid COMMAND_RETRY_PATTERN: {}", e));
+        }
+        match self.executor {
+            ExecutorKind::Docker if self.docker_image.is_empty() => {
+                problems.push("EXECUTOR=docker needs a DOCKER_IMAGE".to_string())
+            }
+            ExecutorKind::Ssh if self.ssh_host.is_empty() => {
+                problems.push("EXECUTOR=ssh needs an SSH_HOST".to_string())
+            }
+            _ => {}
+        }
+        if self.max_cost_usd < 0.0 {
+            problems.push("MAX_COST_USD must not be negative".to_string());
+        }
+        // Without a price there'd be nothing to hold the spend to
+        if self.max_cost_usd > 0.0
+            && cost::price_for(self.resolved_model(), &self.model_prices).is_none()
+        {
+            problems.push(format!(
+                "MAX_COST_USD needs a price for model '{}'; add it under [model_prices] in crab.toml",
+                self.resolved_model()
+            ));
+        }
+
+        if !self.llm_record.is_empty() && !self.llm_replay.is_empty() {
+            problems.push("LLM_RECORD and LLM_REPLAY cannot be used together".to_string());
+        }
+        if self.interactive && !self.batch_file.is_empty() {
+            problems.push("interactive mode and BATCH_FILE cannot be used together".to_string());
+        }
+        if self.plan && (self.interactive || !self.batch_file.is_empty()) {
+            problems.push("--plan only works for a single task".to_string());
+        }
+        if self.no_exec && (self.plan || self.interactive || !self.batch_file.is_empty()) {
+            problems.push("--no-exec only works for a single question".to_string());
+        }
+
+        let mut check = |result: Result<(), CrabError>| {
+            if let Err(e) = result {
+                problems.push(e.to_string());
+            }
+        };
+        check(self.docker_options().map(drop));
+        check(self.extra_headers().map(drop));
+        check(parse_proxy(&self.llm_proxy).map(drop));
+        check(parse_shell_cmd(&self.shell_cmd).map(drop));
+        match self.fallbacks() {
+            Ok(fallbacks) => {
+                let uses_azure = std::iter::once(self.provider)
+                    .chain(fallbacks.iter().map(|(provider, _)| *provider))
+                    .any(|provider| provider == Provider::Azure);
+                if uses_azure {
+                    check(self.azure_deployment().map(drop));
+                }
+            }
+            Err(e) => check(Err(e)),
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+
+    // A value that doesn't parse keeps `current` and is reported by validate
+    fn parse_number<T: FromStr + Display + Copy>(&mut self, key: &str, raw: &str, current: T) -> T {
+        raw.trim().parse().unwrap_or_else(|_| {
+            self.rejected
+                .push(format!("{} must be a number, got '{}'", key, raw.trim()));
+            current
+        })
+    }
+
+    fn parse_bool(&mut self, key: &str, raw: &str, current: bool) -> bool {
+        match raw.trim() {
+            "true" | "1" => true,
+            "false" | "0" => false,
+            other => {
+                self.rejected
+                    .push(format!("{} must be true or false, got '{}'", key, other));
+                current
+            }
+        }
+    }
+
+    fn parse_sampling(&mut self, key: &str, raw: &str, max: f32) -> Option<f32> {
+        let value: f32 = match raw.trim().parse() {
+            Ok(v) => v,
+            Err(_) => {
+                self.rejected
+                    .push(format!("{} must be a number, got '{}'", key, raw.trim()));
+                return None;
+            }
+        };
+        if !(0.0..=max).contains(&value) {
+            self.rejected.push(format!(
+                "{} must be between 0 and {}, got {}",
+                key, max, value
+            ));
+            return None;
+        }
+        Some(value)
+    }
+
+    fn parse_provider(&mut self, name: &str) -> Provider {
+        Provider::from_name(name).unwrap_or_else(|| {
+            self.rejected
+                .push(format!("unknown provider '{}'", name.trim()));
+            Provider::default()
+        })
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, CrabError> {
+        let contents = fs::read_to_string(path).map_err(|e| {
+            CrabError::Config(format!("could not read config file {}: {}", path, e))
+        })?;
+        Self::from_toml(&contents, path)
+    }
+
+    pub fn from_toml(contents: &str, source: &str) -> Result<Self, CrabError> {
+        let deserializer = toml::Deserializer::new(contents);
+        let mut config: Self = serde_path_to_error::deserialize(deserializer)
+            .map_err(|e| CrabError::Parse(describe_toml_error(e, contents, source)))?;
+        config.file_keys = contents
+            .parse::<toml::Table>()
+            .map(|table| table.keys().cloned().collect())
+            .unwrap_or_default();
+        Ok(config)
+    }
+
+    // Only variables that are actually set override what's already there
+    pub fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) {
+        let var = |key: &str| var(key).filter(|v| !v.trim().is_empty());
+
+        // First, so every setting below overrides the preset's
+        if let Some(v) = var("PERSONA") {
+            self.persona = v.trim().to_string();
+        }
+        if !self.persona.is_empty() {
+            match Persona::find(&self.persona) {
+                Some(persona) => persona.apply(self),
+                None => self.rejected.push(format!(
+                    "unknown PERSONA '{}'; use {} (see --list-personas)",
+                    self.persona,
+                    PERSONAS
+                        .iter()
+                        .map(|p| p.name)
+                        .collect::<Vec<_>>()
+                        .join(" or ")
+                )),
+            }
+        }
+        if let Some(v) = var("AGENT_NAME") {
+            self.agent_name = v;
+        }
+        if let Some(v) = var("AGENT_ROLE") {
+            self.agent_role = v;
+        }
+        if let Some(v) = var("AGENT_ID") {
+            self.agent_id = self.parse_number("AGENT_ID", &v, self.agent_id);
+        }
+        if let Some(v) = var("EXECUTOR") {
+            self.executor = match v.trim() {
+                "auto" => ExecutorKind::Auto,
+                "local" => ExecutorKind::Local,
+                "docker" => ExecutorKind::Docker,
+                "ssh" => ExecutorKind::Ssh,
+                // Falling back could run commands somewhere other than intended
+                other => {
+                    self.rejected.push(format!(
+                        "unknown EXECUTOR '{}'; use auto, local, docker or ssh",
+                        other
+                    ));
+                    ExecutorKind::Auto
+                }
+            };
+        }
+        if let Some(v) = var("DOCKER_IMAGE") {
+            self.docker_image = v;
+        }
+        if let Some(v) = var("SANDBOX") {
+            self.sandbox = v;
+        }
+        if let Some(v) = var("EXEC_AS_USER") {
+            self.exec_as_user = v;
+        }
+        if let Some(v) = var("SSH_HOST") {
+            self.ssh_host = v;
+        }
+        if let Some(v) = var("SSH_USER") {
+            self.ssh_user = v;
+        }
+        if let Some(v) = var("SSH_KEY") {
+            self.ssh_key = v;
+        }
+        if let Some(v) = var("SHELL_CMD") {
+            self.shell_cmd = v;
+        }
+        if let Some(v) = var("PRE_HOOK") {
+            self.pre_hook = v;
+        }
+        if let Some(v) = var("POST_HOOK") {
+            self.post_hook = v;
+        }
+        if let Some(v) = var("DOCKER_AUTO_PULL") {
+            self.docker_auto_pull = self.parse_bool("DOCKER_AUTO_PULL", &v, self.docker_auto_pull);
+        }
+        if let Some(v) = var("KEEP_CONTAINER_ON_ERROR") {
+            self.keep_container_on_error =
+                self.parse_bool("KEEP_CONTAINER_ON_ERROR", &v, self.keep_container_on_error);
+        }
+        if let Some(v) = var("DOCKER_MEMORY") {
+            self.docker_memory = v;
+        }
+        if let Some(v) = var("DOCKER_CPUS") {
+            self.docker_cpus = v;
+        }
+        if let Some(v) = var("DOCKER_PIDS_LIMIT") {
+            self.docker_pids_limit = v;
+        }
+        if let Some(v) = var("DOCKER_NETWORK") {
+            self.docker_network = v;
+        }
+        if let Some(v) = var("DOCKER_USER") {
+            self.docker_user = v;
+        }
+        if let Some(v) = var("WORKDIR_MOUNT") {
+            self.workdir_mount = v;
+        }
+        if let Some(v) = var("USER_MSG") {
+            self.user_msg = v;
+        }
+        if let Some(v) = var("USER_MSG_FILE") {
+            self.user_msg_file = v;
+        }
+        if let Some(v) = var("MACROS_FILE") {
+            self.macros_file = v;
+        }
+        if let Some(v) = var("SYSTEM_PROMPT_FILE") {
+            self.system_prompt_file = v;
+        }
+        if let Some(v) = var("COMMAND_START") {
+            self.command_start = v;
+        }
+        if let Some(v) = var("COMMAND_END") {
+            self.command_end = v;
+        }
+        if let Some(v) = var("HISTORY_FILE") {
+            self.history_file = v;
+        }
+        if let Some(v) = var("COMPRESS_HISTORY") {
+            self.compress_history = self.parse_bool("COMPRESS_HISTORY", &v, self.compress_history);
+        }
+        if let Some(v) = var("CHECKPOINT_FILE") {
+            self.checkpoint_file = v;
+        }
+        if let Some(v) = var("MAX_TOKENS") {
+            self.max_tokens = self.parse_number("MAX_TOKENS", &v, self.max_tokens);
+        }
+        if let Some(v) = var("MAX_TOKENS_STEP") {
+            self.max_tokens_step = self.parse_number("MAX_TOKENS_STEP", &v, self.max_tokens_step);
+        }
+        if let Some(v) = var("MAX_TOKENS_FINAL") {
+            self.max_tokens_final =
+                self.parse_number("MAX_TOKENS_FINAL", &v, self.max_tokens_final);
+        }
+        if let Some(name) = var("PROVIDER").or_else(|| var("LLM_PROVIDER")) {
+            self.provider = self.parse_provider(&name);
+        }
+        if let Some(v) = var("MODEL").or_else(|| var("LLM_MODEL")) {
+            self.model = v;
+        }
+        if let Some(v) = var("API_BASE_URL") {
+            self.base_url = v;
+        }
+        if let Some(v) = var("KEY_COMMAND") {
+            self.key_command = v;
+        }
+        if let Some(v) = var("LLM_PROXY") {
+            self.llm_proxy = v;
+        }
+        if let Some(v) = var("LLM_RECORD") {
+            self.llm_record = v;
+        }
+        if let Some(v) = var("LLM_REPLAY") {
+            self.llm_replay = v;
+        }
+        if let Some(v) = var("MOCK_RESPONSES") {
+            self.mock_responses = v;
+        }
+        if let Some(v) = var("EXTRA_HEADERS") {
+            self.extra_headers = v;
+        }
+        if let Some(v) = var("FALLBACK_PROVIDERS") {
+            self.fallback_providers = v;
+        }
+        if let Some(v) = var("CONTEXT_FALLBACKS") {
+            self.context_fallbacks = HashMap::new();
+            for pair in parse_list(&v) {
+                match pair.split_once('=') {
+                    Some((from, to)) if !from.trim().is_empty() && !to.trim().is_empty() => {
+                        self.context_fallbacks
+                            .insert(from.trim().to_string(), to.trim().to_string());
+                    }
+                    _ => self.rejected.push(format!(
+                        "CONTEXT_FALLBACKS entries must look like model=larger-model, got '{}'",
+                        pair
+                    )),
+                }
+            }
+        }
+        if let Some(v) = var("AZURE_ENDPOINT") {
+            self.azure_endpoint = v;
+        }
+        if let Some(v) = var("AZURE_DEPLOYMENT") {
+            self.azure_deployment = v;
+        }
+        if let Some(v) = var("AZURE_API_VERSION") {
+            self.azure_api_version = v;
+        }
+        if let Some(v) = var("TEMPERATURE") {
+            self.temperature = self
+                .parse_sampling("TEMPERATURE", &v, 2.0)
+                .or(self.temperature);
+        }
+        if let Some(v) = var("TOP_P") {
+            self.top_p = self.parse_sampling("TOP_P", &v, 1.0).or(self.top_p);
+        }
+        if let Some(v) = var("REASONING_EFFORT") {
+            self.reasoning_effort = match v.trim() {
+                "" => None,
+                value => ReasoningEffort::parse(value).or_else(|| {
+                    self.rejected.push(format!(
+                        "unknown REASONING_EFFORT '{}'; use low, medium or high",
+                        value
+                    ));
+                    None
+                }),
+            };
+        }
+        if let Some(v) = var("SEED") {
+            self.seed = match v.trim() {
+                "" => None,
+                _ => Some(self.parse_number("SEED", &v, 0)),
+            };
+        }
+        if let Some(v) = var("STOP_SEQUENCES") {
+            self.stop = parse_list(&v);
+        }
+        if let Some(v) = var("PROMPT_CACHE") {
+            self.prompt_cache = self.parse_bool("PROMPT_CACHE", &v, self.prompt_cache);
+        }
+        if let Some(v) = var("STREAM") {
+            self.stream = self.parse_bool("STREAM", &v, self.stream);
+        }
+        if let Some(v) = var("LLM_MAX_RETRIES") {
+            self.llm_max_retries = self.parse_number("LLM_MAX_RETRIES", &v, self.llm_max_retries);
+        }
+        if let Some(v) = var("LLM_MAX_RETRY_AFTER_SECS") {
+            self.llm_max_retry_after_secs = self.parse_number(
+                "LLM_MAX_RETRY_AFTER_SECS",
+                &v,
+                self.llm_max_retry_after_secs,
+            );
+        }
+        if let Some(v) = var("LLM_TIMEOUT_SECS") {
+            self.llm_timeout_secs =
+                self.parse_number("LLM_TIMEOUT_SECS", &v, self.llm_timeout_secs);
+        }
+        if let Some(v) = var("COMMAND_TIMEOUT_SECS") {
+            self.command_timeout_secs =
+                self.parse_number("COMMAND_TIMEOUT_SECS", &v, self.command_timeout_secs);
+        }
+        if let Some(v) = var("COMMAND_RETRIES") {
+            self.command_retries = self.parse_number("COMMAND_RETRIES", &v, self.command_retries);
+        }
+        if let Some(v) = var("COMMAND_RETRY_EXIT_CODES") {
+            self.command_retry_exit_codes = Vec::new();
+            for code in parse_list(&v) {
+                match code.parse() {
+                    Ok(code) => self.command_retry_exit_codes.push(code),
+                    Err(_) => self.rejected.push(format!(
+                        "COMMAND_RETRY_EXIT_CODES entries must be exit codes, got '{}'",
+                        code
+                    )),
+                }
+            }
+        }
+        if let Some(v) = var("COMMAND_RETRY_PATTERN") {
+            self.command_retry_pattern = v;
+        }
+        if let Some(v) = var("COMMAND_RETRY_DELAY_MS") {
+            self.command_retry_delay_ms =
+                self.parse_number("COMMAND_RETRY_DELAY_MS", &v, self.command_retry_delay_ms);
+        }
+        if let Some(v) = var("MAX_FEEDBACK_BYTES") {
+            self.max_feedback_bytes =
+                self.parse_number("MAX_FEEDBACK_BYTES", &v, self.max_feedback_bytes);
+        }
+        if let Some(v) = var("MAX_OUTPUT_BYTES") {
+            self.max_output_bytes =
+                self.parse_number("MAX_OUTPUT_BYTES", &v, self.max_output_bytes);
+        }
+        if let Some(v) = var("MAX_RESPONSE_BYTES") {
+            self.max_response_bytes =
+                self.parse_number("MAX_RESPONSE_BYTES", &v, self.max_response_bytes);
+        }
+        if let Some(v) = var("OUTPUT_TEMPLATE") {
+            self.output_template = v.replace("\\n", "\n");
+        }
+        if let Some(v) = var("ERROR_TEMPLATE") {
+            self.error_template = v.replace("\\n", "\n");
+        }
+        if let Some(v) = var("KEEP_ANSI") {
+            self.keep_ansi = self.parse_bool("KEEP_ANSI", &v, self.keep_ansi);
+        }
+        if let Some(v) = var("SCRIPT_INTERPRETERS") {
+            self.script_interpreters = HashMap::new();
+            for pair in parse_list(&v) {
+                match pair.split_once('=') {
+                    Some((tag, interpreter)) if !interpreter.trim().is_empty() => {
+                        self.script_interpreters
+                            .insert(tag.trim().to_lowercase(), interpreter.trim().to_string());
+                    }
+                    _ => self.rejected.push(format!(
+                        "SCRIPT_INTERPRETERS entries must look like tag=command, got '{}'",
+                        pair
+                    )),
+                }
+            }
+        }
+        if let Some(v) = var("FORWARD_ENV") {
+            self.forward_env = parse_list(&v);
+        }
+        if let Some(v) = var("HITL_ENABLED") {
+            self.hitl_enabled = self.parse_bool("HITL_ENABLED", &v, self.hitl_enabled);
+        }
+        if let Some(v) = var("DRY_RUN") {
+            self.dry_run = self.parse_bool("DRY_RUN", &v, self.dry_run);
+        }
+        if let Some(v) = var("APPROVAL") {
+            self.approval = match v.trim() {
+                "manual" => ApprovalMode::Manual,
+                "auto" => ApprovalMode::Auto,
+                other => {
+                    self.rejected
+                        .push(format!("unknown APPROVAL '{}'; use auto or manual", other));
+                    ApprovalMode::Auto
+                }
+            };
+        }
+        if let Some(v) = var("READONLY") {
+            self.readonly = self.parse_bool("READONLY", &v, self.readonly);
+        }
+        // Comma-separated; replaces the list rather than extending it
+        if let Some(v) = var("READONLY_DENYLIST") {
+            self.readonly_denylist = parse_list(&v);
+        }
+        if let Some(v) = var("ALLOWED_COMMANDS") {
+            self.allowed_commands = parse_list(&v);
+        }
+        if let Some(v) = var("ALLOW_SUDO") {
+            self.allow_sudo = self.parse_bool("ALLOW_SUDO", &v, self.allow_sudo);
+        }
+        if let Some(v) = var("MAX_COMMAND_LEN") {
+            self.max_command_len = self.parse_number("MAX_COMMAND_LEN", &v, self.max_command_len);
+        }
+        if let Some(v) = var("AUDIT_LOG") {
+            self.audit_log = v;
+        }
+        if let Some(v) = var("TRACE_FILE") {
+            self.trace_file = v;
+        }
+        if let Some(v) = var("WATCH_CHANGES") {
+            self.watch_changes = self.parse_bool("WATCH_CHANGES", &v, self.watch_changes);
+        }
+        if let Some(v) = var("SYNTAX_CHECK") {
+            self.syntax_check = self.parse_bool("SYNTAX_CHECK", &v, self.syntax_check);
+        }
+        if let Some(v) = var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+            self.otel_endpoint = v.trim().to_string();
+        }
+        if let Some(v) = var("PROPAGATE_EXIT") {
+            self.propagate_exit = self.parse_bool("PROPAGATE_EXIT", &v, self.propagate_exit);
+        }
+        if let Some(v) = var("MAX_REPEATED_COMMANDS") {
+            self.max_repeated_commands =
+                self.parse_number("MAX_REPEATED_COMMANDS", &v, self.max_repeated_commands);
+        }
+        if let Some(v) = var("MAX_COST_USD") {
+            self.max_cost_usd = self.parse_number("MAX_COST_USD", &v, self.max_cost_usd);
+        }
+        if let Some(v) = var("MAX_ITERATIONS") {
+            self.max_iterations = self.parse_number("MAX_ITERATIONS", &v, self.max_iterations);
+        }
+        if let Some(v) = var("LOOP_DELAY_MS") {
+            self.loop_delay_ms = self.parse_number("LOOP_DELAY_MS", &v, self.loop_delay_ms);
+        }
+        if let Some(v) = var("SESSION_TIMEOUT_SECS") {
+            self.session_timeout_secs =
+                self.parse_number("SESSION_TIMEOUT_SECS", &v, self.session_timeout_secs);
+        }
+        if let Some(v) = var("CONTEXT_LIMIT") {
+            self.context_limit = self.parse_number("CONTEXT_LIMIT", &v, self.context_limit);
+        }
+        if let Some(v) = var("SUMMARIZE_AT") {
+            self.summarize_at = self.parse_number("SUMMARIZE_AT", &v, self.summarize_at);
+        }
+        if let Some(v) = var("ELIDE_OUTPUTS_AFTER") {
+            self.elide_outputs_after =
+                self.parse_number("ELIDE_OUTPUTS_AFTER", &v, self.elide_outputs_after);
+        }
+        if let Some(v) = var("SUMMARIZE_KEEP") {
+            self.summarize_keep = self.parse_number("SUMMARIZE_KEEP", &v, self.summarize_keep);
+        }
+        if let Some(v) = var("SUMMARIZE_OUTPUT_OVER") {
+            self.summarize_output_over =
+                self.parse_number("SUMMARIZE_OUTPUT_OVER", &v, self.summarize_output_over);
+        }
+        if let Some(v) = var("SUMMARY_MAX_TOKENS") {
+            self.summary_max_tokens =
+                self.parse_number("SUMMARY_MAX_TOKENS", &v, self.summary_max_tokens);
+        }
+        if let Some(v) = var("JSON_STATS") {
+            self.json_stats = self.parse_bool("JSON_STATS", &v, self.json_stats);
+        }
+        if let Some(v) = var("QUIET") {
+            self.quiet = self.parse_bool("QUIET", &v, self.quiet);
+        }
+        if let Some(v) = var("COLOR") {
+            self.color = match v.trim() {
+                "auto" => ColorChoice::Auto,
+                "always" => ColorChoice::Always,
+                "never" => ColorChoice::Never,
+                other => {
+                    self.rejected.push(format!(
+                        "unknown COLOR '{}'; use auto, always or never",
+                        other
+                    ));
+                    ColorChoice::Auto
+                }
+            };
+        }
+        if let Some(v) = var("TOOL_CALLING") {
+            self.tool_calling = self.parse_bool("TOOL_CALLING", &v, self.tool_calling);
+        }
+        if let Some(v) = var("RESPONSE_PARSER") {
+            if PARSER_NAMES.contains(&v.trim()) {
+                self.response_parser = v.trim().to_string();
+            } else {
+                self.rejected.push(format!(
+                    "unknown RESPONSE_PARSER '{}'; use {}",
+                    v.trim(),
+                    PARSER_NAMES.join(" or ")
+                ));
+            }
+        }
+        if let Some(v) = var("RESPONSE_FORMAT") {
+            self.response_format = match v.trim() {
+                "text" => ResponseFormat::Text,
+                "json_object" => ResponseFormat::JsonObject,
+                // An integration expecting JSON shouldn't silently get prose
+                other => {
+                    self.rejected.push(format!(
+                        "unknown RESPONSE_FORMAT '{}'; use text or json_object",
+                        other
+                    ));
+                    ResponseFormat::Text
+                }
+            };
+        }
+        if let Some(v) = var("FEEDBACK_STREAMS") {
+            self.feedback_streams = match v.trim() {
+                "stdout" => FeedbackStreams::Stdout,
+                "stderr" => FeedbackStreams::Stderr,
+                "both" => FeedbackStreams::Both,
+                other => {
+                    self.rejected.push(format!(
+                        "unknown FEEDBACK_STREAMS '{}'; use stdout, stderr or both",
+                        other
+                    ));
+                    FeedbackStreams::Both
+                }
+            };
+        }
+        if let Some(v) = var("SAFETY_LEVEL") {
+            self.safety_level = match v.trim() {
+                "none" => SafetyLevel::None,
+                "default" => SafetyLevel::Default,
+                "strict" => SafetyLevel::Strict,
+                other => {
+                    self.rejected.push(format!(
+                        "unknown SAFETY_LEVEL '{}'; use none, default or strict",
+                        other
+                    ));
+                    SafetyLevel::Default
+                }
+            };
+        }
+        if let Some(v) = var("STREAM_OUTPUT") {
+            self.stream_output = self.parse_bool("STREAM_OUTPUT", &v, self.stream_output);
+        }
+        if let Some(v) = var("OUTPUT_ENCODING") {
+            self.output_encoding = v;
+        }
+        if let Some(v) = var("READ_FILE_ROOT") {
+            self.read_file_root = v;
+        }
+        if let Some(v) = var("SCROLLBACK_DIR") {
+            self.scrollback_dir = v;
+        }
+        if let Some(v) = var("MAX_PARALLEL") {
+            self.max_parallel = self.parse_number("MAX_PARALLEL", &v, self.max_parallel);
+        }
+        if let Some(v) = var("MEMORY_FILE") {
+            self.memory_file = v;
+        }
+        if let Some(v) = var("MEMORY_MAX_BYTES") {
+            self.memory_max_bytes =
+                self.parse_number("MEMORY_MAX_BYTES", &v, self.memory_max_bytes);
+        }
+        if let Some(v) = var("REPORT_ENVIRONMENT") {
+            self.report_environment =
+                self.parse_bool("REPORT_ENVIRONMENT", &v, self.report_environment);
+        }
+        if let Some(v) = var("STRUCTURED_TOOLS") {
+            self.structured_tools = parse_list(&v);
+        }
+        if let Some(v) = var("PROBE_TOOLS") {
+            self.probe_tools = parse_list(&v);
+        }
+        if let Some(v) = var("READ_FILE_MAX_BYTES") {
+            self.read_file_max_bytes =
+                self.parse_number("READ_FILE_MAX_BYTES", &v, self.read_file_max_bytes);
+        }
+        if let Some(v) = var("OUTPUT") {
+            self.output = match v.trim() {
+                "json" => OutputFormat::Json,
+                "text" => OutputFormat::Text,
+                other => {
+                    self.rejected
+                        .push(format!("unknown OUTPUT '{}'; use text or json", other));
+                    OutputFormat::Text
+                }
+            };
+        }
+    }
+}
+
+fn describe_toml_error(
+    e: serde_path_to_error::Error<toml::de::Error>,
+    contents: &str,
+    source: &str,
+) -> String {
+    let field = e.path().to_string();
+    let inner = e.into_inner();
+    let reason = inner.message().trim().to_string();
+    match (field.as_str(), inner.span()) {
+        (".", _) => format!("{}: {}", source, reason),
+        (_, Some(span)) => {
+            let line = contents[..span.start].matches('\n').count() + 1;
+            format!(
+                "{}: invalid value for `{}` (line {}): {}",
+                source, field, line, reason
+            )
+        }
+        _ => format!("{}: invalid value for `{}`: {}", source, field, reason),
+    }
+}
+
+// Comma-separated, blanks dropped
+fn parse_list(raw: &str) -> Vec<String> {
+    raw.split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn env_from(pairs: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let map: HashMap<String, String> = pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        move |key| map.get(key).cloned()
+    }
+
+    #[test]
+    fn config_round_trips_model() {
+        let config = Config {
+            provider: Provider::OpenAI,
+            model: "gpt-4o-mini".to_string(),
+            ..Config::default()
+        };
+
+        let json = serde_json::to_string(&config).unwrap();
+        let parsed: Config = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.provider, Provider::OpenAI);
+        assert_eq!(parsed.model, "gpt-4o-mini");
+    }
+
+    #[test]
+    fn file_values_override_defaults() {
+        let mut config = Config::from_toml(
+            "provider = \"anthropic\"\nmodel = \"claude-x\"\nmax_tokens = 4000\nreadonly = true\n",
+            "crab.toml",
+        )
+        .unwrap();
+        config.apply_env(env_from(&[]));
+
+        assert_eq!(config.provider, Provider::Anthropic);
+        assert_eq!(config.model, "claude-x");
+        assert_eq!(config.max_tokens, 4000);
+        assert!(config.readonly);
+        assert_eq!(config.agent_name, "CrabShell");
+        assert_eq!(config.command_timeout_secs, 30);
+    }
+
+    #[test]
+    fn model_prices_load_from_file() {
+        let config = Config::from_toml(
+            "[model_prices.\"local-llama\"]\ninput_per_1k = 0.001\noutput_per_1k = 0.002\n",
+            "crab.toml",
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.model_prices.get("local-llama"),
+            Some(&ModelPrice {
+                input_per_1k: 0.001,
+                output_per_1k: 0.002
+            })
+        );
+    }
+
+    #[test]
+    fn env_overrides_file_values() {
+        let mut config =
+            Config::from_toml("model = \"from-file\"\nmax_tokens = 4000\n", "crab.toml").unwrap();
+        config.apply_env(env_from(&[
+            ("LLM_MODEL", "from-env"),
+            ("MAX_TOKENS", "not-a-number"),
+            ("COMMAND_TIMEOUT_SECS", "90"),
+            ("FORWARD_ENV", "HOME, PATH,,KUBECONFIG"),
+            ("STOP_SEQUENCES", "<<END>>,DONE"),
+            ("AZURE_ENDPOINT", "https://crab.openai.azure.com"),
+            ("AZURE_DEPLOYMENT", "gpt4o-prod"),
+        ]));
+
+        assert_eq!(config.model, "from-env");
+        assert_eq!(config.max_tokens, 4000);
+        assert_eq!(config.command_timeout_secs, 90);
+        assert_eq!(config.forward_env, ["HOME", "PATH", "KUBECONFIG"]);
+        assert_eq!(config.stop, ["<<END>>", "DONE"]);
+        let azure = config.azure_deployment().unwrap();
+        assert_eq!(azure.deployment, "gpt4o-prod");
+        assert_eq!(azure.api_version, crate::llm::DEFAULT_AZURE_API_VERSION);
+    }
+
+    #[test]
+    fn validate_reports_every_problem_at_once() {
+        assert!(Config::default().validate().is_ok());
+
+        let mut config = Config::default();
+        config.apply_env(env_from(&[
+            ("MAX_TOKENS", "0"),
+            ("PROVIDER", "opnai"),
+            ("LLM_TIMEOUT_SECS", "sixty"),
+            ("LLM_RECORD", "calls.jsonl"),
+            ("LLM_REPLAY", "calls.jsonl"),
+            ("FALLBACK_PROVIDERS", "groq,nope"),
+        ]));
+        let problems = config.validate().unwrap_err();
+
+        assert_eq!(problems.len(), 5, "{:?}", problems);
+        for expected in [
+            "MAX_TOKENS must be greater than 0",
+            "unknown provider 'opnai'",
+            "LLM_TIMEOUT_SECS must be a number, got 'sixty'",
+            "LLM_RECORD and LLM_REPLAY cannot be used together",
+            "unknown fallback provider 'nope'",
+        ] {
+            assert!(
+                problems.iter().any(|p| p.contains(expected)),
+                "missing {:?} in {:?}",
+                expected,
+                problems
+            );
+        }
+    }
+
+    #[test]
+    fn typos_in_modes_and_switches_are_rejected() {
+        let mut config = Config::default();
+        config.apply_env(env_from(&[
+            ("APPROVAL", "manul"),
+            ("COLOR", "sometimes"),
+            ("OUTPUT", "yaml"),
+            ("TEMPERATURE", "3"),
+            ("TOP_P", "high"),
+            ("DRY_RUN", "yes"),
+            ("READONLY", "1"),
+            ("STREAM", "0"),
+        ]));
+        assert!(config.readonly);
+        assert!(!config.stream);
+        assert_eq!(config.temperature, None);
+
+        let problems = config.validate().unwrap_err();
+        assert_eq!(problems.len(), 6, "{:?}", problems);
+        for expected in [
+            "unknown APPROVAL 'manul'; use auto or manual",
+            "unknown COLOR 'sometimes'",
+            "unknown OUTPUT 'yaml'",
+            "TEMPERATURE must be between 0 and 2, got 3",
+            "TOP_P must be a number, got 'high'",
+            "DRY_RUN must be true or false, got 'yes'",
+        ] {
+            assert!(
+                problems.iter().any(|p| p.contains(expected)),
+                "missing {:?} in {:?}",
+                expected,
+                problems
+            );
+        }
+    }
+
+    #[test]
+    fn empty_command_delimiters_are_rejected() {
+        let config =
+            Config::from_toml("command_start = \"\"\ncommand_end = \" \"\n", "crab.toml").unwrap();
+        assert_eq!(
+            config.validate().unwrap_err(),
+            vec![
+                "COMMAND_START must not be empty".to_string(),
+                "COMMAND_END must not be empty".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn images_need_a_vision_model() {
+        let with_image = |provider: Provider, model: &str| Config {
+            provider,
+            model: model.to_string(),
+            images: vec!["screenshot.png".to_string()],
+            ..Config::default()
+        };
+
+        assert!(with_image(Provider::OpenAI, "gpt-4o").validate().is_ok());
+        let problems = with_image(Provider::DeepSeek, "deepseek-chat")
+            .validate()
+            .unwrap_err();
+        assert_eq!(
+            problems,
+            vec!["--image needs a vision model on a chat-format provider; deepseek model 'deepseek-chat' can't take images"]
+        );
+    }
+
+    #[test]
+    fn output_encoding_must_be_a_known_label() {
+        let mut config = Config::default();
+        config.apply_env(env_from(&[("OUTPUT_ENCODING", "windows-1252")]));
+        assert!(config.validate().is_ok());
+
+        config.apply_env(env_from(&[("OUTPUT_ENCODING", "ebcdic-ish")]));
+        assert_eq!(
+            config.validate().unwrap_err(),
+            vec![
+                "unknown OUTPUT_ENCODING 'ebcdic-ish'; use a label like utf-8, latin1 or shift_jis"
+            ]
+        );
+    }
+
+    #[test]
+    fn response_parser_is_picked_by_name() {
+        let mut config = Config::default();
+        config.apply_env(env_from(&[("RESPONSE_PARSER", "tags")]));
+        assert_eq!(config.response_parser, "tags");
+        assert!(config.validate().is_ok());
+
+        config.apply_env(env_from(&[("RESPONSE_PARSER", "yaml")]));
+        assert_eq!(config.response_parser, "tags");
+        assert_eq!(
+            config.validate().unwrap_err(),
+            vec!["unknown RESPONSE_PARSER 'yaml'; use default or tags"]
+        );
+    }
+
+    #[test]
+    fn structured_tools_must_have_a_parser() {
+        let mut config = Config::default();
+        config.apply_env(env_from(&[("STRUCTURED_TOOLS", "df, ps")]));
+        assert_eq!(config.structured_tools, vec!["df", "ps"]);
+        assert!(config.validate().is_ok());
+
+        config.apply_env(env_from(&[("STRUCTURED_TOOLS", "df,top")]));
+        assert_eq!(
+            config.validate().unwrap_err(),
+            vec!["unknown STRUCTURED_TOOLS entry 'top'; use ls, df, ps, kubectl"]
+        );
+    }
+
+    #[test]
+    fn executor_is_picked_from_config() {
+        let mut config = Config::default();
+        config.apply_env(env_from(&[("EXECUTOR", "ssh")]));
+        assert_eq!(
+            config.validate().unwrap_err(),
+            vec!["EXECUTOR=ssh needs an SSH_HOST"]
+        );
+
+        config.apply_env(env_from(&[
+            ("SSH_HOST", "build-box"),
+            ("SSH_USER", "deploy"),
+        ]));
+        assert!(config.validate().is_ok());
+        assert_eq!(config.executor_kind(), ExecutorKind::Ssh);
+        assert_eq!(config.ssh_runner(vec!["sh".to_string()]).user, "deploy");
+
+        config.dry_run = true;
+        assert_eq!(config.executor_kind(), ExecutorKind::Local);
+
+        let mut config = Config::default();
+        config.apply_env(env_from(&[("EXECUTOR", "sftp")]));
+        assert_eq!(
+            config.validate().unwrap_err(),
+            vec!["unknown EXECUTOR 'sftp'; use auto, local, docker or ssh"]
+        );
+    }
+
+    #[test]
+    fn persona_applies_its_bundle_and_env_vars_override_it() {
+        let mut config = Config::default();
+        config.apply_env(env_from(&[("PERSONA", "careful")]));
+        assert_eq!(config.temperature, Some(0.1));
+        assert_eq!(config.approval, ApprovalMode::Manual);
+        assert!(config.readonly);
+        assert_eq!(config.step_max_tokens(), 400);
+
+        let mut config = Config::default();
+        config.apply_env(env_from(&[
+            ("PERSONA", "careful"),
+            ("APPROVAL", "auto"),
+            ("TEMPERATURE", "0.5"),
+        ]));
+        assert_eq!(config.temperature, Some(0.5));
+        assert_eq!(config.approval, ApprovalMode::Auto);
+        assert!(config.readonly);
+
+        let mut config =
+            Config::from_toml("approval = \"manual\"\nreadonly = false\n", "crab.toml").unwrap();
+        config.apply_env(env_from(&[("PERSONA", "explorer")]));
+        assert_eq!(config.temperature, Some(0.9));
+        assert_eq!(config.approval, ApprovalMode::Manual);
+        config.apply_env(env_from(&[("PERSONA", "careful")]));
+        assert!(!config.readonly);
+        assert_eq!(config.step_max_tokens(), 400);
+
+        let mut config = Config::default();
+        config.apply_env(env_from(&[("PERSONA", "reckless")]));
+        assert_eq!(
+            config.validate().unwrap_err(),
+            vec!["unknown PERSONA 'reckless'; use careful or explorer (see --list-personas)"]
+        );
+        assert!(list_personas().contains("APPROVAL=manual READONLY=true MAX_TOKENS_STEP=400"));
+    }
+
+    #[test]
+    fn reasoning_effort_accepts_the_three_levels() {
+        let mut config = Config::default();
+        config.apply_env(env_from(&[("REASONING_EFFORT", "medium")]));
+        assert_eq!(config.reasoning_effort, Some(ReasoningEffort::Medium));
+
+        let mut config = Config::default();
+        config.apply_env(env_from(&[("REASONING_EFFORT", "max")]));
+        assert_eq!(config.reasoning_effort, None);
+        assert_eq!(
+            config.validate().unwrap_err(),
+            vec!["unknown REASONING_EFFORT 'max'; use low, medium or high"]
+        );
+    }
+
+    #[test]
+    fn fallback_providers_parse_in_order() {
+        let config = Config {
+            fallback_providers: "openai:gpt-4o-mini, groq".to_string(),
+            ..Config::default()
+        };
+        assert_eq!(
+            config.fallbacks().unwrap(),
+            [
+                (Provider::OpenAI, "gpt-4o-mini".to_string()),
+                (Provider::Groq, String::new())
+            ]
+        );
+
+        let config = Config {
+            fallback_providers: "openai,nope".to_string(),
+            ..Config::default()
+        };
+        assert!(config
+            .fallbacks()
+            .unwrap_err()
+            .to_string()
+            .contains("'nope'"));
+    }
+
+    #[test]
+    fn dotenv_fills_in_unset_vars_without_overriding_real_ones() {
+        let dir = std::env::temp_dir().join(format!("crab-dotenv-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(DOTENV_FILE);
+        fs::write(
+            &path,
+            "# only here\nAZURE_API_VERSION=2099-01-01\nAZURE_DEPLOYMENT=from-file\n",
+        )
+        .unwrap();
+        env::set_var("AZURE_DEPLOYMENT", "from-env");
+
+        load_dotenv(&path);
+        load_dotenv(&dir.join("missing.env"));
+        let mut config = Config::default();
+        config.apply_env(|key| env::var(key).ok());
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(config.azure_api_version, "2099-01-01");
+        assert_eq!(config.azure_deployment, "from-env");
+    }
+
+    #[test]
+    fn missing_named_file_is_an_error() {
+        let err = Config::from_file("/definitely/not/here/crab.toml").unwrap_err();
+        assert!(matches!(err, CrabError::Config(_)), "{:?}", err);
+        assert!(err
+            .to_string()
+            .contains("could not read config file /definitely/not/here/crab.toml"));
+    }
+
+    #[test]
+    fn malformed_toml_names_the_field() {
+        let err = Config::from_toml("model = \"x\"\nmax_tokens = \"lots\"\n", "crab.toml")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("`max_tokens`"), "{}", err);
+        assert!(err.contains("line 2"), "{}", err);
+    }
+
+    #[test]
+    fn cli_flags_populate_config() {
+        let cli = Cli::try_parse_from([
+            "crab",
+            "--config",
+            "a.toml",
+            "--provider",
+            "anthropic",
+            "--model",
+            "claude-x",
+            "--max-tokens",
+            "2048",
+            "--docker-image",
+            "",
+            "--dry-run",
+            "--output",
+            "json",
+            "list",
+            "the files",
+        ])
+        .unwrap();
+        let mut config = Config::default();
+        cli.apply(&mut config);
+
+        assert_eq!(cli.config.as_deref(), Some("a.toml"));
+        assert_eq!(config.provider, Provider::Anthropic);
+        assert_eq!(config.model, "claude-x");
+        assert_eq!(config.max_tokens, 2048);
+        assert_eq!(config.docker_image, "");
+        assert!(config.dry_run);
+        assert!(!config.readonly);
+        assert_eq!(config.output, OutputFormat::Json);
+        assert_eq!(config.user_msg, "list the files");
+    }
+
+    #[test]
+    fn cli_without_flags_leaves_config_alone() {
+        let cli = Cli::try_parse_from(["crab"]).unwrap();
+        let mut config = Config {
+            model: "from-env".to_string(),
+            ..Config::default()
+        };
+        cli.apply(&mut config);
+
+        assert_eq!(config.model, "from-env");
+        assert!(config.user_msg.is_empty());
+    }
+
+    #[test]
+    fn models_is_a_subcommand_but_not_inside_a_task() {
+        let cli = Cli::try_parse_from(["crab", "--provider", "groq", "models", "--json"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(CliCommand::Models { json: true })
+        ));
+        assert_eq!(cli.provider.as_deref(), Some("groq"));
+
+        let cli = Cli::try_parse_from(["crab", "list", "the", "models"]).unwrap();
+        assert!(cli.command.is_none());
+        assert_eq!(cli.task, vec!["list", "the", "models"]);
+    }
+}
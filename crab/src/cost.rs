@@ -0,0 +1,109 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ModelPrice {
+    pub input_per_1k: f64,
+    pub output_per_1k: f64,
+}
+
+impl ModelPrice {
+    // For servers that only report a total, split the difference
+    pub fn blended_per_1k(&self) -> f64 {
+        (self.input_per_1k + self.output_per_1k) / 2.0
+    }
+
+    pub fn cost(&self, prompt_tokens: u64, completion_tokens: u64, unsplit_tokens: u64) -> f64 {
+        (prompt_tokens as f64 * self.input_per_1k
+            + completion_tokens as f64 * self.output_per_1k
+            + unsplit_tokens as f64 * self.blended_per_1k())
+            / 1000.0
+    }
+}
+
+// USD per 1K tokens (input, output) for each provider's default model and a few
+// common alternatives. Anything else can be priced via `model_prices` in crab.toml.
+const PRICES: &[(&str, f64, f64)] = &[
+    ("gpt-4o", 0.0025, 0.01),
+    ("gpt-4o-mini", 0.00015, 0.0006),
+    ("gpt-4-turbo", 0.01, 0.03),
+    ("claude-3-5-sonnet-20241022", 0.003, 0.015),
+    ("claude-3-5-haiku-20241022", 0.0008, 0.004),
+    ("anthropic/claude-3.5-sonnet", 0.003, 0.015),
+    ("openai/gpt-4o", 0.0025, 0.01),
+    ("gemini-1.5-pro", 0.00125, 0.005),
+    ("gemini-1.5-flash", 0.000075, 0.0003),
+    ("llama-3.3-70b-versatile", 0.00059, 0.00079),
+    ("mistral-large-latest", 0.002, 0.006),
+    ("deepseek-chat", 0.00014, 0.00028),
+    ("grok-beta", 0.005, 0.015),
+];
+
+pub fn price_for(model: &str, overrides: &HashMap<String, ModelPrice>) -> Option<ModelPrice> {
+    if let Some(price) = overrides.get(model) {
+        return Some(*price);
+    }
+
+    PRICES
+        .iter()
+        .find(|(name, _, _)| *name == model)
+        .map(|(_, input, output)| ModelPrice {
+            input_per_1k: *input,
+            output_per_1k: *output,
+        })
+}
+
+// `unsplit_tokens` are tokens from responses that only reported a total
+pub fn estimate_cost(
+    model: &str,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    unsplit_tokens: u64,
+    overrides: &HashMap<String, ModelPrice>,
+) -> f64 {
+    let Some(price) = price_for(model, overrides) else {
+        eprintln!(
+            "Warning: No pricing known for model '{}', reporting cost as $0",
+            model
+        );
+        return 0.0;
+    };
+
+    price.cost(prompt_tokens, completion_tokens, unsplit_tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_model_cost_uses_input_and_output_rates() {
+        let cost = estimate_cost("gpt-4o", 2000, 1000, 0, &HashMap::new());
+        assert!((cost - 0.015).abs() < 1e-9, "{}", cost);
+    }
+
+    #[test]
+    fn totals_without_a_split_use_the_blended_rate() {
+        let cost = estimate_cost("gpt-4o", 0, 0, 1000, &HashMap::new());
+        assert!((cost - 0.00625).abs() < 1e-9, "{}", cost);
+    }
+
+    #[test]
+    fn overrides_and_unknown_models() {
+        let mut overrides = HashMap::new();
+        overrides.insert(
+            "local-llama".to_string(),
+            ModelPrice {
+                input_per_1k: 0.001,
+                output_per_1k: 0.002,
+            },
+        );
+
+        let cost = estimate_cost("local-llama", 1000, 1000, 0, &overrides);
+        assert!((cost - 0.003).abs() < 1e-9, "{}", cost);
+        assert_eq!(
+            estimate_cost("mystery-model", 1000, 1000, 0, &overrides),
+            0.0
+        );
+    }
+}
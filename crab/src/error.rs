@@ -0,0 +1,186 @@
+use std::fmt;
+use std::time::Duration;
+
+// One error type for the LLM and command layers, so callers can match on the
+// kind of failure instead of parsing message strings
+#[derive(Debug)]
+pub enum CrabError {
+    Http(reqwest::Error),
+    Stream(String),
+    Api {
+        status: u16,
+        body: String,
+    },
+    Auth(String),
+    Timeout(Duration),
+    // `hint` says why it likely stalled, e.g. waiting on a question
+    CommandTimeout {
+        after: Duration,
+        partial: String,
+        hint: Option<&'static str>,
+    },
+    CommandFailed {
+        code: i32,
+    },
+    Exec(String),
+    Parse(String),
+    Config(String),
+    // The model's reply outgrew MAX_RESPONSE_BYTES and the request was abandoned
+    ResponseTooLarge {
+        limit: usize,
+    },
+    // Refused by a sandbox rule rather than failed
+    Policy(String),
+    // No SessionPool slot came free in time
+    Busy {
+        waited: Duration,
+        max: usize,
+    },
+}
+
+impl fmt::Display for CrabError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CrabError::Http(e) => write!(f, "request failed: {}", e),
+            CrabError::Stream(msg) => write!(f, "stream interrupted: {}", msg),
+            CrabError::Api { status, body } => write!(f, "API error ({}): {}", status, body),
+            CrabError::Auth(detail) => write!(f, "authentication failed: {}", detail),
+            CrabError::Timeout(after) => write!(f, "timed out after {}s", after.as_secs()),
+            CrabError::CommandTimeout {
+                after,
+                partial,
+                hint,
+            } => {
+                write!(f, "command timed out after {}s", after.as_secs())?;
+                if !partial.trim().is_empty() {
+                    write!(f, "\nPartial output before timeout:\n{}", partial)?;
+                }
+                if let Some(hint) = hint {
+                    write!(f, "\n{}", hint)?;
+                }
+                Ok(())
+            }
+            CrabError::CommandFailed { code } => write!(f, "command exited with code {}", code),
+            CrabError::Exec(msg) => write!(f, "{}", msg),
+            CrabError::Parse(msg) => write!(f, "parse error: {}", msg),
+            CrabError::Config(msg) => write!(f, "invalid configuration: {}", msg),
+            CrabError::ResponseTooLarge { limit } => write!(
+                f,
+                "response exceeded {} bytes (MAX_RESPONSE_BYTES), request aborted",
+                limit
+            ),
+            CrabError::Policy(msg) => write!(f, "blocked by policy: {}", msg),
+            CrabError::Busy { waited, max } => write!(
+                f,
+                "no session slot free after {}s ({} already running)",
+                waited.as_secs(),
+                max
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CrabError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CrabError::Http(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for CrabError {
+    fn from(e: reqwest::Error) -> Self {
+        CrabError::Http(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_text_for_each_variant() {
+        let http = reqwest::blocking::Client::new()
+            .get("not a url")
+            .send()
+            .unwrap_err();
+        assert!(CrabError::Http(http)
+            .to_string()
+            .starts_with("request failed: "));
+
+        let cases = [
+            (
+                CrabError::Stream("connection reset".to_string()),
+                "stream interrupted: connection reset",
+            ),
+            (
+                CrabError::Api {
+                    status: 503,
+                    body: "overloaded".to_string(),
+                },
+                "API error (503): overloaded",
+            ),
+            (
+                CrabError::Auth("401 Unauthorized: bad key".to_string()),
+                "authentication failed: 401 Unauthorized: bad key",
+            ),
+            (
+                CrabError::Timeout(Duration::from_secs(60)),
+                "timed out after 60s",
+            ),
+            (
+                CrabError::CommandTimeout {
+                    after: Duration::from_secs(5),
+                    partial: "tick\n".to_string(),
+                    hint: None,
+                },
+                "command timed out after 5s\nPartial output before timeout:\ntick\n",
+            ),
+            (
+                CrabError::CommandTimeout {
+                    after: Duration::from_secs(5),
+                    partial: String::new(),
+                    hint: None,
+                },
+                "command timed out after 5s",
+            ),
+            (
+                CrabError::CommandTimeout {
+                    after: Duration::from_secs(5),
+                    partial: "Continue? [Y/n] ".to_string(),
+                    hint: Some("[hint] answer it up front"),
+                },
+                "command timed out after 5s\nPartial output before timeout:\nContinue? [Y/n] \n[hint] answer it up front",
+            ),
+            (
+                CrabError::CommandFailed { code: 2 },
+                "command exited with code 2",
+            ),
+            (
+                CrabError::Exec("Empty command".to_string()),
+                "Empty command",
+            ),
+            (
+                CrabError::Parse("no response from API".to_string()),
+                "parse error: no response from API",
+            ),
+            (
+                CrabError::Config("DOCKER_CPUS must be a positive number".to_string()),
+                "invalid configuration: DOCKER_CPUS must be a positive number",
+            ),
+            (
+                CrabError::ResponseTooLarge { limit: 4096 },
+                "response exceeded 4096 bytes (MAX_RESPONSE_BYTES), request aborted",
+            ),
+            (
+                CrabError::Policy("/etc/passwd is outside /app/workspace".to_string()),
+                "blocked by policy: /etc/passwd is outside /app/workspace",
+            ),
+        ];
+
+        for (err, expected) in cases {
+            assert_eq!(err.to_string(), expected);
+        }
+    }
+}
@@ -0,0 +1,38 @@
+use std::fmt;
+use std::sync::mpsc::Sender;
+
+// Milestones of a run, for embedders that want progress without parsing stdout
+#[derive(Debug, Clone, PartialEq)]
+pub enum AgentEvent {
+    IterationStarted {
+        iteration: u32,
+    },
+    CommandProposed {
+        command: String,
+    },
+    // None when the command never produced an exit code, e.g. it timed out
+    CommandFinished {
+        command: String,
+        exit_code: Option<i32>,
+    },
+    FinalAnswer {
+        answer: String,
+    },
+}
+
+pub trait EventSink {
+    fn event(&self, event: AgentEvent);
+}
+
+// A receiver that has gone away just stops hearing about the run
+impl EventSink for Sender<AgentEvent> {
+    fn event(&self, event: AgentEvent) {
+        let _ = self.send(event);
+    }
+}
+
+impl fmt::Debug for dyn EventSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EventSink")
+    }
+}
@@ -0,0 +1,280 @@
+use crate::error::CrabError;
+use crate::tools::{shell_quote, CommandOutput};
+
+// `GIT: <op> [args]` on its own line. Commands run through the same runner and
+// working directory as shell commands, so this works inside the container too.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GitAction {
+    Status,
+    Diff(Option<String>),
+    Add(Vec<String>),
+    Commit(String),
+}
+
+impl GitAction {
+    // Add and commit change the repository, so approval and readonly apply to them
+    pub fn modifies(&self) -> bool {
+        matches!(self, GitAction::Add(_) | GitAction::Commit(_))
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            GitAction::Status => "git status".to_string(),
+            GitAction::Diff(None) => "git diff".to_string(),
+            GitAction::Diff(Some(path)) => format!("git diff {}", path),
+            GitAction::Add(paths) => format!("git add {}", paths.join(" ")),
+            GitAction::Commit(message) => format!("git commit -m {}", shell_quote(message)),
+        }
+    }
+}
+
+pub fn extract_git_action(response: &str) -> Option<GitAction> {
+    let line = response
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("GIT:"))?
+        .trim();
+    let (op, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let rest = rest.trim();
+    match op {
+        "status" => Some(GitAction::Status),
+        "diff" => Some(GitAction::Diff(
+            (!rest.is_empty()).then(|| rest.to_string()),
+        )),
+        "add" if !rest.is_empty() => Some(GitAction::Add(
+            rest.split_whitespace().map(|p| p.to_string()).collect(),
+        )),
+        "commit" if !rest.is_empty() => Some(GitAction::Commit(
+            rest.trim_matches(|c| c == '"' || c == '\'').to_string(),
+        )),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileChange {
+    pub path: String,
+    // What `git commit` would record, and what's changed on top of that
+    pub staged: Option<&'static str>,
+    pub unstaged: Option<&'static str>,
+}
+
+fn status_word(code: char) -> Option<&'static str> {
+    match code {
+        'M' => Some("modified"),
+        'A' => Some("added"),
+        'D' => Some("deleted"),
+        'R' => Some("renamed"),
+        'C' => Some("copied"),
+        'T' => Some("type changed"),
+        'U' => Some("unmerged"),
+        '?' => Some("untracked"),
+        _ => None,
+    }
+}
+
+// `git status --porcelain` v1: two status columns, a space, then the path
+// (`old -> new` for renames)
+pub fn parse_porcelain(output: &str) -> Vec<FileChange> {
+    output
+        .lines()
+        .filter(|line| line.len() > 3)
+        .map(|line| {
+            let mut codes = line.chars();
+            let index = codes.next().unwrap_or(' ');
+            let worktree = codes.next().unwrap_or(' ');
+            let path = line[3..].trim_matches('"').to_string();
+            if index == '?' {
+                return FileChange {
+                    path,
+                    staged: None,
+                    unstaged: Some("untracked"),
+                };
+            }
+            FileChange {
+                path,
+                staged: status_word(index),
+                unstaged: status_word(worktree),
+            }
+        })
+        .collect()
+}
+
+fn format_changes(changes: &[FileChange]) -> String {
+    if changes.is_empty() {
+        return "working tree clean\n".to_string();
+    }
+    let mut text = format!("{} changed file(s):\n", changes.len());
+    for change in changes {
+        let mut states = Vec::new();
+        if let Some(staged) = change.staged {
+            states.push(format!("staged {}", staged));
+        }
+        if let Some(unstaged) = change.unstaged {
+            states.push(unstaged.to_string());
+        }
+        text.push_str(&format!("  {} ({})\n", change.path, states.join(", ")));
+    }
+    text
+}
+
+fn git(
+    run: &mut dyn FnMut(&str) -> Result<CommandOutput, CrabError>,
+    args: &str,
+) -> Result<String, CrabError> {
+    let output = run(&format!("git --no-pager {}", args))?;
+    if output.exit_code != 0 {
+        let detail = if output.stderr.trim().is_empty() {
+            output.stdout.trim()
+        } else {
+            output.stderr.trim()
+        };
+        return Err(CrabError::Exec(format!(
+            "git {} failed: {}",
+            args.split_whitespace().next().unwrap_or_default(),
+            detail
+        )));
+    }
+    Ok(output.stdout)
+}
+
+pub fn status(
+    run: &mut dyn FnMut(&str) -> Result<CommandOutput, CrabError>,
+) -> Result<Vec<FileChange>, CrabError> {
+    git(run, "status --porcelain").map(|out| parse_porcelain(&out))
+}
+
+// Runs the action and returns the feedback for the model: a parsed summary, plus
+// the patch itself for diffs
+pub fn run_action(
+    action: &GitAction,
+    run: &mut dyn FnMut(&str) -> Result<CommandOutput, CrabError>,
+) -> Result<String, CrabError> {
+    match action {
+        GitAction::Status => Ok(format!("GIT_STATUS: {}", format_changes(&status(run)?))),
+        GitAction::Diff(path) => {
+            let target = match path {
+                Some(path) => format!(" -- {}", shell_quote(path)),
+                None => String::new(),
+            };
+            let numstat = git(run, &format!("diff HEAD --numstat{}", target))
+                .or_else(|_| git(run, &format!("diff --numstat{}", target)))?;
+            let (mut added, mut removed, mut files) = (0, 0, 0);
+            for line in numstat.lines() {
+                let mut cols = line.split_whitespace();
+                added += cols.next().and_then(|n| n.parse::<u64>().ok()).unwrap_or(0);
+                removed += cols.next().and_then(|n| n.parse::<u64>().ok()).unwrap_or(0);
+                files += 1;
+            }
+            let patch = git(run, &format!("diff HEAD{}", target))
+                .or_else(|_| git(run, &format!("diff{}", target)))?;
+            Ok(format!(
+                "GIT_DIFF: {} file(s), +{} -{}\n{}",
+                files, added, removed, patch
+            ))
+        }
+        GitAction::Add(paths) => {
+            let quoted: Vec<String> = paths.iter().map(|p| shell_quote(p)).collect();
+            git(run, &format!("add -- {}", quoted.join(" ")))?;
+            Ok(format!(
+                "GIT_ADD: staged {}\n{}",
+                paths.join(", "),
+                format_changes(&status(run)?)
+            ))
+        }
+        GitAction::Commit(message) => {
+            let out = git(run, &format!("commit -m {}", shell_quote(message)))?;
+            // "[main 1a2b3c4] message" or "[main (root-commit) 1a2b3c4] message"
+            let head = out.lines().next().unwrap_or_default();
+            let summary = head
+                .strip_prefix('[')
+                .and_then(|h| h.split_once(']'))
+                .map(|(inside, _)| inside.replace(" (root-commit)", ""))
+                .unwrap_or_else(|| head.to_string());
+            let (branch, hash) = summary.rsplit_once(' ').unwrap_or(("", &summary));
+            Ok(format!("GIT_COMMIT: {} on {}", hash, branch))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::{Executor, HostRunner};
+    use std::fs;
+    use std::time::Duration;
+
+    fn temp_repo(
+        name: &str,
+    ) -> (
+        std::path::PathBuf,
+        impl FnMut(&str) -> Result<CommandOutput, CrabError>,
+    ) {
+        let dir = std::env::temp_dir().join(format!("crab-git-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let cd = shell_quote(dir.to_str().unwrap());
+        let run = move |cmd: &str| {
+            HostRunner::default().run(&format!("cd {} && {}", cd, cmd), Duration::from_secs(10))
+        };
+        for setup in [
+            "git init -q",
+            "git config user.email crab@example.com",
+            "git config user.name Crab",
+        ] {
+            assert_eq!(run(setup).unwrap().exit_code, 0, "{}", setup);
+        }
+        (dir, run)
+    }
+
+    #[test]
+    fn porcelain_status_is_parsed_per_file() {
+        let changes = parse_porcelain(
+            " M src/main.rs\nA  new.rs\nMM both.rs\n?? notes.txt\nR  old.rs -> moved.rs\n",
+        );
+
+        assert_eq!(changes.len(), 5);
+        assert_eq!(changes[0].staged, None);
+        assert_eq!(changes[0].unstaged, Some("modified"));
+        assert_eq!(changes[1].staged, Some("added"));
+        assert_eq!(changes[2].staged, Some("modified"));
+        assert_eq!(changes[2].unstaged, Some("modified"));
+        assert_eq!(changes[3].unstaged, Some("untracked"));
+        assert_eq!(changes[4].path, "old.rs -> moved.rs");
+        assert_eq!(
+            extract_git_action("Checking.\nGIT: add src/main.rs notes.txt"),
+            Some(GitAction::Add(vec![
+                "src/main.rs".to_string(),
+                "notes.txt".to_string()
+            ]))
+        );
+        assert_eq!(extract_git_action("GIT: push"), None);
+    }
+
+    #[test]
+    fn status_and_staging_in_a_real_repo() {
+        let (dir, mut run) = temp_repo("stage");
+        fs::write(dir.join("a.txt"), "one\n").unwrap();
+        fs::write(dir.join("b.txt"), "two\n").unwrap();
+
+        let before = status(&mut run).unwrap();
+        let added = run_action(&GitAction::Add(vec!["a.txt".to_string()]), &mut run).unwrap();
+        let after = status(&mut run).unwrap();
+        let committed = run_action(&GitAction::Commit("Add a".to_string()), &mut run).unwrap();
+        let remaining = run_action(&GitAction::Status, &mut run).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+
+        assert!(before.iter().all(|c| c.unstaged == Some("untracked")));
+        assert_eq!(before.len(), 2);
+        assert!(added.starts_with("GIT_ADD: staged a.txt\n2 changed file(s):\n"));
+        assert!(after.contains(&FileChange {
+            path: "a.txt".to_string(),
+            staged: Some("added"),
+            unstaged: None,
+        }));
+        assert!(committed.starts_with("GIT_COMMIT: "));
+        assert_eq!(
+            remaining,
+            "GIT_STATUS: 1 changed file(s):\n  b.txt (untracked)\n"
+        );
+    }
+}
@@ -1,370 +1,2160 @@
-use reqwest::blocking::Client;
+use crate::cassette::Cassette;
+use crate::error::CrabError;
+use crate::mock::{self, MockScript};
+use crate::tools::script_command;
+use crate::wiretrace;
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{NoProxy, Proxy, StatusCode};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::collections::HashMap;
 use std::env;
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const DEFAULT_MAX_RETRIES: u32 = 3;
+const DEFAULT_BASE_DELAY_MS: u64 = 500;
+const DEFAULT_MAX_RETRY_AFTER_SECS: u64 = 60;
+const DEFAULT_TIMEOUT_SECS: u64 = 60;
+// Far beyond any real completion, small enough that a runaway one can't eat memory
+pub const DEFAULT_MAX_RESPONSE_BYTES: usize = 1024 * 1024;
+
+// Tool-calling fields stay empty, and off the wire, for plain text turns
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct Message {
     pub role: String,
     pub content: String,
+    // Assistant turns that ask for tools
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    // `tool` turns: which call this answers, and the tool's name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    // Data URLs attached to a user turn, see image_data_url
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<String>,
+    // Set on turns carrying command results: the last command's exit code, for
+    // elide_stale_outputs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
 }
 
+// A Message as chat-format providers want it: text alone stays a plain string,
+// text with images becomes OpenAI's multimodal content array
 #[derive(Debug, Serialize)]
-struct ChatRequest {
-    model: String,
-    messages: Vec<Message>,
+struct ChatMessage {
+    role: String,
+    content: ChatContent,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<ToolCall>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    max_tokens: Option<u32>,
+    tool_call_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
-struct ChatResponse {
-    choices: Vec<Choice>,
-    usage: Option<Usage>,
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum ChatContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
 }
 
-#[derive(Debug, Deserialize)]
-struct Choice {
-    message: ResponseMessage,
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
 }
 
-#[derive(Debug, Deserialize)]
-struct ResponseMessage {
-    content: String,
+#[derive(Debug, Serialize)]
+struct ImageUrl {
+    url: String,
 }
 
-#[derive(Debug, Deserialize)]
-struct Usage {
-    #[serde(rename = "total_tokens")]
-    total_tokens: Option<u32>,
+impl From<&Message> for ChatMessage {
+    fn from(message: &Message) -> Self {
+        let content = if message.images.is_empty() {
+            ChatContent::Text(message.content.clone())
+        } else {
+            let text = ContentPart::Text {
+                text: message.content.clone(),
+            };
+            let images = message.images.iter().map(|url| ContentPart::ImageUrl {
+                image_url: ImageUrl { url: url.clone() },
+            });
+            ChatContent::Parts(std::iter::once(text).chain(images).collect())
+        };
+        ChatMessage {
+            role: message.role.clone(),
+            content,
+            tool_calls: message.tool_calls.clone(),
+            tool_call_id: message.tool_call_id.clone(),
+            name: message.name.clone(),
+        }
+    }
 }
 
-#[derive(Clone)]
-pub struct LLMClient {
-    client: Client,
-    api_key: String,
-    provider: String,
-    model: String,
+// `--image` takes a file, sent inline as base64, or a data: URL passed as is
+pub fn image_data_url(source: &str) -> Result<String, CrabError> {
+    use base64::Engine;
+    if source.starts_with("data:image/") {
+        return Ok(source.to_string());
+    }
+    let extension = Path::new(source)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+    let mime = match extension.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        _ => {
+            return Err(CrabError::Config(format!(
+                "--image {}: only png, jpeg, gif and webp images can be attached",
+                source
+            )))
+        }
+    };
+    let bytes =
+        fs::read(source).map_err(|e| CrabError::Config(format!("--image {}: {}", source, e)))?;
+    Ok(format!(
+        "data:{};base64,{}",
+        mime,
+        base64::engine::general_purpose::STANDARD.encode(bytes)
+    ))
 }
 
-pub fn get_provider_config(provider: &str) -> (&'static str, &'static str) {
+// Images only go out in the chat format's content array, so Anthropic and Google
+// are left out along with models that can't see
+pub fn supports_vision(provider: Provider, model: &str) -> bool {
+    let model = model.to_lowercase();
+    let family = |names: &[&str]| names.iter().any(|name| model.contains(name));
     match provider {
-        "openai" => ("https://api.openai.com/v1/chat/completions", "Bearer"),
-        "anthropic" => ("https://api.anthropic.com/v1/messages", "x-api-key"),
-        "google" => (
-            "https://generativelanguage.googleapis.com/v1beta/models",
-            "Key",
-        ),
-        "groq" => ("https://api.groq.com/openai/v1/chat/completions", "Bearer"),
-        "openrouter" => ("https://openrouter.ai/api/v1/chat/completions", "Bearer"),
-        "mistral" => ("https://api.mistral.ai/v1/chat/completions", "Bearer"),
-        "deepseek" => ("https://api.deepseek.com/v1/chat/completions", "Bearer"),
-        "xai" => ("https://api.x.ai/v1/chat/completions", "Bearer"),
-        _ => ("https://openrouter.ai/api/v1/chat/completions", "Bearer"),
+        Provider::Mock => true,
+        Provider::OpenAI | Provider::Azure => family(&[
+            "gpt-4o",
+            "gpt-4-turbo",
+            "gpt-4.1",
+            "gpt-5",
+            "o1",
+            "o3",
+            "o4",
+        ]),
+        Provider::OpenRouter => family(&[
+            "gpt-4o", "gpt-4.1", "claude-3", "gemini", "vision", "pixtral", "llava",
+        ]),
+        Provider::Groq | Provider::Mistral | Provider::Xai | Provider::DeepSeek => {
+            family(&["vision", "pixtral", "llava"])
+        }
+        Provider::Anthropic | Provider::Google => false,
     }
 }
 
-impl LLMClient {
-    pub fn new() -> Self {
-        let provider = env::var("LLM_PROVIDER").unwrap_or_else(|_| "openrouter".to_string());
-
-        let (api_key, model) = match provider.as_str() {
-            "openai" => (
-                env::var("OPENAI_API_KEY")
-                    .or_else(|_| env::var("LLM_API_KEY"))
-                    .unwrap_or_default(),
-                env::var("LLM_MODEL").unwrap_or_else(|_| "gpt-4o".to_string()),
-            ),
-            "anthropic" => (
-                env::var("ANTHROPIC_API_KEY")
-                    .or_else(|_| env::var("LLM_API_KEY"))
-                    .unwrap_or_default(),
-                env::var("LLM_MODEL").unwrap_or_else(|_| "claude-3-5-sonnet-20241022".to_string()),
-            ),
-            "google" => (
-                env::var("GOOGLE_API_KEY")
-                    .or_else(|_| env::var("LLM_API_KEY"))
-                    .unwrap_or_default(),
-                env::var("LLM_MODEL").unwrap_or_else(|_| "gemini-1.5-pro".to_string()),
-            ),
-            "groq" => (
-                env::var("GROQ_API_KEY")
-                    .or_else(|_| env::var("LLM_API_KEY"))
-                    .unwrap_or_default(),
-                env::var("LLM_MODEL").unwrap_or_else(|_| "llama-3.3-70b-versatile".to_string()),
-            ),
-            "openrouter" => (
-                env::var("OPENROUTER_API_KEY")
-                    .or_else(|_| env::var("LLM_API_KEY"))
-                    .unwrap_or_default(),
-                env::var("LLM_MODEL").unwrap_or_else(|_| "anthropic/claude-3.5-sonnet".to_string()),
-            ),
-            "mistral" => (
-                env::var("MISTRAL_API_KEY")
-                    .or_else(|_| env::var("LLM_API_KEY"))
-                    .unwrap_or_default(),
-                env::var("LLM_MODEL").unwrap_or_else(|_| "mistral-large-latest".to_string()),
-            ),
-            "deepseek" => (
-                env::var("DEEPSEEK_API_KEY")
-                    .or_else(|_| env::var("LLM_API_KEY"))
-                    .unwrap_or_default(),
-                env::var("LLM_MODEL").unwrap_or_else(|_| "deepseek-chat".to_string()),
-            ),
-            "xai" => (
-                env::var("XAI_API_KEY")
-                    .or_else(|_| env::var("LLM_API_KEY"))
-                    .unwrap_or_default(),
-                env::var("LLM_MODEL").unwrap_or_else(|_| "grok-beta".to_string()),
-            ),
-            _ => (
-                env::var("LLM_API_KEY").unwrap_or_default(),
-                env::var("LLM_MODEL").unwrap_or_else(|_| "auto".to_string()),
-            ),
-        };
+// Largest command-results message worth sending in one turn. Whole requests are
+// capped per provider, and small-context models hit it sooner, so this keeps one
+// noisy command from turning the next call into a 400.
+pub fn max_feedback_bytes(provider: Provider, model: &str) -> usize {
+    let model = model.to_lowercase();
+    if ["gpt-3.5", "8b", "-8k"]
+        .iter()
+        .any(|name| model.contains(name))
+    {
+        return 24 * 1024;
+    }
+    match provider {
+        Provider::Groq => 32 * 1024,
+        Provider::Mistral | Provider::DeepSeek | Provider::Xai => 96 * 1024,
+        Provider::OpenAI | Provider::Azure | Provider::OpenRouter | Provider::Mock => 128 * 1024,
+        Provider::Anthropic => 256 * 1024,
+        Provider::Google => 512 * 1024,
+    }
+}
 
-        let client = Client::builder()
-            .timeout(std::time::Duration::from_secs(120))
-            .build()
-            .expect("Failed to create HTTP client");
+// How hard a reasoning model thinks before answering. OpenAI-style APIs take the
+// level as `reasoning_effort`; Anthropic and Google take a token budget instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReasoningEffort {
+    Low,
+    Medium,
+    High,
+}
 
-        Self {
-            client,
-            api_key,
-            provider,
-            model,
+impl ReasoningEffort {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "low" => Some(ReasoningEffort::Low),
+            "medium" => Some(ReasoningEffort::Medium),
+            "high" => Some(ReasoningEffort::High),
+            _ => None,
         }
     }
 
-    pub fn complete(&self, messages: &[Message], max_tokens: u32) -> Result<(String, u32), String> {
-        if self.provider == "google" {
-            return self.complete_google(messages, max_tokens);
+    fn name(self) -> &'static str {
+        match self {
+            ReasoningEffort::Low => "low",
+            ReasoningEffort::Medium => "medium",
+            ReasoningEffort::High => "high",
         }
+    }
 
-        if self.provider == "anthropic" {
-            return self.complete_anthropic(messages, max_tokens);
+    // Anthropic's minimum budget is 1024
+    fn budget_tokens(self) -> u32 {
+        match self {
+            ReasoningEffort::Low => 1024,
+            ReasoningEffort::Medium => 4096,
+            ReasoningEffort::High => 16384,
         }
+    }
+}
 
-        let (url, auth_prefix) = get_provider_config(&self.provider);
+// Only OpenAI's API documents `seed`; elsewhere it's ignored at best
+pub fn supports_seed(provider: Provider) -> bool {
+    matches!(provider, Provider::OpenAI | Provider::Azure)
+}
 
-        let request_body = ChatRequest {
-            model: self.model.clone(),
-            messages: messages.to_vec(),
-            max_tokens: Some(max_tokens),
-        };
+// Other models reject the parameter outright, so it's only sent to these
+pub fn supports_reasoning_effort(provider: Provider, model: &str) -> bool {
+    let model = model.to_lowercase();
+    let family = |names: &[&str]| names.iter().any(|name| model.contains(name));
+    match provider {
+        Provider::OpenAI | Provider::Azure | Provider::OpenRouter => {
+            family(&["o1", "o3", "o4", "gpt-5"])
+        }
+        Provider::Anthropic => family(&["claude-3-7", "claude-sonnet-4", "claude-opus-4"]),
+        Provider::Google => family(&["gemini-2.5"]),
+        Provider::Xai => family(&["grok-3-mini"]),
+        Provider::Groq | Provider::Mistral | Provider::DeepSeek | Provider::Mock => false,
+    }
+}
 
-        let mut request = self
-            .client
-            .post(url)
-            .header("Authorization", format!("{} {}", auth_prefix, self.api_key))
-            .header("Content-Type", "application/json");
+// OpenAI's tool call shape, used both in responses and when replaying history
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub function: FunctionCall,
+}
 
-        if self.provider == "openrouter" {
-            request = request
-                .header("HTTP-Referer", "https://crabshell.local")
-                .header("X-Title", "CrabShell");
-        }
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FunctionCall {
+    pub name: String,
+    // JSON-encoded, as the model wrote it
+    pub arguments: String,
+}
+
+pub const RUN_COMMAND_TOOL: &str = "run_command";
 
-        let response = request
-            .json(&request_body)
-            .send()
-            .map_err(|e| format!("Request failed: {}", e))?;
+#[derive(Debug, Deserialize)]
+struct RunCommandArgs {
+    command: String,
+    #[serde(default)]
+    stdin: Option<String>,
+}
 
-        if !response.status().is_success() {
-            let status = response.status();
-            let body = response.text().unwrap_or_default();
-            return Err(format!("API error ({}): {}", status, body));
+impl ToolCall {
+    // (command, stdin) for a well-formed run_command call
+    pub fn run_command(&self) -> Result<(String, Option<String>), CrabError> {
+        if self.function.name != RUN_COMMAND_TOOL {
+            return Err(CrabError::Parse(format!(
+                "unknown tool '{}'",
+                self.function.name
+            )));
         }
+        let args: RunCommandArgs = serde_json::from_str(&self.function.arguments).map_err(|e| {
+            CrabError::Parse(format!("invalid {} arguments: {}", RUN_COMMAND_TOOL, e))
+        })?;
+        Ok((args.command, args.stdin.filter(|s| !s.is_empty())))
+    }
+}
 
-        let body: ChatResponse = response
-            .json()
-            .map_err(|e| format!("Failed to parse response: {}", e))?;
+fn tool_definitions() -> Value {
+    serde_json::json!([{
+        "type": "function",
+        "function": {
+            "name": RUN_COMMAND_TOOL,
+            "description": "Run a shell command in the sandbox and return its exit code, stdout and stderr.",
+            "parameters": {
+                "type": "object",
+                "properties": {
+                    "command": { "type": "string", "description": "The shell command to run" },
+                    "stdin": { "type": "string", "description": "Optional text piped to the command's standard input" }
+                },
+                "required": ["command"]
+            }
+        }
+    }])
+}
 
-        let content = body
-            .choices
-            .first()
-            .map(|c| c.message.content.clone())
-            .ok_or_else(|| "No response from API".to_string())?;
+// Rough token estimate (about four characters per token), good enough to stay
+// clear of the context window without pulling in a tokenizer
+pub fn estimate_tokens(messages: &[Message]) -> usize {
+    messages
+        .iter()
+        .map(|m| {
+            let calls: usize = m
+                .tool_calls
+                .iter()
+                .map(|c| c.function.name.len() + c.function.arguments.len())
+                .sum();
+            (m.role.len() + m.content.len() + calls).div_ceil(4)
+        })
+        .sum()
+}
 
-        let tokens = body.usage.and_then(|u| u.total_tokens).unwrap_or(0);
+// Drops the oldest turns until the estimate fits `limit`. System messages and the
+// latest user message always stay, even if they alone are over the limit.
+// Returns how many messages were removed.
+pub fn trim_history(messages: &mut Vec<Message>, limit: usize) -> usize {
+    let last_user = messages.iter().rposition(|m| m.role == "user");
+    let mut removed = 0;
+    let mut index = 0;
+
+    while estimate_tokens(messages) > limit && index < messages.len() {
+        let keep = messages[index].role == "system" || Some(index + removed) == last_user;
+        if keep {
+            index += 1;
+        } else {
+            messages.remove(index);
+            removed += 1;
+        }
+    }
+    // Tool results can't outlive the assistant turn that asked for them
+    while removed > 0 && index < messages.len() && messages[index].role == "tool" {
+        messages.remove(index);
+        removed += 1;
+    }
+    removed
+}
 
-        Ok((content, tokens))
+const ELIDED_PREFIX: &str = "[output elided:";
+
+// Replaces all but the latest `keep` command results with a one-line placeholder.
+// The commands that produced them stay, so the trail of what was done survives;
+// only output the model has long since acted on goes. Returns how many were elided.
+pub fn elide_stale_outputs(messages: &mut [Message], keep: usize) -> usize {
+    let outputs: Vec<usize> = messages
+        .iter()
+        .enumerate()
+        .filter(|(_, m)| m.exit_code.is_some() && !m.content.starts_with(ELIDED_PREFIX))
+        .map(|(i, _)| i)
+        .collect();
+    let stale = outputs.len().saturating_sub(keep);
+    for &i in &outputs[..stale] {
+        let message = &mut messages[i];
+        message.content = format!(
+            "{} {} bytes, exit {}]",
+            ELIDED_PREFIX,
+            message.content.len(),
+            message.exit_code.unwrap_or_default()
+        );
     }
+    stale
+}
 
-    fn complete_anthropic(
-        &self,
-        messages: &[Message],
-        max_tokens: u32,
-    ) -> Result<(String, u32), String> {
-        let url = "https://api.anthropic.com/v1/messages";
+#[derive(Debug, Serialize)]
+struct ChatRequest {
+    model: String,
+    messages: Vec<ChatMessage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    // Some providers reject an empty array, so it's left out entirely
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop: Vec<String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tools: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_choice: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reasoning_effort: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+}
 
-        #[derive(Serialize)]
-        struct AnthropicRequest {
-            model: String,
-            messages: Vec<Message>,
-            max_tokens: u32,
-        }
+#[derive(Debug, Deserialize)]
+struct ChatResponse {
+    choices: Vec<Choice>,
+    usage: Option<Usage>,
+    #[serde(default)]
+    system_fingerprint: Option<String>,
+}
 
-        let request_body = AnthropicRequest {
-            model: self.model.clone(),
-            messages: messages.to_vec(),
-            max_tokens,
-        };
+#[derive(Debug, Deserialize)]
+struct Choice {
+    message: ResponseMessage,
+    #[serde(default)]
+    finish_reason: Option<String>,
+}
 
-        let response = self
-            .client
-            .post(url)
-            .header("x-api-key", &self.api_key)
-            .header("anthropic-version", "2023-06-01")
-            .header("Content-Type", "application/json")
-            .json(&request_body)
-            .send()
-            .map_err(|e| format!("Request failed: {}", e))?;
+#[derive(Debug, Deserialize)]
+struct ResponseMessage {
+    // null when the model only calls tools
+    #[serde(default)]
+    content: Option<String>,
+    // Set in place of content when the model declines
+    #[serde(default)]
+    refusal: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<ToolCall>,
+}
 
-        if !response.status().is_success() {
-            let status = response.status();
-            let body = response.text().unwrap_or_default();
-            return Err(format!("API error ({}): {}", status, body));
-        }
+#[derive(Debug, Deserialize)]
+struct StreamChunk {
+    #[serde(default)]
+    choices: Vec<StreamChoice>,
+    usage: Option<Usage>,
+    #[serde(default)]
+    system_fingerprint: Option<String>,
+}
 
-        #[derive(Deserialize)]
-        struct AnthropicResponse {
-            content: Vec<AnthropicContent>,
-            usage: Option<AnthropicUsage>,
-        }
+#[derive(Debug, Deserialize)]
+struct StreamChoice {
+    delta: StreamDelta,
+    #[serde(default)]
+    finish_reason: Option<String>,
+}
 
-        #[derive(Deserialize)]
-        struct AnthropicContent {
-            text: String,
-        }
+#[derive(Debug, Deserialize)]
+struct StreamDelta {
+    content: Option<String>,
+}
 
-        #[derive(Deserialize)]
-        struct AnthropicUsage {
-            input_tokens: u32,
-            output_tokens: u32,
-        }
+// Splits a server-sent event byte stream into `data:` payloads, each with the
+// name from its `event:` line (empty when there was none). Reads may end mid-line
+// (or mid-codepoint), so incomplete lines stay buffered until the rest arrives.
+#[derive(Default)]
+struct SseDecoder {
+    buffer: Vec<u8>,
+    event: String,
+}
 
-        let body: AnthropicResponse = response
-            .json()
-            .map_err(|e| format!("Failed to parse response: {}", e))?;
+#[derive(Debug, PartialEq)]
+struct SseEvent {
+    event: String,
+    data: String,
+}
 
-        let content = body
-            .content
-            .first()
-            .map(|c| c.text.clone())
-            .ok_or_else(|| "No response from API".to_string())?;
+impl SseDecoder {
+    fn feed(&mut self, bytes: &[u8]) -> Vec<SseEvent> {
+        self.buffer.extend_from_slice(bytes);
+        let mut payloads = Vec::new();
+
+        while let Some(pos) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=pos).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\r', '\n']);
+            if line.is_empty() {
+                self.event.clear();
+            } else if let Some(event) = line.strip_prefix("event:") {
+                self.event = event.trim().to_string();
+            } else if let Some(data) = line.strip_prefix("data:") {
+                payloads.push(SseEvent {
+                    event: self.event.clone(),
+                    data: data.strip_prefix(' ').unwrap_or(data).to_string(),
+                });
+            }
+        }
 
-        let tokens = body
-            .usage
-            .map(|u| u.input_tokens + u.output_tokens)
-            .unwrap_or(0);
+        payloads
+    }
+}
 
-        Ok((content, tokens))
+// The provider's message when a stream event reports a failure: an `error`
+// event, or a chunk carrying an `error` field (OpenAI, OpenRouter) or
+// `"type":"error"` (Anthropic)
+fn stream_error(event: &SseEvent) -> Option<String> {
+    let parsed: Value = serde_json::from_str(&event.data).unwrap_or(Value::Null);
+    let flagged = event.event == "error"
+        || parsed.get("error").is_some()
+        || parsed.get("type").and_then(Value::as_str) == Some("error");
+    if !flagged {
+        return None;
     }
+    let error = parsed.get("error").unwrap_or(&parsed);
+    let message = error
+        .get("message")
+        .and_then(Value::as_str)
+        .or_else(|| error.as_str())
+        .map(str::to_string)
+        .unwrap_or_else(|| event.data.trim().to_string());
+    Some(message)
+}
 
-    fn complete_google(
-        &self,
-        messages: &[Message],
-        max_tokens: u32,
-    ) -> Result<(String, u32), String> {
-        let url = format!(
-            "https://generativelanguage.googleapis.com/v1beta/models/{}:generateContent?key={}",
-            self.model, self.api_key
-        );
+#[derive(Debug, Deserialize)]
+struct Usage {
+    prompt_tokens: Option<u32>,
+    completion_tokens: Option<u32>,
+    total_tokens: Option<u32>,
+}
 
-        #[derive(Serialize)]
-        struct GoogleRequest {
-            contents: Vec<GoogleContent>,
-            generationConfig: GoogleConfig,
+impl Usage {
+    fn token_usage(&self) -> TokenUsage {
+        let prompt = self.prompt_tokens.unwrap_or(0);
+        let completion = self.completion_tokens.unwrap_or(0);
+        TokenUsage {
+            prompt,
+            completion,
+            total: self.total_tokens.unwrap_or(prompt + completion),
+            finish: FinishReason::Stop,
+            fingerprint: None,
+            model: None,
         }
+    }
+}
 
-        #[derive(Serialize)]
-        struct GoogleContent {
-            parts: Vec<GooglePart>,
+// Token counts for one completion, and why it ended. Some servers only report a
+// total, in which case prompt and completion are both 0.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenUsage {
+    pub prompt: u32,
+    pub completion: u32,
+    pub total: u32,
+    #[serde(default)]
+    pub finish: FinishReason,
+    // OpenAI's system_fingerprint, naming the backend configuration that answered
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+    // The model that answered, which after a failover isn't the configured one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+}
+
+// Each provider spells these its own way; anything not listed counts as Stop
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    #[default]
+    Stop,
+    // Hit max_tokens, so the last command may be cut off mid-line
+    Length,
+    // Declined by the model or the provider's content filter
+    Refusal,
+}
+
+impl FinishReason {
+    fn from_openai(reason: Option<&str>) -> Self {
+        match reason {
+            Some("length") => FinishReason::Length,
+            Some("content_filter") => FinishReason::Refusal,
+            _ => FinishReason::Stop,
         }
+    }
 
-        #[derive(Serialize, Deserialize)]
-        struct GooglePart {
-            text: String,
+    fn from_anthropic(reason: Option<&str>) -> Self {
+        match reason {
+            Some("max_tokens") => FinishReason::Length,
+            Some("refusal") => FinishReason::Refusal,
+            _ => FinishReason::Stop,
         }
+    }
 
-        #[derive(Serialize)]
-        struct GoogleConfig {
-            maxOutputTokens: u32,
+    fn from_google(reason: Option<&str>) -> Self {
+        match reason {
+            Some("MAX_TOKENS") => FinishReason::Length,
+            Some("SAFETY" | "PROHIBITED_CONTENT" | "BLOCKLIST" | "SPII") => FinishReason::Refusal,
+            _ => FinishReason::Stop,
         }
+    }
+}
 
-        let contents: Vec<GoogleContent> = messages
-            .iter()
-            .map(|m| GoogleContent {
-                parts: vec![GooglePart {
-                    text: m.content.clone(),
-                }],
-            })
-            .collect();
+#[derive(Debug, Serialize)]
+struct AnthropicRequest {
+    model: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<AnthropicText>,
+    messages: Vec<AnthropicMessage>,
+    max_tokens: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop_sequences: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thinking: Option<Value>,
+}
 
-        let request_body = GoogleRequest {
-            contents,
-            generationConfig: GoogleConfig {
-                maxOutputTokens: max_tokens,
-            },
-        };
+// Plain strings unless a block has to carry cache_control
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum AnthropicText {
+    Plain(String),
+    Blocks(Vec<AnthropicTextBlock>),
+}
 
-        let response = self
-            .client
-            .post(&url)
-            .header("Content-Type", "application/json")
-            .json(&request_body)
-            .send()
-            .map_err(|e| format!("Request failed: {}", e))?;
+#[derive(Debug, Serialize)]
+struct AnthropicTextBlock {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    text: String,
+    cache_control: AnthropicCacheControl,
+}
 
-        if !response.status().is_success() {
-            let status = response.status();
-            let body = response.text().unwrap_or_default();
-            return Err(format!("API error ({}): {}", status, body));
-        }
+#[derive(Debug, Serialize)]
+struct AnthropicCacheControl {
+    #[serde(rename = "type")]
+    kind: &'static str,
+}
 
-        #[derive(Deserialize)]
-        struct GoogleResponse {
-            candidates: Vec<GoogleCandidate>,
+impl AnthropicText {
+    fn new(text: String, cached: bool) -> Self {
+        if !cached {
+            return AnthropicText::Plain(text);
         }
+        AnthropicText::Blocks(vec![AnthropicTextBlock {
+            kind: "text",
+            text,
+            cache_control: AnthropicCacheControl { kind: "ephemeral" },
+        }])
+    }
+}
 
-        #[derive(Deserialize)]
-        struct GoogleCandidate {
-            content: GoogleResponseContent,
+#[derive(Debug, Serialize)]
+struct AnthropicMessage {
+    role: String,
+    content: AnthropicText,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicResponse {
+    content: Vec<AnthropicContent>,
+    usage: Option<AnthropicUsage>,
+    #[serde(default)]
+    stop_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicContent {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    text: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicUsage {
+    input_tokens: u32,
+    output_tokens: u32,
+    // Cached prompt tokens aren't counted in input_tokens
+    #[serde(default)]
+    cache_creation_input_tokens: u32,
+    #[serde(default)]
+    cache_read_input_tokens: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    OpenAI,
+    #[default]
+    OpenRouter,
+    Anthropic,
+    Google,
+    Groq,
+    Mistral,
+    DeepSeek,
+    Xai,
+    Azure,
+    // Scripted replies for demos and smoke tests; see mock.rs
+    Mock,
+}
+
+impl Provider {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "openai" => Some(Provider::OpenAI),
+            "openrouter" => Some(Provider::OpenRouter),
+            "anthropic" => Some(Provider::Anthropic),
+            "google" => Some(Provider::Google),
+            "groq" => Some(Provider::Groq),
+            "mistral" => Some(Provider::Mistral),
+            "deepseek" => Some(Provider::DeepSeek),
+            "xai" => Some(Provider::Xai),
+            "azure" => Some(Provider::Azure),
+            "mock" => Some(Provider::Mock),
+            _ => None,
         }
+    }
 
-        #[derive(Deserialize)]
-        struct GoogleResponseContent {
-            parts: Vec<GooglePart>,
+    pub fn name(&self) -> &'static str {
+        match self {
+            Provider::OpenAI => "openai",
+            Provider::OpenRouter => "openrouter",
+            Provider::Anthropic => "anthropic",
+            Provider::Google => "google",
+            Provider::Groq => "groq",
+            Provider::Mistral => "mistral",
+            Provider::DeepSeek => "deepseek",
+            Provider::Xai => "xai",
+            Provider::Azure => "azure",
+            Provider::Mock => "mock",
         }
+    }
+}
 
-        let body: GoogleResponse = response
-            .json()
-            .map_err(|e| format!("Failed to parse response: {}", e))?;
+#[derive(Clone)]
+pub struct LLMClient {
+    client: Client,
+    api_key: String,
+    provider: Provider,
+    model: String,
+    max_retries: u32,
+    base_delay: Duration,
+    max_retry_after: Duration,
+    timeout: Duration,
+    proxy: Option<Proxy>,
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    stop: Vec<String>,
+    prompt_cache: bool,
+    max_response_bytes: usize,
+    base_url: Option<String>,
+    azure: Option<AzureDeployment>,
+    // Tried in order when this backend is down; see with_failover
+    fallbacks: Vec<LLMClient>,
+    // Larger-context model per model name, tried once on a context-length error
+    context_fallbacks: HashMap<String, String>,
+    extra_headers: HeaderMap,
+    cassette: Option<Arc<Cassette>>,
+    // Only read by the mock provider; without a script it echoes
+    mock: Option<Arc<MockScript>>,
+    // Ask for a JSON object reply; see json_output
+    json: bool,
+    // TRACE_FILE; see wiretrace.rs
+    trace_file: Option<String>,
+    // REASONING_EFFORT; see reasoning_effort()
+    reasoning_effort: Option<ReasoningEffort>,
+    // SEED, for providers where supports_seed
+    seed: Option<u64>,
+}
 
-        let content = body
-            .candidates
-            .first()
-            .and_then(|c| c.content.parts.first())
-            .map(|p| p.text.clone())
-            .ok_or_else(|| "No response from API".to_string())?;
+enum RequestError {
+    Retryable(CrabError, Option<Duration>),
+    Timeout,
+    Fatal(CrabError),
+}
 
-        Ok((content, 0))
+// Without an explicit proxy reqwest already follows HTTP_PROXY/HTTPS_PROXY/NO_PROXY.
+// Commands never see those variables unless FORWARD_ENV lists them.
+fn build_http_client(timeout: Duration, proxy: Option<&Proxy>) -> Client {
+    let mut builder = Client::builder().timeout(timeout).connect_timeout(timeout);
+    if let Some(proxy) = proxy {
+        builder = builder.proxy(proxy.clone());
     }
+    builder.build().expect("Failed to create HTTP client")
 }
 
-pub fn build_system_prompt(agent_name: &str, agent_role: &str, image: &str) -> String {
-    format!(
-        r#"You are {name}, an autonomous AI agent trapped in a secure Linux 'Cubicle' (Docker container).
-Your Role: {role}
+// LLM_PROXY overrides the environment's proxy for every scheme; NO_PROXY still applies
+pub fn parse_proxy(url: &str) -> Result<Option<Proxy>, CrabError> {
+    let url = url.trim();
+    if url.is_empty() {
+        return Ok(None);
+    }
+    let invalid = |why: String| CrabError::Config(format!("invalid LLM_PROXY '{}': {}", url, why));
+    let parsed = reqwest::Url::parse(url).map_err(|e| invalid(e.to_string()))?;
+    if !matches!(parsed.scheme(), "http" | "https") || parsed.host_str().is_none() {
+        return Err(invalid(
+            "expected http://host:port or https://host:port".to_string(),
+        ));
+    }
+    let proxy = Proxy::all(url).map_err(|e| invalid(e.to_string()))?;
+    Ok(Some(proxy.no_proxy(NoProxy::from_env())))
+}
 
-WORKSPACE DIRECTORY STRUCTURE (/app/workspace):
-Your persistent workspace is organized into specialized folders:
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
 
-📂 WORK (Sandbox): /app/workspace/work/
-   - Your private working directory for all tasks
-   - This is your SCRATCHPAD - use it freely for intermediate files
-   - ALWAYS cd to this directory before starting work
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+// Enough of a response body to recognise it in an error message
+fn body_snippet(body: &str) -> String {
+    const MAX_CHARS: usize = 200;
+    let body = body.trim();
+    if body.is_empty() {
+        return "empty".to_string();
+    }
+    match body.char_indices().nth(MAX_CHARS) {
+        Some((end, _)) => format!("{}...", &body[..end]),
+        None => body.to_string(),
+    }
+}
+
+fn is_retryable_status(status: StatusCode) -> bool {
+    matches!(status.as_u16(), 429 | 500 | 502 | 503)
+}
+
+fn backoff_delay(base: Duration, attempt: u32) -> Duration {
+    let exp = base.saturating_mul(1u32 << attempt.min(16));
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    // Up to 25% jitter so parallel agents don't retry in lockstep
+    let jitter = exp.mul_f64((nanos % 250) as f64 / 1000.0);
+    exp + jitter
+}
+
+// Headers the client sets itself; letting config replace them would break auth
+const PROTECTED_HEADERS: &[&str] = &["authorization", "content-type", "x-api-key", "api-key"];
+
+// "Name:Value" pairs separated by commas, e.g. "X-Title:My Agent,X-Proxy-Auth:abc"
+pub fn parse_extra_headers(raw: &str) -> Result<HeaderMap, CrabError> {
+    let mut headers = HeaderMap::new();
+    for spec in raw.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let invalid = |why: &str| CrabError::Config(format!("invalid header '{}': {}", spec, why));
+        let (name, value) = spec
+            .split_once(':')
+            .ok_or_else(|| invalid("expected Name:Value"))?;
+        let name = HeaderName::from_bytes(name.trim().as_bytes())
+            .map_err(|_| invalid("bad header name"))?;
+        if PROTECTED_HEADERS.contains(&name.as_str()) {
+            return Err(invalid("this header is set by CrabShell"));
+        }
+        let value = HeaderValue::from_str(value.trim()).map_err(|_| invalid("bad header value"))?;
+        headers.insert(name, value);
+    }
+    Ok(headers)
+}
+
+// Connection failures, timeouts and 5xx; anything else would fail on every backend
+fn is_failover_error(err: &CrabError) -> bool {
+    match err {
+        CrabError::Http(_) | CrabError::Timeout(_) => true,
+        CrabError::Api { status, .. } => *status >= 500,
+        _ => false,
+    }
+}
+
+// Providers word it differently, but it's always a 400 (or 413) naming the
+// context window or the prompt's length; other 400s are left alone
+fn is_context_length_error(err: &CrabError) -> bool {
+    let CrabError::Api { status, body } = err else {
+        return false;
+    };
+    let body = body.to_lowercase();
+    matches!(status, 400 | 413)
+        && [
+            "context_length_exceeded",
+            "context length",
+            "context window",
+            "prompt is too long",
+            "maximum number of tokens",
+        ]
+        .iter()
+        .any(|marker| body.contains(marker))
+}
+
+// Only the method, URL and status are ever logged: bodies and headers carry the API key,
+// and so can Google's query string, which is dropped from the URL.
+fn send_request(request: RequestBuilder) -> Result<Response, RequestError> {
+    let started = Instant::now();
+    let response = request.send().map_err(|e| {
+        // The URL goes too, for the same reason; it would otherwise end up in the error text
+        let e = e.without_url();
+        log::warn!("HTTP request failed: {}", e);
+        if e.is_timeout() {
+            RequestError::Timeout
+        } else {
+            RequestError::Retryable(CrabError::Http(e), None)
+        }
+    })?;
+
+    let mut url = response.url().clone();
+    url.set_query(None);
+    log::info!(
+        "POST {} -> {} in {}ms",
+        url,
+        response.status(),
+        started.elapsed().as_millis()
+    );
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let retry_after = if status == StatusCode::TOO_MANY_REQUESTS {
+            response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(parse_retry_after)
+        } else {
+            None
+        };
+        let body = response.text().unwrap_or_default();
+        if matches!(status, StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) {
+            return Err(RequestError::Fatal(CrabError::Auth(format!(
+                "{}: {}",
+                status, body
+            ))));
+        }
+
+        let err = CrabError::Api {
+            status: status.as_u16(),
+            body,
+        };
+        return Err(if is_retryable_status(status) {
+            RequestError::Retryable(err, retry_after)
+        } else {
+            RequestError::Fatal(err)
+        });
+    }
+
+    Ok(response)
+}
+
+pub fn default_model(provider: Provider) -> &'static str {
+    match provider {
+        Provider::OpenAI => "gpt-4o",
+        Provider::Anthropic => "claude-3-5-sonnet-20241022",
+        Provider::Google => "gemini-1.5-pro",
+        Provider::Groq => "llama-3.3-70b-versatile",
+        Provider::OpenRouter => "anthropic/claude-3.5-sonnet",
+        Provider::Mistral => "mistral-large-latest",
+        Provider::DeepSeek => "deepseek-chat",
+        Provider::Xai => "grok-beta",
+        // Azure routes on the deployment; the model name is informational
+        Provider::Azure => "gpt-4o",
+        Provider::Mock => "mock",
+    }
+}
+
+// Every variable an API key may be read from, so the keys can be kept out of messages
+pub const API_KEY_VARS: &[&str] = &[
+    "OPENAI_API_KEY",
+    "ANTHROPIC_API_KEY",
+    "GOOGLE_API_KEY",
+    "GROQ_API_KEY",
+    "OPENROUTER_API_KEY",
+    "MISTRAL_API_KEY",
+    "DEEPSEEK_API_KEY",
+    "XAI_API_KEY",
+    "AZURE_OPENAI_API_KEY",
+    "LLM_API_KEY",
+];
+
+pub fn api_key_var(provider: Provider) -> &'static str {
+    match provider {
+        Provider::OpenAI => "OPENAI_API_KEY",
+        Provider::Anthropic => "ANTHROPIC_API_KEY",
+        Provider::Google => "GOOGLE_API_KEY",
+        Provider::Groq => "GROQ_API_KEY",
+        Provider::OpenRouter => "OPENROUTER_API_KEY",
+        Provider::Mistral => "MISTRAL_API_KEY",
+        Provider::DeepSeek => "DEEPSEEK_API_KEY",
+        Provider::Xai => "XAI_API_KEY",
+        Provider::Azure => "AZURE_OPENAI_API_KEY",
+        // Never needs one
+        Provider::Mock => "LLM_API_KEY",
+    }
+}
+
+pub fn provider_api_key(provider: Provider) -> String {
+    env::var(api_key_var(provider))
+        .or_else(|_| env::var("LLM_API_KEY"))
+        .unwrap_or_default()
+}
+
+pub fn get_provider_config(provider: Provider) -> (&'static str, &'static str) {
+    match provider {
+        Provider::OpenAI => ("https://api.openai.com/v1/chat/completions", "Bearer"),
+        Provider::Anthropic => ("https://api.anthropic.com/v1/messages", "x-api-key"),
+        Provider::Google => (
+            "https://generativelanguage.googleapis.com/v1beta/models",
+            "Key",
+        ),
+        Provider::Groq => ("https://api.groq.com/openai/v1/chat/completions", "Bearer"),
+        Provider::OpenRouter => ("https://openrouter.ai/api/v1/chat/completions", "Bearer"),
+        Provider::Mistral => ("https://api.mistral.ai/v1/chat/completions", "Bearer"),
+        Provider::DeepSeek => ("https://api.deepseek.com/v1/chat/completions", "Bearer"),
+        Provider::Xai => ("https://api.x.ai/v1/chat/completions", "Bearer"),
+        // Every Azure resource has its own endpoint, see AzureDeployment
+        Provider::Azure => ("", "api-key"),
+        Provider::Mock => ("", "Bearer"),
+    }
+}
+
+pub const DEFAULT_AZURE_API_VERSION: &str = "2024-06-01";
+
+// Azure OpenAI routes on the deployment rather than the model name
+#[derive(Debug, Clone, PartialEq)]
+pub struct AzureDeployment {
+    pub endpoint: String,
+    pub deployment: String,
+    pub api_version: String,
+}
+
+impl AzureDeployment {
+    pub fn new(endpoint: &str, deployment: &str, api_version: &str) -> Result<Self, CrabError> {
+        let endpoint = endpoint.trim().trim_end_matches('/');
+        let deployment = deployment.trim();
+        if endpoint.is_empty() || deployment.is_empty() {
+            return Err(CrabError::Config(
+                "the azure provider needs AZURE_ENDPOINT and AZURE_DEPLOYMENT".to_string(),
+            ));
+        }
+        let api_version = match api_version.trim() {
+            "" => DEFAULT_AZURE_API_VERSION,
+            v => v,
+        };
+        Ok(Self {
+            endpoint: endpoint.to_string(),
+            deployment: deployment.to_string(),
+            api_version: api_version.to_string(),
+        })
+    }
+
+    fn chat_url(&self) -> String {
+        format!(
+            "{}/openai/deployments/{}/chat/completions?api-version={}",
+            self.endpoint, self.deployment, self.api_version
+        )
+    }
+}
+
+// The one thing the agent loop needs from a model, so the loop can run against
+// scripted replies in tests
+pub trait Completer {
+    fn complete(
+        &self,
+        messages: &[Message],
+        max_tokens: u32,
+    ) -> Result<(String, TokenUsage), CrabError>;
+
+    // Defaults to delivering the whole reply as a single delta
+    fn complete_stream(
+        &self,
+        messages: &[Message],
+        max_tokens: u32,
+        on_delta: &mut dyn FnMut(&str),
+    ) -> Result<(String, TokenUsage), CrabError> {
+        let (content, tokens) = self.complete(messages, max_tokens)?;
+        on_delta(&content);
+        Ok((content, tokens))
+    }
+
+    // Offers the run_command tool and returns the whole assistant turn, tool calls
+    // included. Defaults to a plain completion for backends without tool calling.
+    fn complete_with_tools(
+        &self,
+        messages: &[Message],
+        max_tokens: u32,
+    ) -> Result<(Message, TokenUsage), CrabError> {
+        let (content, tokens) = self.complete(messages, max_tokens)?;
+        Ok((assistant_message(content), tokens))
+    }
+
+    // The final answer under RESPONSE_FORMAT=json_object. Defaults to a plain
+    // completion, leaving the system prompt to ask for JSON.
+    fn complete_json(
+        &self,
+        messages: &[Message],
+        max_tokens: u32,
+    ) -> Result<(String, TokenUsage), CrabError> {
+        self.complete(messages, max_tokens)
+    }
+}
+
+// A plain text reply as a whole assistant turn
+fn assistant_message(content: String) -> Message {
+    Message {
+        role: "assistant".to_string(),
+        content,
+        ..Default::default()
+    }
+}
+
+impl Completer for LLMClient {
+    fn complete(
+        &self,
+        messages: &[Message],
+        max_tokens: u32,
+    ) -> Result<(String, TokenUsage), CrabError> {
+        LLMClient::complete(self, messages, max_tokens)
+    }
+
+    fn complete_stream(
+        &self,
+        messages: &[Message],
+        max_tokens: u32,
+        on_delta: &mut dyn FnMut(&str),
+    ) -> Result<(String, TokenUsage), CrabError> {
+        LLMClient::complete_stream(self, messages, max_tokens, on_delta)
+    }
+
+    fn complete_with_tools(
+        &self,
+        messages: &[Message],
+        max_tokens: u32,
+    ) -> Result<(Message, TokenUsage), CrabError> {
+        LLMClient::complete_with_tools(self, messages, max_tokens)
+    }
+
+    fn complete_json(
+        &self,
+        messages: &[Message],
+        max_tokens: u32,
+    ) -> Result<(String, TokenUsage), CrabError> {
+        LLMClient::complete(&self.json_output(), messages, max_tokens)
+    }
+}
+
+impl LLMClient {
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    pub fn new(provider: Provider, model: String) -> Self {
+        let api_key = provider_api_key(provider);
+        let model = if model.trim().is_empty() {
+            default_model(provider).to_string()
+        } else {
+            model
+        };
+
+        let timeout = Duration::from_secs(DEFAULT_TIMEOUT_SECS);
+
+        Self {
+            client: build_http_client(timeout, None),
+            api_key,
+            provider,
+            model,
+            max_retries: DEFAULT_MAX_RETRIES,
+            base_delay: Duration::from_millis(DEFAULT_BASE_DELAY_MS),
+            max_retry_after: Duration::from_secs(DEFAULT_MAX_RETRY_AFTER_SECS),
+            timeout,
+            proxy: None,
+            temperature: None,
+            top_p: None,
+            stop: Vec::new(),
+            prompt_cache: false,
+            max_response_bytes: DEFAULT_MAX_RESPONSE_BYTES,
+            base_url: None,
+            azure: None,
+            fallbacks: Vec::new(),
+            context_fallbacks: HashMap::new(),
+            extra_headers: HeaderMap::new(),
+            cassette: None,
+            mock: None,
+            json: false,
+            trace_file: None,
+            reasoning_effort: None,
+            seed: None,
+        }
+    }
+
+    pub fn with_retries(mut self, max_retries: u32, base_delay: Duration) -> Self {
+        self.max_retries = max_retries;
+        self.base_delay = base_delay;
+        self
+    }
+
+    pub fn with_max_retry_after(mut self, max_retry_after: Duration) -> Self {
+        self.max_retry_after = max_retry_after;
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.client = build_http_client(timeout, self.proxy.as_ref());
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_proxy(mut self, proxy: Option<Proxy>) -> Self {
+        self.client = build_http_client(self.timeout, proxy.as_ref());
+        self.proxy = proxy;
+        self
+    }
+
+    pub fn with_sampling(mut self, temperature: Option<f32>, top_p: Option<f32>) -> Self {
+        self.temperature = temperature;
+        self.top_p = top_p;
+        self
+    }
+
+    pub fn with_seed(mut self, seed: Option<u64>) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn with_reasoning_effort(mut self, effort: Option<ReasoningEffort>) -> Self {
+        self.reasoning_effort = effort;
+        self
+    }
+
+    // None for models that don't take it, whatever REASONING_EFFORT says
+    fn reasoning_effort(&self) -> Option<ReasoningEffort> {
+        self.reasoning_effort
+            .filter(|_| supports_reasoning_effort(self.provider, &self.model))
+    }
+
+    pub fn with_stop(mut self, stop: Vec<String>) -> Self {
+        self.stop = stop;
+        self
+    }
+
+    // Only Anthropic needs telling; OpenAI caches a repeated prefix on its own,
+    // which the unchanged message order already gives it
+    pub fn with_prompt_cache(mut self, prompt_cache: bool) -> Self {
+        self.prompt_cache = prompt_cache;
+        self
+    }
+
+    pub fn with_max_response_bytes(mut self, max_response_bytes: usize) -> Self {
+        self.max_response_bytes = max_response_bytes;
+        self
+    }
+
+    pub fn with_base_url(mut self, base_url: &str) -> Self {
+        let base_url = base_url.trim().trim_end_matches('/');
+        self.base_url = (!base_url.is_empty()).then(|| base_url.to_string());
+        self
+    }
+
+    pub fn with_extra_headers(mut self, headers: HeaderMap) -> Self {
+        self.extra_headers = headers;
+        self
+    }
+
+    pub fn with_cassette(mut self, cassette: Option<Arc<Cassette>>) -> Self {
+        self.cassette = cassette;
+        self
+    }
+
+    pub fn with_trace_file(mut self, path: &str) -> Self {
+        self.trace_file = (!path.is_empty()).then(|| path.to_string());
+        self
+    }
+
+    pub fn with_mock(mut self, mock: Option<Arc<MockScript>>) -> Self {
+        self.mock = mock;
+        self
+    }
+
+    pub fn with_fallbacks(mut self, fallbacks: Vec<LLMClient>) -> Self {
+        self.fallbacks = fallbacks;
+        self
+    }
+
+    pub fn with_context_fallbacks(mut self, context_fallbacks: HashMap<String, String>) -> Self {
+        self.context_fallbacks = context_fallbacks;
+        self
+    }
+
+    pub fn with_azure(mut self, azure: AzureDeployment) -> Self {
+        self.azure = Some(azure);
+        self
+    }
+
+    // A copy, fallbacks included, that asks OpenAI-style APIs for a response_format
+    // of json_object and Google for application/json. Anthropic has no such switch
+    // and relies on the system prompt.
+    pub fn json_output(&self) -> Self {
+        let mut client = self.clone();
+        client.json = true;
+        client.fallbacks = self.fallbacks.iter().map(LLMClient::json_output).collect();
+        client
+    }
+
+    #[cfg(test)]
+    fn with_api_key(mut self, api_key: &str) -> Self {
+        self.api_key = api_key.to_string();
+        self
+    }
+
+    fn endpoint_url(&self) -> String {
+        if let Some(azure) = self
+            .azure
+            .as_ref()
+            .filter(|_| self.provider == Provider::Azure)
+        {
+            return azure.chat_url();
+        }
+        let Some(base) = &self.base_url else {
+            return get_provider_config(self.provider).0.to_string();
+        };
+
+        match self.provider {
+            Provider::Anthropic => format!("{}/messages", base),
+            Provider::Google => format!("{}/models", base),
+            _ => format!("{}/chat/completions", base),
+        }
+    }
+
+    pub fn complete(
+        &self,
+        messages: &[Message],
+        max_tokens: u32,
+    ) -> Result<(String, TokenUsage), CrabError> {
+        self.through_cassette(messages, || {
+            self.with_failover(|client| client.complete_direct(messages, max_tokens))
+                .map(|(content, tokens)| (assistant_message(content), tokens))
+        })
+        .map(|(message, tokens)| (message.content, tokens))
+    }
+
+    pub fn complete_with_tools(
+        &self,
+        messages: &[Message],
+        max_tokens: u32,
+    ) -> Result<(Message, TokenUsage), CrabError> {
+        self.through_cassette(messages, || {
+            self.with_failover(|client| client.complete_with_tools_direct(messages, max_tokens))
+        })
+    }
+
+    // Like `complete`, but hands each content delta to `on_delta` as it arrives.
+    // Providers without an OpenAI-style stream get a single delta with the full text.
+    pub fn complete_stream(
+        &self,
+        messages: &[Message],
+        max_tokens: u32,
+        on_delta: &mut dyn FnMut(&str),
+    ) -> Result<(String, TokenUsage), CrabError> {
+        let mut streamed = false;
+        let (message, tokens) = self.through_cassette(messages, || {
+            streamed = true;
+            self.with_failover(|client| {
+                client.complete_stream_direct(messages, max_tokens, on_delta)
+            })
+            .map(|(content, tokens)| (assistant_message(content), tokens))
+        })?;
+        if !streamed {
+            on_delta(&message.content);
+        }
+        Ok((message.content, tokens))
+    }
+
+    // Replays the next recorded reply instead of calling `call`, or records what it returns
+    fn through_cassette(
+        &self,
+        messages: &[Message],
+        call: impl FnOnce() -> Result<(Message, TokenUsage), CrabError>,
+    ) -> Result<(Message, TokenUsage), CrabError> {
+        let Some(cassette) = &self.cassette else {
+            return call();
+        };
+        if let Some(replayed) = cassette.next_response() {
+            return replayed;
+        }
+        let (message, tokens) = call()?;
+        cassette.record(messages, &message, tokens.clone());
+        Ok((message, tokens))
+    }
+
+    // Tries each fallback in turn while the previous backend is unreachable or
+    // failing server-side; a 4xx is the request's fault and would fail anywhere.
+    // The exception is a prompt too long for the model, which gets one more try
+    // on the larger model CONTEXT_FALLBACKS names for it.
+    fn with_failover<T>(
+        &self,
+        mut call: impl FnMut(&LLMClient) -> Result<(T, TokenUsage), CrabError>,
+    ) -> Result<(T, TokenUsage), CrabError> {
+        let mut served_by = self;
+        let mut result = call(self);
+        for fallback in &self.fallbacks {
+            match &result {
+                Err(e) if is_failover_error(e) => eprintln!(
+                    "[LLM] {} failed ({}), falling back to {}",
+                    served_by.provider.name(),
+                    e,
+                    fallback.provider.name()
+                ),
+                _ => break,
+            }
+            served_by = fallback;
+            result = call(fallback);
+        }
+        let larger = match &result {
+            Err(e) if is_context_length_error(e) => {
+                served_by.context_fallbacks.get(&served_by.model)
+            }
+            _ => None,
+        };
+        let switched;
+        if let Some(larger) = larger {
+            eprintln!(
+                "[LLM] The request is too long for {}, retrying once with {}",
+                served_by.model, larger
+            );
+            let mut client = served_by.clone();
+            client.model = larger.clone();
+            client.context_fallbacks.clear();
+            switched = client;
+            served_by = &switched;
+            result = call(served_by);
+        }
+        if let Ok((_, usage)) = &mut result {
+            log::info!(
+                "Request served by {} ({})",
+                served_by.provider.name(),
+                served_by.model
+            );
+            usage.model = Some(served_by.model.clone());
+        }
+        result
+    }
+
+    fn mock_reply(&self, messages: &[Message]) -> (String, TokenUsage) {
+        match &self.mock {
+            Some(script) => script.next_reply(messages),
+            None => mock::echo(messages),
+        }
+    }
+
+    fn complete_direct(
+        &self,
+        messages: &[Message],
+        max_tokens: u32,
+    ) -> Result<(String, TokenUsage), CrabError> {
+        if self.provider == Provider::Mock {
+            return Ok(self.mock_reply(messages));
+        }
+        log::debug!(
+            "Requesting completion from {} ({}) with {} messages",
+            self.provider.name(),
+            self.model,
+            messages.len()
+        );
+        self.with_retry(|| self.complete_once(messages, max_tokens))
+    }
+
+    // The model ids this key can use, sorted; `crab models`
+    pub fn list_models(&self) -> Result<Vec<String>, CrabError> {
+        if matches!(self.provider, Provider::Azure | Provider::Mock) {
+            return Err(CrabError::Config(format!(
+                "listing models is not supported for {}{}",
+                self.provider.name(),
+                if self.provider == Provider::Azure {
+                    "; it serves the deployments set up on the resource"
+                } else {
+                    ""
+                }
+            )));
+        }
+        let mut models = self.with_retry(|| self.list_models_once())?;
+        models.sort();
+        Ok(models)
+    }
+
+    fn models_url(&self) -> String {
+        let chat = self.endpoint_url();
+        match self.provider {
+            // The chat endpoint is the models list already, one model per path
+            Provider::Google => format!("{}?key={}", chat, self.api_key),
+            Provider::Anthropic => format!("{}/models", chat.trim_end_matches("/messages")),
+            _ => format!("{}/models", chat.trim_end_matches("/chat/completions")),
+        }
+    }
+
+    fn list_models_once(&self) -> Result<Vec<String>, RequestError> {
+        #[derive(Deserialize)]
+        struct ModelList {
+            #[serde(default)]
+            data: Vec<ModelEntry>,
+            // Google's shape: {"models": [{"name": "models/gemini-..."}]}
+            #[serde(default)]
+            models: Vec<ModelEntry>,
+        }
+
+        #[derive(Deserialize)]
+        struct ModelEntry {
+            #[serde(default)]
+            id: String,
+            #[serde(default)]
+            name: String,
+        }
+
+        let (_, auth_prefix) = get_provider_config(self.provider);
+        let mut request = self.client.get(self.models_url());
+        match self.provider {
+            _ if self.api_key.is_empty() => {}
+            Provider::Google => {}
+            Provider::Anthropic => {
+                request = request
+                    .header("x-api-key", &self.api_key)
+                    .header("anthropic-version", "2023-06-01")
+            }
+            _ => {
+                request =
+                    request.header("Authorization", format!("{} {}", auth_prefix, self.api_key))
+            }
+        }
+        let response = self.send(request.headers(self.extra_headers.clone()))?;
+        let list: ModelList = self.read_json(response)?;
+        Ok(list
+            .data
+            .into_iter()
+            .chain(list.models)
+            .map(|m| {
+                if m.id.is_empty() {
+                    m.name.trim_start_matches("models/").to_string()
+                } else {
+                    m.id
+                }
+            })
+            .filter(|id| !id.is_empty())
+            .collect())
+    }
+
+    // Anthropic and Google keep to plain text here; their tool formats differ
+    fn complete_with_tools_direct(
+        &self,
+        messages: &[Message],
+        max_tokens: u32,
+    ) -> Result<(Message, TokenUsage), CrabError> {
+        if matches!(
+            self.provider,
+            Provider::Google | Provider::Anthropic | Provider::Mock
+        ) {
+            let (content, tokens) = self.complete_direct(messages, max_tokens)?;
+            return Ok((assistant_message(content), tokens));
+        }
+
+        log::debug!(
+            "Requesting tool-enabled completion from {} ({}) with {} messages",
+            self.provider.name(),
+            self.model,
+            messages.len()
+        );
+        self.with_retry(|| self.chat_once(messages, max_tokens, true))
+    }
+
+    fn complete_stream_direct(
+        &self,
+        messages: &[Message],
+        max_tokens: u32,
+        on_delta: &mut dyn FnMut(&str),
+    ) -> Result<(String, TokenUsage), CrabError> {
+        if matches!(
+            self.provider,
+            Provider::Google | Provider::Anthropic | Provider::Mock
+        ) {
+            let (content, tokens) = self.complete_direct(messages, max_tokens)?;
+            on_delta(&content);
+            return Ok((content, tokens));
+        }
+
+        log::debug!(
+            "Streaming completion from {} ({}) with {} messages",
+            self.provider.name(),
+            self.model,
+            messages.len()
+        );
+        self.with_retry(|| self.stream_once(messages, max_tokens, on_delta))
+    }
+
+    fn with_retry<T>(
+        &self,
+        mut attempt_fn: impl FnMut() -> Result<T, RequestError>,
+    ) -> Result<T, CrabError> {
+        let mut attempt = 0;
+        loop {
+            let (msg, delay) = match attempt_fn() {
+                Ok(result) => return Ok(result),
+                Err(RequestError::Retryable(err, retry_after)) if attempt < self.max_retries => {
+                    let delay = match retry_after {
+                        Some(wait) => wait.min(self.max_retry_after),
+                        None => backoff_delay(self.base_delay, attempt),
+                    };
+                    (err.to_string(), delay)
+                }
+                Err(RequestError::Timeout) if attempt < self.max_retries => (
+                    format!("Request timed out after {}s", self.timeout.as_secs()),
+                    backoff_delay(self.base_delay, attempt),
+                ),
+                Err(RequestError::Timeout) => return Err(CrabError::Timeout(self.timeout)),
+                Err(RequestError::Retryable(err, _)) | Err(RequestError::Fatal(err)) => {
+                    return Err(err)
+                }
+            };
+
+            attempt += 1;
+            log::debug!("Attempt {} failed: {}", attempt, msg);
+            eprintln!(
+                "[LLM] {} (retry {}/{} in {}ms)",
+                msg,
+                attempt,
+                self.max_retries,
+                delay.as_millis()
+            );
+            thread::sleep(delay);
+        }
+    }
+
+    fn complete_once(
+        &self,
+        messages: &[Message],
+        max_tokens: u32,
+    ) -> Result<(String, TokenUsage), RequestError> {
+        if self.provider == Provider::Google {
+            return self.complete_google(messages, max_tokens);
+        }
+
+        if self.provider == Provider::Anthropic {
+            return self.complete_anthropic(messages, max_tokens);
+        }
+
+        let (message, tokens) = self.chat_once(messages, max_tokens, false)?;
+        Ok((message.content, tokens))
+    }
+
+    fn chat_once(
+        &self,
+        messages: &[Message],
+        max_tokens: u32,
+        with_tools: bool,
+    ) -> Result<(Message, TokenUsage), RequestError> {
+        let mut request_body = self.build_chat_request(messages, max_tokens);
+        if with_tools {
+            request_body.tools = Some(tool_definitions());
+            request_body.tool_choice = Some("auto");
+        }
+        let response = self.send(self.chat_request().json(&request_body))?;
+
+        let body: ChatResponse = self.read_json(response)?;
+
+        let choice = body.choices.into_iter().next().ok_or_else(|| {
+            RequestError::Fatal(CrabError::Parse("no response from API".to_string()))
+        })?;
+        let mut tokens = body.usage.map(|u| u.token_usage()).unwrap_or_default();
+        tokens.finish = FinishReason::from_openai(choice.finish_reason.as_deref());
+        tokens.fingerprint = body.system_fingerprint;
+        if choice.message.refusal.is_some() {
+            tokens.finish = FinishReason::Refusal;
+        }
+        let message = Message {
+            role: "assistant".to_string(),
+            content: choice
+                .message
+                .content
+                .or(choice.message.refusal)
+                .unwrap_or_default(),
+            tool_calls: choice.message.tool_calls,
+            ..Default::default()
+        };
+
+        Ok((message, tokens))
+    }
+
+    fn stream_once(
+        &self,
+        messages: &[Message],
+        max_tokens: u32,
+        on_delta: &mut dyn FnMut(&str),
+    ) -> Result<(String, TokenUsage), RequestError> {
+        let mut request_body = self.build_chat_request(messages, max_tokens);
+        request_body.stream = true;
+        if self.provider == Provider::OpenAI {
+            request_body.stream_options = Some(serde_json::json!({ "include_usage": true }));
+        }
+
+        let mut response = self.send(self.chat_request().json(&request_body))?;
+        let status = response.status();
+        // Every event's data, kept for TRACE_FILE
+        let mut events = Vec::new();
+
+        let mut decoder = SseDecoder::default();
+        let mut content = String::new();
+        let mut tokens = TokenUsage::default();
+        let mut chunk = [0u8; 4096];
+        let mut received = 0;
+        let mut finish = FinishReason::Stop;
+        let mut fingerprint = None;
+
+        'read: loop {
+            let n = response
+                .read(&mut chunk)
+                .map_err(|e| RequestError::Fatal(CrabError::Stream(e.to_string())))?;
+            if n == 0 {
+                break;
+            }
+            received += n;
+            if self.max_response_bytes > 0 && received > self.max_response_bytes {
+                return Err(RequestError::Fatal(self.too_large()));
+            }
+
+            for event in decoder.feed(&chunk[..n]) {
+                if self.trace_file.is_some() {
+                    events.push(event.data.clone());
+                }
+                // What already arrived is dropped with it; a retry would hand
+                // the same deltas to on_delta again, so there isn't one
+                if let Some(message) = stream_error(&event) {
+                    self.trace_response(status, &events.join("\n"));
+                    return Err(RequestError::Fatal(CrabError::Stream(format!(
+                        "{} reported an error mid-stream: {}",
+                        self.provider.name(),
+                        message
+                    ))));
+                }
+                let payload = event.data;
+                if payload.trim() == "[DONE]" {
+                    break 'read;
+                }
+
+                let parsed: StreamChunk = match serde_json::from_str(&payload) {
+                    Ok(parsed) => parsed,
+                    Err(_) => continue,
+                };
+
+                if let Some(usage) = parsed.usage {
+                    tokens = usage.token_usage();
+                }
+                if parsed.system_fingerprint.is_some() {
+                    fingerprint = parsed.system_fingerprint;
+                }
+
+                for choice in parsed.choices {
+                    if choice.finish_reason.is_some() {
+                        finish = FinishReason::from_openai(choice.finish_reason.as_deref());
+                    }
+                    if let Some(delta) = choice.delta.content {
+                        if !delta.is_empty() {
+                            on_delta(&delta);
+                            content.push_str(&delta);
+                        }
+                    }
+                }
+            }
+        }
+
+        self.trace_response(status, &events.join("\n"));
+        // The usage chunk comes after the one carrying finish_reason
+        tokens.finish = finish;
+        tokens.fingerprint = fingerprint;
+        Ok((content, tokens))
+    }
+
+    // send_request, with the request and any error body copied to TRACE_FILE
+    fn send(&self, request: RequestBuilder) -> Result<Response, RequestError> {
+        let Some(path) = &self.trace_file else {
+            return send_request(request);
+        };
+        let secrets = [self.api_key.as_str()];
+        if let Some(Ok(built)) = request.try_clone().map(RequestBuilder::build) {
+            wiretrace::request(path, &built, &secrets);
+        }
+        let result = send_request(request);
+        if let Err(
+            RequestError::Retryable(CrabError::Api { status, body }, _)
+            | RequestError::Fatal(CrabError::Api { status, body }),
+        ) = &result
+        {
+            wiretrace::response(path, *status, body, &secrets);
+        }
+        result
+    }
+
+    fn trace_response(&self, status: StatusCode, body: &str) {
+        if let Some(path) = &self.trace_file {
+            wiretrace::response(path, status.as_u16(), body, &[self.api_key.as_str()]);
+        }
+    }
+
+    fn too_large(&self) -> CrabError {
+        CrabError::ResponseTooLarge {
+            limit: self.max_response_bytes,
+        }
+    }
+
+    // Reads at most one byte past the cap, so an oversized body is never held whole
+    fn read_json<T: serde::de::DeserializeOwned>(
+        &self,
+        response: Response,
+    ) -> Result<T, RequestError> {
+        let limit = match self.max_response_bytes {
+            0 => u64::MAX,
+            cap => cap as u64 + 1,
+        };
+        let status = response.status();
+        let mut body = Vec::new();
+        response
+            .take(limit)
+            .read_to_end(&mut body)
+            .map_err(|e| RequestError::Fatal(CrabError::Stream(e.to_string())))?;
+        if self.max_response_bytes > 0 && body.len() > self.max_response_bytes {
+            return Err(RequestError::Fatal(self.too_large()));
+        }
+        self.trace_response(status, &String::from_utf8_lossy(&body));
+        serde_json::from_slice(&body).map_err(|e| {
+            let err = CrabError::Parse(format!(
+                "invalid response: {} (body: {})",
+                e,
+                body_snippet(&String::from_utf8_lossy(&body))
+            ));
+            // Cut-off or garbled JSON is usually a proxy dropping the connection, so
+            // it's worth another try; well-formed JSON of the wrong shape isn't
+            if e.is_eof() || e.is_syntax() {
+                RequestError::Retryable(err, None)
+            } else {
+                RequestError::Fatal(err)
+            }
+        })
+    }
+
+    fn chat_request(&self) -> RequestBuilder {
+        let (_, auth_prefix) = get_provider_config(self.provider);
+
+        let mut request = self
+            .client
+            .post(self.endpoint_url())
+            .header("Content-Type", "application/json");
+
+        // Local OpenAI-compatible servers often run without any key
+        match self.provider {
+            _ if self.api_key.is_empty() => {}
+            Provider::Azure => request = request.header(auth_prefix, &self.api_key),
+            _ => {
+                request =
+                    request.header("Authorization", format!("{} {}", auth_prefix, self.api_key))
+            }
+        }
+
+        if self.provider == Provider::OpenRouter {
+            request = request
+                .header("HTTP-Referer", "https://crabshell.local")
+                .header("X-Title", "CrabShell");
+        }
+
+        // Applied last so configured headers replace the OpenRouter defaults above
+        request.headers(self.extra_headers.clone())
+    }
+
+    fn build_chat_request(&self, messages: &[Message], max_tokens: u32) -> ChatRequest {
+        ChatRequest {
+            model: self.model.clone(),
+            messages: messages.iter().map(ChatMessage::from).collect(),
+            max_tokens: Some(max_tokens),
+            temperature: self.temperature,
+            top_p: self.top_p,
+            stop: self.stop.clone(),
+            stream: false,
+            stream_options: None,
+            tools: None,
+            tool_choice: None,
+            response_format: self
+                .json
+                .then(|| serde_json::json!({ "type": "json_object" })),
+            reasoning_effort: self.reasoning_effort().map(ReasoningEffort::name),
+            seed: self.seed.filter(|_| supports_seed(self.provider)),
+        }
+    }
+
+    // Anthropic takes the system prompt as a top-level field rather than a message
+    fn build_anthropic_request(&self, messages: &[Message], max_tokens: u32) -> AnthropicRequest {
+        let system = messages
+            .iter()
+            .filter(|m| m.role == "system")
+            .map(|m| m.content.as_str())
+            .collect::<Vec<_>>()
+            .join("\n\n");
+
+        // With caching, the system prompt and everything before the newest turn are
+        // the prefix the next iteration will send again
+        let turns: Vec<&Message> = messages.iter().filter(|m| m.role != "system").collect();
+        let last_prior = turns.len().checked_sub(2);
+        // The budget comes out of max_tokens, so it's added on top to keep the reply's
+        // share; thinking also refuses any temperature but the default
+        let budget = self.reasoning_effort().map(ReasoningEffort::budget_tokens);
+        AnthropicRequest {
+            model: self.model.clone(),
+            system: (!system.is_empty()).then(|| AnthropicText::new(system, self.prompt_cache)),
+            messages: turns
+                .iter()
+                .enumerate()
+                .map(|(i, m)| AnthropicMessage {
+                    role: m.role.clone(),
+                    content: AnthropicText::new(
+                        m.content.clone(),
+                        self.prompt_cache && Some(i) == last_prior,
+                    ),
+                })
+                .collect(),
+            max_tokens: max_tokens + budget.unwrap_or(0),
+            temperature: self.temperature.filter(|_| budget.is_none()),
+            top_p: self.top_p,
+            stop_sequences: self.stop.clone(),
+            thinking: budget
+                .map(|budget| serde_json::json!({ "type": "enabled", "budget_tokens": budget })),
+        }
+    }
+
+    fn complete_anthropic(
+        &self,
+        messages: &[Message],
+        max_tokens: u32,
+    ) -> Result<(String, TokenUsage), RequestError> {
+        let url = self.endpoint_url();
+
+        let request_body = self.build_anthropic_request(messages, max_tokens);
+
+        let response = self.send(
+            self.client
+                .post(&url)
+                .header("x-api-key", &self.api_key)
+                .header("anthropic-version", "2023-06-01")
+                .header("Content-Type", "application/json")
+                .headers(self.extra_headers.clone())
+                .json(&request_body),
+        )?;
+
+        let body: AnthropicResponse = self.read_json(response)?;
+
+        let content = body
+            .content
+            .iter()
+            .filter(|c| c.kind == "text")
+            .map(|c| c.text.as_str())
+            .collect::<Vec<_>>()
+            .join("");
+        let finish = FinishReason::from_anthropic(body.stop_reason.as_deref());
+
+        // A refusal may come back with no text at all
+        if content.is_empty() && finish != FinishReason::Refusal {
+            return Err(RequestError::Fatal(CrabError::Parse(
+                "no response from API".to_string(),
+            )));
+        }
+
+        let mut tokens = body
+            .usage
+            .map(|u| {
+                let prompt =
+                    u.input_tokens + u.cache_creation_input_tokens + u.cache_read_input_tokens;
+                TokenUsage {
+                    prompt,
+                    completion: u.output_tokens,
+                    total: prompt + u.output_tokens,
+                    finish: FinishReason::Stop,
+                    fingerprint: None,
+                    model: None,
+                }
+            })
+            .unwrap_or_default();
+        tokens.finish = finish;
+
+        Ok((content, tokens))
+    }
+
+    fn complete_google(
+        &self,
+        messages: &[Message],
+        max_tokens: u32,
+    ) -> Result<(String, TokenUsage), RequestError> {
+        let url = format!(
+            "{}/{}:generateContent?key={}",
+            self.endpoint_url(),
+            self.model,
+            self.api_key
+        );
+
+        #[derive(Serialize)]
+        #[serde(rename_all = "camelCase")]
+        struct GoogleRequest {
+            contents: Vec<GoogleContent>,
+            generation_config: GoogleConfig,
+        }
+
+        #[derive(Serialize)]
+        struct GoogleContent {
+            parts: Vec<GooglePart>,
+        }
+
+        // Thought summaries come back as parts of their own, marked `thought`
+        #[derive(Serialize, Deserialize)]
+        struct GooglePart {
+            #[serde(default)]
+            text: String,
+            #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+            thought: bool,
+        }
+
+        #[derive(Serialize)]
+        #[serde(rename_all = "camelCase")]
+        struct GoogleConfig {
+            max_output_tokens: u32,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            temperature: Option<f32>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            top_p: Option<f32>,
+            #[serde(skip_serializing_if = "Vec::is_empty")]
+            stop_sequences: Vec<String>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            response_mime_type: Option<&'static str>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            thinking_config: Option<Value>,
+        }
+
+        let contents: Vec<GoogleContent> = messages
+            .iter()
+            .map(|m| GoogleContent {
+                parts: vec![GooglePart {
+                    text: m.content.clone(),
+                    thought: false,
+                }],
+            })
+            .collect();
+
+        let request_body = GoogleRequest {
+            contents,
+            generation_config: GoogleConfig {
+                max_output_tokens: max_tokens,
+                temperature: self.temperature,
+                top_p: self.top_p,
+                stop_sequences: self.stop.clone(),
+                response_mime_type: self.json.then_some("application/json"),
+                thinking_config: self
+                    .reasoning_effort()
+                    .map(|e| serde_json::json!({ "thinkingBudget": e.budget_tokens() })),
+            },
+        };
+
+        let response = self.send(
+            self.client
+                .post(&url)
+                .header("Content-Type", "application/json")
+                .headers(self.extra_headers.clone())
+                .json(&request_body),
+        )?;
+
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct GoogleResponse {
+            // Left out when the prompt itself was blocked
+            #[serde(default)]
+            candidates: Vec<GoogleCandidate>,
+            prompt_feedback: Option<GooglePromptFeedback>,
+            usage_metadata: Option<GoogleUsage>,
+        }
+
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct GooglePromptFeedback {
+            block_reason: Option<String>,
+        }
+
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct GoogleUsage {
+            #[serde(default)]
+            prompt_token_count: u32,
+            #[serde(default)]
+            candidates_token_count: u32,
+            #[serde(default)]
+            total_token_count: u32,
+        }
+
+        // A candidate stopped for safety has no content
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct GoogleCandidate {
+            #[serde(default)]
+            content: GoogleResponseContent,
+            #[serde(default)]
+            finish_reason: Option<String>,
+        }
+
+        #[derive(Default, Deserialize)]
+        struct GoogleResponseContent {
+            #[serde(default)]
+            parts: Vec<GooglePart>,
+        }
+
+        let body: GoogleResponse = self.read_json(response)?;
+
+        let blocked = body
+            .prompt_feedback
+            .is_some_and(|feedback| feedback.block_reason.is_some());
+        let finish = if blocked {
+            FinishReason::Refusal
+        } else {
+            FinishReason::from_google(
+                body.candidates
+                    .first()
+                    .and_then(|c| c.finish_reason.as_deref()),
+            )
+        };
+
+        let answer: Vec<&str> = body
+            .candidates
+            .first()
+            .map(|c| {
+                c.content
+                    .parts
+                    .iter()
+                    .filter(|part| !part.thought)
+                    .map(|part| part.text.as_str())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let content = match answer.is_empty() {
+            false => answer.concat(),
+            true if finish == FinishReason::Refusal => String::new(),
+            true => {
+                return Err(RequestError::Fatal(CrabError::Parse(
+                    "no response from API".to_string(),
+                )))
+            }
+        };
+
+        let mut tokens = body
+            .usage_metadata
+            .map(|u| TokenUsage {
+                prompt: u.prompt_token_count,
+                completion: u.candidates_token_count,
+                total: u.total_token_count,
+                finish: FinishReason::Stop,
+                fingerprint: None,
+                model: None,
+            })
+            .unwrap_or_default();
+        tokens.finish = finish;
+
+        Ok((content, tokens))
+    }
+}
+
+// Placeholders: {agent_name}, {agent_role}, {docker_image}, {command_start},
+// {command_end}. Anything else in
+// braces, like the JSON schema below, is left as written.
+pub const DEFAULT_SYSTEM_PROMPT: &str = r#"You are {agent_name}, an autonomous AI agent trapped in a secure Linux 'Cubicle' (Docker container).
+Your Role: {agent_role}
+
+WORKSPACE DIRECTORY STRUCTURE (/app/workspace):
+Your persistent workspace is organized into specialized folders:
+
+📂 WORK (Sandbox): /app/workspace/work/
+   - Your private working directory for all tasks
+   - This is your SCRATCHPAD - use it freely for intermediate files
+   - ALWAYS cd to this directory before starting work
 
 📥 IN (Input): /app/workspace/in/
    - Files uploaded by the user via Telegram land here
@@ -392,7 +2182,7 @@ Your persistent workspace is organized into specialized folders:
 
 Your Environment:
 - OS: Debian/Linux (Docker)
-- Image: {image}
+- Image: {docker_image}
 - Tools: curl, jq, sed, awk, python3, bash, node, npm, sqlite3, ffmpeg
 - Network: Air-gapped (No direct internet access)
 
@@ -414,46 +2204,430 @@ ASSET PROCUREMENT:
 RESPONSE CONTRACT (MANDATORY):
 Return ONLY valid JSON. No markdown, no code fences.
 Schema:
-{{
+{
   "userId": "<telegram user id string>",
   "message": "Short plain text for Telegram bubble",
   "action": "" | "FILE:<filename.ext>",
   "terminal": "" | "single shell command to execute in container",
+  "stdin": "" | "text piped to the terminal command's standard input",
   "panelActions": ["CALENDAR_CREATE:title|prompt|start_time|end_time|color|symbol"]
-}}
+}
+
+Rules:
+- message must be minimal and never markdown.
+- terminal command executes in container terminal and is not shown directly to user.
+- For future actions/events use calendar (panelActions) with an explicit color.
+- If no file should be sent, action must be empty string.
+- If no command should be executed, terminal must be empty string.
+- Leave stdin empty unless the command reads its input from standard input.
+- Outside the JSON contract, a command goes between {command_start} and {command_end}.
+- A Python script may go in a ```python fence instead of a shell command; it runs with python3.
+- Independent read-only commands may start with a `# parallel` line to run concurrently; never mark ones that cd or write.
+- Start every command with a `# explanation: <what it does and why, one line>` line, after `# parallel` if there is one.
+- To set an environment variable for one command only, add a `# env: NAME=value` line next to its explanation rather than an inline assignment."#;
+
+// Put ahead of the system prompt by SAFETY_LEVEL: the default preamble, or that
+// plus STRICT_SAFETY_PREAMBLE; `none` leaves both out
+pub const SAFETY_PREAMBLE: &str = "SAFETY:
+- Focus on security, efficiency, and completing the user's request.
+- Do not try to escape the cubicle. Do not mention Docker to the user.
+";
+
+pub const STRICT_SAFETY_PREAMBLE: &str = "- Refuse destructive operations without confirmation: before deleting or overwriting files, dropping data, force-pushing, killing processes or changing permissions, stop and ask the user, and only go ahead once they have confirmed in this conversation.
+- Prefer read-only commands, and never use sudo or touch anything outside the workspace.
+";
+
+// Used for the first call in --plan mode, with the same placeholders as above.
+// The reply is shown to the operator before anything runs.
+pub const PLANNING_PROMPT: &str = r#"You are {agent_name}, an autonomous AI agent working in a Linux sandbox ({docker_image}).
+Your Role: {agent_role}
+
+Before doing anything, write a plan for the user's request.
+
+Rules:
+- Reply with a numbered list of steps, one line each, and nothing else.
+- Say what each step does and why; do not include commands to run yet.
+- Mention anything destructive or irreversible explicitly.
+- Keep it short: most tasks need fewer than ten steps.
+
+The user will review the plan. Once it is approved you will carry it out."#;
+
+pub fn render_system_prompt(
+    template: &str,
+    agent_name: &str,
+    agent_role: &str,
+    image: &str,
+    delimiters: &CommandDelimiters,
+) -> String {
+    template
+        .replace("{agent_name}", agent_name)
+        .replace("{agent_role}", agent_role)
+        .replace("{docker_image}", image)
+        .replace("{command_start}", &delimiters.start)
+        .replace("{command_end}", &delimiters.end)
+}
+
+pub const DEFAULT_COMMAND_START: &str = "```";
+pub const DEFAULT_COMMAND_END: &str = "```";
+
+// Markers around a command in free-form replies. The default pair keeps the
+// shell-fence rules (language tags, own lines); any other pair may sit inline.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandDelimiters {
+    pub start: String,
+    pub end: String,
+}
+
+impl Default for CommandDelimiters {
+    fn default() -> Self {
+        Self::new(DEFAULT_COMMAND_START, DEFAULT_COMMAND_END)
+    }
+}
+
+impl CommandDelimiters {
+    pub fn new(start: &str, end: &str) -> Self {
+        Self {
+            start: start.to_string(),
+            end: end.to_string(),
+        }
+    }
+
+    fn is_fence(&self) -> bool {
+        self.start == DEFAULT_COMMAND_START && self.end == DEFAULT_COMMAND_END
+    }
+}
+
+const THINKING_OPEN: &str = "<thinking>";
+const THINKING_CLOSE: &str = "</thinking>";
+const REASONING_HEADING: &str = "## reasoning";
+
+// Splits a response into (reasoning, visible text). Reasoning is anything inside
+// <thinking>...</thinking>, or a `## Reasoning` section running to the next heading.
+// An unclosed <thinking> swallows the rest, which keeps half-streamed thoughts hidden.
+pub fn split_reasoning(response: &str) -> (String, String) {
+    let mut reasoning = Vec::new();
+    let mut outside = String::new();
+    let mut rest = response;
+    while let Some(start) = rest.find(THINKING_OPEN) {
+        outside.push_str(&rest[..start]);
+        let inner = &rest[start + THINKING_OPEN.len()..];
+        match inner.find(THINKING_CLOSE) {
+            Some(end) => {
+                reasoning.push(inner[..end].trim().to_string());
+                rest = &inner[end + THINKING_CLOSE.len()..];
+            }
+            None => {
+                reasoning.push(inner.trim().to_string());
+                rest = "";
+            }
+        }
+    }
+    outside.push_str(rest);
+
+    let mut visible = String::new();
+    let mut section: Option<Vec<&str>> = None;
+    for line in outside.split_inclusive('\n') {
+        let trimmed = line.trim();
+        if trimmed.to_lowercase() == REASONING_HEADING {
+            section = Some(Vec::new());
+            continue;
+        }
+        match section.as_mut() {
+            Some(lines) if !trimmed.starts_with('#') => lines.push(line),
+            _ => {
+                if let Some(lines) = section.take() {
+                    reasoning.push(lines.concat().trim().to_string());
+                }
+                visible.push_str(line);
+            }
+        }
+    }
+    if let Some(lines) = section {
+        reasoning.push(lines.concat().trim().to_string());
+    }
+
+    if reasoning.is_empty() {
+        return (String::new(), visible);
+    }
+    let reasoning = reasoning
+        .into_iter()
+        .filter(|r| !r.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    (reasoning, visible.trim_start().to_string())
+}
+
+// Shell commands only, with the default delimiters and no script interpreters
+pub fn extract_commands(response: &str) -> Vec<String> {
+    extract_commands_and_scripts(response, &CommandDelimiters::default(), &HashMap::new())
+}
+
+// Like extract_commands, but fences tagged with a key of `interpreters` (e.g.
+// ```python) also count, each becoming a command that hands the script to the
+// interpreter
+pub fn extract_commands_and_scripts(
+    response: &str,
+    delimiters: &CommandDelimiters,
+    interpreters: &HashMap<String, String>,
+) -> Vec<String> {
+    let (_, response) = split_reasoning(response);
+    let response = response.as_str();
+
+    if let Ok(parsed) = serde_json::from_str::<Value>(response) {
+        let terminal = parsed
+            .get("terminal")
+            .and_then(|v| v.as_str())
+            .map(|v| v.trim().to_string())
+            .unwrap_or_default();
+
+        if !terminal.is_empty() {
+            return vec![terminal];
+        }
+    }
+
+    let fenced = if delimiters.is_fence() {
+        extract_fenced_blocks(response, interpreters)
+    } else {
+        extract_delimited_blocks(response, delimiters)
+    };
+    let legacy = extract_marker_commands(response, &delimiters.start);
+
+    let fence_start = fenced.first().map(|(offset, _)| *offset);
+    let marker_start = legacy.first().map(|(offset, _)| *offset);
+    let blocks = match (fence_start, marker_start) {
+        (Some(fence), Some(marker)) if marker < fence => legacy,
+        (Some(_), _) => fenced,
+        _ => legacy,
+    };
+
+    blocks.into_iter().map(|(_, cmd)| cmd).collect()
+}
+
+pub const EXPLANATION_MARKER: &str = "# explanation:";
+
+// Takes the model's `# explanation:` line out of a command's leading comments,
+// returning it and the command without it. Other comments, `# parallel`
+// included, stay where they are.
+pub fn split_explanation(cmd: &str) -> (Option<String>, String) {
+    let mut explanation = None;
+    let mut lines = Vec::new();
+    let mut leading = true;
+    for line in cmd.lines() {
+        let trimmed = line.trim();
+        leading = leading && trimmed.starts_with('#');
+        match trimmed.strip_prefix(EXPLANATION_MARKER) {
+            Some(text) if leading && explanation.is_none() => {
+                explanation = Some(text.trim().to_string()).filter(|t| !t.is_empty());
+            }
+            _ => lines.push(line),
+        }
+    }
+    (explanation, lines.join("\n").trim().to_string())
+}
+
+pub const ENV_MARKER: &str = "# env:";
+
+// Takes `# env: NAME=value` lines out of a command's leading comments, returning
+// the pairs in order and the command without them. Quotes around the value are
+// dropped; a line without `=` comes back with an empty value for the policy to
+// refuse, see tools::env_override_violation.
+pub fn split_env_directives(cmd: &str) -> (Vec<(String, String)>, String) {
+    let mut env = Vec::new();
+    let mut lines = Vec::new();
+    let mut leading = true;
+    for line in cmd.lines() {
+        let trimmed = line.trim();
+        leading = leading && trimmed.starts_with('#');
+        match trimmed.strip_prefix(ENV_MARKER) {
+            Some(assignment) if leading => {
+                let (name, value) = assignment
+                    .trim()
+                    .split_once('=')
+                    .unwrap_or((assignment, ""));
+                let value = value.trim();
+                let unquoted = ['\'', '"']
+                    .iter()
+                    .find_map(|q| value.strip_prefix(*q).and_then(|v| v.strip_suffix(*q)));
+                env.push((
+                    name.trim().to_string(),
+                    unquoted.unwrap_or(value).to_string(),
+                ));
+            }
+            _ => lines.push(line),
+        }
+    }
+    (env, lines.join("\n").trim().to_string())
+}
+
+// How refusals open; kept to declining the task, so "I can't find the file" is
+// still an answer
+const REFUSAL_OPENERS: &[&str] = &[
+    "i can't help with",
+    "i cannot help with",
+    "i can't assist with",
+    "i cannot assist with",
+    "i'm not able to help with",
+    "i am not able to help with",
+    "i'm unable to help with",
+    "i won't help",
+    "i will not help",
+    "i must decline",
+    "i'm sorry, but i can't",
+    "i'm sorry, but i cannot",
+    "i am sorry, but i cannot",
+    "sorry, but i can't",
+    "sorry, i can't help",
+];
+// A long answer that opens with one usually goes on to help with something
+const REFUSAL_MAX_BYTES: usize = 600;
+
+// For the providers that don't flag a refusal, a short final answer that opens by
+// declining counts as one
+pub fn looks_like_refusal(answer: &str) -> bool {
+    let answer = answer.trim().to_lowercase().replace('\u{2019}', "'");
+    answer.len() <= REFUSAL_MAX_BYTES
+        && REFUSAL_OPENERS
+            .iter()
+            .any(|opener| answer.starts_with(opener))
+}
+
+pub const ABORT_MARKER: &str = "ABORT:";
+
+// The reason behind an `ABORT: <reason>` line, or the JSON contract's
+// `"action": "ABORT:<reason>"`; thoughts don't count. An empty reason still aborts.
+pub fn extract_abort(response: &str) -> Option<String> {
+    let (_, response) = split_reasoning(response);
+    if let Ok(parsed) = serde_json::from_str::<Value>(&response) {
+        return parsed
+            .get("action")
+            .and_then(|v| v.as_str())
+            .and_then(|action| action.trim().strip_prefix(ABORT_MARKER))
+            .map(|reason| reason.trim().to_string());
+    }
+    response
+        .lines()
+        .find_map(|line| line.trim().strip_prefix(ABORT_MARKER))
+        .map(|reason| reason.trim().to_string())
+}
+
+// The JSON contract's optional `stdin`, fed to its `terminal` command. Only read
+// alongside a command, and only from the JSON contract.
+pub fn extract_stdin(response: &str) -> Option<String> {
+    let (_, response) = split_reasoning(response);
+    let parsed = serde_json::from_str::<Value>(&response).ok()?;
+    let has_command = parsed
+        .get("terminal")
+        .and_then(|v| v.as_str())
+        .is_some_and(|v| !v.trim().is_empty());
+    parsed
+        .get("stdin")
+        .and_then(|v| v.as_str())
+        .filter(|v| has_command && !v.is_empty())
+        .map(|v| v.to_string())
+}
 
-Rules:
-- message must be minimal and never markdown.
-- terminal command executes in container terminal and is not shown directly to user.
-- For future actions/events use calendar (panelActions) with an explicit color.
-- If no file should be sent, action must be empty string.
-- If no command should be executed, terminal must be empty string.
+// Shell fences, plus whichever script languages have an interpreter; anything
+// else is left for the model to explain, not run
+const SHELL_FENCE_TAGS: &[&str] = &["", "bash", "sh", "shell"];
+
+// Models sometimes put the language on the block's first line instead of the
+// fence, e.g. "```\nbash\nls"; that line would otherwise run as a command.
+// Comments stay, since the shell skips them anyway.
+fn strip_language_line(cmd: &str) -> &str {
+    match cmd.split_once('\n') {
+        Some((first, rest)) if SHELL_FENCE_TAGS[1..].contains(&first.trim()) => rest.trim(),
+        _ => cmd,
+    }
+}
 
-Focus on security, efficiency, and completing the user's request.
-Do not try to escape the cubicle. Do not mention Docker to the user."#,
-        name = agent_name,
-        role = agent_role,
-        image = image
-    )
+// A block with nothing but comments, which the loop answers without running it
+pub fn is_comment_only(cmd: &str) -> bool {
+    cmd.lines()
+        .map(str::trim)
+        .all(|line| line.is_empty() || line.starts_with('#'))
 }
 
-pub fn extract_command(response: &str) -> Option<String> {
-    if let Ok(parsed) = serde_json::from_str::<Value>(response) {
-        let terminal = parsed
-            .get("terminal")
-            .and_then(|v| v.as_str())
-            .map(|v| v.trim().to_string())
-            .unwrap_or_default();
+// Returns (byte offset of the opening fence, command) for each closed runnable fence
+fn extract_fenced_blocks(
+    response: &str,
+    interpreters: &HashMap<String, String>,
+) -> Vec<(usize, String)> {
+    let mut blocks = Vec::new();
+    // None for a shell fence, the interpreter for a script
+    let mut open: Option<(usize, Option<Option<&str>>)> = None;
+    let mut body: Vec<&str> = Vec::new();
+    let mut offset = 0;
+
+    for line in response.split_inclusive('\n') {
+        let trimmed = line.trim();
+        match open {
+            None => {
+                if let Some(tag) = trimmed.strip_prefix("```") {
+                    let tag = tag.trim().to_lowercase();
+                    let runs = if SHELL_FENCE_TAGS.contains(&tag.as_str()) {
+                        Some(None)
+                    } else {
+                        interpreters.get(&tag).map(|i| Some(i.as_str()))
+                    };
+                    open = Some((offset, runs));
+                    body.clear();
+                }
+            }
+            Some((start, runs)) => {
+                if trimmed == "```" {
+                    let cmd = body.concat().trim().to_string();
+                    match runs {
+                        _ if cmd.is_empty() => {}
+                        Some(None) => blocks.push((start, strip_language_line(&cmd).to_string())),
+                        Some(Some(interpreter)) => {
+                            blocks.push((start, script_command(interpreter, &body.concat())))
+                        }
+                        None => {}
+                    }
+                    open = None;
+                } else {
+                    body.push(line);
+                }
+            }
+        }
+        offset += line.len();
+    }
+    blocks
+}
 
-        if !terminal.is_empty() {
-            return Some(terminal);
+// Returns (byte offset of the start marker, body) for each closed custom-delimited block
+fn extract_delimited_blocks(
+    response: &str,
+    delimiters: &CommandDelimiters,
+) -> Vec<(usize, String)> {
+    let mut blocks = Vec::new();
+    // An empty marker matches everywhere and would never move the offset on
+    if delimiters.start.is_empty() || delimiters.end.is_empty() {
+        return blocks;
+    }
+    let mut offset = 0;
+    while let Some(start) = response[offset..].find(&delimiters.start) {
+        let start = offset + start;
+        let body_start = start + delimiters.start.len();
+        let Some(len) = response[body_start..].find(&delimiters.end) else {
+            break;
+        };
+        let cmd = strip_language_line(response[body_start..body_start + len].trim());
+        if !cmd.is_empty() {
+            blocks.push((start, cmd.to_string()));
         }
+        offset = body_start + len + delimiters.end.len();
     }
+    blocks
+}
 
+// Returns (byte offset of the marker line, command) for each legacy COMMAND: block
+fn extract_marker_commands(response: &str, command_start: &str) -> Vec<(usize, String)> {
     if !response.contains("ACTION: EXECUTE") {
-        return None;
+        return Vec::new();
     }
 
+    let mut commands = Vec::new();
     let lines: Vec<&str> = response.lines().collect();
     for (i, line) in lines.iter().enumerate() {
         if line.trim().starts_with("COMMAND:") {
@@ -463,14 +2637,1303 @@ pub fn extract_command(response: &str) -> Option<String> {
             for next_line in lines.iter().skip(i + 1) {
                 let trimmed = next_line.trim();
                 // Stop extracting if we hit another primary marker
-                if trimmed.starts_with("FILE:") || trimmed.starts_with("ACTION:") {
+                if trimmed.starts_with("FILE:")
+                    || trimmed.starts_with("ACTION:")
+                    || trimmed.starts_with("COMMAND:")
+                    || trimmed.starts_with("```")
+                    || trimmed.starts_with(command_start)
+                {
                     break;
                 }
                 cmd_lines.push(*next_line);
             }
 
-            return Some(cmd_lines.join("\n").trim().to_string());
+            let cmd = cmd_lines.join("\n").trim().to_string();
+            if !cmd.is_empty() {
+                let offset = line.as_ptr() as usize - response.as_ptr() as usize;
+                commands.push((offset, cmd));
+            }
+        }
+    }
+    commands
+}
+
+// Canned-HTTP helpers for exercising the client against a local listener
+#[cfg(test)]
+pub mod test_support {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    pub fn http_response(status: &str, body: &str) -> String {
+        format!(
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )
+    }
+
+    pub fn read_request(stream: &mut std::net::TcpStream) -> String {
+        let mut buf = Vec::new();
+        let mut chunk = [0u8; 4096];
+        loop {
+            let n = stream.read(&mut chunk).unwrap_or(0);
+            if n == 0 {
+                break;
+            }
+            buf.extend_from_slice(&chunk[..n]);
+            let text = String::from_utf8_lossy(&buf);
+            if let Some(header_end) = text.find("\r\n\r\n") {
+                let content_length = text[..header_end]
+                    .lines()
+                    .find_map(|l| {
+                        let (k, v) = l.split_once(':')?;
+                        k.eq_ignore_ascii_case("content-length")
+                            .then(|| v.trim().parse::<usize>().ok())?
+                    })
+                    .unwrap_or(0);
+                if buf.len() >= header_end + 4 + content_length {
+                    break;
+                }
+            }
+        }
+        String::from_utf8_lossy(&buf).to_string()
+    }
+
+    // Serves each canned response to one connection, in order, and returns the raw requests
+    pub fn mock_server(responses: Vec<String>) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                requests.push(read_request(&mut stream));
+                let _ = stream.write_all(response.as_bytes());
+            }
+            requests
+        });
+        (format!("http://{}/v1", addr), handle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_support::{http_response, mock_server};
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn list_models_reads_each_providers_models_endpoint() {
+        let (url, server) = mock_server(vec![http_response(
+            "200 OK",
+            r#"{"object":"list","data":[{"id":"gpt-4o-mini"},{"id":"gpt-4o"}]}"#,
+        )]);
+        let client = LLMClient::new(Provider::OpenAI, String::new())
+            .with_api_key("sk-test")
+            .with_base_url(&url);
+        assert_eq!(client.list_models().unwrap(), vec!["gpt-4o", "gpt-4o-mini"]);
+        let requests = server.join().unwrap();
+        assert!(
+            requests[0].starts_with("GET /v1/models "),
+            "{}",
+            requests[0]
+        );
+        assert!(requests[0].contains("authorization: Bearer sk-test"));
+
+        let (url, server) = mock_server(vec![http_response(
+            "200 OK",
+            r#"{"models":[{"name":"models/gemini-1.5-pro"}]}"#,
+        )]);
+        let client = LLMClient::new(Provider::Google, String::new())
+            .with_api_key("g-key")
+            .with_base_url(&url);
+        assert_eq!(client.list_models().unwrap(), vec!["gemini-1.5-pro"]);
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("GET /v1/models?key=g-key "));
+
+        let err = LLMClient::new(Provider::Azure, String::new())
+            .list_models()
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("not supported for azure"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn images_turn_the_user_content_into_a_multimodal_array() {
+        let client = LLMClient::new(Provider::OpenAI, "gpt-4o".to_string());
+        let messages = vec![
+            Message {
+                role: "system".to_string(),
+                content: "You are Crabby.".to_string(),
+                ..Default::default()
+            },
+            Message {
+                role: "user".to_string(),
+                content: "What does this dashboard show?".to_string(),
+                images: vec!["data:image/png;base64,iVBORw0KGgo=".to_string()],
+                ..Default::default()
+            },
+        ];
+
+        let body = serde_json::to_value(client.build_chat_request(&messages, 50)).unwrap();
+
+        assert_eq!(body["messages"][0]["content"], "You are Crabby.");
+        assert_eq!(
+            body["messages"][1]["content"],
+            serde_json::json!([
+                { "type": "text", "text": "What does this dashboard show?" },
+                { "type": "image_url", "image_url": { "url": "data:image/png;base64,iVBORw0KGgo=" } }
+            ])
+        );
+        assert!(body["messages"][1].get("images").is_none());
+
+        let path = std::env::temp_dir().join(format!("crab-image-{}.png", std::process::id()));
+        fs::write(&path, b"\x89PNG").unwrap();
+        let url = image_data_url(path.to_str().unwrap());
+        let _ = fs::remove_file(&path);
+        assert_eq!(url.unwrap(), "data:image/png;base64,iVBORw==");
+        assert!(image_data_url("notes.txt").is_err());
+
+        assert!(supports_vision(Provider::OpenAI, "gpt-4o-mini"));
+        assert!(!supports_vision(Provider::DeepSeek, "deepseek-chat"));
+        assert!(!supports_vision(
+            Provider::Anthropic,
+            "claude-3-5-sonnet-20241022"
+        ));
+    }
+
+    fn test_messages() -> Vec<Message> {
+        vec![Message {
+            role: "user".to_string(),
+            content: "hi".to_string(),
+            ..Default::default()
+        }]
+    }
+
+    #[test]
+    fn default_system_prompt_fills_in_the_agent() {
+        let prompt = render_system_prompt(
+            DEFAULT_SYSTEM_PROMPT,
+            "Crabby",
+            "Kubernetes SRE",
+            "hermit/k8s",
+            &CommandDelimiters::default(),
+        );
+
+        assert!(prompt.starts_with("You are Crabby, "));
+        assert!(prompt.contains("Your Role: Kubernetes SRE"));
+        assert!(prompt.contains("- Image: hermit/k8s"));
+        assert!(prompt.contains("{\n  \"userId\""));
+        assert!(!prompt.contains("{agent_name}"));
+    }
+
+    #[test]
+    fn custom_system_prompt_substitutes_known_placeholders_only() {
+        let template = "{agent_name} is a {agent_role} on {docker_image}. Reply as {format}.";
+
+        let prompt = render_system_prompt(
+            template,
+            "Crabby",
+            "Kubernetes SRE",
+            "hermit/k8s",
+            &CommandDelimiters::default(),
+        );
+
+        assert_eq!(
+            prompt,
+            "Crabby is a Kubernetes SRE on hermit/k8s. Reply as {format}."
+        );
+    }
+
+    #[test]
+    fn complete_retries_transient_errors() {
+        let ok = r#"{"choices":[{"message":{"content":"done"}}],"usage":{"total_tokens":7}}"#;
+        let (url, server) = mock_server(vec![
+            http_response("503 Service Unavailable", "{}"),
+            http_response("503 Service Unavailable", "{}"),
+            http_response("200 OK", ok),
+        ]);
+
+        let client = LLMClient::new(Provider::OpenRouter, String::new())
+            .with_retries(3, Duration::from_millis(10))
+            .with_base_url(&url);
+
+        let (content, tokens) = client.complete(&test_messages(), 100).unwrap();
+        assert_eq!(content, "done");
+        assert_eq!(tokens.total, 7);
+        assert_eq!(server.join().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn truncated_json_is_retried_and_reported_with_a_snippet() {
+        let cut = r#"{"choices":[{"message":{"content":"do"#;
+        let ok = r#"{"choices":[{"message":{"content":"done"}}]}"#;
+        let (url, server) = mock_server(vec![
+            http_response("200 OK", cut),
+            http_response("200 OK", ok),
+        ]);
+        let client = LLMClient::new(Provider::OpenAI, String::new())
+            .with_retries(1, Duration::from_millis(10))
+            .with_base_url(&url);
+        let (content, _) = client.complete(&test_messages(), 100).unwrap();
+        assert_eq!(content, "done");
+        assert_eq!(server.join().unwrap().len(), 2);
+
+        let (url, server) = mock_server(vec![http_response("200 OK", cut)]);
+        let client = LLMClient::new(Provider::OpenAI, String::new())
+            .with_retries(0, Duration::from_millis(10))
+            .with_base_url(&url);
+        match client.complete(&test_messages(), 100).unwrap_err() {
+            CrabError::Parse(msg) => {
+                assert!(msg.starts_with("invalid response: EOF"), "{}", msg);
+                assert!(msg.ends_with(&format!("(body: {})", cut)), "{}", msg);
+            }
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn length_finish_reason_marks_the_reply_truncated() {
+        let cut = r#"{"choices":[{"message":{"content":"ACTION: EXECUTE\nCOMMAND: grep -r \"TO"},"finish_reason":"length"}],"usage":{"total_tokens":16}}"#;
+        let whole = r#"{"choices":[{"message":{"content":"done"},"finish_reason":"stop"}]}"#;
+        let (url, server) = mock_server(vec![
+            http_response("200 OK", cut),
+            http_response("200 OK", whole),
+        ]);
+        let client = LLMClient::new(Provider::OpenAI, String::new()).with_base_url(&url);
+
+        let (_, tokens) = client.complete(&test_messages(), 16).unwrap();
+        assert_eq!(tokens.finish, FinishReason::Length);
+        let (_, tokens) = client.complete(&test_messages(), 16).unwrap();
+        assert_eq!(tokens.finish, FinishReason::Stop);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn refusals_are_flagged_for_each_provider() {
+        let filtered =
+            r#"{"choices":[{"message":{"content":""},"finish_reason":"content_filter"}]}"#;
+        let (url, server) = mock_server(vec![http_response("200 OK", filtered)]);
+        let client = LLMClient::new(Provider::OpenAI, String::new()).with_base_url(&url);
+        let (_, tokens) = client.complete(&test_messages(), 64).unwrap();
+        assert_eq!(tokens.finish, FinishReason::Refusal);
+        server.join().unwrap();
+
+        // Anthropic may refuse with no text at all
+        let refused = r#"{"content":[],"stop_reason":"refusal"}"#;
+        let (url, server) = mock_server(vec![http_response("200 OK", refused)]);
+        let client = LLMClient::new(Provider::Anthropic, String::new()).with_base_url(&url);
+        let (content, tokens) = client.complete(&test_messages(), 64).unwrap();
+        assert_eq!(content, "");
+        assert_eq!(tokens.finish, FinishReason::Refusal);
+        server.join().unwrap();
+
+        assert!(looks_like_refusal("I’m sorry, but I can’t help with that."));
+        assert!(!looks_like_refusal(
+            "I can't find config.yaml anywhere under /etc."
+        ));
+    }
+
+    #[test]
+    fn complete_honors_retry_after_on_rate_limit() {
+        let ok = r#"{"choices":[{"message":{"content":"done"}}]}"#;
+        let rate_limited = "HTTP/1.1 429 Too Many Requests\r\nRetry-After: 2\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{}";
+        let (url, server) =
+            mock_server(vec![rate_limited.to_string(), http_response("200 OK", ok)]);
+
+        let client = LLMClient::new(Provider::OpenRouter, String::new())
+            .with_retries(3, Duration::from_millis(10))
+            .with_base_url(&url);
+
+        let started = std::time::Instant::now();
+        let (content, _) = client.complete(&test_messages(), 100).unwrap();
+        assert_eq!(content, "done");
+        assert!(started.elapsed() >= Duration::from_millis(1900));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn retry_after_is_parsed_in_both_forms() {
+        assert_eq!(parse_retry_after(" 5 "), Some(Duration::from_secs(5)));
+        let future = SystemTime::now() + Duration::from_secs(120);
+        let wait = parse_retry_after(&httpdate::fmt_http_date(future)).unwrap();
+        assert!(wait > Duration::from_secs(100) && wait <= Duration::from_secs(120));
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+
+    #[test]
+    fn complete_reports_timeout_on_stalled_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!(
+            "http://{}/v1/chat/completions",
+            listener.local_addr().unwrap()
+        );
+        // Accept the connection but never answer
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            thread::sleep(Duration::from_secs(2));
+            drop(stream);
+        });
+
+        let client = LLMClient::new(Provider::OpenRouter, String::new())
+            .with_retries(0, Duration::from_millis(10))
+            .with_timeout(Duration::from_millis(300))
+            .with_base_url(&url);
+
+        let err = client.complete(&test_messages(), 100).unwrap_err();
+        assert!(matches!(err, CrabError::Timeout(_)));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn request_body_uses_configured_model() {
+        let client = LLMClient::new(Provider::OpenAI, "gpt-4o-mini".to_string());
+        let body = serde_json::to_value(client.build_chat_request(&test_messages(), 50)).unwrap();
+        assert_eq!(body["model"], "gpt-4o-mini");
+        assert_eq!(body["max_tokens"], 50);
+    }
+
+    #[test]
+    fn json_answers_are_requested_as_a_json_object() {
+        let ok = r#"{"choices":[{"message":{"content":"{\"ok\":true}"},"finish_reason":"stop"}]}"#;
+        let (url, server) = mock_server(vec![http_response("200 OK", ok)]);
+        let client = LLMClient::new(Provider::OpenAI, String::new()).with_base_url(&url);
+        let (content, _) = Completer::complete_json(&client, &test_messages(), 64).unwrap();
+        let requests = server.join().unwrap();
+
+        assert_eq!(content, r#"{"ok":true}"#);
+        assert!(
+            requests[0].contains(r#""response_format":{"type":"json_object"}"#),
+            "{}",
+            requests[0]
+        );
+        // Command turns go out without it
+        let body = serde_json::to_value(client.build_chat_request(&test_messages(), 50)).unwrap();
+        assert!(body.get("response_format").is_none());
+    }
+
+    #[test]
+    fn reasoning_effort_goes_only_to_models_that_take_it() {
+        let effort = Some(ReasoningEffort::High);
+        let client =
+            LLMClient::new(Provider::OpenAI, "o3-mini".to_string()).with_reasoning_effort(effort);
+        let body = serde_json::to_value(client.build_chat_request(&test_messages(), 50)).unwrap();
+        assert_eq!(body["reasoning_effort"], "high");
+
+        let client = LLMClient::new(Provider::OpenAI, "gpt-4o-mini".to_string())
+            .with_reasoning_effort(effort);
+        let body = serde_json::to_value(client.build_chat_request(&test_messages(), 50)).unwrap();
+        assert!(body.get("reasoning_effort").is_none());
+
+        let client = LLMClient::new(Provider::Anthropic, "claude-sonnet-4-5".to_string())
+            .with_sampling(Some(0.2), None)
+            .with_reasoning_effort(effort);
+        let body =
+            serde_json::to_value(client.build_anthropic_request(&test_messages(), 50)).unwrap();
+        assert_eq!(
+            body["thinking"],
+            serde_json::json!({ "type": "enabled", "budget_tokens": 16384 })
+        );
+        assert_eq!(body["max_tokens"], 16434);
+        assert!(body.get("temperature").is_none());
+
+        let client = LLMClient::new(Provider::Anthropic, "claude-3-5-haiku".to_string())
+            .with_reasoning_effort(effort);
+        let body =
+            serde_json::to_value(client.build_anthropic_request(&test_messages(), 50)).unwrap();
+        assert!(body.get("thinking").is_none());
+    }
+
+    #[test]
+    fn seed_is_sent_to_openai_and_the_fingerprint_comes_back() {
+        let ok =
+            r#"{"choices":[{"message":{"content":"ok"}}],"system_fingerprint":"fp_44709d6fcb"}"#;
+        let (url, server) = mock_server(vec![http_response("200 OK", ok)]);
+        let client = LLMClient::new(Provider::OpenAI, String::new())
+            .with_base_url(&url)
+            .with_seed(Some(42));
+        let (_, tokens) = client.complete(&test_messages(), 64).unwrap();
+        let requests = server.join().unwrap();
+
+        assert!(requests[0].contains(r#""seed":42"#), "{}", requests[0]);
+        assert_eq!(tokens.fingerprint.as_deref(), Some("fp_44709d6fcb"));
+
+        let client = LLMClient::new(Provider::Groq, String::new()).with_seed(Some(42));
+        let body = serde_json::to_value(client.build_chat_request(&test_messages(), 50)).unwrap();
+        assert!(body.get("seed").is_none());
+    }
+
+    #[test]
+    fn google_thought_parts_are_left_out_of_the_answer() {
+        let ok = r#"{"candidates":[{"content":{"parts":[{"text":"Let me think. rm -rf?","thought":true},{"text":"```bash\nls\n```"}]}}]}"#;
+        let (url, server) = mock_server(vec![http_response("200 OK", ok)]);
+        let client = LLMClient::new(Provider::Google, "gemini-2.5-flash".to_string())
+            .with_base_url(&url)
+            .with_reasoning_effort(Some(ReasoningEffort::Low));
+        let (content, _) = client.complete(&test_messages(), 64).unwrap();
+        let requests = server.join().unwrap();
+
+        assert_eq!(content, "```bash\nls\n```");
+        assert!(
+            requests[0].contains(r#""thinkingConfig":{"thinkingBudget":1024}"#),
+            "{}",
+            requests[0]
+        );
+    }
+
+    #[test]
+    fn trace_file_keeps_the_traffic_but_never_the_key() {
+        let path = std::env::temp_dir().join(format!("crab-trace-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let ok = r#"{"choices":[{"message":{"content":"hello back"}}]}"#;
+        let (url, server) = mock_server(vec![
+            http_response("200 OK", ok),
+            http_response("400 Bad Request", r#"{"error":"bad key sk-live-1234"}"#),
+        ]);
+        let client = LLMClient::new(Provider::OpenAI, String::new())
+            .with_api_key("sk-live-1234")
+            .with_retries(0, Duration::from_millis(1))
+            .with_base_url(&url)
+            .with_extra_headers(HeaderMap::from_iter([(
+                HeaderName::from_static("x-api-key"),
+                HeaderValue::from_static("other-secret"),
+            )]))
+            .with_trace_file(path.to_str().unwrap());
+        client.complete(&test_messages(), 64).unwrap();
+        let _ = client.complete(&test_messages(), 64);
+        server.join().unwrap();
+        let trace = fs::read_to_string(&path).unwrap();
+        let _ = fs::remove_file(&path);
+
+        assert!(!trace.contains("sk-live-1234"), "{}", trace);
+        assert!(!trace.contains("other-secret"), "{}", trace);
+        let entries: Vec<wiretrace::WireEntry> = trace
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0].direction, "request");
+        assert!(entries[0].body.contains(r#""content":"hi""#));
+        assert_eq!(entries[0].headers["authorization"], "***");
+        assert_eq!(entries[0].headers["x-api-key"], "***");
+        assert_eq!(entries[1].status, Some(200));
+        assert_eq!(entries[1].body, ok);
+        assert_eq!(entries[3].status, Some(400));
+        assert!(entries[3].body.contains("bad key ***"));
+    }
+
+    #[test]
+    fn request_body_includes_sampling_only_when_set() {
+        let client = LLMClient::new(Provider::OpenRouter, String::new());
+        let body = serde_json::to_value(client.build_chat_request(&test_messages(), 50)).unwrap();
+        assert!(body.get("temperature").is_none());
+        assert!(body.get("top_p").is_none());
+
+        let client = client.with_sampling(Some(0.0), Some(0.9));
+        let body = serde_json::to_string(&client.build_chat_request(&test_messages(), 50)).unwrap();
+        assert!(body.contains(r#""temperature":0.0"#));
+        assert!(body.contains(r#""top_p":0.9"#));
+    }
+
+    #[test]
+    fn request_body_includes_stop_sequences_as_configured() {
+        let stop = vec!["<<END>>".to_string(), "\nUSER:".to_string()];
+        let client = LLMClient::new(Provider::OpenAI, String::new()).with_stop(stop);
+        let body = serde_json::to_value(client.build_chat_request(&test_messages(), 50)).unwrap();
+        assert_eq!(body["stop"], serde_json::json!(["<<END>>", "\nUSER:"]));
+
+        let body = serde_json::to_value(client.build_anthropic_request(&test_messages(), 50));
+        assert_eq!(
+            body.unwrap()["stop_sequences"],
+            serde_json::json!(["<<END>>", "\nUSER:"])
+        );
+    }
+
+    #[test]
+    fn request_body_omits_stop_when_unset() {
+        let client = LLMClient::new(Provider::OpenAI, String::new());
+        let body = serde_json::to_value(client.build_chat_request(&test_messages(), 50)).unwrap();
+        assert!(body.get("stop").is_none());
+
+        let body = serde_json::to_value(client.build_anthropic_request(&test_messages(), 50));
+        assert!(body.unwrap().get("stop_sequences").is_none());
+    }
+
+    const CANNED_SSE: &str = concat!(
+        ": OPENROUTER PROCESSING\n\n",
+        "data: {\"choices\":[{\"delta\":{\"role\":\"assistant\"}}]}\n\n",
+        "data: {\"choices\":[{\"delta\":{\"content\":\"Hello\"}}]}\n\n",
+        "data: {\"choices\":[{\"delta\":{\"content\":\", wörld\"}}]}\r\n\r\n",
+        "data: {\"choices\":[],\"usage\":{\"total_tokens\":12}}\n\n",
+        "data: [DONE]\n\n",
+    );
+
+    #[test]
+    fn sse_decoder_handles_chunks_split_across_reads() {
+        let mut decoder = SseDecoder::default();
+        let mut payloads = Vec::new();
+        // 5-byte reads split lines, JSON and the multi-byte 'ö'
+        for piece in CANNED_SSE.as_bytes().chunks(5) {
+            payloads.extend(decoder.feed(piece).into_iter().map(|e| e.data));
+        }
+
+        assert_eq!(payloads.len(), 5);
+        assert!(payloads[2].contains(", wörld"));
+        assert_eq!(payloads[4], "[DONE]");
+    }
+
+    #[test]
+    fn complete_stream_yields_deltas_and_full_text() {
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            CANNED_SSE.len(),
+            CANNED_SSE
+        );
+        let (url, server) = mock_server(vec![response]);
+        let client = LLMClient::new(Provider::OpenRouter, String::new()).with_base_url(&url);
+
+        let mut deltas = Vec::new();
+        let (content, tokens) = client
+            .complete_stream(&test_messages(), 100, &mut |d| deltas.push(d.to_string()))
+            .unwrap();
+
+        assert_eq!(deltas, vec!["Hello", ", wörld"]);
+        assert_eq!(content, "Hello, wörld");
+        assert_eq!(tokens.total, 12);
+        let requests = server.join().unwrap();
+        assert!(requests[0].contains(r#""stream":true"#));
+    }
+
+    #[test]
+    fn error_events_mid_stream_fail_the_completion() {
+        let streams = [
+            "event: error\ndata: {\"type\":\"error\",\"error\":{\"type\":\"overloaded_error\",\"message\":\"Overloaded\"}}\n\n",
+            "data: {\"error\":{\"message\":\"Overloaded\",\"code\":502}}\n\n",
+        ];
+        for error in streams {
+            let body = format!(
+                "data: {{\"choices\":[{{\"delta\":{{\"content\":\"Hel\"}}}}]}}\n\n\
+                 data: {{\"choices\":[{{\"delta\":{{\"content\":\"lo\"}}}}]}}\n\n{}\
+                 data: [DONE]\n\n",
+                error
+            );
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            let (url, server) = mock_server(vec![response]);
+            let client = LLMClient::new(Provider::OpenRouter, String::new()).with_base_url(&url);
+
+            let mut deltas = Vec::new();
+            let result =
+                client.complete_stream(&test_messages(), 100, &mut |d| deltas.push(d.to_string()));
+            server.join().unwrap();
+
+            assert_eq!(deltas, vec!["Hel", "lo"]);
+            match result {
+                Err(CrabError::Stream(message)) => assert_eq!(
+                    message,
+                    "openrouter reported an error mid-stream: Overloaded"
+                ),
+                other => panic!("expected a stream error, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn streamed_responses_past_the_cap_are_aborted() {
+        let delta = format!(
+            "data: {{\"choices\":[{{\"delta\":{{\"content\":\"{}\"}}}}]}}\n\n",
+            "a".repeat(500)
+        );
+        let body = delta.repeat(20);
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let (url, server) = mock_server(vec![response]);
+        let client = LLMClient::new(Provider::OpenRouter, String::new())
+            .with_base_url(&url)
+            .with_max_response_bytes(4096);
+
+        let mut streamed = 0;
+        let result = client.complete_stream(&test_messages(), 100, &mut |d| streamed += d.len());
+        server.join().unwrap();
+
+        assert!(matches!(
+            result,
+            Err(CrabError::ResponseTooLarge { limit: 4096 })
+        ));
+        assert!(streamed < 4096);
+    }
+
+    fn two_message_conversation() -> Vec<Message> {
+        vec![
+            Message {
+                role: "system".to_string(),
+                content: "You are a shell agent.".to_string(),
+                ..Default::default()
+            },
+            Message {
+                role: "user".to_string(),
+                content: "list files".to_string(),
+                ..Default::default()
+            },
+        ]
+    }
+
+    #[test]
+    fn openai_compatible_requests_keep_system_as_message() {
+        for provider in [Provider::OpenAI, Provider::OpenRouter] {
+            let client = LLMClient::new(provider, "m".to_string());
+            let body =
+                serde_json::to_value(client.build_chat_request(&two_message_conversation(), 64))
+                    .unwrap();
+            assert_eq!(
+                body,
+                serde_json::json!({
+                    "model": "m",
+                    "messages": [
+                        {"role": "system", "content": "You are a shell agent."},
+                        {"role": "user", "content": "list files"}
+                    ],
+                    "max_tokens": 64
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn anthropic_request_lifts_system_prompt() {
+        let client = LLMClient::new(Provider::Anthropic, "claude".to_string());
+        let body =
+            serde_json::to_value(client.build_anthropic_request(&two_message_conversation(), 64))
+                .unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "model": "claude",
+                "system": "You are a shell agent.",
+                "messages": [{"role": "user", "content": "list files"}],
+                "max_tokens": 64
+            })
+        );
+    }
+
+    #[test]
+    fn anthropic_request_marks_the_stable_prefix_cacheable_when_enabled() {
+        let client =
+            LLMClient::new(Provider::Anthropic, "claude".to_string()).with_prompt_cache(true);
+        let mut messages = two_message_conversation();
+        messages.push(assistant_message("COMMAND: ls".to_string()));
+        messages.push(Message {
+            role: "user".to_string(),
+            content: "COMMAND_OUTPUT: notes.txt".to_string(),
+            ..Default::default()
+        });
+
+        let body = serde_json::to_value(client.build_anthropic_request(&messages, 64)).unwrap();
+
+        let cached = serde_json::json!({"type": "ephemeral"});
+        assert_eq!(
+            body["system"],
+            serde_json::json!([{
+                "type": "text",
+                "text": "You are a shell agent.",
+                "cache_control": cached
+            }])
+        );
+        assert_eq!(body["messages"][0]["content"], "list files");
+        assert_eq!(body["messages"][1]["content"][0]["cache_control"], cached);
+        assert_eq!(body["messages"][2]["content"], "COMMAND_OUTPUT: notes.txt");
+    }
+
+    #[test]
+    fn anthropic_response_joins_text_blocks() {
+        let ok = r#"{"content":[{"type":"text","text":"ls"},{"type":"text","text":" -la"}],"usage":{"input_tokens":10,"output_tokens":3}}"#;
+        let (url, server) = mock_server(vec![http_response("200 OK", ok)]);
+        let client = LLMClient::new(Provider::Anthropic, String::new()).with_base_url(&url);
+
+        let (content, tokens) = client.complete(&two_message_conversation(), 64).unwrap();
+        assert_eq!(content, "ls -la");
+        assert_eq!(
+            tokens,
+            TokenUsage {
+                prompt: 10,
+                completion: 3,
+                total: 13,
+                finish: FinishReason::Stop,
+                model: Some("claude-3-5-sonnet-20241022".to_string()),
+                ..TokenUsage::default()
+            }
+        );
+        let requests = server.join().unwrap();
+        assert!(requests[0].contains("anthropic-version: 2023-06-01"));
+        assert!(requests[0].contains(r#""system":"You are a shell agent.""#));
+    }
+
+    #[test]
+    fn custom_base_url_receives_request_without_auth_when_keyless() {
+        let ok = r#"{"choices":[{"message":{"content":"hi"}}]}"#;
+        let (url, server) = mock_server(vec![http_response("200 OK", ok)]);
+        let client = LLMClient::new(Provider::OpenAI, "llama3".to_string())
+            .with_api_key("")
+            .with_base_url(&format!("{}/", url));
+
+        assert_eq!(client.endpoint_url(), format!("{}/chat/completions", url));
+        client.complete(&test_messages(), 10).unwrap();
+
+        let request = server.join().unwrap().remove(0);
+        assert!(request.starts_with("POST /v1/chat/completions "));
+        assert!(!request.to_ascii_lowercase().contains("authorization:"));
+    }
+
+    #[test]
+    fn complete_with_tools_offers_run_command_and_parses_calls() {
+        let ok = r#"{"choices":[{"message":{"role":"assistant","content":null,"tool_calls":[{"id":"call_1","type":"function","function":{"name":"run_command","arguments":"{\"command\":\"sort\",\"stdin\":\"b\\na\"}"}}]}}]}"#;
+        let (url, server) = mock_server(vec![http_response("200 OK", ok)]);
+        let client = LLMClient::new(Provider::OpenAI, "gpt".to_string()).with_base_url(&url);
+
+        let (message, _) = client.complete_with_tools(&test_messages(), 10).unwrap();
+
+        assert_eq!(message.content, "");
+        assert_eq!(message.tool_calls.len(), 1);
+        assert_eq!(message.tool_calls[0].id, "call_1");
+        let (command, stdin) = message.tool_calls[0].run_command().unwrap();
+        assert_eq!(command, "sort");
+        assert_eq!(stdin.as_deref(), Some("b\na"));
+
+        let request = server.join().unwrap().remove(0);
+        assert!(request.contains(r#""tool_choice":"auto""#));
+        assert!(request.contains(r#""name":"run_command""#));
+    }
+
+    #[test]
+    fn tool_messages_serialize_with_their_call_id() {
+        let call = ToolCall {
+            id: "call_1".to_string(),
+            kind: "function".to_string(),
+            function: FunctionCall {
+                name: RUN_COMMAND_TOOL.to_string(),
+                arguments: r#"{"command":"ls"}"#.to_string(),
+            },
+        };
+        let messages = vec![
+            Message {
+                role: "assistant".to_string(),
+                tool_calls: vec![call],
+                ..Default::default()
+            },
+            Message {
+                role: "tool".to_string(),
+                content: "main.rs".to_string(),
+                tool_call_id: Some("call_1".to_string()),
+                name: Some(RUN_COMMAND_TOOL.to_string()),
+                ..Default::default()
+            },
+        ];
+
+        assert_eq!(
+            serde_json::to_value(&messages).unwrap(),
+            serde_json::json!([
+                {"role": "assistant", "content": "", "tool_calls": [{
+                    "id": "call_1",
+                    "type": "function",
+                    "function": {"name": "run_command", "arguments": "{\"command\":\"ls\"}"}
+                }]},
+                {"role": "tool", "content": "main.rs", "tool_call_id": "call_1", "name": "run_command"}
+            ])
+        );
+        // Plain turns stay exactly as before
+        let plain = serde_json::to_value(&test_messages()[0]).unwrap();
+        assert_eq!(plain, serde_json::json!({"role": "user", "content": "hi"}));
+    }
+
+    #[test]
+    fn run_command_rejects_unknown_tools_and_bad_arguments() {
+        let mut call = ToolCall {
+            id: "1".to_string(),
+            kind: "function".to_string(),
+            function: FunctionCall {
+                name: "delete_everything".to_string(),
+                arguments: "{}".to_string(),
+            },
+        };
+        assert!(call.run_command().is_err());
+
+        call.function.name = RUN_COMMAND_TOOL.to_string();
+        call.function.arguments = "not json".to_string();
+        assert!(call.run_command().is_err());
+    }
+
+    #[test]
+    fn azure_routes_on_the_deployment_with_an_api_key_header() {
+        let ok = r#"{"choices":[{"message":{"content":"hi"}}]}"#;
+        let (url, server) = mock_server(vec![http_response("200 OK", ok)]);
+        let endpoint = format!("{}/", url.trim_end_matches("/v1"));
+        let azure = AzureDeployment::new(&endpoint, "gpt4o-prod", "").unwrap();
+        let client = LLMClient::new(Provider::Azure, String::new())
+            .with_api_key("azure-secret")
+            .with_azure(azure);
+
+        assert_eq!(
+            client.endpoint_url(),
+            format!(
+                "{}/openai/deployments/gpt4o-prod/chat/completions?api-version={}",
+                url.trim_end_matches("/v1"),
+                DEFAULT_AZURE_API_VERSION
+            )
+        );
+        client.complete(&test_messages(), 10).unwrap();
+
+        let request = server.join().unwrap().remove(0);
+        assert!(request.starts_with(
+            "POST /openai/deployments/gpt4o-prod/chat/completions?api-version=2024-06-01 "
+        ));
+        assert!(request.contains("api-key: azure-secret"));
+        assert!(!request.to_ascii_lowercase().contains("authorization:"));
+    }
+
+    #[test]
+    fn azure_deployment_requires_endpoint_and_deployment() {
+        assert!(AzureDeployment::new("", "prod", "").is_err());
+        assert!(AzureDeployment::new("https://x.openai.azure.com", " ", "").is_err());
+        let azure =
+            AzureDeployment::new("https://x.openai.azure.com", "prod", "2025-01-01").unwrap();
+        assert_eq!(azure.api_version, "2025-01-01");
+    }
+
+    #[test]
+    fn complete_falls_back_to_the_next_provider_on_5xx() {
+        let ok = r#"{"choices":[{"message":{"content":"from groq"}}]}"#;
+        let (down_url, down) = mock_server(vec![http_response("503 Service Unavailable", "{}")]);
+        let (up_url, up) = mock_server(vec![http_response("200 OK", ok)]);
+        let fallback = LLMClient::new(Provider::Groq, "llama".to_string()).with_base_url(&up_url);
+        let client = LLMClient::new(Provider::OpenRouter, String::new())
+            .with_retries(0, Duration::from_millis(10))
+            .with_base_url(&down_url)
+            .with_fallbacks(vec![fallback]);
+
+        let (content, usage) = client.complete(&test_messages(), 10).unwrap();
+
+        assert_eq!(content, "from groq");
+        assert_eq!(usage.model.as_deref(), Some("llama"));
+        assert_eq!(down.join().unwrap().len(), 1);
+        assert_eq!(up.join().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn context_length_errors_retry_once_with_the_larger_model() {
+        let too_long = r#"{"error":{"message":"This model's maximum context length is 128000 tokens.","code":"context_length_exceeded"}}"#;
+        let ok = r#"{"choices":[{"message":{"content":"fits now"}}]}"#;
+        let (url, server) = mock_server(vec![
+            http_response("400 Bad Request", too_long),
+            http_response("200 OK", ok),
+        ]);
+        let client = LLMClient::new(Provider::OpenAI, "gpt-4o-mini".to_string())
+            .with_retries(0, Duration::from_millis(10))
+            .with_base_url(&url)
+            .with_context_fallbacks(HashMap::from([(
+                "gpt-4o-mini".to_string(),
+                "gpt-4o".to_string(),
+            )]));
+
+        let (content, _) = client.complete(&test_messages(), 10).unwrap();
+
+        assert_eq!(content, "fits now");
+        let requests = server.join().unwrap();
+        assert!(requests[0].contains(r#""model":"gpt-4o-mini""#));
+        assert!(requests[1].contains(r#""model":"gpt-4o""#));
+
+        // Any other 400 fails as before
+        let (url, server) = mock_server(vec![http_response(
+            "400 Bad Request",
+            r#"{"error":{"message":"Invalid value for 'temperature'"}}"#,
+        )]);
+        let client = LLMClient::new(Provider::OpenAI, "gpt-4o-mini".to_string())
+            .with_retries(0, Duration::from_millis(10))
+            .with_base_url(&url)
+            .with_context_fallbacks(HashMap::from([(
+                "gpt-4o-mini".to_string(),
+                "gpt-4o".to_string(),
+            )]));
+        assert!(client.complete(&test_messages(), 10).is_err());
+        assert_eq!(server.join().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn client_errors_do_not_fall_back() {
+        let (url, server) = mock_server(vec![http_response("400 Bad Request", "{}")]);
+        // Nothing listens here, so any fallback attempt would fail with a different error
+        let fallback = LLMClient::new(Provider::Groq, String::new())
+            .with_retries(0, Duration::from_millis(10))
+            .with_base_url("http://127.0.0.1:9");
+        let client = LLMClient::new(Provider::OpenRouter, String::new())
+            .with_retries(0, Duration::from_millis(10))
+            .with_base_url(&url)
+            .with_fallbacks(vec![fallback]);
+
+        let err = client.complete(&test_messages(), 10).unwrap_err();
+
+        assert!(matches!(err, CrabError::Api { status: 400, .. }));
+        assert_eq!(server.join().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn extra_headers_are_sent_and_replace_openrouter_defaults() {
+        let ok = r#"{"choices":[{"message":{"content":"hi"}}]}"#;
+        let (url, server) = mock_server(vec![http_response("200 OK", ok)]);
+        let headers = parse_extra_headers("X-Title: My Agent, X-Proxy-Token:abc123").unwrap();
+        let client = LLMClient::new(Provider::OpenRouter, String::new())
+            .with_api_key("sk-or")
+            .with_base_url(&url)
+            .with_extra_headers(headers);
+
+        client.complete(&test_messages(), 10).unwrap();
+
+        let request = server.join().unwrap().remove(0).to_ascii_lowercase();
+        assert!(request.contains("x-title: my agent\r\n"));
+        assert!(!request.contains("x-title: crabshell"));
+        assert!(request.contains("x-proxy-token: abc123\r\n"));
+        assert!(request.contains("authorization: bearer sk-or\r\n"));
+    }
+
+    #[test]
+    fn malformed_or_protected_extra_headers_are_rejected() {
+        for spec in [
+            "NoColon",
+            "Bad Name:x",
+            "Authorization:Bearer x",
+            "content-type:text/plain",
+        ] {
+            let err = parse_extra_headers(spec).unwrap_err();
+            assert!(matches!(err, CrabError::Config(_)), "{}", spec);
+        }
+        assert!(parse_extra_headers("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn llm_proxy_carries_the_request() {
+        let ok = r#"{"choices":[{"message":{"content":"via proxy"}}]}"#;
+        let (proxy_url, proxy) = mock_server(vec![http_response("200 OK", ok)]);
+        let client = LLMClient::new(Provider::OpenAI, String::new())
+            .with_base_url("http://llm.crab.invalid/v1")
+            .with_proxy(parse_proxy(proxy_url.trim_end_matches("/v1")).unwrap());
+
+        let (content, _) = client.complete(&test_messages(), 10).unwrap();
+
+        assert_eq!(content, "via proxy");
+        let request = proxy.join().unwrap().remove(0);
+        assert!(request.starts_with("POST http://llm.crab.invalid/v1/chat/completions "));
+    }
+
+    #[test]
+    fn bad_llm_proxy_is_a_config_error() {
+        assert!(parse_proxy("").unwrap().is_none());
+        for url in ["not a url", "ftp://proxy:21", "http://[::1"] {
+            let err = parse_proxy(url).unwrap_err();
+            assert!(matches!(err, CrabError::Config(_)), "{}", url);
+            assert!(err.to_string().contains("LLM_PROXY"));
+        }
+    }
+
+    #[test]
+    fn complete_fails_fast_on_client_errors() {
+        let (url, server) = mock_server(vec![http_response("401 Unauthorized", "{}")]);
+
+        let client = LLMClient::new(Provider::OpenRouter, String::new())
+            .with_retries(3, Duration::from_millis(10))
+            .with_base_url(&url);
+
+        let err = client.complete(&test_messages(), 100).unwrap_err();
+        assert!(err.to_string().contains("401"));
+        assert_eq!(server.join().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn each_command_keeps_its_own_explanation() {
+        let response = "ACTION: EXECUTE\n```bash\n# parallel\n# explanation: count the log files\nls /var/log | wc -l\n```\n```bash\ndf -h /\n```";
+        let commands = extract_commands(response);
+        let explained: Vec<(Option<String>, String)> =
+            commands.iter().map(|cmd| split_explanation(cmd)).collect();
+
+        assert_eq!(
+            explained,
+            vec![
+                (
+                    Some("count the log files".to_string()),
+                    "# parallel\nls /var/log | wc -l".to_string()
+                ),
+                (None, "df -h /".to_string()),
+            ]
+        );
+        // Only a leading comment counts, not an echo of the marker later on
+        assert_eq!(
+            split_explanation("echo '# explanation: no'\n# explanation: late"),
+            (
+                None,
+                "echo '# explanation: no'\n# explanation: late".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn extract_commands_returns_every_block_in_order() {
+        let response = "ACTION: EXECUTE\nCOMMAND: cd /app/workspace/work\nCOMMAND: ls -la\nCOMMAND: cat <<'EOF' > notes.txt\nhello\nEOF";
+
+        assert_eq!(
+            extract_commands(response),
+            vec![
+                "cd /app/workspace/work".to_string(),
+                "ls -la".to_string(),
+                "cat <<'EOF' > notes.txt\nhello\nEOF".to_string(),
+            ]
+        );
+        assert!(extract_commands("All done, nothing to run.").is_empty());
+    }
+
+    #[test]
+    fn extract_commands_accepts_fence_with_language_tag() {
+        let response = "```bash\nls -la /app/workspace\n```";
+        assert_eq!(extract_commands(response), vec!["ls -la /app/workspace"]);
+    }
+
+    #[test]
+    fn extract_commands_accepts_bare_fence() {
+        let response = "```\ndf -h\n```";
+        assert_eq!(extract_commands(response), vec!["df -h"]);
+    }
+
+    #[test]
+    fn commented_blocks_stay_one_command_without_their_language_line() {
+        let response =
+            "```bash\n# list what's there\nls -la  # long form\n\n# then the size\ndu -sh .\n```";
+        assert_eq!(
+            extract_commands(response),
+            vec!["# list what's there\nls -la  # long form\n\n# then the size\ndu -sh ."]
+        );
+        let annotated = "```\nbash\n# disk\ndf -h\n```";
+        assert_eq!(extract_commands(annotated), vec!["# disk\ndf -h"]);
+        let delimiters = CommandDelimiters::new("<cmd>", "</cmd>");
+        assert_eq!(
+            extract_commands_and_scripts("<cmd>sh\nuptime</cmd>", &delimiters, &HashMap::new()),
+            vec!["uptime"]
+        );
+        // A lone word is a command, even if it names a shell
+        assert_eq!(extract_commands("```\nbash\n```"), vec!["bash"]);
+
+        assert!(is_comment_only("# nothing to do yet\n\n  # really"));
+        assert!(!is_comment_only("# list\nls"));
+    }
+
+    #[test]
+    fn abort_is_read_from_a_line_or_the_json_action() {
+        assert_eq!(
+            extract_abort("The repo is gone.\nABORT: /src does not exist").as_deref(),
+            Some("/src does not exist")
+        );
+        assert_eq!(
+            extract_abort(r#"{"message":"","action":"ABORT: no credentials","terminal":""}"#)
+                .as_deref(),
+            Some("no credentials")
+        );
+        assert_eq!(
+            extract_abort("<thinking>ABORT: maybe?</thinking>\n```bash\nls\n```"),
+            None
+        );
+        assert_eq!(
+            extract_abort(r#"{"action":"","message":"ABORT: no"}"#),
+            None
+        );
+    }
+
+    #[test]
+    fn env_directives_come_out_of_the_leading_comments() {
+        let (env, cmd) = split_env_directives(
+            "# env: RUST_LOG=debug\n# explanation: run the tests\n# env: GREETING=\"hello there\"\ncargo test\n# env: LATE=1",
+        );
+        assert_eq!(
+            env,
+            vec![
+                ("RUST_LOG".to_string(), "debug".to_string()),
+                ("GREETING".to_string(), "hello there".to_string()),
+            ]
+        );
+        assert_eq!(
+            cmd,
+            "# explanation: run the tests\ncargo test\n# env: LATE=1"
+        );
+    }
+
+    #[test]
+    fn extract_commands_finds_fence_inside_prose() {
+        let response = "Let me check the disk first.\n\n```sh\ndf -h\ndu -sh /app\n```\n\nThen I'll report back. Here is some Python for reference:\n```python\nprint('not run')\n```";
+        assert_eq!(extract_commands(response), vec!["df -h\ndu -sh /app"]);
+    }
+
+    #[test]
+    fn script_fences_run_through_their_interpreter() {
+        let response = "```python\nfor n in range(3):\n    print(n)\n```\nThen:\n```bash\nls\n```\n```ruby\nputs 1\n```";
+        let interpreters = HashMap::from([("python".to_string(), "python3 -c".to_string())]);
+
+        assert_eq!(
+            extract_commands_and_scripts(response, &CommandDelimiters::default(), &interpreters),
+            vec![
+                "python3 -c 'for n in range(3):\n    print(n)\n'".to_string(),
+                "ls".to_string(),
+            ]
+        );
+        assert_eq!(extract_commands(response), vec!["ls"]);
+    }
+
+    #[test]
+    fn extract_commands_prefers_whichever_convention_comes_first() {
+        let marker_first = "ACTION: EXECUTE\nCOMMAND: whoami\n\n```bash\nuptime\n```";
+        assert_eq!(extract_commands(marker_first), vec!["whoami"]);
+
+        let fence_first = "```bash\nuptime\n```\nACTION: EXECUTE\nCOMMAND: whoami";
+        assert_eq!(extract_commands(fence_first), vec!["uptime"]);
+    }
+
+    #[test]
+    fn custom_delimiters_drive_extraction_and_the_prompt() {
+        let delimiters = CommandDelimiters::new("<cmd>", "</cmd>");
+        let response = "Checking disk.\n<cmd>df -h</cmd> then <cmd>\ndu -sh /app\n</cmd>\n```bash\nuptime\n```\n<cmd>unclosed";
+
+        assert_eq!(
+            extract_commands_and_scripts(response, &delimiters, &HashMap::new()),
+            vec!["df -h", "du -sh /app"]
+        );
+
+        let prompt =
+            render_system_prompt(DEFAULT_SYSTEM_PROMPT, "Crabby", "SRE", "img", &delimiters);
+        assert!(prompt.contains("a command goes between <cmd> and </cmd>."));
+        assert!(!prompt.contains("{command_start}"));
+    }
+
+    #[test]
+    fn empty_delimiters_find_nothing_instead_of_looping() {
+        let response = "<cmd>df -h</cmd>\nACTION: EXECUTE\nCOMMAND: uptime";
+        for delimiters in [
+            CommandDelimiters::new("", ""),
+            CommandDelimiters::new("<cmd>", ""),
+            CommandDelimiters::new("", "</cmd>"),
+        ] {
+            assert!(extract_delimited_blocks(response, &delimiters).is_empty());
+        }
+    }
+
+    #[test]
+    fn extract_stdin_reads_the_json_contract_field() {
+        let response = r#"{"message": "", "terminal": "sort", "stdin": "b\na\n"}"#;
+        assert_eq!(extract_stdin(response).as_deref(), Some("b\na\n"));
+
+        assert_eq!(extract_stdin(r#"{"terminal": "", "stdin": "x"}"#), None);
+        assert_eq!(extract_stdin(r#"{"terminal": "ls", "stdin": ""}"#), None);
+        assert_eq!(extract_stdin("ACTION: EXECUTE\nCOMMAND: sort"), None);
+    }
+
+    #[test]
+    fn extract_commands_ignores_commands_inside_thinking() {
+        let response = "<thinking>\nMaybe wipe it first:\n```bash\nrm -rf /app/workspace\n```\nNo, just look.\n</thinking>\n```bash\nls /app/workspace\n```";
+        assert_eq!(extract_commands(response), vec!["ls /app/workspace"]);
+
+        let response = "## Reasoning\nACTION: EXECUTE\nCOMMAND: reboot\n\n## Plan\nACTION: EXECUTE\nCOMMAND: uptime";
+        assert_eq!(extract_commands(response), vec!["uptime"]);
+    }
+
+    #[test]
+    fn split_reasoning_separates_thoughts_from_the_answer() {
+        let (reasoning, visible) =
+            split_reasoning("<thinking>The user wants a number.</thinking>\n\nThe answer is 42.");
+        assert_eq!(reasoning, "The user wants a number.");
+        assert_eq!(visible, "The answer is 42.");
+
+        let (reasoning, visible) = split_reasoning("## Reasoning\nEasy one.\n## Answer\n42");
+        assert_eq!(reasoning, "Easy one.");
+        assert_eq!(visible, "## Answer\n42");
+
+        let (reasoning, visible) = split_reasoning("Plain answer <b>bold</b>\n");
+        assert!(reasoning.is_empty());
+        assert_eq!(visible, "Plain answer <b>bold</b>\n");
+
+        let (_, visible) = split_reasoning("<thinking>still going");
+        assert!(visible.is_empty());
+    }
+
+    fn message(role: &str, content: &str) -> Message {
+        Message {
+            role: role.to_string(),
+            content: content.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn trim_history_keeps_system_prompt_and_latest_user_message() {
+        let mut messages = vec![message("system", &"s".repeat(400))];
+        for i in 0..50 {
+            messages.push(message(
+                "user",
+                &format!("question {} {}", i, "q".repeat(400)),
+            ));
+            messages.push(message("assistant", &"a".repeat(400)));
         }
+        messages.push(message("user", "latest question"));
+
+        let removed = trim_history(&mut messages, 1000);
+
+        assert!(removed > 0);
+        assert!(estimate_tokens(&messages) <= 1000);
+        assert_eq!(messages[0].role, "system");
+        assert_eq!(messages.last().unwrap().content, "latest question");
+        // What survives is the most recent tail of the conversation
+        assert_eq!(messages[messages.len() - 2].role, "assistant");
+    }
+
+    #[test]
+    fn stale_outputs_are_elided_but_their_commands_stay() {
+        let output = |content: &str, exit_code: i32| Message {
+            exit_code: Some(exit_code),
+            ..message("user", content)
+        };
+        let mut messages = vec![
+            message("system", "prompt"),
+            message("user", "why is the build failing?"),
+            message("assistant", "COMMAND: find . -name '*.log'"),
+            output(&"x".repeat(4321), 0),
+            message("assistant", "COMMAND: grep -r error build.log"),
+            output("build.log:12: error: missing semicolon", 1),
+            message("assistant", "COMMAND: sed -n 10,14p src/main.c"),
+            output("int main() {\n  return 0\n}", 0),
+        ];
+
+        assert_eq!(elide_stale_outputs(&mut messages, 1), 2);
+
+        assert_eq!(messages[3].content, "[output elided: 4321 bytes, exit 0]");
+        assert_eq!(messages[5].content, "[output elided: 38 bytes, exit 1]");
+        assert_eq!(messages[2].content, "COMMAND: find . -name '*.log'");
+        assert_eq!(messages[4].content, "COMMAND: grep -r error build.log");
+        assert_eq!(messages[7].content, "int main() {\n  return 0\n}");
+        assert_eq!(elide_stale_outputs(&mut messages, 1), 0);
+    }
+
+    #[test]
+    fn trim_history_leaves_small_conversations_alone() {
+        let mut messages = vec![message("system", "prompt"), message("user", "hi")];
+        assert_eq!(trim_history(&mut messages, 1000), 0);
+        assert_eq!(messages.len(), 2);
     }
-    None
 }
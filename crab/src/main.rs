@@ -1,187 +1,317 @@
-mod llm;
-mod tools;
-
-use llm::{build_system_prompt, extract_command, LLMClient, Message};
-use serde::{Deserialize, Serialize};
+use clap::Parser;
+use hermit_crab::cassette::Cassette;
+use hermit_crab::checkpoint;
+use hermit_crab::config::{self, Cli, CliCommand, Config, ExecutorKind, OutputFormat};
+use hermit_crab::llm::{
+    self, api_key_var, provider_api_key, Message, Provider, DEFAULT_SYSTEM_PROMPT,
+};
+use hermit_crab::mock::MockScript;
+use hermit_crab::redact::Redactor;
+use hermit_crab::style;
+use hermit_crab::telemetry;
+use hermit_crab::tools::{
+    cleanup_active_containers, ensure_image, output_encoding, parse_shell_cmd,
+    reap_background_processes, running_in_container, set_output_encoding, DockerSession, Executor,
+    HostRunner, Shell,
+};
+use hermit_crab::{
+    api_key_from_sources, build_client, build_system_prompt, debug_info, ensure_workspace_dir,
+    estimate_report, expand_task, fetch_meeting_context, fetch_memory_from_shell, load_macros,
+    offer_to_save_key, parse_history_from_base64, parse_history_from_file, plan_first,
+    process_exit_code, prompt_for_api_key, prompt_report, read_batch_tasks, read_user_message,
+    report_outcome, require_task, run_agent_loop, run_batch, run_hook, run_repl, shut_down,
+    LoopOutcome, PostHook, RunReport, RunStats,
+};
+use std::collections::HashMap;
 use std::env;
 use std::fs;
+use std::io::{self, IsTerminal, Write};
 use std::path::Path;
-use std::thread;
-use std::time::Duration;
-use tools::{build_meeting_prompt, execute_command, extract_delegate_action};
-
-const WORKSPACE_DIR: &str = "/app/workspace";
-
-#[derive(Debug, Serialize, Deserialize)]
-struct Config {
-    agent_name: String,
-    agent_role: String,
-    docker_image: String,
-    user_msg: String,
-    history: Vec<Message>,
-    max_tokens: u32,
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// RUST_LOG picks the level (off unless set); output goes to stderr.
+// HTTP internals stay at warn even under RUST_LOG=trace, since they'd dump headers.
+fn init_logging() {
+    env_logger::Builder::new()
+        .filter_module("reqwest", log::LevelFilter::Warn)
+        .filter_module("hyper", log::LevelFilter::Warn)
+        .parse_default_env()
+        .target(env_logger::Target::Stderr)
+        .init();
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct MemoryEntry {
-    content: String,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct MeetingContext {
-    meeting_id: i32,
-    topic: String,
-    transcript: String,
-    participant_role: String,
-}
-
-fn parse_history_from_file(file_path: &str) -> Vec<Message> {
-    let path = Path::new(file_path);
-    if !path.exists() {
-        eprintln!("History file not found: {}", file_path);
-        return Vec::new();
+fn main() {
+    // Before anything reads the environment, RUST_LOG included
+    config::load_dotenv(Path::new(config::DOTENV_FILE));
+    init_logging();
+    let cli = Cli::parse();
+    if cli.list_personas {
+        print!("{}", config::list_personas());
+        return;
     }
-
-    match fs::read_to_string(path) {
-        Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
-            eprintln!("Failed to parse history JSON: {}", e);
-            Vec::new()
-        }),
+    let mut config = match Config::load(&cli) {
+        Ok(config) => config,
         Err(e) => {
-            eprintln!("Failed to read history file: {}", e);
-            Vec::new()
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
         }
+    };
+    if cli.debug_info {
+        print!(
+            "{}",
+            debug_info(&config, &provider_api_key(config.provider))
+        );
+        return;
     }
-}
-
-fn wait_for_approval(max_wait_secs: u64) -> bool {
-    let lock_file = "/tmp/hermit_approval.lock";
-    let deny_file = "/tmp/hermit_deny.lock";
-    let mut waited = 0;
-
-    println!("[HITL] Waiting for approval...");
-
-    while waited < max_wait_secs {
-        if Path::new(lock_file).exists() {
-            let _ = fs::remove_file(lock_file);
-            println!("[HITL] Approved!");
-            return true;
+    if let Err(problems) = config.validate() {
+        for problem in &problems {
+            eprintln!("Error: {}", problem);
         }
-
-        if Path::new(deny_file).exists() {
-            let _ = fs::remove_file(deny_file);
-            println!("[HITL] Denied!");
-            return false;
+        std::process::exit(1);
+    }
+    if let Some(encoding) = output_encoding(&config.output_encoding) {
+        set_output_encoding(encoding);
+    }
+    telemetry::init(&config.otel_endpoint);
+    style::init(config.color);
+    // Caught here rather than as a cryptic docker failure halfway through the run
+    let docker_options = match config.docker_options() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
         }
-
-        thread::sleep(Duration::from_secs(1));
-        waited += 1;
+    };
+    if let Err(e) = config.extra_headers() {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
     }
-
-    println!("[HITL] Approval timeout!");
-    false
-}
-
-fn ensure_workspace_dir() {
-    let workspace = Path::new(WORKSPACE_DIR);
-    if !workspace.exists() {
-        if let Err(e) = fs::create_dir_all(workspace) {
-            eprintln!("Warning: Could not create workspace directory: {}", e);
+    let proxy = match llm::parse_proxy(&config.llm_proxy) {
+        Ok(proxy) => proxy,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let cassette = match Cassette::from_config(&config.llm_record, &config.llm_replay) {
+        Ok(cassette) => cassette.map(Arc::new),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let shell_cmd = match parse_shell_cmd(&config.shell_cmd) {
+        Ok(shell_cmd) => shell_cmd,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let fallbacks = match config.fallbacks() {
+        Ok(fallbacks) => fallbacks,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let uses_azure = std::iter::once(config.provider)
+        .chain(fallbacks.iter().map(|(provider, _)| *provider))
+        .any(|provider| provider == Provider::Azure);
+    let uses_mock = std::iter::once(config.provider)
+        .chain(fallbacks.iter().map(|(provider, _)| *provider))
+        .any(|provider| provider == Provider::Mock);
+    let mock = match uses_mock.then(|| MockScript::from_config(&config.mock_responses)) {
+        Some(Err(e)) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        Some(Ok(mock)) => Some(Arc::new(mock)),
+        None => None,
+    };
+    let azure = match uses_azure.then(|| config.azure_deployment()) {
+        Some(Err(e)) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        Some(Ok(azure)) => Some(azure),
+        None => None,
+    };
+    // A custom base URL usually means a local server that doesn't need a key, and
+    // neither a replay, an estimate, a printed prompt nor the mock provider reaches
+    // the API. Checked before ensure_workspace_dir so a saved key lands in the .env
+    // we load.
+    let replaying = cassette.as_ref().is_some_and(|c| c.is_replay());
+    if config.base_url.is_empty()
+        && !replaying
+        && config.provider != Provider::Mock
+        && !cli.estimate
+        && !cli.print_prompt
+        && provider_api_key(config.provider).is_empty()
+    {
+        let var = api_key_var(config.provider);
+        let interactive = io::stdin().is_terminal() && io::stderr().is_terminal();
+        let key = match api_key_from_sources(var, &config.key_command) {
+            Ok(Some(key)) => Ok(key),
+            Ok(None) => prompt_for_api_key(var, interactive, |prompt| {
+                rpassword::prompt_password(prompt)
+            })
+            .inspect(|key| {
+                if interactive {
+                    offer_to_save_key(var, key, &mut io::stdin().lock(), &mut io::stderr());
+                }
+            }),
+            Err(e) => Err(e),
+        };
+        match key {
+            // Set in our own environment so redaction knows about it too; commands
+            // never see it, since FORWARD_ENV is an allowlist and skips API keys
+            Ok(key) => env::set_var(var, &key),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
         }
     }
-
-    if let Ok(cwd) = env::current_dir() {
-        if cwd != Path::new(WORKSPACE_DIR) {
-            if let Err(e) = env::set_current_dir(WORKSPACE_DIR) {
-                eprintln!("Warning: Could not change to workspace directory: {}", e);
-            } else {
-                eprintln!("[Workspace] Working directory set to {}", WORKSPACE_DIR);
+    if let Some(CliCommand::Models { json }) = &cli.command {
+        let client = build_client(
+            &config,
+            config.provider,
+            config.model.clone(),
+            &azure,
+            &proxy,
+        )
+        .with_base_url(&config.base_url);
+        match client.list_models() {
+            Ok(models) if *json => {
+                println!("{}", serde_json::to_string(&models).unwrap_or_default())
+            }
+            Ok(models) => {
+                for model in models {
+                    println!("{}", model);
+                }
+            }
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
             }
         }
+        return;
     }
-}
-
-fn save_meeting_note(meeting_id: i32, note: &str) {
-    use std::io::Write;
-    let note_file = format!("{}/meeting_{}.txt", WORKSPACE_DIR, meeting_id);
-    let timestamp = chrono_timestamp();
-    let content = format!("[{}] {}\n", timestamp, note);
+    let history_file = config.history_file.clone();
 
-    if let Err(e) = fs::OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(&note_file)
-        .and_then(|mut f| f.write_all(content.as_bytes()))
+    // Read before ensure_workspace_dir changes directory, so relative paths work
+    let batch_tasks = if config.batch_file.is_empty() {
+        None
+    } else {
+        match read_batch_tasks(&config.batch_file) {
+            Ok(tasks) => Some(tasks),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    };
+    // Keeps updating the checkpoint it came from unless CHECKPOINT_FILE says otherwise
+    let resumed = match &cli.resume {
+        Some(path) => match checkpoint::resume(path, &config) {
+            Ok(resumed) => {
+                if config.checkpoint_file.is_empty() {
+                    config.checkpoint_file = fs::canonicalize(path)
+                        .map(|p| p.display().to_string())
+                        .unwrap_or_else(|_| path.clone());
+                }
+                Some(resumed)
+            }
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    if config.user_msg.is_empty()
+        && !config.interactive
+        && batch_tasks.is_none()
+        && resumed.is_none()
     {
-        eprintln!("Warning: Could not save meeting note: {}", e);
+        let stdin = io::stdin();
+        let piped = (!stdin.is_terminal()).then(|| stdin.lock());
+        match read_user_message(&config.user_msg_file, piped) {
+            Ok(msg) => config.user_msg = msg,
+            Err(e) => {
+                eprintln!("Error: Could not read user message: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    let macros = if config.macros_file.is_empty() {
+        Ok(HashMap::new())
+    } else {
+        load_macros(&config.macros_file)
+    };
+    match macros.and_then(|macros| expand_task(&config.user_msg, &macros, &config.prompt_vars)) {
+        Ok(task) => config.user_msg = task,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    }
+    // The REPL just asks again, and batch tasks and checkpoints bring their own
+    if !config.interactive && batch_tasks.is_none() && resumed.is_none() {
+        if let Err(e) = require_task(&config.user_msg) {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
     }
-}
-
-fn chrono_timestamp() -> String {
-    use std::time::{SystemTime, UNIX_EPOCH};
-    let duration = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap_or_default();
-    let secs = duration.as_secs();
-    let datetime = format!(
-        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
-        1970 + secs / 31536000,
-        (secs % 31536000) / 2592000 + 1,
-        (secs % 2592000) / 86400 + 1,
-        (secs % 86400) / 3600,
-        (secs % 3600) / 60,
-        secs % 60
-    );
-    datetime
-}
-
-fn main() {
-    let agent_name = env::var("AGENT_NAME").unwrap_or_else(|_| "CrabShell".to_string());
-    let agent_role = env::var("AGENT_ROLE").unwrap_or_else(|_| "General Assistant".to_string());
-    let docker_image = env::var("DOCKER_IMAGE").unwrap_or_else(|_| "hermit/base".to_string());
-    let user_msg = env::var("USER_MSG").unwrap_or_default();
-    let history_file = env::var("HISTORY_FILE").unwrap_or_default();
-    let max_tokens: u32 = env::var("MAX_TOKENS")
-        .unwrap_or_else(|_| "1000".to_string())
-        .parse()
-        .unwrap_or(1000);
-    let agent_id: i32 = env::var("AGENT_ID")
-        .unwrap_or_else(|_| "0".to_string())
-        .parse()
-        .unwrap_or(0);
 
-    let hitl_enabled = env::var("HITL_ENABLED").unwrap_or_else(|_| "false".to_string()) == "true";
+    let prompt_template = if config.system_prompt_file.is_empty() {
+        DEFAULT_SYSTEM_PROMPT.to_string()
+    } else {
+        match fs::read_to_string(&config.system_prompt_file) {
+            Ok(template) => template,
+            Err(e) => {
+                eprintln!(
+                    "Error: Could not read SYSTEM_PROMPT_FILE {}: {}",
+                    config.system_prompt_file, e
+                );
+                std::process::exit(1);
+            }
+        }
+    };
 
     ensure_workspace_dir();
 
-    let api_key = env::var("OPENAI_API_KEY")
-        .or_else(|_| env::var("OPENROUTER_API_KEY"))
-        .expect("No API key found");
-
-    let history = if history_file.is_empty() {
+    config.history = if history_file.is_empty() {
         let history_b64 = env::var("HISTORY").unwrap_or_default();
         parse_history_from_base64(&history_b64)
     } else {
         parse_history_from_file(&history_file)
     };
+    let config = config;
 
-    let mut system_prompt = build_system_prompt(&agent_name, &agent_role, &docker_image);
-    system_prompt.push_str(&build_meeting_prompt());
-    system_prompt.push_str(&format!("\n\nWORKSPACE: All file operations should be performed in {} directory. This is your persistent workspace that survives across sessions.\n", WORKSPACE_DIR));
+    let mount = docker_options.mount.as_ref().filter(|_| {
+        matches!(config.executor, ExecutorKind::Auto | ExecutorKind::Docker)
+            && !running_in_container()
+    });
+    let system_prompt = build_system_prompt(&config, &prompt_template, mount);
 
-    let memory_context = fetch_memory_from_shell(agent_id, &user_msg);
+    let memory_context = fetch_memory_from_shell(config.agent_id, &config.user_msg);
 
-    let meeting_context = fetch_meeting_context(agent_id);
+    let meeting_context = fetch_meeting_context(config.agent_id);
 
     let mut messages = vec![Message {
         role: "system".to_string(),
         content: system_prompt,
+        ..Default::default()
     }];
 
     if !memory_context.is_empty() {
         messages.push(Message {
             role: "system".to_string(),
             content: format!("Relevant past memories:\n{}", memory_context),
+            ..Default::default()
         });
     }
 
@@ -189,172 +319,317 @@ fn main() {
         messages.push(Message {
             role: "system".to_string(),
             content: format!("Active meeting context:\n{}", meeting_context),
+            ..Default::default()
         });
     }
 
-    for msg in &history {
+    for msg in &config.history {
         messages.push(msg.clone());
     }
 
-    messages.push(Message {
-        role: "user".to_string(),
-        content: user_msg,
-    });
-
-    let client = LLMClient::new();
-    let mut iterations = 0;
-    let max_iterations = 5;
-
-    while iterations < max_iterations {
-        iterations += 1;
-
-        match client.complete(&messages, max_tokens) {
-            Ok((response, _tokens)) => {
-                if let Some((role, task)) = extract_delegate_action(&response) {
-                    println!("[MEETING] Sub-task delegation requested...");
-                    println!("[MEETING] TARGET_ROLE: {}", role);
-                    println!("[MEETING] TASK: {}", task);
-
-                    if hitl_enabled {
-                        println!("[HITL] DELEGATION_APPROVAL_REQUIRED for role: {}", role);
-                    }
-
-                    messages.push(Message {
-                        role: "assistant".to_string(),
-                        content: response.clone(),
-                    });
-                    messages.push(Message {
-                        role: "user".to_string(),
-                        content: "Delegation request logged. Waiting for operator approval..."
-                            .to_string(),
-                    });
-                    continue;
-                }
-
-                if let Some(cmd) = extract_command(&response) {
-                    // Make sure we stream the important markers to stdout for the orchestrator
-                    for line in response.lines() {
-                        let trimmed = line.trim();
-                        if trimmed.starts_with("COMMAND:") || trimmed.starts_with("FILE:") {
-                            println!("{}", trimmed);
-                        }
-                    }
-
-                    messages.push(Message {
-                        role: "assistant".to_string(),
-                        content: response.clone(),
-                    });
-
-                    let needs_approval = tools::is_dangerous_command(&cmd);
-
-                    if needs_approval && hitl_enabled {
-                        println!("[HITL] APPROVAL_REQUIRED: {}", cmd);
-
-                        let approved = wait_for_approval(600);
-
-                        if !approved {
-                            let error_msg = "ERROR: Command denied by user".to_string();
-                            messages.push(Message {
-                                role: "user".to_string(),
-                                content: error_msg,
-                            });
-                            continue;
-                        }
+    // Interactive mode hands the task to the REPL as its first turn instead, and
+    // batch mode pushes each of its own
+    if !config.interactive && batch_tasks.is_none() {
+        let images = match config
+            .images
+            .iter()
+            .map(|image| llm::image_data_url(image))
+            .collect::<Result<_, _>>()
+        {
+            Ok(images) => images,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        };
+        messages.push(Message {
+            role: "user".to_string(),
+            content: config.user_msg.clone(),
+            images,
+            ..Default::default()
+        });
+    }
+    // The checkpoint's system prompt was already grounded in this session
+    let resume_at = match resumed {
+        Some(resumed) => {
+            eprintln!(
+                "[Crab] Resuming after iteration {} with {} messages",
+                resumed.iterations,
+                resumed.messages.len()
+            );
+            messages = resumed.messages;
+            resumed.iterations
+        }
+        None => 0,
+    };
 
-                        println!("[HITL] EXECUTING: {}", cmd);
-                    }
+    // API_BASE_URL only applies to the main provider; fallbacks use their own endpoints
+    let client = build_client(
+        &config,
+        config.provider,
+        config.model.clone(),
+        &azure,
+        &proxy,
+    )
+    .with_base_url(&config.base_url)
+    .with_cassette(cassette)
+    .with_mock(mock.clone())
+    .with_fallbacks(
+        fallbacks
+            .into_iter()
+            .map(|(provider, model)| {
+                build_client(&config, provider, model, &azure, &proxy).with_mock(mock.clone())
+            })
+            .collect(),
+    );
+    // Nothing has been sent or started yet, so there's nothing to clean up either
+    if cli.estimate {
+        print!("{}", estimate_report(&messages, &config, client.model()));
+        return;
+    }
+    if cli.print_prompt {
+        let redactor = Redactor::with_api_keys(&config.redact_patterns);
+        print!("{}", prompt_report(&messages, &redactor));
+        return;
+    }
+    let post_hook = Arc::new(PostHook::new(&config.post_hook, shell_cmd.clone()));
+    let interrupt = Arc::new(AtomicBool::new(false));
+    let flag = Arc::clone(&interrupt);
+    let forced_post_hook = Arc::clone(&post_hook);
+    if let Err(e) = ctrlc::set_handler(move || {
+        // The second Ctrl-C doesn't wait for the current step to finish
+        if flag.swap(true, Ordering::SeqCst) {
+            cleanup_active_containers();
+            reap_background_processes();
+            forced_post_hook.run(130);
+            std::process::exit(130);
+        }
+        eprintln!(
+            "\n[Crab] Interrupted, stopping after the current step (Ctrl-C again to force quit)"
+        );
+    }) {
+        eprintln!("Warning: Could not install Ctrl-C handler: {}", e);
+    }
 
-                    match execute_command(&cmd) {
-                        Ok(output) => {
-                            let output_msg = format!("COMMAND_OUTPUT:\n{}", output);
-                            messages.push(Message {
-                                role: "user".to_string(),
-                                content: output_msg,
-                            });
-                        }
-                        Err(e) => {
-                            let error_msg = format!("ERROR: {}", e);
-                            messages.push(Message {
-                                role: "user".to_string(),
-                                content: error_msg,
-                            });
-                        }
-                    }
-                } else {
-                    println!("{}", response);
-                    break;
-                }
+    let executor = config.executor_kind();
+    let session = if executor == ExecutorKind::Local && running_in_container() {
+        eprintln!("[Sandbox] Already inside a container, running commands directly");
+        None
+    } else if executor != ExecutorKind::Docker {
+        None
+    } else {
+        if let Err(e) = ensure_image(&config.docker_image, config.docker_auto_pull) {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        match DockerSession::start(&config.docker_image, &config.forward_env, &docker_options) {
+            Ok(session) => {
+                eprintln!(
+                    "[Sandbox] Started container {} from {}",
+                    session.container_id(),
+                    config.docker_image
+                );
+                Some(
+                    session
+                        .with_shell(shell_cmd.clone())
+                        .with_stream_output(config.stream_output),
+                )
             }
             Err(e) => {
                 eprintln!("Error: {}", e);
                 std::process::exit(1);
             }
         }
-    }
-
-    if iterations >= max_iterations {
-        eprintln!("Max iterations reached");
-        std::process::exit(1);
-    }
-}
+    };
 
-fn fetch_memory_from_shell(agent_id: i32, _query: &str) -> String {
-    if agent_id == 0 {
-        return String::new();
+    let remote = (executor == ExecutorKind::Ssh).then(|| {
+        eprintln!("[Sandbox] Running commands over SSH on {}", config.ssh_host);
+        config.ssh_runner(shell_cmd.clone())
+    });
+    let sandbox = if config.dry_run || config.sandbox.trim().is_empty() {
+        Vec::new()
+    } else if executor != ExecutorKind::Local {
+        eprintln!("Warning: SANDBOX only wraps host commands, ignoring it");
+        Vec::new()
+    } else {
+        match config.sandbox_prefix() {
+            Ok(prefix) => {
+                eprintln!(
+                    "[Sandbox] Running host commands under {}",
+                    config.sandbox.trim()
+                );
+                prefix
+            }
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    };
+    let run_as = if config.exec_as_user.trim().is_empty() {
+        None
+    } else if executor != ExecutorKind::Local {
+        eprintln!("Warning: EXEC_AS_USER only applies to host commands, ignoring it");
+        None
+    } else {
+        match config.host_user() {
+            Ok(user) => {
+                eprintln!(
+                    "[Sandbox] Running host commands as {}",
+                    config.exec_as_user.trim()
+                );
+                user
+            }
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    };
+    let mut shell = Shell::new();
+    let hook_shell = shell_cmd.clone();
+    let host = HostRunner {
+        forward_env: config.forward_env.clone(),
+        shell: shell_cmd,
+        sandbox,
+        run_as,
+        stream_output: config.stream_output,
+    };
+    let runner: &dyn Executor = match (&session, &remote) {
+        (Some(session), _) => session,
+        (None, Some(remote)) => remote,
+        (None, None) => &host,
+    };
+    // Run where the commands will run, so a shell missing from the image is caught too
+    if !config.dry_run {
+        let probe = runner.run("exit 0", Duration::from_secs(config.command_timeout_secs));
+        if let Some(problem) = match probe {
+            Ok(output) if output.exit_code == 0 => None,
+            Ok(output) => Some(output.stderr.trim().to_string()),
+            Err(e) => Some(e.to_string()),
+        } {
+            eprintln!(
+                "Error: SHELL_CMD '{}' could not run a command: {}",
+                config.shell_cmd, problem
+            );
+            std::process::exit(1);
+        }
     }
 
-    let memory_file = format!("{}/memory_{}.json", WORKSPACE_DIR, agent_id);
-    if let Ok(contents) = fs::read_to_string(&memory_file) {
-        if let Ok(memories) = serde_json::from_str::<Vec<MemoryEntry>>(&contents) {
-            return memories
-                .iter()
-                .map(|m| format!("- {}", m.content))
-                .collect::<Vec<_>>()
-                .join("\n");
+    // Whatever it started may be half up, so the post-hook still gets to tear it down
+    if !config.pre_hook.trim().is_empty() {
+        if let Err(e) = run_hook("PRE_HOOK", config.pre_hook.trim(), &hook_shell, &[]) {
+            eprintln!("Error: {}", e);
+            cleanup_active_containers();
+            post_hook.run(1);
+            std::process::exit(1);
         }
     }
 
-    String::new()
-}
+    let mut stats = RunStats {
+        deadline: (config.session_timeout_secs > 0)
+            .then(|| Instant::now() + Duration::from_secs(config.session_timeout_secs)),
+        interrupt,
+        resume_at,
+        ..RunStats::default()
+    };
+    let outcome = if config.interactive {
+        run_repl(
+            &config.user_msg,
+            &mut messages,
+            &mut io::stdin().lock(),
+            &mut io::stderr(),
+            |messages, task| {
+                messages.push(Message {
+                    role: "user".to_string(),
+                    content: task.to_string(),
+                    ..Default::default()
+                });
+                let outcome =
+                    run_agent_loop(&client, messages, &mut shell, runner, &config, &mut stats);
+                report_outcome(&outcome, &config);
+                outcome
+            },
+        );
+        LoopOutcome::Finished { last_exit_code: 0 }
+    } else if let Some(tasks) = &batch_tasks {
+        let mut out: Box<dyn Write> = match config.output {
+            OutputFormat::Text => Box::new(io::stdout()),
+            OutputFormat::Json => Box::new(io::stderr()),
+        };
+        let summary = run_batch(
+            tasks,
+            &mut messages,
+            config.batch_shared_history,
+            &mut out,
+            |messages| {
+                let outcome =
+                    run_agent_loop(&client, messages, &mut shell, runner, &config, &mut stats);
+                report_outcome(&outcome, &config);
+                outcome
+            },
+            |outcome| process_exit_code(outcome, config.propagate_exit) == 0,
+        );
+        match summary.failed {
+            0 => LoopOutcome::Finished { last_exit_code: 0 },
+            failed => LoopOutcome::BatchFailed(failed),
+        }
+    } else {
+        let approved = if config.plan {
+            plan_first(
+                &client,
+                &mut messages,
+                &config,
+                &mut stats,
+                &mut io::stdin().lock(),
+                &mut io::stderr(),
+            )
+        } else {
+            Ok(true)
+        };
+        match approved {
+            Ok(true) => run_agent_loop(
+                &client,
+                &mut messages,
+                &mut shell,
+                runner,
+                &config,
+                &mut stats,
+            ),
+            Ok(false) => LoopOutcome::PlanDeclined,
+            Err(e) => LoopOutcome::Failed(e),
+        }
+    };
+    let keep_container = config.keep_container_on_error && outcome.is_failure();
+    shut_down(
+        &history_file,
+        config.compress_history,
+        &messages,
+        session,
+        keep_container,
+    );
+    let code = process_exit_code(&outcome, config.propagate_exit);
+    post_hook.run(code);
 
-fn fetch_meeting_context(agent_id: i32) -> String {
-    if agent_id == 0 {
-        return String::new();
+    stats.price(client.model(), &config.model_prices);
+    if config.json_stats {
+        eprintln!("{}", serde_json::to_string(&stats).unwrap_or_default());
+    } else {
+        eprintln!("{}", stats.summary());
     }
 
-    let meeting_file = format!("{}/meeting_context_{}.json", WORKSPACE_DIR, agent_id);
-    if let Ok(contents) = fs::read_to_string(&meeting_file) {
-        if let Ok(meetings) = serde_json::from_str::<Vec<MeetingContext>>(&contents) {
-            return meetings
-                .iter()
-                .map(|m| {
-                    format!(
-                        "Meeting: {}\nParticipant: {}\nTranscript:\n{}",
-                        m.topic, m.participant_role, m.transcript
-                    )
-                })
-                .collect::<Vec<_>>()
-                .join("\n\n");
-        }
+    // Those modes already reported each turn as it finished
+    if !config.interactive && batch_tasks.is_none() {
+        report_outcome(&outcome, &config);
     }
 
-    String::new()
-}
-
-fn parse_history_from_base64(encoded: &str) -> Vec<Message> {
-    use base64::Engine;
-    let decoded = base64::engine::general_purpose::STANDARD
-        .decode(encoded)
-        .ok();
+    if config.output == OutputFormat::Json {
+        let report = RunReport::new(&outcome, &messages, &stats);
+        println!("{}", serde_json::to_string(&report).unwrap_or_default());
+    }
 
-    match decoded {
-        Some(bytes) => {
-            let json_str = String::from_utf8(bytes).ok();
-            match json_str {
-                Some(s) => serde_json::from_str(&s).unwrap_or_default(),
-                None => Vec::new(),
-            }
-        }
-        None => Vec::new(),
+    telemetry::flush();
+    if code != 0 {
+        std::process::exit(code);
     }
 }
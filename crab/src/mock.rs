@@ -0,0 +1,92 @@
+use crate::error::CrabError;
+use crate::llm::{Message, TokenUsage};
+use std::fs;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// PROVIDER=mock answers without an API or a recording. MOCK_RESPONSES names a
+// file of replies separated by lines holding only `%%`, handed out in order;
+// once they run out, or with no file at all, the last user message is echoed
+// back as the answer.
+const SEPARATOR: &str = "%%";
+
+pub struct MockScript {
+    replies: Vec<String>,
+    next: AtomicUsize,
+}
+
+impl MockScript {
+    pub fn from_config(path: &str) -> Result<MockScript, CrabError> {
+        if path.is_empty() {
+            return Ok(MockScript::parse(""));
+        }
+        fs::read_to_string(path)
+            .map(|content| MockScript::parse(&content))
+            .map_err(|e| CrabError::Config(format!("MOCK_RESPONSES {}: {}", path, e)))
+    }
+
+    fn parse(content: &str) -> MockScript {
+        let mut replies = Vec::new();
+        let mut reply = String::new();
+        for line in content.lines() {
+            if line.trim() == SEPARATOR {
+                replies.push(std::mem::take(&mut reply));
+            } else {
+                reply.push_str(line);
+                reply.push('\n');
+            }
+        }
+        replies.push(reply);
+        replies.retain(|reply| !reply.trim().is_empty());
+        MockScript {
+            replies: replies.iter().map(|r| r.trim().to_string()).collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn next_reply(&self, messages: &[Message]) -> (String, TokenUsage) {
+        let index = self.next.fetch_add(1, Ordering::SeqCst);
+        match self.replies.get(index) {
+            Some(reply) => (reply.clone(), TokenUsage::default()),
+            None => echo(messages),
+        }
+    }
+}
+
+pub fn echo(messages: &[Message]) -> (String, TokenUsage) {
+    let last_user = messages
+        .iter()
+        .rev()
+        .find(|m| m.role == "user")
+        .map(|m| m.content.trim().to_string())
+        .unwrap_or_default();
+    (last_user, TokenUsage::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(content: &str) -> Vec<Message> {
+        vec![Message {
+            role: "user".to_string(),
+            content: content.to_string(),
+            ..Default::default()
+        }]
+    }
+
+    #[test]
+    fn scripted_replies_come_in_order_then_the_task_is_echoed() {
+        let script = MockScript::parse("ACTION: EXECUTE\nCOMMAND: ls\n%%\n\nAll done.\n%%\n");
+
+        assert_eq!(
+            script.next_reply(&user("list")).0,
+            "ACTION: EXECUTE\nCOMMAND: ls"
+        );
+        assert_eq!(script.next_reply(&user("list")).0, "All done.");
+        assert_eq!(script.next_reply(&user("  and again ")).0, "and again");
+        assert!(matches!(
+            MockScript::from_config("/nonexistent/replies.txt"),
+            Err(CrabError::Config(_))
+        ));
+    }
+}
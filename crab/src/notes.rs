@@ -0,0 +1,110 @@
+use crate::error::CrabError;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+// MEMORY_FILE holds one note per line, oldest first. It's small on purpose:
+// facts worth carrying between runs, not transcripts.
+pub const DEFAULT_MEMORY_MAX_BYTES: usize = 4096;
+
+pub fn load(path: &Path) -> Vec<String> {
+    match fs::read_to_string(path) {
+        Ok(content) => content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(str::to_string)
+            .collect(),
+        Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+        Err(e) => {
+            eprintln!(
+                "Warning: Could not read MEMORY_FILE {}: {}",
+                path.display(),
+                e
+            );
+            Vec::new()
+        }
+    }
+}
+
+// Goes in front of the system prompt; empty until something has been remembered
+pub fn prompt_section(notes: &[String]) -> String {
+    let mut section = String::from(
+        "PERSISTENT NOTES (kept from earlier sessions; add one with a line REMEMBER: <note>):\n",
+    );
+    if notes.is_empty() {
+        section.push_str("(none yet)\n");
+    }
+    for note in notes {
+        section.push_str(&format!("- {}\n", note));
+    }
+    section.push('\n');
+    section
+}
+
+pub fn extract_notes(response: &str) -> Vec<String> {
+    response
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("REMEMBER:"))
+        .map(|note| note.trim().to_string())
+        .filter(|note| !note.is_empty())
+        .collect()
+}
+
+// Appends `note`, then drops the oldest notes until the file fits in `max_bytes`.
+// Returns how many were dropped.
+pub fn remember(path: &Path, note: &str, max_bytes: usize) -> Result<usize, CrabError> {
+    let mut notes = load(path);
+    let mut note = note.split_whitespace().collect::<Vec<_>>().join(" ");
+    if note.len() >= max_bytes {
+        let mut end = max_bytes.saturating_sub(1);
+        while !note.is_char_boundary(end) {
+            end -= 1;
+        }
+        note.truncate(end);
+    }
+    notes.push(note);
+
+    let size = |notes: &[String]| notes.iter().map(|n| n.len() + 1).sum::<usize>();
+    let mut dropped = 0;
+    while size(&notes[dropped..]) > max_bytes {
+        dropped += 1;
+    }
+    let content: String = notes[dropped..]
+        .iter()
+        .map(|n| format!("{}\n", n))
+        .collect();
+
+    // Renamed into place so a crash mid-write can't lose the older notes
+    let failed = |e: std::io::Error| {
+        CrabError::Exec(format!("Could not save note to {}: {}", path.display(), e))
+    };
+    let tmp = path.with_extension(format!("crab-tmp-{}", std::process::id()));
+    fs::write(&tmp, content)
+        .and_then(|_| fs::rename(&tmp, path))
+        .map_err(failed)?;
+    Ok(dropped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oldest_notes_are_dropped_past_the_cap() {
+        let path = std::env::temp_dir().join(format!("crab-notes-cap-{}.txt", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        for note in ["first note", "second note", "third note"] {
+            remember(&path, note, 24).unwrap();
+        }
+        let notes = load(&path);
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(notes, ["second note", "third note"]);
+        assert_eq!(
+            extract_notes("REMEMBER: uses pnpm\nDone.\n  REMEMBER:   \n"),
+            ["uses pnpm"]
+        );
+    }
+}
@@ -0,0 +1,126 @@
+use crate::config::Config;
+use crate::llm::{extract_commands_and_scripts, split_reasoning, CommandDelimiters};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+// How the loop reads a reply: the commands to run and, once there are none,
+// the answer to hand back. RESPONSE_PARSER picks a built-in one; an embedder
+// with its own convention passes theirs to run_agent_with_parser.
+pub trait ResponseParser {
+    fn extract_commands(&self, response: &str) -> Vec<String>;
+    fn extract_final_answer(&self, response: &str) -> String;
+}
+
+impl fmt::Debug for dyn ResponseParser {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ResponseParser")
+    }
+}
+
+pub const PARSER_NAMES: &[&str] = &["default", "tags"];
+
+// Told to the model with RESPONSE_PARSER=tags, after the usual instructions
+pub const TAGS_PROMPT: &str = "\nREPLY FORMAT: Ignore the command format described above. Put each command to run in its own <command>...</command> tags, and when you are done, put the final answer in <answer>...</answer> tags with no commands in the same reply.\n";
+
+pub fn from_config(config: &Config) -> Arc<dyn ResponseParser> {
+    match config.response_parser.trim() {
+        "tags" => Arc::new(TagsParser),
+        _ => Arc::new(DefaultParser {
+            delimiters: config.command_delimiters(),
+            interpreters: config.script_interpreters.clone(),
+        }),
+    }
+}
+
+// COMMAND: lines, fences (or COMMAND_START/COMMAND_END blocks) and
+// SCRIPT_INTERPRETERS scripts; the answer is the reply less any reasoning.
+pub struct DefaultParser {
+    pub delimiters: CommandDelimiters,
+    pub interpreters: HashMap<String, String>,
+}
+
+impl ResponseParser for DefaultParser {
+    fn extract_commands(&self, response: &str) -> Vec<String> {
+        extract_commands_and_scripts(response, &self.delimiters, &self.interpreters)
+    }
+
+    fn extract_final_answer(&self, response: &str) -> String {
+        split_reasoning(response).1
+    }
+}
+
+// <command>...</command> for each command and <answer>...</answer> for the
+// answer; a reply without answer tags is taken whole
+pub struct TagsParser;
+
+fn tagged<'a>(text: &'a str, tag: &str) -> Vec<&'a str> {
+    let (open, close) = (format!("<{}>", tag), format!("</{}>", tag));
+    let mut found = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(&open) {
+        let after = &rest[start + open.len()..];
+        let Some(end) = after.find(&close) else {
+            break;
+        };
+        found.push(after[..end].trim());
+        rest = &after[end + close.len()..];
+    }
+    found
+}
+
+impl ResponseParser for TagsParser {
+    fn extract_commands(&self, response: &str) -> Vec<String> {
+        tagged(&split_reasoning(response).1, "command")
+            .into_iter()
+            .filter(|cmd| !cmd.is_empty())
+            .map(str::to_string)
+            .collect()
+    }
+
+    fn extract_final_answer(&self, response: &str) -> String {
+        let answer = split_reasoning(response).1;
+        match tagged(&answer, "answer").first() {
+            Some(tagged) => tagged.to_string(),
+            None => answer.trim().to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_parser_matches_the_built_in_extraction() {
+        let parser = from_config(&Config::default());
+        assert_eq!(
+            parser.extract_commands("ACTION: EXECUTE\nCOMMAND: df -h"),
+            vec!["df -h"]
+        );
+        assert_eq!(
+            parser.extract_final_answer("<thinking>check df</thinking>The disk is half full."),
+            "The disk is half full."
+        );
+    }
+
+    #[test]
+    fn tags_parser_reads_commands_and_the_answer_from_tags() {
+        let parser = from_config(&Config {
+            response_parser: "tags".to_string(),
+            ..Config::default()
+        });
+        assert_eq!(
+            parser.extract_commands(
+                "Two checks.\n<command>df -h</command>\n<command>\nfree -m\n</command>\nCOMMAND: ls"
+            ),
+            vec!["df -h", "free -m"]
+        );
+        assert!(parser.extract_commands("<command>unclosed").is_empty());
+        assert_eq!(
+            parser.extract_final_answer("Done. <answer>Half full.</answer>"),
+            "Half full."
+        );
+        assert_eq!(parser.extract_final_answer("  Half full.\n"), "Half full.");
+    }
+}
@@ -0,0 +1,125 @@
+use crate::error::CrabError;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+// Bounds how many agent sessions, and so containers, run at once when the
+// library serves many requests. Clones share the same slots; a permit gives its
+// slot back when dropped, however the session ended.
+#[derive(Clone)]
+pub struct SessionPool {
+    slots: Arc<Slots>,
+    max: usize,
+    wait: Duration,
+}
+
+struct Slots {
+    active: Mutex<usize>,
+    freed: Condvar,
+}
+
+pub struct SessionPermit {
+    slots: Arc<Slots>,
+}
+
+impl SessionPool {
+    // `max` of 0 is taken as 1; `wait` is how long acquire blocks for a free slot
+    pub fn new(max: usize, wait: Duration) -> Self {
+        Self {
+            slots: Arc::new(Slots {
+                active: Mutex::new(0),
+                freed: Condvar::new(),
+            }),
+            max: max.max(1),
+            wait,
+        }
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    pub fn active(&self) -> usize {
+        *self.lock()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, usize> {
+        self.slots.active.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn acquire(&self) -> Result<SessionPermit, CrabError> {
+        let deadline = Instant::now() + self.wait;
+        let mut active = self.lock();
+        while *active >= self.max {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(CrabError::Busy {
+                    waited: self.wait,
+                    max: self.max,
+                });
+            }
+            active = self
+                .slots
+                .freed
+                .wait_timeout(active, left)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+        *active += 1;
+        Ok(SessionPermit {
+            slots: Arc::clone(&self.slots),
+        })
+    }
+}
+
+impl Drop for SessionPermit {
+    fn drop(&mut self) {
+        let mut active = self.slots.active.lock().unwrap_or_else(|e| e.into_inner());
+        *active -= 1;
+        self.slots.freed.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    #[test]
+    fn no_more_than_max_sessions_hold_a_permit_at_once() {
+        let pool = SessionPool::new(2, Duration::from_secs(10));
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+
+        let handles: Vec<_> = (0..6)
+            .map(|_| {
+                let (pool, running, peak) = (pool.clone(), running.clone(), peak.clone());
+                thread::spawn(move || {
+                    let _permit = pool.acquire().unwrap();
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(50));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+        assert_eq!(pool.active(), 0);
+    }
+
+    #[test]
+    fn acquire_gives_up_after_the_wait() {
+        let pool = SessionPool::new(1, Duration::from_millis(50));
+        let held = pool.acquire().unwrap();
+
+        let err = pool.acquire().err().unwrap();
+        assert!(matches!(err, CrabError::Busy { max: 1, .. }));
+
+        drop(held);
+        assert!(pool.acquire().is_ok());
+    }
+}
@@ -0,0 +1,114 @@
+use crate::error::CrabError;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// With SCROLLBACK_DIR set, output cut down to MAX_OUTPUT_BYTES is also kept whole
+// in a file there, and the model can page through it with READ_OUTPUT instead
+// of running the command again. Files hold the redacted text, like the context.
+static NEXT: AtomicU64 = AtomicU64::new(1);
+
+// Returns the name the model asks for it by, e.g. 4121-7.stdout
+pub fn save(dir: &str, stream: &str, content: &str) -> Result<String, CrabError> {
+    let name = format!(
+        "{}-{}.{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::SeqCst),
+        stream
+    );
+    fs::create_dir_all(dir)
+        .and_then(|_| fs::write(Path::new(dir).join(&name), content))
+        .map_err(|e| CrabError::Exec(format!("Could not save {} to {}: {}", stream, dir, e)))?;
+    Ok(name)
+}
+
+// Numbered lines from `from_line` (1-based) on, as many as fit in `max_bytes`,
+// with a pointer to where the next page starts
+pub fn read(
+    dir: &str,
+    name: &str,
+    from_line: usize,
+    max_bytes: usize,
+) -> Result<String, CrabError> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.'));
+    if !valid {
+        return Err(CrabError::Policy(format!(
+            "READ_OUTPUT takes a name from a truncated result, got '{}'",
+            name
+        )));
+    }
+    let content = fs::read_to_string(Path::new(dir).join(name))
+        .map_err(|e| CrabError::Exec(format!("READ_OUTPUT {}: {}", name, e)))?;
+
+    let from_line = from_line.max(1);
+    let mut page = String::new();
+    for (i, line) in content.lines().enumerate().skip(from_line - 1) {
+        let numbered = format!("{:>6}\t{}\n", i + 1, line);
+        if !page.is_empty() && page.len() + numbered.len() > max_bytes {
+            page.push_str(&format!(
+                "[more: READ_OUTPUT: {} with FROM_LINE: {}]\n",
+                name,
+                i + 1
+            ));
+            return Ok(page);
+        }
+        page.push_str(&numbered);
+    }
+    if page.is_empty() {
+        page.push_str(&format!(
+            "[{} has {} lines]\n",
+            name,
+            content.lines().count()
+        ));
+    }
+    Ok(page)
+}
+
+// `READ_OUTPUT: <name>`, optionally followed by `FROM_LINE: <n>`
+pub fn extract_read_output(response: &str) -> Option<(String, usize)> {
+    let mut name = None;
+    let mut from_line = 1;
+    for line in response.lines() {
+        let line = line.trim();
+        if let Some(n) = line.strip_prefix("READ_OUTPUT:") {
+            name = Some(n.trim().to_string());
+        } else if let Some(n) = line.strip_prefix("FROM_LINE:") {
+            from_line = n.trim().parse().unwrap_or(1);
+        }
+    }
+    name.filter(|n| !n.is_empty()).map(|n| (n, from_line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pages_through_a_saved_output() {
+        let dir = std::env::temp_dir().join(format!("crab-scrollback-{}", std::process::id()));
+        let dir = dir.to_str().unwrap();
+        let content: String = (1..=100).map(|n| format!("line {}\n", n)).collect();
+        let name = save(dir, "stdout", &content).unwrap();
+
+        let first = read(dir, &name, 1, 64).unwrap();
+        let later = read(dir, &name, 99, 64).unwrap();
+        let escape = read(dir, "../etc/passwd", 1, 64);
+        let _ = fs::remove_dir_all(dir);
+
+        assert!(first.starts_with("     1\tline 1\n"));
+        assert!(first.ends_with(&format!(
+            "[more: READ_OUTPUT: {} with FROM_LINE: 5]\n",
+            name
+        )));
+        assert_eq!(later, "    99\tline 99\n   100\tline 100\n");
+        assert!(matches!(escape, Err(CrabError::Policy(_))));
+        assert_eq!(
+            extract_read_output("READ_OUTPUT: 12-3.stdout\nFROM_LINE: 40"),
+            Some(("12-3.stdout".to_string(), 40))
+        );
+    }
+}
@@ -0,0 +1,274 @@
+use serde_json::{json, Map, Value};
+
+// With STRUCTURED_TOOLS set, the stdout of a few common commands reaches the
+// model as compact JSON instead of aligned columns. Anything a parser doesn't
+// fully understand is left as plain text.
+pub const KNOWN_TOOLS: &[&str] = &["ls", "df", "ps", "kubectl"];
+
+// Returns the JSON form of `stdout` if `cmd` is a plain invocation of a tool in
+// `allowed` and its output parses cleanly
+pub fn parse(allowed: &[String], cmd: &str, stdout: &str) -> Option<String> {
+    // Pipes, redirects and the like mean the output isn't the tool's own
+    if cmd
+        .chars()
+        .any(|c| matches!(c, '|' | ';' | '&' | '>' | '<' | '`' | '$' | '\n'))
+    {
+        return None;
+    }
+    let words: Vec<&str> = cmd.split_whitespace().collect();
+    let tool = words.first()?.rsplit('/').next()?;
+    if !allowed.iter().any(|a| a == tool) {
+        return None;
+    }
+    let args = &words[1..];
+    let value = match tool {
+        "ls" if args.iter().any(|a| is_short_flag(a, 'l')) => parse_ls_long(stdout)?,
+        "df" => parse_table(stdout, &["Mounted on"])?,
+        "ps" => parse_table(stdout, &[])?,
+        "kubectl" if args.first() == Some(&"get") && wants_json(args) => parse_kubectl(stdout)?,
+        _ => return None,
+    };
+    serde_json::to_string(&value).ok()
+}
+
+fn is_short_flag(arg: &str, flag: char) -> bool {
+    arg.len() > 1 && arg.starts_with('-') && !arg.starts_with("--") && arg[1..].contains(flag)
+}
+
+fn wants_json(args: &[&str]) -> bool {
+    args.windows(2)
+        .any(|w| matches!(w[0], "-o" | "--output") && w[1] == "json")
+        || args
+            .iter()
+            .any(|a| matches!(*a, "-ojson" | "--output=json" | "-o=json"))
+}
+
+// "Use%" -> "use_pct", "Mounted on" -> "mounted_on", "1K-blocks" -> "1k_blocks"
+fn column_key(header: &str) -> String {
+    let header = header.replace('%', "_pct");
+    let mut key = String::new();
+    for c in header.chars() {
+        if c.is_ascii_alphanumeric() {
+            key.push(c.to_ascii_lowercase());
+        } else if !key.ends_with('_') {
+            key.push('_');
+        }
+    }
+    key.trim_matches('_').to_string()
+}
+
+// Whitespace-separated columns under a header row, as one object per row. The
+// last column takes the rest of the line, since that's where ps puts the
+// command and df the mount point; `multiword` lists headers with a space in them.
+fn parse_table(stdout: &str, multiword: &[&str]) -> Option<Value> {
+    let mut lines = stdout.lines().filter(|l| !l.trim().is_empty());
+    let mut header = lines.next()?.to_string();
+    for name in multiword {
+        header = header.replace(name, &name.replace(' ', "\u{0}"));
+    }
+    let keys: Vec<String> = header
+        .split_whitespace()
+        .map(|h| column_key(&h.replace('\u{0}', " ")))
+        .collect();
+    if keys.len() < 2 || keys.iter().any(|k| k.is_empty()) {
+        return None;
+    }
+    let mut rows = Vec::new();
+    let mut carried = String::new();
+    for line in lines {
+        // df wraps a long device name onto a line of its own
+        let line = format!("{} {}", carried, line);
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < keys.len() {
+            if !carried.is_empty() || fields.len() != 1 {
+                return None;
+            }
+            carried = fields[0].to_string();
+            continue;
+        }
+        carried.clear();
+        let mut row = Map::new();
+        for (i, key) in keys.iter().enumerate() {
+            let value = if i + 1 == keys.len() {
+                fields[i..].join(" ")
+            } else {
+                fields[i].to_string()
+            };
+            row.insert(key.clone(), Value::String(value));
+        }
+        rows.push(Value::Object(row));
+    }
+    if !carried.is_empty() {
+        return None;
+    }
+    Some(Value::Array(rows))
+}
+
+// `ls -l` of a single directory; listings of several, or in a format this
+// doesn't recognize, stay as text
+fn parse_ls_long(stdout: &str) -> Option<Value> {
+    let mut entries = Vec::new();
+    for line in stdout.lines() {
+        if line.is_empty() || line.starts_with("total ") {
+            continue;
+        }
+        let fields = split_ls_line(line)?;
+        let mode = fields[0];
+        let kind = match mode.chars().next()? {
+            '-' => "file",
+            'd' => "dir",
+            'l' => "link",
+            'c' | 'b' => "device",
+            'p' => "fifo",
+            's' => "socket",
+            _ => return None,
+        };
+        let size: u64 = fields[4].parse().ok()?;
+        let links: u64 = fields[1].parse().ok()?;
+        let (name, target) = match fields[8].split_once(" -> ") {
+            Some((name, target)) if kind == "link" => (name, Some(target)),
+            _ => (fields[8], None),
+        };
+        let mut entry = json!({
+            "name": name,
+            "type": kind,
+            "mode": mode,
+            "links": links,
+            "owner": fields[2],
+            "group": fields[3],
+            "size": size,
+            "modified": format!("{} {} {}", fields[5], fields[6], fields[7]),
+        });
+        if let Some(target) = target {
+            entry["target"] = json!(target);
+        }
+        entries.push(entry);
+    }
+    Some(Value::Array(entries))
+}
+
+// ls pads its columns, so the first eight fields are split on runs of spaces
+// and the name keeps whatever spacing it has
+fn split_ls_line(line: &str) -> Option<Vec<&str>> {
+    let mut fields = Vec::new();
+    let mut rest = line;
+    for _ in 0..8 {
+        rest = rest.trim_start();
+        let end = rest.find(char::is_whitespace)?;
+        fields.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+    let name = rest.strip_prefix(' ')?;
+    fields.push(name.trim_start());
+    Some(fields)
+}
+
+// Each object down to what's usually asked about: kind, name, namespace, when
+// it was made and, when it has one, its phase
+fn parse_kubectl(stdout: &str) -> Option<Value> {
+    let value: Value = serde_json::from_str(stdout).ok()?;
+    let summarize = |item: &Value| {
+        let meta = &item["metadata"];
+        let mut out = Map::new();
+        for (key, field) in [
+            ("kind", &item["kind"]),
+            ("name", &meta["name"]),
+            ("namespace", &meta["namespace"]),
+            ("created", &meta["creationTimestamp"]),
+            ("phase", &item["status"]["phase"]),
+        ] {
+            if !field.is_null() {
+                out.insert(key.to_string(), field.clone());
+            }
+        }
+        Value::Object(out)
+    };
+    match value.get("items") {
+        Some(Value::Array(items)) => Some(Value::Array(items.iter().map(summarize).collect())),
+        Some(_) => None,
+        None if value.get("metadata").is_some() => Some(summarize(&value)),
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn all() -> Vec<String> {
+        KNOWN_TOOLS.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn df_output_becomes_one_object_per_filesystem() {
+        let stdout = "\
+Filesystem      Size  Used Avail Use% Mounted on
+/dev/sda1        40G   12G   26G  32% /
+tmpfs           2.0G     0  2.0G   0% /dev/shm
+/dev/mapper/vg0-long-volume-name
+                100G   50G   50G  50% /mnt/my data
+";
+        let parsed = parse(&all(), "df -h", stdout).unwrap();
+        let expected = json!([
+            {"filesystem": "/dev/sda1", "size": "40G", "used": "12G", "avail": "26G",
+             "use_pct": "32%", "mounted_on": "/"},
+            {"filesystem": "tmpfs", "size": "2.0G", "used": "0", "avail": "2.0G",
+             "use_pct": "0%", "mounted_on": "/dev/shm"},
+            {"filesystem": "/dev/mapper/vg0-long-volume-name", "size": "100G", "used": "50G",
+             "avail": "50G", "use_pct": "50%", "mounted_on": "/mnt/my data"},
+        ]);
+        assert_eq!(serde_json::from_str::<Value>(&parsed).unwrap(), expected);
+    }
+
+    #[test]
+    fn ls_long_listing_keeps_names_and_link_targets() {
+        let stdout = "\
+total 8
+drwxr-xr-x 2 crab crab 4096 Mar  3 10:00 src
+-rw-r--r-- 1 crab crab  120 Mar  3  2023 notes two.txt
+lrwxrwxrwx 1 crab crab    3 Mar  3 10:00 latest -> src
+";
+        let parsed: Value =
+            serde_json::from_str(&parse(&all(), "ls -la", stdout).unwrap()).unwrap();
+        assert_eq!(parsed[0]["type"], "dir");
+        assert_eq!(parsed[1]["name"], "notes two.txt");
+        assert_eq!(parsed[1]["size"], 120);
+        assert_eq!(parsed[1]["modified"], "Mar 3 2023");
+        assert_eq!(parsed[2]["name"], "latest");
+        assert_eq!(parsed[2]["target"], "src");
+    }
+
+    #[test]
+    fn ps_keeps_the_whole_command_line() {
+        let stdout = "  PID TTY          TIME CMD\n    1 ?        00:00:01 sleep 100\n";
+        let parsed: Value = serde_json::from_str(&parse(&all(), "ps", stdout).unwrap()).unwrap();
+        assert_eq!(
+            parsed,
+            json!([{"pid": "1", "tty": "?", "time": "00:00:01", "cmd": "sleep 100"}])
+        );
+    }
+
+    #[test]
+    fn kubectl_items_are_cut_to_the_usual_fields() {
+        let stdout = r#"{"kind":"List","items":[{"kind":"Pod","metadata":{"name":"web-1",
+            "namespace":"default","creationTimestamp":"2024-01-01T00:00:00Z","uid":"x"},
+            "spec":{"containers":[]},"status":{"phase":"Running"}}]}"#;
+        let parsed = parse(&all(), "kubectl get pods -o json", stdout).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(&parsed).unwrap(),
+            json!([{"kind": "Pod", "name": "web-1", "namespace": "default",
+                    "created": "2024-01-01T00:00:00Z", "phase": "Running"}])
+        );
+        assert_eq!(parse(&all(), "kubectl get pods", stdout), None);
+    }
+
+    #[test]
+    fn other_commands_and_odd_output_pass_through() {
+        let df = "Filesystem Size\n/dev/sda1 40G\n";
+        assert_eq!(parse(&["ls".to_string()], "df", df), None);
+        assert_eq!(parse(&all(), "df | tail -1", df), None);
+        assert_eq!(parse(&all(), "ls", "src\nnotes.txt\n"), None);
+        assert_eq!(parse(&all(), "ls -l", "something else entirely\n"), None);
+        assert_eq!(parse(&all(), "cat /etc/hosts", df), None);
+    }
+}
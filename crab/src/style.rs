@@ -0,0 +1,124 @@
+use crate::config::ColorChoice;
+use anstyle::{AnsiColor, Style};
+use std::io::{self, IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Colors for what crab itself prints. Off until `init` decides per stream, so
+// embedders and tests get plain text; COLOR=always forces it, otherwise
+// NO_COLOR or a stream that isn't a terminal turns it off.
+static STDOUT_COLOR: AtomicBool = AtomicBool::new(false);
+static STDERR_COLOR: AtomicBool = AtomicBool::new(false);
+
+pub fn init(choice: ColorChoice) {
+    let no_color = std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+    STDOUT_COLOR.store(
+        Palette::resolve(choice, no_color, io::stdout().is_terminal()).enabled,
+        Ordering::Relaxed,
+    );
+    STDERR_COLOR.store(
+        Palette::resolve(choice, no_color, io::stderr().is_terminal()).enabled,
+        Ordering::Relaxed,
+    );
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Palette {
+    pub enabled: bool,
+}
+
+impl Palette {
+    pub fn resolve(choice: ColorChoice, no_color: bool, is_terminal: bool) -> Palette {
+        let enabled = match choice {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => !no_color && is_terminal,
+        };
+        Palette { enabled }
+    }
+
+    pub fn stdout() -> Palette {
+        Palette {
+            enabled: STDOUT_COLOR.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn stderr() -> Palette {
+        Palette {
+            enabled: STDERR_COLOR.load(Ordering::Relaxed),
+        }
+    }
+
+    fn paint(&self, style: Style, text: &str) -> String {
+        if self.enabled {
+            format!("{}{}{:#}", style, text, style)
+        } else {
+            text.to_string()
+        }
+    }
+
+    pub fn prompt(&self, text: &str) -> String {
+        self.paint(AnsiColor::Green.on_default().bold(), text)
+    }
+
+    pub fn command(&self, text: &str) -> String {
+        self.paint(AnsiColor::Cyan.on_default().bold(), text)
+    }
+
+    pub fn error(&self, text: &str) -> String {
+        self.paint(AnsiColor::Red.on_default().bold(), text)
+    }
+
+    pub fn answer(&self, text: &str) -> String {
+        self.paint(Style::new().bold(), text)
+    }
+}
+
+// Dims streamed command output chunk by chunk, so a line cut short by the
+// command dying doesn't leave the terminal dimmed
+pub struct DimmedWriter<W: Write>(pub W);
+
+impl<W: Write> Write for DimmedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let style = Style::new().dimmed();
+        write!(self.0, "{}", style)?;
+        self.0.write_all(buf)?;
+        write!(self.0, "{:#}", style)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colors_follow_no_color_and_the_terminal_unless_forced() {
+        let painted = |palette: Palette| palette.command("COMMAND: ls");
+
+        assert_eq!(
+            painted(Palette::resolve(ColorChoice::Auto, true, true)),
+            "COMMAND: ls"
+        );
+        assert_eq!(
+            painted(Palette::resolve(ColorChoice::Auto, false, false)),
+            "COMMAND: ls"
+        );
+        assert_eq!(
+            painted(Palette::resolve(ColorChoice::Never, false, true)),
+            "COMMAND: ls"
+        );
+        assert_eq!(
+            painted(Palette::resolve(ColorChoice::Auto, false, true)),
+            "\x1b[1m\x1b[36mCOMMAND: ls\x1b[0m"
+        );
+        assert!(painted(Palette::resolve(ColorChoice::Always, true, false)).contains("\x1b["));
+
+        let mut dimmed = DimmedWriter(Vec::new());
+        dimmed.write_all(b"tick\n").unwrap();
+        assert_eq!(dimmed.0, b"\x1b[2mtick\n\x1b[0m");
+    }
+}
@@ -0,0 +1,427 @@
+use crate::tools::sha256_hex;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::subscriber::{Interest, Subscriber};
+use tracing::{Event, Metadata};
+
+// The loop opens tracing spans around each LLM request and command. Nothing
+// listens unless OTEL_EXPORTER_OTLP_ENDPOINT is set, in which case a SpanCollector
+// becomes the global subscriber and posts every finished run to the collector's
+// /v1/traces as OTLP/HTTP JSON. Only crab's own spans are kept, never reqwest's.
+const SERVICE_NAME: &str = "hermit-crab";
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(5);
+// How long exit waits for exports still in flight
+const FLUSH_GRACE: Duration = Duration::from_secs(1);
+
+// The installed exporter, for flush
+static GLOBAL: OnceLock<Arc<SpanCollector>> = OnceLock::new();
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttributeValue {
+    Str(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FinishedSpan {
+    pub name: String,
+    pub trace_id: String,
+    pub span_id: String,
+    pub parent_span_id: Option<String>,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub attributes: Vec<(String, AttributeValue)>,
+}
+
+impl FinishedSpan {
+    pub fn attribute(&self, key: &str) -> Option<&AttributeValue> {
+        self.attributes
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value)
+    }
+}
+
+struct OpenSpan {
+    span: FinishedSpan,
+    parent: Option<u64>,
+    refs: usize,
+}
+
+struct FieldRecorder<'a>(&'a mut Vec<(String, AttributeValue)>);
+
+impl FieldRecorder<'_> {
+    fn set(&mut self, field: &Field, value: AttributeValue) {
+        match self.0.iter_mut().find(|(k, _)| k == field.name()) {
+            Some((_, existing)) => *existing = value,
+            None => self.0.push((field.name().to_string(), value)),
+        }
+    }
+}
+
+impl Visit for FieldRecorder<'_> {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.set(field, AttributeValue::Int(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.set(field, AttributeValue::Int(value as i64));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.set(field, AttributeValue::Float(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.set(field, AttributeValue::Bool(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.set(field, AttributeValue::Str(value.to_string()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.set(field, AttributeValue::Str(format!("{:?}", value)));
+    }
+}
+
+thread_local! {
+    // Spans entered on this thread, innermost last, for finding a new span's parent
+    static ENTERED: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+}
+
+// Also usable on its own as a test subscriber: with no endpoint it just keeps
+// what finished, for `finished()` to hand back.
+#[derive(Default)]
+pub struct SpanCollector {
+    endpoint: Option<String>,
+    next_id: AtomicU64,
+    open: Mutex<HashMap<u64, OpenSpan>>,
+    finished: Mutex<Vec<FinishedSpan>>,
+    // Exports run on their own threads so a slow collector never holds up the loop
+    exports: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl SpanCollector {
+    pub fn otlp(endpoint: &str) -> Self {
+        SpanCollector {
+            endpoint: Some(format!("{}/v1/traces", endpoint.trim_end_matches('/'))),
+            ..SpanCollector::default()
+        }
+    }
+
+    pub fn finished(&self) -> Vec<FinishedSpan> {
+        self.finished
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    fn export(&self, endpoint: &str) {
+        let spans = std::mem::take(&mut *self.finished.lock().unwrap_or_else(|e| e.into_inner()));
+        let endpoint = endpoint.to_string();
+        let export = thread::spawn(move || {
+            let sent = reqwest::blocking::Client::new()
+                .post(&endpoint)
+                .timeout(EXPORT_TIMEOUT)
+                .json(&otlp_json(&spans))
+                .send()
+                .and_then(|response| response.error_for_status());
+            if let Err(e) = sent {
+                eprintln!(
+                    "Warning: Could not export {} spans to {}: {}",
+                    spans.len(),
+                    endpoint,
+                    e
+                );
+            }
+        });
+        let mut exports = self.exports.lock().unwrap_or_else(|e| e.into_inner());
+        exports.retain(|export| !export.is_finished());
+        exports.push(export);
+    }
+
+    // Waits up to `grace` for exports still in flight; true if they all finished
+    pub fn flush(&self, grace: Duration) -> bool {
+        let deadline = Instant::now() + grace;
+        let exports = std::mem::take(&mut *self.exports.lock().unwrap_or_else(|e| e.into_inner()));
+        for export in exports {
+            while !export.is_finished() {
+                if Instant::now() >= deadline {
+                    return false;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            let _ = export.join();
+        }
+        true
+    }
+}
+
+// Ids only need to be unique, not unpredictable
+fn random_hex(len: usize, salt: u64) -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let seed = format!("{}-{}-{}", std::process::id(), nanos, salt);
+    sha256_hex(seed.as_bytes())[..len].to_string()
+}
+
+impl Subscriber for SpanCollector {
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        if self.enabled(metadata) {
+            Interest::always()
+        } else {
+            Interest::never()
+        }
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.is_span() && metadata.target().starts_with("hermit_crab")
+    }
+
+    fn new_span(&self, attributes: &Attributes<'_>) -> Id {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst) + 1;
+        let parent = match attributes.parent() {
+            Some(parent) => Some(parent.into_u64()),
+            None if attributes.is_contextual() => {
+                ENTERED.with(|entered| entered.borrow().last().copied())
+            }
+            None => None,
+        };
+
+        let mut open = self.open.lock().unwrap_or_else(|e| e.into_inner());
+        let parent_span = parent.and_then(|p| open.get(&p)).map(|p| &p.span);
+        let mut span = FinishedSpan {
+            name: attributes.metadata().name().to_string(),
+            trace_id: parent_span.map_or_else(|| random_hex(32, id), |p| p.trace_id.clone()),
+            span_id: random_hex(16, id),
+            parent_span_id: parent_span.map(|p| p.span_id.clone()),
+            start: SystemTime::now(),
+            end: SystemTime::now(),
+            attributes: Vec::new(),
+        };
+        attributes.record(&mut FieldRecorder(&mut span.attributes));
+        let parent = parent.filter(|p| open.contains_key(p));
+        open.insert(
+            id,
+            OpenSpan {
+                span,
+                parent,
+                refs: 1,
+            },
+        );
+        Id::from_u64(id)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        if let Some(open) = self
+            .open
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get_mut(&span.into_u64())
+        {
+            values.record(&mut FieldRecorder(&mut open.span.attributes));
+        }
+    }
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    // Events already go through `log`
+    fn event(&self, _event: &Event<'_>) {}
+
+    fn enter(&self, span: &Id) {
+        ENTERED.with(|entered| entered.borrow_mut().push(span.into_u64()));
+    }
+
+    fn exit(&self, span: &Id) {
+        ENTERED.with(|entered| {
+            let mut entered = entered.borrow_mut();
+            if let Some(at) = entered.iter().rposition(|id| *id == span.into_u64()) {
+                entered.remove(at);
+            }
+        });
+    }
+
+    fn clone_span(&self, span: &Id) -> Id {
+        if let Some(open) = self
+            .open
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get_mut(&span.into_u64())
+        {
+            open.refs += 1;
+        }
+        span.clone()
+    }
+
+    fn try_close(&self, span: Id) -> bool {
+        let root_finished = {
+            let mut open = self.open.lock().unwrap_or_else(|e| e.into_inner());
+            let Some(entry) = open.get_mut(&span.into_u64()) else {
+                return false;
+            };
+            entry.refs -= 1;
+            if entry.refs > 0 {
+                return false;
+            }
+            let mut closed = open.remove(&span.into_u64()).map(|e| (e.span, e.parent));
+            let root_finished = closed.as_ref().is_some_and(|(_, parent)| parent.is_none());
+            if let Some((mut finished, _)) = closed.take() {
+                finished.end = SystemTime::now();
+                self.finished
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .push(finished);
+            }
+            root_finished
+        };
+        // Sent once the whole run is in, outside the locks
+        if root_finished {
+            if let Some(endpoint) = &self.endpoint {
+                self.export(endpoint);
+            }
+        }
+        true
+    }
+}
+
+// Makes the OTLP exporter the global subscriber; a no-op when `endpoint` is empty
+pub fn init(endpoint: &str) {
+    if endpoint.is_empty() {
+        return;
+    }
+    let collector = Arc::new(SpanCollector::otlp(endpoint));
+    if let Err(e) = tracing::subscriber::set_global_default(collector.clone()) {
+        eprintln!("Warning: Could not install the OTLP exporter: {}", e);
+        return;
+    }
+    let _ = GLOBAL.set(collector);
+}
+
+// Gives exports still in flight a moment before the process exits
+pub fn flush() {
+    if let Some(collector) = GLOBAL.get() {
+        if !collector.flush(FLUSH_GRACE) {
+            eprintln!("Warning: Gave up waiting for the trace export");
+        }
+    }
+}
+
+fn unix_nanos(at: SystemTime) -> String {
+    at.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+// The JSON encoding of an OTLP ExportTraceServiceRequest; 64-bit ints and
+// timestamps are strings, as proto3's JSON mapping wants
+pub fn otlp_json(spans: &[FinishedSpan]) -> serde_json::Value {
+    let value = |v: &AttributeValue| match v {
+        AttributeValue::Str(s) => serde_json::json!({ "stringValue": s }),
+        AttributeValue::Int(i) => serde_json::json!({ "intValue": i.to_string() }),
+        AttributeValue::Float(f) => serde_json::json!({ "doubleValue": f }),
+        AttributeValue::Bool(b) => serde_json::json!({ "boolValue": b }),
+    };
+    let spans: Vec<serde_json::Value> = spans
+        .iter()
+        .map(|span| {
+            serde_json::json!({
+                "traceId": span.trace_id,
+                "spanId": span.span_id,
+                "parentSpanId": span.parent_span_id.clone().unwrap_or_default(),
+                "name": span.name,
+                "kind": 1,
+                "startTimeUnixNano": unix_nanos(span.start),
+                "endTimeUnixNano": unix_nanos(span.end),
+                "attributes": span
+                    .attributes
+                    .iter()
+                    .map(|(key, v)| serde_json::json!({ "key": key, "value": value(v) }))
+                    .collect::<Vec<_>>(),
+            })
+        })
+        .collect();
+    serde_json::json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{ "key": "service.name", "value": { "stringValue": SERVICE_NAME } }]
+            },
+            "scopeSpans": [{ "scope": { "name": SERVICE_NAME }, "spans": spans }]
+        }]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn children_share_the_root_trace_and_export_as_otlp() {
+        let collector = Arc::new(SpanCollector::default());
+        tracing::subscriber::with_default(collector.clone(), || {
+            let run = tracing::info_span!("agent.run");
+            let _run = run.enter();
+            let request = tracing::info_span!(
+                "llm.request",
+                model = "gpt-4o",
+                total_tokens = tracing::field::Empty
+            );
+            request.record("total_tokens", 42);
+        });
+        let spans = collector.finished();
+
+        assert_eq!(spans.len(), 2);
+        let (request, run) = (&spans[0], &spans[1]);
+        assert_eq!(request.name, "llm.request");
+        assert_eq!(request.trace_id, run.trace_id);
+        assert_eq!(request.parent_span_id.as_ref(), Some(&run.span_id));
+        assert_eq!(run.parent_span_id, None);
+        assert_eq!(
+            request.attribute("total_tokens"),
+            Some(&AttributeValue::Int(42))
+        );
+
+        let json = otlp_json(&spans);
+        let exported = &json["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+        assert_eq!(exported["name"], "llm.request");
+        assert_eq!(exported["traceId"].as_str().unwrap().len(), 32);
+        assert_eq!(exported["spanId"].as_str().unwrap().len(), 16);
+        assert_eq!(
+            exported["attributes"][0],
+            serde_json::json!({ "key": "model", "value": { "stringValue": "gpt-4o" } })
+        );
+        assert_eq!(
+            exported["attributes"][1]["value"],
+            serde_json::json!({ "intValue": "42" })
+        );
+    }
+
+    #[test]
+    fn a_slow_collector_does_not_hold_up_the_run() {
+        // Accepts the connection and never answers
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let collector = Arc::new(SpanCollector::otlp(&endpoint));
+
+        let started = Instant::now();
+        tracing::subscriber::with_default(collector.clone(), || {
+            let _run = tracing::info_span!("agent.run").entered();
+        });
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        assert!(!collector.flush(Duration::from_millis(100)));
+        drop(listener);
+    }
+}
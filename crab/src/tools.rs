@@ -1,25 +1,1817 @@
-use std::process::Command;
+use crate::error::CrabError;
+use crate::llm::API_KEY_VARS;
+use crate::style::{DimmedWriter, Palette};
+use encoding_rs::Encoding;
+use nix::unistd::{Uid, User};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-pub fn execute_command(cmd: &str) -> Result<String, String> {
+const KILL_GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+// Containers owned by live sessions, so a signal handler can remove them
+// even though it can't reach the sessions themselves.
+static ACTIVE_CONTAINERS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+// Host processes started detached by run_in_background, stopped when the session ends
+static BACKGROUND_PIDS: Mutex<Vec<i32>> = Mutex::new(Vec::new());
+
+// Set once from OUTPUT_ENCODING at startup; None decodes as UTF-8
+static OUTPUT_ENCODING: Mutex<Option<&'static Encoding>> = Mutex::new(None);
+
+// The cubicle image already is the sandbox; nesting docker inside it isn't possible
+pub fn running_in_container() -> bool {
+    Path::new("/.dockerenv").exists()
+}
+
+// Host variables commands see when FORWARD_ENV isn't set. Anything not listed,
+// API keys included, is cleared from a host command's environment.
+pub const DEFAULT_FORWARD_ENV: &[&str] = &["HOME", "PATH", "LANG", "LC_ALL", "TERM", "TZ", "USER"];
+
+// Program and leading arguments commands are run with; the command is appended last
+pub const DEFAULT_SHELL_CMD: &[&str] = &["/bin/sh", "-c"];
+
+pub fn parse_shell_cmd(raw: &str) -> Result<Vec<String>, CrabError> {
+    let shell: Vec<String> = raw.split_whitespace().map(|s| s.to_string()).collect();
+    if shell.is_empty() {
+        return Err(CrabError::Config("SHELL_CMD is empty".to_string()));
+    }
+    Ok(shell)
+}
+
+// Shells whose `-n` parses a command without running it; with any other
+// SHELL_CMD program SYNTAX_CHECK has nothing to ask
+const SYNTAX_CHECK_SHELLS: &[&str] = &["sh", "bash", "dash", "zsh", "ksh", "mksh", "ash"];
+
+pub fn checks_syntax(shell: &[String]) -> bool {
+    shell
+        .first()
+        .and_then(|program| Path::new(program).file_name())
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| SYNTAX_CHECK_SHELLS.contains(&name))
+}
+
+// The parse error `shell -n` reports for `cmd`, or None when it parses or can't
+// be checked. Runs on the host even for a container, since nothing executes; a
+// shell only the image has is skipped.
+pub fn syntax_error(shell: &[String], cmd: &str) -> Option<String> {
+    if !checks_syntax(shell) {
+        return None;
+    }
+    let output = Command::new(&shell[0])
+        .arg("-n")
+        .args(&shell[1..])
+        .arg(cmd)
+        .stdin(Stdio::null())
+        .output();
+    match output {
+        Ok(output) if !output.status.success() => {
+            let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+            Some(if stderr.is_empty() {
+                format!("{} -n failed", shell[0])
+            } else {
+                stderr
+            })
+        }
+        Ok(_) => None,
+        Err(e) => {
+            log::debug!(
+                "Skipping the syntax check, {} -n failed to start: {}",
+                shell[0],
+                e
+            );
+            None
+        }
+    }
+}
+
+fn default_shell() -> Vec<String> {
+    DEFAULT_SHELL_CMD.iter().map(|s| s.to_string()).collect()
+}
+
+// API keys stay with crab even when FORWARD_ENV names them
+fn forwarded(forward_env: &[String]) -> impl Iterator<Item = &String> {
+    forward_env
+        .iter()
+        .filter(|name| !API_KEY_VARS.contains(&name.as_str()))
+}
+
+fn forward_host_env(command: &mut Command, forward_env: &[String]) {
+    command.env_clear();
+    for name in forwarded(forward_env) {
+        if let Some(value) = std::env::var_os(name) {
+            command.env(name, value);
+        }
+    }
+}
+
+// `-e NAME` without a value makes docker copy it from our environment, so values
+// don't show up in the process list; unset names are skipped by docker itself.
+fn docker_env_args(forward_env: &[String]) -> Vec<String> {
+    forwarded(forward_env)
+        .flat_map(|name| ["-e".to_string(), name.clone()])
+        .collect()
+}
+
+// Flags for the sandbox container, validated as they're set; None leaves docker's default
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DockerOptions {
+    pub memory: Option<String>,
+    pub cpus: Option<String>,
+    pub pids_limit: Option<u32>,
+    pub network: Option<String>,
+    pub user: Option<String>,
+    pub mount: Option<WorkdirMount>,
+}
+
+// Where WORKDIR_MOUNT shows up inside the container; also its working directory
+pub const CONTAINER_WORKDIR: &str = "/workspace";
+
+// Host directories that must never be handed to agent-generated commands
+const FORBIDDEN_MOUNTS: &[&str] = &[
+    "/", "/bin", "/boot", "/dev", "/etc", "/lib", "/lib64", "/proc", "/root", "/sbin", "/sys",
+    "/usr", "/var",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorkdirMount {
+    pub host_path: String,
+    pub read_only: bool,
+}
+
+impl DockerOptions {
+    // memory: docker syntax, e.g. "512m" or "2g"; cpus: e.g. "1.5"; empty means unset
+    pub fn limits(self, memory: &str, cpus: &str, pids_limit: &str) -> Result<Self, CrabError> {
+        let (memory, cpus, pids_limit) = (memory.trim(), cpus.trim(), pids_limit.trim());
+        let mut limits = self;
+
+        if !memory.is_empty() {
+            // Docker refuses anything below 6MB with a far less helpful message
+            match parse_memory_bytes(memory) {
+                Some(bytes) if bytes >= 6 * 1024 * 1024 => limits.memory = Some(memory.to_string()),
+                _ => {
+                    return Err(CrabError::Config(format!(
+                        "DOCKER_MEMORY '{}' must be a size of at least 6m, like 512m or 2g",
+                        memory
+                    )))
+                }
+            }
+        }
+        if !cpus.is_empty() {
+            match cpus.parse::<f64>() {
+                Ok(n) if n > 0.0 && n.is_finite() => limits.cpus = Some(cpus.to_string()),
+                _ => {
+                    return Err(CrabError::Config(format!(
+                        "DOCKER_CPUS '{}' must be a positive number, like 1.5",
+                        cpus
+                    )))
+                }
+            }
+        }
+        if !pids_limit.is_empty() {
+            match pids_limit.parse::<u32>() {
+                Ok(n) if n > 0 => limits.pids_limit = Some(n),
+                _ => {
+                    return Err(CrabError::Config(format!(
+                        "DOCKER_PIDS_LIMIT '{}' must be a positive whole number",
+                        pids_limit
+                    )))
+                }
+            }
+        }
+        Ok(limits)
+    }
+
+    // "none" cuts the sandbox off entirely; "bridge", "host" or a named network also work
+    pub fn network(mut self, network: &str) -> Result<Self, CrabError> {
+        let network = network.trim();
+        if network.is_empty() {
+            return Ok(self);
+        }
+        let valid = network.starts_with(|c: char| c.is_ascii_alphanumeric())
+            && network
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "_.-:".contains(c));
+        if !valid {
+            return Err(CrabError::Config(format!(
+                "DOCKER_NETWORK '{}' is not a valid network name",
+                network
+            )));
+        }
+        self.network = Some(network.to_string());
+        Ok(self)
+    }
+
+    // `user`, `uid`, `user:group` or `uid:gid`, as docker's --user takes them.
+    // Unset keeps the image's own user, which is root for most images.
+    pub fn user(mut self, user: &str) -> Result<Self, CrabError> {
+        let user = user.trim();
+        if user.is_empty() {
+            return Ok(self);
+        }
+        let part = |p: &str| {
+            p.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_')
+                && p.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c))
+        };
+        let valid = match user.split_once(':') {
+            Some((name, group)) => part(name) && part(group),
+            None => part(user),
+        };
+        if !valid {
+            return Err(CrabError::Config(format!(
+                "DOCKER_USER '{}' must be a user or uid, optionally with :group or :gid, like 1000:1000",
+                user
+            )));
+        }
+        self.user = Some(user.to_string());
+        Ok(self)
+    }
+
+    // `path` or `path:ro`. The path must be an existing directory other than `/`,
+    // a system directory or the home directory itself.
+    pub fn mount(mut self, spec: &str) -> Result<Self, CrabError> {
+        let spec = spec.trim();
+        if spec.is_empty() {
+            return Ok(self);
+        }
+        let (path, read_only) = match spec.strip_suffix(":ro") {
+            Some(path) => (path, true),
+            None => (spec.strip_suffix(":rw").unwrap_or(spec), false),
+        };
+
+        let host_path = std::fs::canonicalize(path)
+            .ok()
+            .filter(|p| p.is_dir())
+            .ok_or_else(|| {
+                CrabError::Config(format!(
+                    "WORKDIR_MOUNT '{}' is not an existing directory",
+                    path
+                ))
+            })?;
+        let home = std::env::var_os("HOME").and_then(|h| std::fs::canonicalize(h).ok());
+        if FORBIDDEN_MOUNTS.iter().any(|p| host_path == Path::new(p))
+            || home.as_deref() == Some(host_path.as_path())
+        {
+            return Err(CrabError::Config(format!(
+                "WORKDIR_MOUNT refuses to expose {}; mount a project directory instead",
+                host_path.display()
+            )));
+        }
+
+        self.mount = Some(WorkdirMount {
+            host_path: host_path.to_string_lossy().to_string(),
+            read_only,
+        });
+        Ok(self)
+    }
+
+    fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(memory) = &self.memory {
+            // Same value for swap, otherwise docker quietly allows twice the limit
+            args.extend(["--memory".to_string(), memory.clone()]);
+            args.extend(["--memory-swap".to_string(), memory.clone()]);
+        }
+        if let Some(cpus) = &self.cpus {
+            args.extend(["--cpus".to_string(), cpus.clone()]);
+        }
+        if let Some(pids) = self.pids_limit {
+            args.extend(["--pids-limit".to_string(), pids.to_string()]);
+        }
+        if let Some(network) = &self.network {
+            args.extend(["--network".to_string(), network.clone()]);
+        }
+        if let Some(user) = &self.user {
+            args.extend(["--user".to_string(), user.clone()]);
+        }
+        if let Some(mount) = &self.mount {
+            let mut volume = format!("{}:{}", mount.host_path, CONTAINER_WORKDIR);
+            if mount.read_only {
+                volume.push_str(":ro");
+            }
+            args.extend(["-v".to_string(), volume]);
+            args.extend(["-w".to_string(), CONTAINER_WORKDIR.to_string()]);
+        }
+        args
+    }
+}
+
+fn parse_memory_bytes(raw: &str) -> Option<u64> {
+    let raw = raw.to_ascii_lowercase();
+    let (number, unit) = match raw.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => raw.split_at(i),
+        None => (raw.as_str(), ""),
+    };
+    let multiplier = match unit {
+        "" | "b" => 1,
+        "k" => 1024,
+        "m" => 1024 * 1024,
+        "g" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+// SANDBOX: host commands run under firejail or bubblewrap, for some isolation
+// where docker isn't available. Everything is read-only apart from the working
+// directory and /tmp, and there's no network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sandbox {
+    Firejail,
+    Bwrap,
+}
+
+impl Sandbox {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "firejail" => Some(Sandbox::Firejail),
+            "bwrap" | "bubblewrap" => Some(Sandbox::Bwrap),
+            _ => None,
+        }
+    }
+
+    pub fn binary(&self) -> &'static str {
+        match self {
+            Sandbox::Firejail => "firejail",
+            Sandbox::Bwrap => "bwrap",
+        }
+    }
+
+    // What goes in front of SHELL_CMD; checked at startup so a missing tool fails
+    // before the model has been asked anything
+    pub fn prefix(&self, writable: &Path) -> Result<Vec<String>, CrabError> {
+        let binary = find_on_path(self.binary()).ok_or_else(|| {
+            CrabError::Config(format!(
+                "SANDBOX={} needs {} on PATH; install it (e.g. `apt install {}`) or unset SANDBOX",
+                self.binary(),
+                self.binary(),
+                match self {
+                    Sandbox::Firejail => "firejail",
+                    Sandbox::Bwrap => "bubblewrap",
+                }
+            ))
+        })?;
+        let writable = writable.display().to_string();
+        let args = match self {
+            Sandbox::Firejail => vec![
+                "--quiet".to_string(),
+                "--noprofile".to_string(),
+                "--net=none".to_string(),
+                "--read-only=/".to_string(),
+                format!("--read-write={}", writable),
+                "--read-write=/tmp".to_string(),
+                "--".to_string(),
+            ],
+            Sandbox::Bwrap => [
+                "--ro-bind",
+                "/",
+                "/",
+                "--dev",
+                "/dev",
+                "--proc",
+                "/proc",
+                "--bind",
+                "/tmp",
+                "/tmp",
+                "--bind",
+                &writable,
+                &writable,
+                "--unshare-net",
+                "--",
+            ]
+            .map(String::from)
+            .to_vec(),
+        };
+        let mut prefix = vec![binary.display().to_string()];
+        prefix.extend(args);
+        Ok(prefix)
+    }
+}
+
+// EXEC_AS_USER: host commands drop to this account's uid and gid before they
+// start. Only root can switch to another account, so that's checked up front.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HostUser {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl HostUser {
+    // A name or numeric uid from the passwd database, with that entry's primary gid
+    pub fn lookup(user: &str) -> Result<Self, CrabError> {
+        let user = user.trim();
+        let invalid = |why: String| CrabError::Config(format!("EXEC_AS_USER '{}': {}", user, why));
+        let entry = match user.parse::<u32>() {
+            Ok(uid) => User::from_uid(Uid::from_raw(uid)),
+            Err(_) => User::from_name(user),
+        }
+        .map_err(|e| invalid(format!("could not look it up: {}", e)))?
+        .ok_or_else(|| invalid("no such user".to_string()))?;
+        let target = HostUser {
+            uid: entry.uid.as_raw(),
+            gid: entry.gid.as_raw(),
+        };
+        let euid = Uid::effective().as_raw();
+        if euid != 0 && euid != target.uid {
+            return Err(invalid(format!(
+                "switching users needs crab to run as root, but it runs as uid {}",
+                euid
+            )));
+        }
+        Ok(target)
+    }
+
+    // Supplementary groups are dropped too, since std clears them when root sets a uid
+    fn apply(&self, command: &mut Command) {
+        command.gid(self.gid).uid(self.uid);
+    }
+}
+
+// `sandbox` is prepended to `shell` for host commands; empty runs them directly
+#[allow(clippy::too_many_arguments)]
+pub fn execute_command(
+    cmd: &str,
+    image: &str,
+    timeout: Duration,
+    forward_env: &[String],
+    options: &DockerOptions,
+    shell: &[String],
+    sandbox: &[String],
+    run_as: Option<HostUser>,
+    stdin: Option<&str>,
+    stream: bool,
+) -> Result<CommandOutput, CrabError> {
     let parts: Vec<&str> = cmd.split_whitespace().collect();
 
     if parts.is_empty() {
-        return Err("Empty command".to_string());
+        return Err(CrabError::Exec("Empty command".to_string()));
     }
 
-    let output = Command::new("sh")
-        .arg("-c")
-        .arg(cmd)
-        .output()
-        .map_err(|e| format!("Failed to execute: {}", e))?;
+    if shell.is_empty() {
+        return Err(CrabError::Config("SHELL_CMD is empty".to_string()));
+    }
+    let host_shell: Vec<&String> = sandbox.iter().chain(shell).collect();
+    let (program, shell_args) = (host_shell[0], &host_shell[1..]);
 
-    if output.status.success() {
-        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    // A one-off `docker run` ends with its shell, taking anything detached along,
+    // so only host commands are backgrounded here. The detached shell is already
+    // inside the sandbox, so it isn't wrapped a second time.
+    if image.is_empty() && is_background_command(cmd) {
+        let log = background_log();
+        let mut command = Command::new(program);
+        command
+            .args(shell_args)
+            .arg(background_wrapper(cmd, shell, &log));
+        forward_host_env(&mut command, forward_env);
+        if let Some(user) = run_as {
+            user.apply(&mut command);
+        }
+        return run_in_background(command, timeout, &log, true);
+    }
+
+    let command = if image.is_empty() {
+        let mut command = Command::new(program);
+        command.args(shell_args).arg(cmd);
+        forward_host_env(&mut command, forward_env);
+        if let Some(user) = run_as {
+            user.apply(&mut command);
+        }
+        command
     } else {
-        Err(String::from_utf8_lossy(&output.stderr).to_string())
+        let mut command = Command::new("docker");
+        command.args(["run", "--rm"]);
+        if stdin.is_some() {
+            command.arg("-i");
+        }
+        command.args(options.args());
+        command.args(docker_env_args(forward_env));
+        command.arg(image).args(shell).arg(cmd);
+        command
+    };
+
+    hint_interactive(
+        cmd,
+        stdin,
+        run_with_timeout(command, timeout, stdin, stream),
+    )
+}
+
+// Servers and watchers that never exit on their own, matched anywhere in the command
+const LONG_RUNNING_PATTERNS: &[&str] = &[
+    "npm run dev",
+    "npm start",
+    "yarn dev",
+    "yarn start",
+    "pnpm dev",
+    "next dev",
+    "webpack serve",
+    "http-server",
+    "http.server",
+    "flask run",
+    "rails server",
+    "manage.py runserver",
+    "uvicorn ",
+    "gunicorn ",
+    "php -S ",
+];
+
+// An unquoted `&` anywhere (not `&&`, nor bash's `&>`) unless the shell `wait`s
+// for its jobs, a leading `nohup`, or a known server command
+pub fn is_background_command(cmd: &str) -> bool {
+    let cmd = cmd.trim();
+    let tokens = tokenize_shell(cmd);
+    let at_command = |i: usize| i == 0 || matches!(tokens[i - 1], ShellToken::Op(_));
+    let detaches = tokens.iter().enumerate().any(|(i, token)| {
+        matches!(token, ShellToken::Op(op) if op == "&")
+            && !matches!(tokens.get(i + 1), Some(ShellToken::Op(next)) if next.starts_with('>'))
+    });
+    let waits = tokens
+        .iter()
+        .enumerate()
+        .any(|(i, token)| matches!(token, ShellToken::Word(w) if w == "wait") && at_command(i));
+    (detaches && !waits)
+        || cmd
+            .split(['&', ';', '|'])
+            .any(|part| part.trim_start().starts_with("nohup "))
+        || LONG_RUNNING_PATTERNS.iter().any(|p| cmd.contains(p))
+}
+
+fn background_log() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or_default();
+    format!("/tmp/crab-bg-{}-{}.log", std::process::id(), nanos)
+}
+
+// Starts `cmd` detached under the same shell, its output in `log`, and prints its
+// PID. Our pipes aren't inherited, so the wrapper returns as soon as it's launched.
+fn background_wrapper(cmd: &str, shell: &[String], log: &str) -> String {
+    let cmd = cmd.trim();
+    let cmd = cmd.strip_suffix('&').unwrap_or(cmd).trim_end();
+    let shell = shell
+        .iter()
+        .map(|part| shell_quote(part))
+        .collect::<Vec<_>>()
+        .join(" ");
+    format!(
+        "nohup {} {} > {} 2>&1 < /dev/null & echo $!",
+        shell,
+        shell_quote(cmd),
+        log
+    )
+}
+
+// `track` registers the PID for reap_background_processes; processes inside a
+// session's container go away with the container instead
+fn run_in_background(
+    command: Command,
+    timeout: Duration,
+    log: &str,
+    track: bool,
+) -> Result<CommandOutput, CrabError> {
+    let output = run_with_timeout(command, timeout, None, false)?;
+    let pid: i32 = output.stdout.trim().parse().map_err(|_| {
+        CrabError::Exec(format!(
+            "Failed to start background command: {}",
+            output.stderr.trim()
+        ))
+    })?;
+    if track {
+        if let Ok(mut pids) = BACKGROUND_PIDS.lock() {
+            pids.push(pid);
+        }
+    }
+    log::info!("Backgrounded command as PID {}", pid);
+    Ok(CommandOutput {
+        stdout: format!(
+            "Started in the background with PID {}; output goes to {}. It keeps running until the session ends.\n",
+            pid, log
+        ),
+        stderr: String::new(),
+        exit_code: 0,
+    })
+}
+
+// Signals each tracked process's group, which reaches whatever it forked too
+pub fn reap_background_processes() {
+    let pids: Vec<i32> = match BACKGROUND_PIDS.lock() {
+        Ok(mut pids) => pids.drain(..).collect(),
+        Err(_) => return,
+    };
+
+    for pid in pids {
+        unsafe {
+            let pgid = libc::getpgid(pid);
+            if pgid > 0 && pgid != libc::getpgrp() {
+                libc::kill(-pgid, libc::SIGTERM);
+            } else {
+                libc::kill(pid, libc::SIGTERM);
+            }
+        }
+    }
+}
+
+// Where streamed command output goes; stdout is reserved for the orchestrator
+fn live_output(stream: bool) -> Option<Box<dyn Write + Send>> {
+    stream.then(|| -> Box<dyn Write + Send> {
+        if Palette::stderr().enabled {
+            Box::new(DimmedWriter(io::stderr()))
+        } else {
+            Box::new(io::stderr())
+        }
+    })
+}
+
+// With a `tee`, each line is passed on as soon as it's read. The buffer gets the
+// same bytes either way; only the model's copy is redacted.
+fn spawn_reader(
+    pipe: Option<impl Read + Send + 'static>,
+    tee: Option<Box<dyn Write + Send>>,
+) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        match (pipe, tee) {
+            (Some(pipe), Some(mut tee)) => {
+                let mut reader = BufReader::new(pipe);
+                loop {
+                    let start = buf.len();
+                    match reader.read_until(b'\n', &mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(_) => {
+                            let _ = tee.write_all(&buf[start..]).and_then(|_| tee.flush());
+                        }
+                    }
+                }
+            }
+            (Some(mut pipe), None) => {
+                let _ = pipe.read_to_end(&mut buf);
+            }
+            (None, _) => {}
+        }
+        buf
+    })
+}
+
+// Written from its own thread so a child that fills its stdout pipe before reading
+// its input can't deadlock us. Dropping the pipe afterwards closes the child's stdin.
+fn spawn_writer(pipe: Option<impl Write + Send + 'static>, input: &str) -> thread::JoinHandle<()> {
+    let input = input.to_string();
+    thread::spawn(move || {
+        if let Some(mut pipe) = pipe {
+            // A child that exits without reading everything gives EPIPE; that's its call
+            let _ = pipe.write_all(input.as_bytes());
+        }
+    })
+}
+
+// The child leads its own process group, so signalling the group also reaches
+// anything `sh -c` forked instead of just the shell itself.
+fn terminate_group(child: &mut Child) {
+    let pgid = -(child.id() as i32);
+    unsafe {
+        libc::kill(pgid, libc::SIGTERM);
+    }
+
+    let deadline = Instant::now() + KILL_GRACE_PERIOD;
+    while Instant::now() < deadline {
+        if let Ok(Some(_)) = child.try_wait() {
+            return;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    unsafe {
+        libc::kill(pgid, libc::SIGKILL);
+    }
+    let _ = child.wait();
+}
+
+// How long the readers get to drain once the shell has exited before whatever
+// still holds its pipes is taken to be a leftover process
+const STRAGGLER_WAIT: Duration = Duration::from_millis(200);
+
+fn readers_done_within(readers: &[&thread::JoinHandle<Vec<u8>>], wait: Duration) -> bool {
+    let deadline = Instant::now() + wait;
+    loop {
+        if readers.iter().all(|r| r.is_finished()) {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// The shell is gone but something it started (`sleep 99 & echo hi`) still holds
+// the pipes; its group goes the way a timed-out command's would
+fn terminate_stragglers(pgid: i32, readers: &[&thread::JoinHandle<Vec<u8>>]) {
+    unsafe {
+        libc::kill(-pgid, libc::SIGTERM);
+    }
+    if !readers_done_within(readers, KILL_GRACE_PERIOD) {
+        unsafe {
+            libc::kill(-pgid, libc::SIGKILL);
+        }
+    }
+}
+
+// A reader still blocked at `deadline` (a process that left the group has the
+// pipe) is abandoned with nothing
+fn join_reader(reader: thread::JoinHandle<Vec<u8>>, deadline: Instant) -> Vec<u8> {
+    while !reader.is_finished() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    if reader.is_finished() {
+        reader.join().unwrap_or_default()
+    } else {
+        Vec::new()
     }
 }
 
+fn run_with_timeout(
+    mut command: Command,
+    timeout: Duration,
+    stdin: Option<&str>,
+    stream: bool,
+) -> Result<CommandOutput, CrabError> {
+    let stdin_mode = if stdin.is_some() {
+        Stdio::piped()
+    } else {
+        Stdio::null()
+    };
+    command
+        .stdin(stdin_mode)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0);
+
+    let mut child = command
+        .spawn()
+        .map_err(|e| CrabError::Exec(format!("Failed to execute: {}", e)))?;
+    let stdout = spawn_reader(child.stdout.take(), live_output(stream));
+    let stderr = spawn_reader(child.stderr.take(), live_output(stream));
+    let writer = spawn_writer(child.stdin.take(), stdin.unwrap_or_default());
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break Some(status),
+            Ok(None) if Instant::now() >= deadline => break None,
+            Ok(None) => thread::sleep(Duration::from_millis(10)),
+            Err(e) => {
+                return Err(CrabError::Exec(format!(
+                    "Failed to wait for command: {}",
+                    e
+                )))
+            }
+        }
+    };
+
+    if status.is_none() {
+        terminate_group(&mut child);
+    } else if !readers_done_within(&[&stdout, &stderr], STRAGGLER_WAIT) {
+        terminate_stragglers(child.id() as i32, &[&stdout, &stderr]);
+    }
+
+    let until = Instant::now() + KILL_GRACE_PERIOD;
+    let stdout = join_reader(stdout, until);
+    let stderr = join_reader(stderr, until);
+    let _ = writer.join();
+
+    match status {
+        Some(status) => Ok(collect_output(Output {
+            status,
+            stdout,
+            stderr,
+        })),
+        None => Err(CrabError::CommandTimeout {
+            after: timeout,
+            partial: format!("{}{}", decode_output(&stdout), decode_output(&stderr)),
+            hint: None,
+        }),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandOutput {
+    pub stdout: String,
+    pub stderr: String,
+    pub exit_code: i32,
+}
+
+fn collect_output(output: Output) -> CommandOutput {
+    // Shell convention: a signal-terminated process reports 128 + signal
+    let exit_code = output
+        .status
+        .code()
+        .or_else(|| output.status.signal().map(|sig| 128 + sig))
+        .unwrap_or(-1);
+
+    CommandOutput {
+        stdout: decode_output(&output.stdout),
+        stderr: decode_output(&output.stderr),
+        exit_code,
+    }
+}
+
+// Accepts any WHATWG label (latin1, windows-1252, shift_jis, ...)
+pub fn output_encoding(label: &str) -> Option<&'static Encoding> {
+    Encoding::for_label(label.trim().as_bytes())
+}
+
+pub fn set_output_encoding(encoding: &'static Encoding) {
+    *OUTPUT_ENCODING.lock().unwrap_or_else(|e| e.into_inner()) = Some(encoding);
+}
+
+pub fn decode_output(bytes: &[u8]) -> String {
+    let encoding = *OUTPUT_ENCODING.lock().unwrap_or_else(|e| e.into_inner());
+    decode_output_as(bytes, encoding.unwrap_or(encoding_rs::UTF_8))
+}
+
+// Invalid bytes are replaced rather than refused, but output that is mostly
+// control bytes (`cat /bin/ls`) reaches the model as a one-line summary
+pub fn decode_output_as(bytes: &[u8], encoding: &'static Encoding) -> String {
+    let (text, _) = encoding.decode_without_bom_handling(bytes);
+    let unprintable = text
+        .chars()
+        .filter(|&c| {
+            c == char::REPLACEMENT_CHARACTER
+                || (c.is_control() && !matches!(c, '\n' | '\r' | '\t' | '\x1b'))
+        })
+        .count();
+    if text.contains('\0') || unprintable * 10 > text.chars().count() * 3 {
+        return format!(
+            "[binary output, {} bytes, sha256={}]",
+            bytes.len(),
+            sha256_hex(bytes)
+        );
+    }
+    text.into_owned()
+}
+
+// Only used for fingerprints, never for security, so it's kept here rather than
+// pulling in a crypto crate
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    const K: [u32; 64] = [
+        0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4,
+        0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe,
+        0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f,
+        0x4a7484aa, 0x5cb0a9dc, 0x76f988da, 0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7,
+        0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc,
+        0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b,
+        0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070, 0x19a4c116,
+        0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+        0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7,
+        0xc67178f2,
+    ];
+    let mut h: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+        0x5be0cd19,
+    ];
+
+    let mut message = bytes.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((bytes.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh] = h;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = hh
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            hh = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (state, value) in h.iter_mut().zip([a, b, c, d, e, f, g, hh]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+    h.iter().map(|word| format!("{:08x}", word)).collect()
+}
+
+fn floor_char_boundary(text: &str, mut index: usize) -> usize {
+    while !text.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+fn ceil_char_boundary(text: &str, mut index: usize) -> usize {
+    while !text.is_char_boundary(index) {
+        index += 1;
+    }
+    index
+}
+
+// Keeps the head and tail of oversized output, the way you'd skim a long log.
+// The result, marker included, never exceeds `max_bytes`; 0 disables the cap.
+pub fn truncate_output(text: &str, max_bytes: usize) -> String {
+    if max_bytes == 0 || text.len() <= max_bytes {
+        return text.to_string();
+    }
+
+    // Size the marker for the worst case so the final count can't overflow the cap
+    let marker_len = format!("\n... [{} bytes truncated] ...\n", text.len()).len();
+    // Too small a cap for the marker: a plain cut is the most that fits
+    if max_bytes < marker_len {
+        return text[..floor_char_boundary(text, max_bytes)].to_string();
+    }
+    let budget = max_bytes - marker_len;
+    let head_end = floor_char_boundary(text, budget / 2);
+    let tail_start = ceil_char_boundary(text, text.len() - (budget - budget / 2));
+
+    format!(
+        "{}\n... [{} bytes truncated] ...\n{}",
+        &text[..head_end],
+        tail_start - head_end,
+        &text[tail_start..]
+    )
+}
+
+// Removes terminal escape sequences: CSI (colors, cursor moves), OSC (titles,
+// hyperlinks) terminated by BEL or ST, and any other two-byte escape.
+pub fn strip_ansi(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('[') => {
+                // Parameters and intermediates, then a single final byte in @..~
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\x07' {
+                        break;
+                    }
+                    if c == '\x1b' && chars.peek() == Some(&'\\') {
+                        chars.next();
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    out
+}
+
+impl CommandOutput {
+    pub fn without_ansi(self) -> Self {
+        Self {
+            stdout: strip_ansi(&self.stdout),
+            stderr: strip_ansi(&self.stderr),
+            exit_code: self.exit_code,
+        }
+    }
+
+    pub fn truncated(self, max_bytes: usize) -> Self {
+        Self {
+            stdout: truncate_output(&self.stdout, max_bytes),
+            stderr: truncate_output(&self.stderr, max_bytes),
+            exit_code: self.exit_code,
+        }
+    }
+}
+
+// How results are framed for the model. OUTPUT_TEMPLATE takes {command},
+// {exit_code}, {stdout} and {stderr}; ERROR_TEMPLATE takes {command} and {error}.
+pub const DEFAULT_OUTPUT_TEMPLATE: &str =
+    "COMMAND_OUTPUT:\nexit_code: {exit_code}\n--- stdout ---\n{stdout}\n--- stderr ---\n{stderr}";
+pub const DEFAULT_ERROR_TEMPLATE: &str = "ERROR: {error}";
+
+// One pass over the template, so braces inside the values are left alone
+fn render_template(template: &str, values: &[(&str, &str)]) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        rendered.push_str(&rest[..open]);
+        let after = &rest[open..];
+        let value = after.find('}').and_then(|close| {
+            let name = &after[1..close];
+            values
+                .iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| (*value, close))
+        });
+        match value {
+            Some((value, close)) => {
+                rendered.push_str(value);
+                rest = &after[close + 1..];
+            }
+            None => {
+                rendered.push('{');
+                rest = &after[1..];
+            }
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+pub fn format_command_output(template: &str, command: &str, output: &CommandOutput) -> String {
+    // Empty stdout and stderr sections read as a missing result and the model
+    // tends to retry; a custom OUTPUT_TEMPLATE keeps its own framing
+    if template == DEFAULT_OUTPUT_TEMPLATE
+        && output.stdout.trim().is_empty()
+        && output.stderr.trim().is_empty()
+    {
+        return format!(
+            "COMMAND_OUTPUT: (command produced no output, exit code {})",
+            output.exit_code
+        );
+    }
+    render_template(
+        template,
+        &[
+            ("command", command),
+            ("exit_code", &output.exit_code.to_string()),
+            ("stdout", output.stdout.trim_end()),
+            ("stderr", output.stderr.trim_end()),
+        ],
+    )
+}
+
+pub fn format_command_error(template: &str, command: &str, error: &str) -> String {
+    render_template(template, &[("command", command), ("error", error)])
+}
+
+// How one command runs: `stdin` is written to it and then closed (None gives it
+// no input at all), and it's given up on after `timeout`
+#[derive(Debug, Clone, Copy)]
+pub struct ExecOpts<'a> {
+    pub stdin: Option<&'a str>,
+    pub timeout: Duration,
+}
+
+// Past this many files WATCH_CHANGES gives up on scanning the host workspace
+const MAX_WATCHED_FILES: usize = 20_000;
+
+// What the filesystem looked like before a command, for WATCH_CHANGES
+#[derive(Debug, Clone, PartialEq)]
+pub enum FsSnapshot {
+    // `docker diff` against the image: path -> 'A', 'C' or 'D'
+    Docker(BTreeMap<String, char>),
+    // Every file under the workspace: path -> (mtime, size)
+    Files(BTreeMap<String, (SystemTime, u64)>),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FsChanges {
+    pub added: Vec<String>,
+    pub changed: Vec<String>,
+    pub deleted: Vec<String>,
+}
+
+impl FsChanges {
+    pub fn between(before: &FsSnapshot, after: &FsSnapshot) -> Self {
+        let mut changes = FsChanges::default();
+        match (before, after) {
+            (FsSnapshot::Docker(before), FsSnapshot::Docker(after)) => {
+                // A file already marked C that changes again isn't seen; docker
+                // only tracks state against the image, not when it last changed
+                for (path, kind) in after {
+                    if before.get(path) == Some(kind) {
+                        continue;
+                    }
+                    match kind {
+                        'A' => changes.added.push(path.clone()),
+                        'D' => changes.deleted.push(path.clone()),
+                        _ => changes.changed.push(path.clone()),
+                    }
+                }
+                // Gone from the diff means put back the way the image has it
+                for path in before.keys().filter(|p| !after.contains_key(*p)) {
+                    changes.changed.push(path.clone());
+                }
+                // Creating /tmp/x also marks /tmp changed, which says nothing new
+                let named: Vec<String> = changes
+                    .added
+                    .iter()
+                    .chain(&changes.changed)
+                    .chain(&changes.deleted)
+                    .cloned()
+                    .collect();
+                changes.changed.retain(|dir| {
+                    !named
+                        .iter()
+                        .any(|p| p.len() > dir.len() && p.starts_with(&format!("{}/", dir)))
+                });
+                changes.changed.sort();
+            }
+            (FsSnapshot::Files(before), FsSnapshot::Files(after)) => {
+                for (path, stamp) in after {
+                    match before.get(path) {
+                        None => changes.added.push(path.clone()),
+                        Some(old) if old != stamp => changes.changed.push(path.clone()),
+                        Some(_) => {}
+                    }
+                }
+                changes.deleted = before
+                    .keys()
+                    .filter(|p| !after.contains_key(*p))
+                    .cloned()
+                    .collect();
+            }
+            _ => {}
+        }
+        changes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() && self.deleted.is_empty()
+    }
+
+    // e.g. `FILES_CHANGED: added /tmp/a; deleted /tmp/b`, empty when nothing did
+    pub fn summary(&self) -> String {
+        let parts: Vec<String> = [
+            ("added", &self.added),
+            ("changed", &self.changed),
+            ("deleted", &self.deleted),
+        ]
+        .iter()
+        .filter(|(_, paths)| !paths.is_empty())
+        .map(|(label, paths)| format!("{} {}", label, paths.join(", ")))
+        .collect();
+        if parts.is_empty() {
+            return String::new();
+        }
+        format!("FILES_CHANGED: {}", parts.join("; "))
+    }
+}
+
+// Files under `root` with their mtime and size, skipping .git
+pub fn scan_files(root: &Path) -> Result<FsSnapshot, CrabError> {
+    let mut files = BTreeMap::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let entries = std::fs::read_dir(&dir)
+            .map_err(|e| CrabError::Exec(format!("Could not scan {}: {}", dir.display(), e)))?;
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let Ok(meta) = entry.metadata() else {
+                continue;
+            };
+            if meta.is_dir() {
+                if entry.file_name() != ".git" {
+                    dirs.push(path);
+                }
+                continue;
+            }
+            if files.len() >= MAX_WATCHED_FILES {
+                return Err(CrabError::Exec(format!(
+                    "{} has over {} files, too many to watch for changes",
+                    root.display(),
+                    MAX_WATCHED_FILES
+                )));
+            }
+            let modified = meta.modified().unwrap_or(UNIX_EPOCH);
+            files.insert(path.display().to_string(), (modified, meta.len()));
+        }
+    }
+    Ok(FsSnapshot::Files(files))
+}
+
+// Where the agent loop sends commands: the host, a container or another machine
+// over ssh, picked by EXECUTOR; also lets the loop be exercised without a shell.
+// Sync so MAX_PARALLEL can share one executor between threads
+pub trait Executor: Sync {
+    fn execute(&self, cmd: &str, opts: &ExecOpts) -> Result<CommandOutput, CrabError>;
+
+    // For WATCH_CHANGES; None when the executor has no way to tell
+    fn snapshot(&self) -> Option<Result<FsSnapshot, CrabError>> {
+        None
+    }
+
+    fn run(&self, cmd: &str, timeout: Duration) -> Result<CommandOutput, CrabError> {
+        self.execute(
+            cmd,
+            &ExecOpts {
+                stdin: None,
+                timeout,
+            },
+        )
+    }
+}
+
+pub struct HostRunner {
+    pub forward_env: Vec<String>,
+    pub shell: Vec<String>,
+    // From Sandbox::prefix; empty runs commands directly
+    pub sandbox: Vec<String>,
+    // EXEC_AS_USER; None runs commands as crab's own user
+    pub run_as: Option<HostUser>,
+    pub stream_output: bool,
+}
+
+impl Default for HostRunner {
+    fn default() -> Self {
+        Self {
+            forward_env: DEFAULT_FORWARD_ENV.iter().map(|s| s.to_string()).collect(),
+            shell: default_shell(),
+            sandbox: Vec::new(),
+            run_as: None,
+            stream_output: false,
+        }
+    }
+}
+
+impl Executor for HostRunner {
+    fn execute(&self, cmd: &str, opts: &ExecOpts) -> Result<CommandOutput, CrabError> {
+        execute_command(
+            cmd,
+            "",
+            opts.timeout,
+            &self.forward_env,
+            &DockerOptions::default(),
+            &self.shell,
+            &self.sandbox,
+            self.run_as,
+            opts.stdin,
+            self.stream_output,
+        )
+    }
+
+    // The workspace crab was started in; commands can write elsewhere unseen
+    fn snapshot(&self) -> Option<Result<FsSnapshot, CrabError>> {
+        Some(
+            std::env::current_dir()
+                .map_err(|e| {
+                    CrabError::Exec(format!("Could not read the current directory: {}", e))
+                })
+                .and_then(|dir| scan_files(&dir)),
+        )
+    }
+}
+
+// One long-lived container per agent run, so filesystem state survives between commands
+pub struct DockerSession {
+    container_id: String,
+    forward_env: Vec<String>,
+    shell: Vec<String>,
+    stream_output: bool,
+    // Left running when dropped, see keep
+    kept: bool,
+}
+
+// Checked before the loop starts, so a typo in DOCKER_IMAGE fails up front instead
+// of on the first command after the model has already burned tokens
+pub fn ensure_image(image: &str, auto_pull: bool) -> Result<(), CrabError> {
+    let docker = |args: &[&str]| {
+        Command::new("docker")
+            .args(args)
+            .output()
+            .map_err(|e| CrabError::Exec(format!("Failed to run docker: {}", e)))
+    };
+
+    let inspect = docker(&["image", "inspect", image])?;
+    if inspect.status.success() {
+        return Ok(());
+    }
+    if !auto_pull {
+        return Err(CrabError::Exec(format!(
+            "Docker image '{}' is not available locally (set DOCKER_AUTO_PULL=true to pull it)",
+            image
+        )));
+    }
+
+    eprintln!("[Sandbox] Image {} not found locally, pulling it", image);
+    let pull = docker(&["pull", image])?;
+    if !pull.status.success() {
+        return Err(CrabError::Exec(format!(
+            "Docker image '{}' is not available locally and could not be pulled: {}",
+            image,
+            String::from_utf8_lossy(&pull.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+impl DockerSession {
+    pub fn start(
+        image: &str,
+        forward_env: &[String],
+        options: &DockerOptions,
+    ) -> Result<Self, CrabError> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+        let name = format!("crabshell-{}-{}", std::process::id(), nanos);
+
+        let output = Command::new("docker")
+            .args(["run", "-d", "--rm", "--name", &name])
+            .args(options.args())
+            .arg(image)
+            .args([
+                "sh",
+                "-c",
+                "trap 'exit 0' TERM; while :; do sleep 3600; done",
+            ])
+            .output()
+            .map_err(|e| CrabError::Exec(format!("Failed to start container: {}", e)))?;
+
+        if !output.status.success() {
+            return Err(CrabError::Exec(format!(
+                "Failed to start container from {}: {}",
+                image,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+
+        let container_id = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if let Ok(mut active) = ACTIVE_CONTAINERS.lock() {
+            active.push(container_id.clone());
+        }
+
+        Ok(Self {
+            container_id,
+            forward_env: forward_env.to_vec(),
+            shell: default_shell(),
+            stream_output: false,
+            kept: false,
+        })
+    }
+
+    pub fn with_shell(mut self, shell: Vec<String>) -> Self {
+        self.shell = shell;
+        self
+    }
+
+    pub fn with_stream_output(mut self, stream_output: bool) -> Self {
+        self.stream_output = stream_output;
+        self
+    }
+
+    pub fn container_id(&self) -> &str {
+        &self.container_id
+    }
+
+    // Ends the session without removing the container, so it can be inspected;
+    // returns its id. The Ctrl-C cleanup leaves it alone too.
+    pub fn keep(mut self) -> String {
+        self.kept = true;
+        self.container_id.clone()
+    }
+
+    pub fn exec(
+        &self,
+        cmd: &str,
+        stdin: Option<&str>,
+        timeout: Duration,
+    ) -> Result<CommandOutput, CrabError> {
+        if cmd.split_whitespace().next().is_none() {
+            return Err(CrabError::Exec("Empty command".to_string()));
+        }
+
+        if is_background_command(cmd) {
+            let log = background_log();
+            let mut command = Command::new("docker");
+            command.arg("exec");
+            command.args(docker_env_args(&self.forward_env));
+            command.arg(&self.container_id).args(&self.shell);
+            command.arg(background_wrapper(cmd, &self.shell, &log));
+            return run_in_background(command, timeout, &log, false);
+        }
+
+        // `docker exec` doesn't forward signals, so the in-container side also gets
+        // a deadline; the host-side timeout is the backstop if that never fires.
+        let secs = timeout.as_secs().max(1).to_string();
+        let grace = KILL_GRACE_PERIOD.as_secs().to_string();
+        let mut command = Command::new("docker");
+        command.arg("exec");
+        if stdin.is_some() {
+            command.arg("-i");
+        }
+        command.args(docker_env_args(&self.forward_env));
+        command.args([&self.container_id, "timeout", "-k", &grace, &secs]);
+        command.args(&self.shell).arg(cmd);
+
+        hint_interactive(
+            cmd,
+            stdin,
+            run_with_timeout(
+                command,
+                timeout + KILL_GRACE_PERIOD * 2,
+                stdin,
+                self.stream_output,
+            ),
+        )
+    }
+}
+
+impl Executor for DockerSession {
+    fn execute(&self, cmd: &str, opts: &ExecOpts) -> Result<CommandOutput, CrabError> {
+        self.exec(cmd, opts.stdin, opts.timeout)
+    }
+
+    // Bind mounts such as WORKDIR_MOUNT aren't part of the container's layer,
+    // so writes there don't show up
+    fn snapshot(&self) -> Option<Result<FsSnapshot, CrabError>> {
+        let output = match Command::new("docker")
+            .args(["diff", &self.container_id])
+            .output()
+        {
+            Ok(output) if output.status.success() => output,
+            Ok(output) => {
+                return Some(Err(CrabError::Exec(format!(
+                    "docker diff failed: {}",
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ))))
+            }
+            Err(e) => return Some(Err(CrabError::Exec(format!("docker diff failed: {}", e)))),
+        };
+        let paths = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| {
+                let (kind, path) = line.split_once(' ')?;
+                Some((path.to_string(), kind.chars().next()?))
+            })
+            .collect();
+        Some(Ok(FsSnapshot::Docker(paths)))
+    }
+}
+
+impl Drop for DockerSession {
+    fn drop(&mut self) {
+        if !self.kept {
+            remove_container(&self.container_id);
+        }
+        if let Ok(mut active) = ACTIVE_CONTAINERS.lock() {
+            active.retain(|id| id != &self.container_id);
+        }
+    }
+}
+
+fn remove_container(container_id: &str) {
+    let _ = Command::new("docker")
+        .args(["rm", "-f", container_id])
+        .output();
+}
+
+pub fn cleanup_active_containers() {
+    let ids: Vec<String> = match ACTIVE_CONTAINERS.lock() {
+        Ok(mut active) => active.drain(..).collect(),
+        Err(_) => return,
+    };
+
+    for id in ids {
+        remove_container(&id);
+    }
+}
+
+// EXECUTOR=ssh: each command goes through the `ssh` binary to SSH_HOST, so
+// ~/.ssh/config, agents and known_hosts all apply. FORWARD_ENV isn't sent along;
+// sshd only takes the variables its AcceptEnv allows.
+pub struct SshRunner {
+    pub host: String,
+    // Both optional; empty leaves them to ssh's own config
+    pub user: String,
+    pub key: String,
+    pub shell: Vec<String>,
+    pub stream_output: bool,
+}
+
+impl SshRunner {
+    // The remote side gets one string for its login shell, so every part is quoted
+    fn command(&self, remote: &[&str]) -> Command {
+        let mut command = Command::new("ssh");
+        // A password or host-key prompt would wait on a terminal nobody is at
+        command.args(["-o", "BatchMode=yes"]);
+        if !self.key.is_empty() {
+            command.arg("-i").arg(&self.key);
+        }
+        if !self.user.is_empty() {
+            command.arg("-l").arg(&self.user);
+        }
+        let remote = remote
+            .iter()
+            .map(|part| shell_quote(part))
+            .collect::<Vec<_>>()
+            .join(" ");
+        command.arg("--").arg(&self.host).arg(remote);
+        command
+    }
+}
+
+impl Executor for SshRunner {
+    fn execute(&self, cmd: &str, opts: &ExecOpts) -> Result<CommandOutput, CrabError> {
+        if cmd.split_whitespace().next().is_none() {
+            return Err(CrabError::Exec("Empty command".to_string()));
+        }
+        let shell: Vec<&str> = self.shell.iter().map(String::as_str).collect();
+
+        if is_background_command(cmd) {
+            let log = background_log();
+            let wrapper = background_wrapper(cmd, &self.shell, &log);
+            let command = self.command(&[&shell[..], &[&wrapper]].concat());
+            return run_in_background(command, opts.timeout, &log, false);
+        }
+
+        // Killing ssh doesn't stop the remote command, so it gets its own deadline
+        // as in docker exec
+        let secs = opts.timeout.as_secs().max(1).to_string();
+        let grace = KILL_GRACE_PERIOD.as_secs().to_string();
+        let remote = [&["timeout", "-k", &grace, &secs], &shell[..], &[cmd]].concat();
+        hint_interactive(
+            cmd,
+            opts.stdin,
+            run_with_timeout(
+                self.command(&remote),
+                opts.timeout + KILL_GRACE_PERIOD * 2,
+                opts.stdin,
+                self.stream_output,
+            ),
+        )
+    }
+}
+
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+// Fence tag -> command line the script is passed to as its last argument
+pub const DEFAULT_SCRIPT_INTERPRETERS: &[(&str, &str)] = &[
+    ("python", "python3 -c"),
+    ("python3", "python3 -c"),
+    ("py", "python3 -c"),
+];
+
+// The script goes in as one quoted argument, so it runs like any other command:
+// same cwd, timeout and sandbox, with the interpreter's traceback on stderr
+pub fn script_command(interpreter: &str, script: &str) -> String {
+    format!("{} {}", interpreter, shell_quote(script))
+}
+
+// Splits off the first simple command at a top-level `&&`, `;` or newline.
+// Returns None when the first command is piped, since a piped `cd` has no effect.
+fn split_leading_command(cmd: &str) -> Option<(&str, &str)> {
+    let bytes = cmd.as_bytes();
+    let mut quote: Option<u8> = None;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let b = bytes[i];
+        match quote {
+            Some(q) => {
+                if b == q {
+                    quote = None;
+                } else if b == b'\\' && q == b'"' {
+                    i += 1;
+                }
+            }
+            None => match b {
+                b'\'' | b'"' => quote = Some(b),
+                b'\\' => i += 1,
+                b'&' if bytes.get(i + 1) == Some(&b'&') => {
+                    return Some((&cmd[..i], &cmd[i + 2..]));
+                }
+                b';' | b'\n' => return Some((&cmd[..i], &cmd[i + 1..])),
+                b'|' | b'&' => return None,
+                _ => {}
+            },
+        }
+        i += 1;
+    }
+    Some((cmd, ""))
+}
+
+// Tracks the working directory across commands, since every command runs in a
+// fresh `sh`. Leading cd/pushd/popd are handled here and later commands are
+// prefixed with a cd into the tracked directory, so this works for any runner.
+#[derive(Debug, Default)]
+pub struct Shell {
+    cwd: Option<String>,
+    previous: Option<String>,
+    stack: Vec<String>,
+}
+
+impl Shell {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[cfg(test)]
+    pub fn cwd(&self) -> Option<&str> {
+        self.cwd.as_deref()
+    }
+
+    // `stdin` goes to whatever is left once leading cd/pushd/popd are handled
+    pub fn run(
+        &mut self,
+        runner: &dyn Executor,
+        cmd: &str,
+        stdin: Option<&str>,
+        timeout: Duration,
+    ) -> Result<CommandOutput, CrabError> {
+        self.run_with_env(runner, cmd, stdin, &[], timeout)
+    }
+
+    // Like `run`, with `env` exported for this command alone; the caller has
+    // already checked it against env_override_violation
+    pub fn run_with_env(
+        &mut self,
+        runner: &dyn Executor,
+        cmd: &str,
+        stdin: Option<&str>,
+        env: &[(String, String)],
+        timeout: Duration,
+    ) -> Result<CommandOutput, CrabError> {
+        let mut remaining = cmd.trim();
+        let mut stdout = String::new();
+
+        while let Some((head, rest)) = split_leading_command(remaining) {
+            let words: Vec<&str> = head.split_whitespace().collect();
+            let result = match words.first().copied() {
+                Some("cd") => self.change_dir(runner, &words[1..], timeout),
+                Some("pushd") => self.push_dir(runner, &words[1..], timeout),
+                Some("popd") => self.pop_dir(runner, timeout),
+                _ => break,
+            };
+
+            match result {
+                Ok(printed) => stdout.push_str(&printed),
+                // A failed cd reads like the shell's own error, not a crash
+                Err(CrabError::Exec(message)) => {
+                    return Ok(CommandOutput {
+                        stdout,
+                        stderr: format!("{}\n", message),
+                        exit_code: 1,
+                    })
+                }
+                Err(e) => return Err(e),
+            }
+            remaining = rest.trim();
+        }
+
+        if remaining.is_empty() {
+            return Ok(CommandOutput {
+                stdout,
+                stderr: String::new(),
+                exit_code: 0,
+            });
+        }
+
+        let exports: String = env
+            .iter()
+            .map(|(name, value)| format!("export {}={}\n", name, shell_quote(value)))
+            .collect();
+        let remaining = format!("{}{}", exports, remaining);
+        let mut output = runner.execute(&self.in_cwd(&remaining), &ExecOpts { stdin, timeout })?;
+        output.stdout.insert_str(0, &stdout);
+        Ok(output)
+    }
+
+    // On a line of its own, so a cd that fails stops every line after it, not
+    // just the first
+    pub fn in_cwd(&self, cmd: &str) -> String {
+        match &self.cwd {
+            Some(dir) => format!("cd {} || exit 1\n{}", shell_quote(dir), cmd),
+            None => cmd.to_string(),
+        }
+    }
+
+    // Lets the real shell resolve the target (relative paths, ~, symlinks) and
+    // confirm it exists, then remembers the absolute result
+    fn resolve(
+        &self,
+        runner: &dyn Executor,
+        target: &str,
+        timeout: Duration,
+    ) -> Result<String, CrabError> {
+        let probe = self.in_cwd(&format!("cd {} && pwd", target));
+        let output = runner.run(&probe, timeout)?;
+        let resolved = output.stdout.trim();
+        if output.exit_code != 0 || resolved.is_empty() {
+            return Err(CrabError::Exec(format!(
+                "cd: {}: No such directory",
+                target
+            )));
+        }
+        Ok(resolved.to_string())
+    }
+
+    fn current(&mut self, runner: &dyn Executor, timeout: Duration) -> Result<String, CrabError> {
+        match &self.cwd {
+            Some(dir) => Ok(dir.clone()),
+            None => self.resolve(runner, ".", timeout),
+        }
+    }
+
+    fn enter(
+        &mut self,
+        runner: &dyn Executor,
+        dir: String,
+        timeout: Duration,
+    ) -> Result<(), CrabError> {
+        let from = self.current(runner, timeout)?;
+        self.previous = Some(from);
+        self.cwd = Some(dir);
+        Ok(())
+    }
+
+    fn change_dir(
+        &mut self,
+        runner: &dyn Executor,
+        args: &[&str],
+        timeout: Duration,
+    ) -> Result<String, CrabError> {
+        if args == ["-"] {
+            let previous = self
+                .previous
+                .clone()
+                .ok_or_else(|| CrabError::Exec("cd: OLDPWD not set".to_string()))?;
+            self.enter(runner, previous.clone(), timeout)?;
+            return Ok(format!("{}\n", previous));
+        }
+
+        let target = if args.is_empty() { "~" } else { args[0] };
+        let dir = self.resolve(runner, target, timeout)?;
+        self.enter(runner, dir, timeout)?;
+        Ok(String::new())
+    }
+
+    fn push_dir(
+        &mut self,
+        runner: &dyn Executor,
+        args: &[&str],
+        timeout: Duration,
+    ) -> Result<String, CrabError> {
+        let target = args
+            .first()
+            .ok_or_else(|| CrabError::Exec("pushd: no other directory".to_string()))?;
+        let dir = self.resolve(runner, target, timeout)?;
+        let from = self.current(runner, timeout)?;
+        self.stack.push(from);
+        self.enter(runner, dir, timeout)?;
+        Ok(self.describe_stack())
+    }
+
+    fn pop_dir(&mut self, runner: &dyn Executor, timeout: Duration) -> Result<String, CrabError> {
+        let dir = self
+            .stack
+            .pop()
+            .ok_or_else(|| CrabError::Exec("popd: directory stack empty".to_string()))?;
+        self.enter(runner, dir, timeout)?;
+        Ok(self.describe_stack())
+    }
+
+    fn describe_stack(&self) -> String {
+        let mut dirs: Vec<&str> = self.cwd.iter().map(|d| d.as_str()).collect();
+        dirs.extend(self.stack.iter().rev().map(|d| d.as_str()));
+        format!("{}\n", dirs.join(" "))
+    }
+}
+
+#[allow(dead_code)]
 pub fn can_execute_command(cmd: &str) -> bool {
     let allowed = [
         "curl", "jq", "cat", "ls", "echo", "grep", "awk", "sed", "python3", "node",
@@ -84,15 +1876,482 @@ pub fn is_dangerous_command(cmd: &str) -> bool {
         "spawn_agent",
     ];
 
-    let parts: Vec<&str> = cmd.split_whitespace().collect();
-    if parts.is_empty() {
-        return false;
+    let parts: Vec<&str> = cmd.split_whitespace().collect();
+    if parts.is_empty() {
+        return false;
+    }
+
+    let base_cmd = parts[0];
+    dangerous
+        .iter()
+        .any(|&d| base_cmd == d || base_cmd.starts_with(d))
+}
+
+// Looked up for the system prompt's ENVIRONMENT section
+pub const DEFAULT_PROBE_TOOLS: &[&str] = &["git", "curl", "wget", "python3", "node", "jq", "make"];
+
+// Searches PATH the way `which` does, without starting a process. Answers are
+// kept for the life of the process, since every prompt asks about the same tools.
+pub fn find_on_path(tool: &str) -> Option<PathBuf> {
+    static FOUND: OnceLock<Mutex<HashMap<String, Option<PathBuf>>>> = OnceLock::new();
+    let mut found = FOUND
+        .get_or_init(Mutex::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    found
+        .entry(tool.to_string())
+        .or_insert_with(|| {
+            let path = std::env::var_os("PATH")?;
+            std::env::split_paths(&path)
+                .map(|dir| dir.join(tool))
+                .find(|candidate| {
+                    candidate
+                        .metadata()
+                        .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+                })
+        })
+        .clone()
+}
+
+pub const DEFAULT_READONLY_DENYLIST: &[&str] = &[
+    "rm",
+    "rmdir",
+    "mv",
+    "cp",
+    "dd",
+    "mkfs",
+    "shred",
+    "truncate",
+    "touch",
+    "mkdir",
+    "ln",
+    "tee",
+    "chmod",
+    "chown",
+    "chgrp",
+    "mount",
+    "umount",
+    "kill",
+    "killall",
+    "pkill",
+    "shutdown",
+    "reboot",
+    "halt",
+    "poweroff",
+    "useradd",
+    "userdel",
+    "usermod",
+    "passwd",
+    "crontab",
+    "sed -i",
+    "apt install",
+    "apt remove",
+    "apt-get install",
+    "apt-get remove",
+    "yum install",
+    "dnf install",
+    "apk add",
+    "pip install",
+    "pip3 install",
+    "npm install",
+    "cargo install",
+    ">",
+    ">>",
+];
+
+#[derive(Debug, PartialEq)]
+enum ShellToken {
+    Word(String),
+    Op(String),
+}
+
+// A rough shell lexer: enough to find command words and redirections without
+// being fooled by quoted text or by substrings of file names
+fn tokenize_shell(cmd: &str) -> Vec<ShellToken> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut chars = cmd.chars().peekable();
+
+    fn flush(tokens: &mut Vec<ShellToken>, word: &mut String, in_word: &mut bool) {
+        if *in_word {
+            tokens.push(ShellToken::Word(std::mem::take(word)));
+            *in_word = false;
+        }
+    }
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                in_word = true;
+                for q in chars.by_ref() {
+                    if q == '\'' {
+                        break;
+                    }
+                    word.push(q);
+                }
+            }
+            '"' => {
+                in_word = true;
+                while let Some(q) = chars.next() {
+                    match q {
+                        '"' => break,
+                        '\\' => word.extend(chars.next()),
+                        _ => word.push(q),
+                    }
+                }
+            }
+            '\\' => {
+                in_word = true;
+                word.extend(chars.next());
+            }
+            '>' => {
+                // "2>": the digits are a file descriptor, not an argument
+                if in_word && word.chars().all(|d| d.is_ascii_digit()) {
+                    word.clear();
+                    in_word = false;
+                }
+                flush(&mut tokens, &mut word, &mut in_word);
+                let op = match chars.peek() {
+                    Some('>') => {
+                        chars.next();
+                        ">>"
+                    }
+                    Some('&') => {
+                        chars.next();
+                        ">&"
+                    }
+                    _ => ">",
+                };
+                tokens.push(ShellToken::Op(op.to_string()));
+            }
+            ';' | '|' | '&' | '<' | '(' | ')' | '\n' => {
+                flush(&mut tokens, &mut word, &mut in_word);
+                let mut op = c.to_string();
+                if (c == '|' || c == '&') && chars.peek() == Some(&c) {
+                    chars.next();
+                    op.push(c);
+                }
+                tokens.push(ShellToken::Op(op));
+            }
+            c if c.is_whitespace() => flush(&mut tokens, &mut word, &mut in_word),
+            _ => {
+                in_word = true;
+                word.push(c);
+            }
+        }
+    }
+    flush(&mut tokens, &mut word, &mut in_word);
+    tokens
+}
+
+// The model opts a command in by starting it with this comment line. It's only
+// honoured for commands that can't disturb each other: no directory changes and
+// nothing the default readonly denylist would refuse, redirects included.
+pub const PARALLEL_MARKER: &str = "# parallel";
+
+pub fn is_parallel_safe(cmd: &str) -> bool {
+    let Some(body) = cmd.trim_start().strip_prefix(PARALLEL_MARKER) else {
+        return false;
+    };
+    let denylist: Vec<String> = DEFAULT_READONLY_DENYLIST
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    let changes_dir = body
+        .split(|c: char| c.is_whitespace() || matches!(c, ';' | '&' | '|' | '(' | ')'))
+        .any(|word| matches!(word, "cd" | "pushd" | "popd"));
+    !changes_dir && readonly_violation(body, &denylist).is_none()
+}
+
+// Prefixes that run the real command as their argument
+const COMMAND_WRAPPERS: &[&str] = &["sudo", "env", "nohup", "time", "xargs", "exec", "command"];
+
+// Returns the denylist entry a command trips, if any. Entries are matched against
+// each simple command's leading words (so "sed -i" or "apt install" work), and
+// ">"/">>" against redirections other than to /dev/null.
+pub fn readonly_violation(cmd: &str, denylist: &[String]) -> Option<String> {
+    let tokens = tokenize_shell(cmd);
+    let mut segment: Vec<&str> = Vec::new();
+
+    let check_segment = |segment: &[&str]| -> Option<String> {
+        let mut words = segment
+            .iter()
+            .skip_while(|w| w.contains('=') && !w.starts_with('='))
+            .skip_while(|w| COMMAND_WRAPPERS.contains(w))
+            .map(|w| w.rsplit('/').next().unwrap_or(w));
+        let first = words.next()?;
+        let rest: Vec<&str> = words.collect();
+
+        denylist
+            .iter()
+            .find(|entry| {
+                let mut parts = entry.split_whitespace();
+                let Some(head) = parts.next() else {
+                    return false;
+                };
+                let head_matches = first == head || first.starts_with(&format!("{}.", head));
+                head_matches && parts.enumerate().all(|(i, p)| rest.get(i) == Some(&p))
+            })
+            .cloned()
+    };
+
+    for (i, token) in tokens.iter().enumerate() {
+        match token {
+            ShellToken::Word(w) => segment.push(w),
+            ShellToken::Op(op) if op == ">" || op == ">>" => {
+                let target = match tokens.get(i + 1) {
+                    Some(ShellToken::Word(w)) => w.as_str(),
+                    _ => "",
+                };
+                if target != "/dev/null" && denylist.iter().any(|e| e == op) {
+                    return Some(op.clone());
+                }
+            }
+            ShellToken::Op(op) if op == ">&" || op == "<" => {}
+            ShellToken::Op(_) => {
+                if let Some(entry) = check_segment(&segment) {
+                    return Some(entry);
+                }
+                segment.clear();
+            }
+        }
+    }
+    check_segment(&segment)
+}
+
+// The inverse of readonly_violation: every stage of a pipeline or list has to
+// start with an allowed command, wrappers like sudo or xargs included. Returns
+// what was refused. Substitutions could hide any command, so they're refused
+// outright.
+pub fn allowlist_violation(cmd: &str, allowlist: &[String]) -> Option<String> {
+    if cmd.contains("$(") || cmd.contains('`') || cmd.contains("<(") || cmd.contains(">(") {
+        return Some("command substitution".to_string());
+    }
+    let allowed = |word: &str| {
+        let name = word.rsplit('/').next().unwrap_or(word);
+        allowlist.iter().any(|entry| entry == name)
+    };
+    let check_segment = |segment: &[&str]| -> Option<String> {
+        let mut words = segment
+            .iter()
+            .skip_while(|w| w.contains('=') && !w.starts_with('='));
+        for word in words.by_ref() {
+            if !allowed(word) {
+                return Some(word.to_string());
+            }
+            if !COMMAND_WRAPPERS.contains(word) {
+                break;
+            }
+        }
+        None
+    };
+
+    let tokens = tokenize_shell(cmd);
+    let mut segment: Vec<&str> = Vec::new();
+    for token in &tokens {
+        match token {
+            ShellToken::Word(w) => segment.push(w),
+            ShellToken::Op(op) if matches!(op.as_str(), ">" | ">>" | ">&" | "<") => {}
+            ShellToken::Op(_) => {
+                if let Some(refused) = check_segment(&segment) {
+                    return Some(refused);
+                }
+                segment.clear();
+            }
+        }
+    }
+    check_segment(&segment)
+}
+
+// A "command" longer than this is refused unseen: no real one-liner needs it,
+// and a runaway or injected reply shouldn't get a multi-megabyte script executed
+pub const DEFAULT_MAX_COMMAND_LEN: usize = 16 * 1024;
+
+const PRIVILEGE_ESCALATION: &[&str] = &["sudo", "doas", "su"];
+
+// Variables a `# env:` line may not set, since each makes programs load or run
+// other code; anything starting LD_ or DYLD_ is refused as well
+const ENV_OVERRIDE_DENYLIST: &[&str] = &[
+    "PATH",
+    "IFS",
+    "ENV",
+    "BASH_ENV",
+    "PS4",
+    "PROMPT_COMMAND",
+    "SHELLOPTS",
+    "PYTHONSTARTUP",
+    "PYTHONPATH",
+    "PERL5OPT",
+    "PERL5LIB",
+    "RUBYOPT",
+    "NODE_OPTIONS",
+    "GIT_SSH_COMMAND",
+];
+
+// Parts of a name that mark a credential
+const CREDENTIAL_MARKERS: &[&str] = &["KEY", "TOKEN", "SECRET", "PASSWORD", "PASSWD"];
+
+// Why a requested override is refused, or None when it may be set. Besides the
+// denylist, host variables are off limits: the FORWARD_ENV ones commands get
+// anyway and credentials set in crab's own environment.
+pub fn env_override_violation(name: &str, forward_env: &[String]) -> Option<String> {
+    let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Some(format!("'{}' is not a variable name", name));
+    }
+    let upper = name.to_ascii_uppercase();
+    if ENV_OVERRIDE_DENYLIST.contains(&upper.as_str())
+        || upper.starts_with("LD_")
+        || upper.starts_with("DYLD_")
+    {
+        return Some(format!("'{}' can make programs run other code", name));
+    }
+    if forward_env.iter().any(|f| f == name) {
+        return Some(format!(
+            "'{}' comes from the host through FORWARD_ENV",
+            name
+        ));
+    }
+    if CREDENTIAL_MARKERS.iter().any(|m| upper.contains(m)) && std::env::var_os(name).is_some() {
+        return Some(format!("'{}' holds a host credential", name));
+    }
+    None
+}
+
+// Returns the escalation command any stage of a pipeline or list starts with,
+// looking past env assignments and wrappers like nohup or env.
+pub fn privilege_escalation(cmd: &str) -> Option<String> {
+    let check_segment = |segment: &[&str]| -> Option<String> {
+        segment
+            .iter()
+            .skip_while(|w| w.contains('=') && !w.starts_with('='))
+            .map(|w| w.rsplit('/').next().unwrap_or(w))
+            .find(|w| PRIVILEGE_ESCALATION.contains(w) || !COMMAND_WRAPPERS.contains(w))
+            .filter(|w| PRIVILEGE_ESCALATION.contains(w))
+            .map(str::to_string)
+    };
+
+    let tokens = tokenize_shell(cmd);
+    let mut segment: Vec<&str> = Vec::new();
+    for token in &tokens {
+        match token {
+            ShellToken::Word(w) => segment.push(w),
+            ShellToken::Op(op) if matches!(op.as_str(), ">" | ">>" | ">&" | "<") => {}
+            ShellToken::Op(_) => {
+                if let Some(found) = check_segment(&segment) {
+                    return Some(found);
+                }
+                segment.clear();
+            }
+        }
     }
+    check_segment(&segment)
+}
 
-    let base_cmd = parts[0];
-    dangerous
+pub const INTERACTIVE_HINT: &str = "[hint] command appears to require interactive input, but it has no terminal and stdin is /dev/null; add non-interactive flags (-y, --yes, DEBIAN_FRONTEND=noninteractive, ssh -o BatchMode=yes) or pipe the answer in";
+
+// Tools that ask on the terminal itself, so an empty stdin doesn't stop them waiting
+const TERMINAL_PROMPTERS: &[&str] = &[
+    "ssh", "scp", "sftp", "sudo", "su", "passwd", "ftp", "telnet", "vi", "vim", "nano", "less",
+    "more", "top", "htop", "man",
+];
+
+// "Do you want to continue? [Y/n]", "Password:" and the like near the end
+fn ends_with_prompt(output: &str) -> bool {
+    static PROMPT: OnceLock<regex::Regex> = OnceLock::new();
+    let prompt = PROMPT.get_or_init(|| {
+        regex::Regex::new(
+            r"(?i)\[y/n\]|\(y/n\)|\[yes/no\]|\(yes/no[^)]*\)|pass(word|phrase)[^:\n]*:\s*$|press (any key|enter|return)|continue\?|are you sure",
+        )
+        .unwrap()
+    });
+    output
+        .lines()
+        .rev()
+        .filter(|line| !line.trim().is_empty())
+        .take(3)
+        .any(|line| prompt.is_match(line))
+}
+
+// Whether a run that failed or timed out was most likely stuck on a question:
+// its output ends in a prompt, a `read` had nothing to read, or a tool that asks
+// on the terminal ran out the clock
+pub fn waits_for_input(cmd: &str, output: &str, timed_out: bool) -> bool {
+    if ends_with_prompt(output) {
+        return true;
+    }
+    let tokens = tokenize_shell(cmd);
+    // A `while read ...; done < file` gets its input from further along
+    let redirected = tokens
         .iter()
-        .any(|&d| base_cmd == d || base_cmd.starts_with(d))
+        .any(|t| matches!(t, ShellToken::Op(op) if op == "<"));
+    let check_segment = |segment: &[&str], piped: bool| {
+        let first = segment
+            .iter()
+            .skip_while(|w| w.contains('=') && !w.starts_with('='))
+            .map(|w| w.rsplit('/').next().unwrap_or(w))
+            .next();
+        match first {
+            Some("read") => !piped && !redirected,
+            Some(tool) => timed_out && TERMINAL_PROMPTERS.contains(&tool),
+            None => false,
+        }
+    };
+
+    let mut segment: Vec<&str> = Vec::new();
+    let mut piped = false;
+    for token in &tokens {
+        match token {
+            ShellToken::Word(w) => segment.push(w),
+            ShellToken::Op(op) if op.starts_with('>') || op == "<" => {}
+            ShellToken::Op(op) => {
+                if check_segment(&segment, piped) {
+                    return true;
+                }
+                piped = op == "|";
+                segment.clear();
+            }
+        }
+    }
+    check_segment(&segment, piped)
+}
+
+// Commands get no terminal and, unless the JSON reply gave them `stdin`,
+// /dev/null to read, so a question fails on EOF or hangs until the timeout;
+// either way the result says so, to steer the model to -y and friends
+fn hint_interactive(
+    cmd: &str,
+    stdin: Option<&str>,
+    result: Result<CommandOutput, CrabError>,
+) -> Result<CommandOutput, CrabError> {
+    if stdin.is_some() {
+        return result;
+    }
+    match result {
+        Ok(mut output)
+            if output.exit_code != 0
+                && waits_for_input(cmd, &format!("{}{}", output.stdout, output.stderr), false) =>
+        {
+            if !output.stderr.is_empty() && !output.stderr.ends_with('\n') {
+                output.stderr.push('\n');
+            }
+            output.stderr.push_str(INTERACTIVE_HINT);
+            output.stderr.push('\n');
+            Ok(output)
+        }
+        Err(CrabError::CommandTimeout { after, partial, .. })
+            if waits_for_input(cmd, &partial, true) =>
+        {
+            Err(CrabError::CommandTimeout {
+                after,
+                partial,
+                hint: Some(INTERACTIVE_HINT),
+            })
+        }
+        result => result,
+    }
 }
 
 pub fn build_meeting_prompt() -> String {
@@ -113,6 +2372,160 @@ Use this directory for all file operations to ensure data survives between sessi
     .to_string()
 }
 
+// Files are read and written by crab itself, not through the shell, so the root
+// and byte cap hold whatever the model asks for
+pub const DEFAULT_READ_FILE_MAX_BYTES: usize = 64 * 1024;
+
+// Resolves `path` against `root` without touching the filesystem, refusing
+// anything that `..` or an absolute path would take outside it. Returns the
+// canonical root alongside the joined path.
+fn confine(root: &Path, path: &str, op: &str) -> Result<(PathBuf, PathBuf), CrabError> {
+    let root = root
+        .canonicalize()
+        .map_err(|e| CrabError::Exec(format!("{}: {}: {}", op, root.display(), e)))?;
+    let mut lexical = PathBuf::new();
+    for component in root.join(path).components() {
+        match component {
+            Component::ParentDir => {
+                lexical.pop();
+            }
+            Component::CurDir => {}
+            other => lexical.push(other),
+        }
+    }
+    if !lexical.starts_with(&root) {
+        return Err(outside_root(op, path, &root));
+    }
+    Ok((root, lexical))
+}
+
+fn outside_root(op: &str, path: &str, root: &Path) -> CrabError {
+    CrabError::Policy(format!("{}: {} is outside {}", op, path, root.display()))
+}
+
+// Returns the file with `cat -n` style line numbers, cut off after `max_bytes`.
+// Symlinks are resolved before the root check, so they don't get out either.
+pub fn read_file(root: &Path, path: &str, max_bytes: usize) -> Result<String, CrabError> {
+    let (root, lexical) = confine(root, path, "read_file")?;
+    let resolved = lexical
+        .canonicalize()
+        .map_err(|e| CrabError::Exec(format!("read_file: {}: {}", path, e)))?;
+    if !resolved.starts_with(&root) {
+        return Err(outside_root("read_file", path, &root));
+    }
+
+    let mut bytes = Vec::new();
+    std::fs::File::open(&resolved)
+        .and_then(|file| file.take(max_bytes as u64 + 1).read_to_end(&mut bytes))
+        .map_err(|e| CrabError::Exec(format!("read_file: {}: {}", path, e)))?;
+    let truncated = bytes.len() > max_bytes;
+    bytes.truncate(max_bytes);
+
+    let mut numbered: String = String::from_utf8_lossy(&bytes)
+        .lines()
+        .enumerate()
+        .map(|(i, line)| format!("{:>6}\t{}\n", i + 1, line))
+        .collect();
+    if truncated {
+        numbered.push_str(&format!("[truncated after {} bytes]\n", max_bytes));
+    }
+    Ok(numbered)
+}
+
+// Where a write to `path` would land: the lexical path, with its nearest existing
+// ancestor checked for symlinks that lead out of the root
+fn write_target(root: &Path, path: &str) -> Result<PathBuf, CrabError> {
+    let (root, lexical) = confine(root, path, "write_file")?;
+    if lexical == root || path.ends_with('/') {
+        return Err(CrabError::Exec(format!("write_file: {}: not a file", path)));
+    }
+    let existing = lexical
+        .ancestors()
+        .find(|p| p.exists())
+        .and_then(|p| p.canonicalize().ok())
+        .unwrap_or_else(|| root.clone());
+    if !existing.starts_with(&root) {
+        return Err(outside_root("write_file", path, &root));
+    }
+    Ok(lexical)
+}
+
+// A unified diff of what write_file would change; a new file diffs against nothing
+pub fn write_file_diff(root: &Path, path: &str, content: &str) -> Result<String, CrabError> {
+    let target = write_target(root, path)?;
+    let current = match std::fs::read_to_string(&target) {
+        Ok(current) => current,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(CrabError::Exec(format!("write_file: {}: {}", path, e))),
+    };
+    let old_name = if target.exists() {
+        format!("a/{}", path)
+    } else {
+        "/dev/null".to_string()
+    };
+    Ok(similar::TextDiff::from_lines(current.as_str(), content)
+        .unified_diff()
+        .header(&old_name, &format!("b/{}", path))
+        .to_string())
+}
+
+// Writes to a temporary file next to the target and renames it into place, so a
+// reader never sees half a file. Missing parent directories are created.
+pub fn write_file(root: &Path, path: &str, content: &str) -> Result<usize, CrabError> {
+    let target = write_target(root, path)?;
+    let failed = |e: std::io::Error| CrabError::Exec(format!("write_file: {}: {}", path, e));
+    let dir = target.parent().unwrap_or(root);
+    std::fs::create_dir_all(dir).map_err(failed)?;
+
+    let name = target
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let tmp = dir.join(format!(".{}.crab-tmp-{}", name, std::process::id()));
+    let written = std::fs::write(&tmp, content).and_then(|_| std::fs::rename(&tmp, &target));
+    if let Err(e) = written {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(failed(e));
+    }
+    Ok(content.len())
+}
+
+// `READ_FILE: <path>` on its own line, optionally followed by `MAX_BYTES: <n>`
+pub fn extract_read_file(response: &str) -> Option<(String, Option<usize>)> {
+    let mut path = None;
+    let mut max_bytes = None;
+    for line in response.lines() {
+        let line = line.trim();
+        if let Some(p) = line.strip_prefix("READ_FILE:") {
+            path = Some(p.trim().to_string());
+        } else if let Some(n) = line.strip_prefix("MAX_BYTES:") {
+            max_bytes = n.trim().parse().ok();
+        }
+    }
+    path.filter(|p| !p.is_empty()).map(|p| (p, max_bytes))
+}
+
+// `WRITE_FILE: <path>` followed by a fenced block holding the whole new content.
+// The fence's body is taken as written, trailing newline included.
+pub fn extract_write_file(response: &str) -> Option<(String, String)> {
+    let mut lines = response.split_inclusive('\n');
+    let path = lines
+        .by_ref()
+        .find_map(|line| line.trim().strip_prefix("WRITE_FILE:"))?
+        .trim()
+        .to_string();
+    lines.by_ref().find(|line| line.trim().starts_with("```"))?;
+
+    let mut content = String::new();
+    for line in lines {
+        if line.trim() == "```" {
+            return (!path.is_empty()).then_some((path, content));
+        }
+        content.push_str(line);
+    }
+    None
+}
+
 pub fn extract_delegate_action(response: &str) -> Option<(String, String)> {
     if !response.contains("ACTION: DELEGATE") {
         return None;
@@ -141,3 +2554,1191 @@ pub fn extract_delegate_action(response: &str) -> Option<(String, String)> {
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn read_file_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("crab-read-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::write(
+            root.join("src/main.rs"),
+            "fn main() {\n    println!(\"hi\");\n}\n",
+        )
+        .unwrap();
+        root
+    }
+
+    #[test]
+    fn read_file_numbers_lines() {
+        let root = read_file_root("plain");
+
+        let content = read_file(&root, "src/main.rs", 1024).unwrap();
+        let _ = std::fs::remove_dir_all(&root);
+
+        assert_eq!(
+            content,
+            "     1\tfn main() {\n     2\t    println!(\"hi\");\n     3\t}\n"
+        );
+        assert_eq!(
+            extract_read_file("READ_FILE: src/main.rs\nMAX_BYTES: 12"),
+            Some(("src/main.rs".to_string(), Some(12)))
+        );
+    }
+
+    #[test]
+    fn read_file_truncates_at_the_byte_cap() {
+        let root = read_file_root("cap");
+
+        let content = read_file(&root, "src/main.rs", 12).unwrap();
+        let _ = std::fs::remove_dir_all(&root);
+
+        assert_eq!(content, "     1\tfn main() {\n[truncated after 12 bytes]\n");
+    }
+
+    #[test]
+    fn write_file_creates_a_new_file() {
+        let root = read_file_root("create");
+
+        let diff = write_file_diff(&root, "notes/todo.txt", "one\ntwo\n").unwrap();
+        let written = write_file(&root, "notes/todo.txt", "one\ntwo\n").unwrap();
+        let content = std::fs::read_to_string(root.join("notes/todo.txt")).unwrap();
+        let leftovers = std::fs::read_dir(root.join("notes")).unwrap().count();
+        let _ = std::fs::remove_dir_all(&root);
+
+        assert!(diff.starts_with("--- /dev/null\n+++ b/notes/todo.txt\n"));
+        assert!(diff.ends_with("+one\n+two\n"));
+        assert_eq!(written, 8);
+        assert_eq!(content, "one\ntwo\n");
+        assert_eq!(leftovers, 1);
+    }
+
+    #[test]
+    fn write_file_overwrites_and_diffs_against_the_old_content() {
+        let root = read_file_root("overwrite");
+        let new = "fn main() {\n    println!(\"bye\");\n}\n";
+
+        let diff = write_file_diff(&root, "src/main.rs", new).unwrap();
+        write_file(&root, "src/main.rs", new).unwrap();
+        let content = std::fs::read_to_string(root.join("src/main.rs")).unwrap();
+        let _ = std::fs::remove_dir_all(&root);
+
+        assert!(diff.starts_with("--- a/src/main.rs\n+++ b/src/main.rs\n@@ -1,3 +1,3 @@\n"));
+        assert!(diff.contains("\n-    println!(\"hi\");\n+    println!(\"bye\");\n"));
+        assert_eq!(content, new);
+        assert_eq!(
+            extract_write_file(
+                "Updating it.\nWRITE_FILE: src/main.rs\n```rust\nfn main() {}\n```\n"
+            ),
+            Some(("src/main.rs".to_string(), "fn main() {}\n".to_string()))
+        );
+    }
+
+    #[test]
+    fn write_file_refuses_paths_outside_the_root() {
+        let root = read_file_root("write-escape");
+
+        let traversal = write_file(&root.join("src"), "../../crab-escaped.txt", "x");
+        let absolute = write_file_diff(&root, "/etc/crab.conf", "x");
+        let _ = std::fs::remove_dir_all(&root);
+
+        assert!(matches!(traversal, Err(CrabError::Policy(_))));
+        assert!(matches!(absolute, Err(CrabError::Policy(_))));
+        assert!(!std::env::temp_dir().join("crab-escaped.txt").exists());
+    }
+
+    #[test]
+    fn read_file_refuses_paths_outside_the_root() {
+        let root = read_file_root("escape");
+
+        let traversal = read_file(&root.join("src"), "../../etc/passwd", 1024);
+        let absolute = read_file(&root, "/etc/passwd", 1024);
+        let _ = std::fs::remove_dir_all(&root);
+
+        assert!(matches!(traversal, Err(CrabError::Policy(_))));
+        assert!(matches!(absolute, Err(CrabError::Policy(_))));
+    }
+
+    #[test]
+    fn stdin_is_piped_to_the_command() {
+        let output = HostRunner::default()
+            .execute(
+                "sort",
+                &ExecOpts {
+                    stdin: Some("hello\nworld\nabc\n"),
+                    timeout: TIMEOUT,
+                },
+            )
+            .unwrap();
+        assert_eq!(output.stdout, "abc\nhello\nworld\n");
+
+        let output = HostRunner::default()
+            .execute(
+                "sort",
+                &ExecOpts {
+                    stdin: Some("hello\nworld"),
+                    timeout: TIMEOUT,
+                },
+            )
+            .unwrap();
+        assert_eq!(output.stdout, "hello\nworld\n");
+    }
+
+    #[test]
+    fn stdin_left_unread_does_not_break_the_run() {
+        // Several pipe buffers' worth, so the writer hits EPIPE once `true` exits
+        let input = "x".repeat(1 << 20);
+        let output = HostRunner::default()
+            .execute(
+                "true",
+                &ExecOpts {
+                    stdin: Some(&input),
+                    timeout: TIMEOUT,
+                },
+            )
+            .unwrap();
+        assert_eq!(output.exit_code, 0);
+    }
+
+    #[test]
+    fn host_commands_only_see_forwarded_env() {
+        // cargo sets CARGO_PKG_NAME for the test process itself
+        let runner = HostRunner {
+            forward_env: vec!["PATH".to_string(), "CARGO_PKG_NAME".to_string()],
+            ..HostRunner::default()
+        };
+        let output = runner
+            .run(
+                "echo \"$CARGO_PKG_NAME:${CARGO_MANIFEST_DIR:-unset}\"",
+                TIMEOUT,
+            )
+            .unwrap();
+        assert_eq!(output.stdout.trim(), "hermit-crab:unset");
+
+        let output = HostRunner::default()
+            .run("echo \"${CARGO_PKG_NAME:-unset}\"", TIMEOUT)
+            .unwrap();
+        assert_eq!(output.stdout.trim(), "unset");
+
+        let forward = vec!["TERM".to_string(), "OPENAI_API_KEY".to_string()];
+        assert_eq!(docker_env_args(&forward), ["-e", "TERM"]);
+    }
+
+    #[test]
+    fn local_executor_gives_up_at_the_timeout() {
+        let err = HostRunner::default()
+            .execute(
+                "cat; sleep 5",
+                &ExecOpts {
+                    stdin: Some("started\n"),
+                    timeout: Duration::from_millis(300),
+                },
+            )
+            .unwrap_err();
+        match err {
+            CrabError::CommandTimeout { partial, .. } => assert_eq!(partial, "started\n"),
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn a_grandchild_holding_the_pipes_does_not_hold_up_the_command() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "sleep 30 & echo hi"]);
+        let started = Instant::now();
+        let output = run_with_timeout(command, Duration::from_secs(20), None, false).unwrap();
+
+        assert_eq!(output.stdout, "hi\n");
+        assert_eq!(output.exit_code, 0);
+        assert!(
+            started.elapsed() < Duration::from_secs(5),
+            "took {:?}",
+            started.elapsed()
+        );
+    }
+
+    #[test]
+    fn sandboxed_commands_have_no_network() {
+        if find_on_path("python3").is_none() {
+            return;
+        }
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let connect = format!(
+            "python3 -c \"import socket; socket.create_connection(('127.0.0.1', {}), 2)\"",
+            listener.local_addr().unwrap().port()
+        );
+        // Gets through without a sandbox
+        let output = HostRunner::default().run(&connect, TIMEOUT).unwrap();
+        assert_eq!(output.exit_code, 0, "{}", output.stderr);
+
+        // Only the sandboxes installed here are tried
+        for sandbox in [Sandbox::Firejail, Sandbox::Bwrap] {
+            let Ok(prefix) = sandbox.prefix(&std::env::temp_dir()) else {
+                continue;
+            };
+            let runner = HostRunner {
+                sandbox: prefix,
+                ..HostRunner::default()
+            };
+            let output = runner.run(&connect, TIMEOUT).unwrap();
+            assert_ne!(output.exit_code, 0, "{} let it connect", sandbox.binary());
+        }
+    }
+
+    #[test]
+    fn exec_as_user_runs_host_commands_as_that_uid() {
+        assert!(HostUser::lookup("no-such-crab-user").is_err());
+        assert!(HostUser::lookup("4000000000").is_err());
+        // A uid of our own passes the root check, and brings its primary group
+        let me = User::from_uid(Uid::effective()).unwrap().unwrap();
+        let numeric = HostUser::lookup(&me.uid.to_string()).unwrap();
+        assert_eq!(
+            (numeric.uid, numeric.gid),
+            (me.uid.as_raw(), me.gid.as_raw())
+        );
+        // Only root may switch users
+        if !Uid::effective().is_root() {
+            return;
+        }
+        let nobody = HostUser::lookup("nobody").unwrap();
+        assert_ne!(nobody.uid, 0);
+        let runner = HostRunner {
+            run_as: Some(nobody),
+            ..HostRunner::default()
+        };
+        let output = runner.run("id -u; id -g", TIMEOUT).unwrap();
+        assert_eq!(
+            output.stdout,
+            format!("{}\n{}\n", nobody.uid, nobody.gid),
+            "{}",
+            output.stderr
+        );
+    }
+
+    #[test]
+    fn ssh_runner_quotes_the_remote_command() {
+        let runner = SshRunner {
+            host: "build-box".to_string(),
+            user: "deploy".to_string(),
+            key: "/keys/id_ed25519".to_string(),
+            shell: default_shell(),
+            stream_output: false,
+        };
+        let command = runner.command(&["sh", "-c", "echo 'hi' | wc -c"]);
+        let args: Vec<_> = command.get_args().map(|a| a.to_string_lossy()).collect();
+
+        assert_eq!(command.get_program(), "ssh");
+        assert_eq!(
+            args,
+            [
+                "-o",
+                "BatchMode=yes",
+                "-i",
+                "/keys/id_ed25519",
+                "-l",
+                "deploy",
+                "--",
+                "build-box",
+                r#"'sh' '-c' 'echo '\''hi'\'' | wc -c'"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn commands_run_through_the_configured_shell() {
+        let runner = HostRunner {
+            shell: parse_shell_cmd("bash -O extglob -c").unwrap(),
+            ..HostRunner::default()
+        };
+        let output = runner
+            .run("shopt -q extglob && echo bash", TIMEOUT)
+            .unwrap();
+        assert_eq!(output.stdout, "bash\n");
+
+        assert!(parse_shell_cmd("  ").is_err());
+        let err = HostRunner {
+            shell: parse_shell_cmd("/no/such/shell -c").unwrap(),
+            ..HostRunner::default()
+        }
+        .run("true", TIMEOUT);
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn execute_command_runs_on_host_without_image() {
+        let output = execute_command(
+            "echo hello",
+            "",
+            TIMEOUT,
+            &[],
+            &DockerOptions::default(),
+            &default_shell(),
+            &[],
+            None,
+            None,
+            false,
+        )
+        .unwrap();
+        assert_eq!(output.stdout, "hello\n");
+        assert_eq!(output.exit_code, 0);
+    }
+
+    #[test]
+    fn python_scripts_run_with_their_output_and_traceback() {
+        let runner = HostRunner::default();
+        let script = "name = 'crab'\nprint(f\"hello {name}\")\n";
+        let output = runner
+            .run(&script_command("python3 -c", script), TIMEOUT)
+            .unwrap();
+        assert_eq!(output.exit_code, 0);
+        assert_eq!(output.stdout, "hello crab\n");
+
+        let failed = runner
+            .run(
+                &script_command("python3 -c", "import json\njson.loads('{')"),
+                TIMEOUT,
+            )
+            .unwrap();
+        assert_ne!(failed.exit_code, 0);
+        assert!(failed.stderr.contains("Traceback"), "{}", failed.stderr);
+        assert!(failed.stderr.contains("JSONDecodeError"));
+
+        let shell = runner.run("echo still a shell", TIMEOUT).unwrap();
+        assert_eq!(shell.stdout, "still a shell\n");
+    }
+
+    #[test]
+    fn allowlist_checks_every_stage_of_a_pipeline() {
+        let allowlist: Vec<String> = ["ls", "cat", "grep", "kubectl"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+
+        assert_eq!(allowlist_violation("ls -la /var/log", &allowlist), None);
+        assert_eq!(
+            allowlist_violation("cat app.log | grep ERROR > /dev/null", &allowlist),
+            None
+        );
+        assert_eq!(
+            allowlist_violation("rm -rf /tmp/scratch", &allowlist),
+            Some("rm".to_string())
+        );
+        assert_eq!(
+            allowlist_violation("cat app.log | sort | grep ERROR", &allowlist),
+            Some("sort".to_string())
+        );
+        assert_eq!(
+            allowlist_violation("ls && /usr/bin/curl example.com", &allowlist),
+            Some("/usr/bin/curl".to_string())
+        );
+        assert_eq!(
+            allowlist_violation("sudo ls", &allowlist),
+            Some("sudo".to_string())
+        );
+        assert_eq!(
+            allowlist_violation("cat $(which rm)", &allowlist),
+            Some("command substitution".to_string())
+        );
+    }
+
+    #[test]
+    fn only_marked_commands_without_shared_state_run_in_parallel() {
+        assert!(is_parallel_safe("# parallel\ntail -n 50 /var/log/syslog"));
+        assert!(is_parallel_safe("# parallel\nsleep 1 && echo done"));
+        assert!(!is_parallel_safe("tail -n 50 /var/log/syslog"));
+        assert!(!is_parallel_safe("# parallel\ncd /var/log && ls"));
+        assert!(!is_parallel_safe("# parallel\necho hi > notes.txt"));
+        assert!(!is_parallel_safe("# parallel\nrm -rf build"));
+    }
+
+    #[test]
+    fn server_style_commands_are_recognised() {
+        for cmd in [
+            "sleep 100 &",
+            "nohup ./worker",
+            "cd /app && nohup ./worker",
+            "cd app && npm run dev",
+            "python3 -m http.server 8080",
+            "./server & sleep 1; curl localhost",
+            "(sleep 100 &)",
+        ] {
+            assert!(is_background_command(cmd), "{}", cmd);
+        }
+        for cmd in [
+            "make && make test",
+            "echo a & echo b; wait",
+            "npm test",
+            "echo 'a & b'",
+            "make &> build.log",
+            "ls 2>&1",
+        ] {
+            assert!(!is_background_command(cmd), "{}", cmd);
+        }
+    }
+
+    #[test]
+    fn background_commands_return_a_pid_without_blocking() {
+        let started = Instant::now();
+        let output = HostRunner::default().run("sleep 100 &", TIMEOUT).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(output.exit_code, 0);
+
+        let pid: i32 = output
+            .stdout
+            .strip_prefix("Started in the background with PID ")
+            .and_then(|rest| rest.split(';').next())
+            .and_then(|pid| pid.parse().ok())
+            .unwrap();
+        let running = |pid: i32| {
+            let state = std::fs::read_to_string(format!("/proc/{}/stat", pid)).unwrap_or_default();
+            !state.is_empty() && !state.contains(") Z ")
+        };
+        assert!(running(pid));
+
+        reap_background_processes();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while running(pid) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert!(!running(pid));
+    }
+
+    #[test]
+    fn streamed_output_is_still_captured_whole() {
+        let output = execute_command(
+            "echo one; echo oops >&2; printf 'two\\nno newline'",
+            "",
+            TIMEOUT,
+            &[],
+            &DockerOptions::default(),
+            &default_shell(),
+            &[],
+            None,
+            None,
+            true,
+        )
+        .unwrap();
+        assert_eq!(
+            output,
+            CommandOutput {
+                stdout: "one\ntwo\nno newline".to_string(),
+                stderr: "oops\n".to_string(),
+                exit_code: 0,
+            }
+        );
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedSink(std::sync::Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedSink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn tee_sees_the_same_bytes_as_the_buffer() {
+        let sink = SharedSink::default();
+        let input: &[u8] = b"line one\n\xffnot utf-8\nno newline";
+
+        let captured = spawn_reader(Some(input), Some(Box::new(sink.clone())))
+            .join()
+            .unwrap();
+
+        assert_eq!(captured, input);
+        assert_eq!(*sink.0.lock().unwrap(), input);
+    }
+
+    #[test]
+    fn execute_command_separates_streams_and_exit_code() {
+        let output = execute_command(
+            "echo data; echo warning >&2; exit 3",
+            "",
+            TIMEOUT,
+            &[],
+            &DockerOptions::default(),
+            &default_shell(),
+            &[],
+            None,
+            None,
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            output,
+            CommandOutput {
+                stdout: "data\n".to_string(),
+                stderr: "warning\n".to_string(),
+                exit_code: 3,
+            }
+        );
+        assert_eq!(
+            format_command_output(DEFAULT_OUTPUT_TEMPLATE, "cmd", &output),
+            "COMMAND_OUTPUT:\nexit_code: 3\n--- stdout ---\ndata\n--- stderr ---\nwarning"
+        );
+    }
+
+    #[test]
+    fn silent_commands_get_an_explicit_no_output_notice() {
+        let output = HostRunner::default()
+            .execute(
+                "true",
+                &ExecOpts {
+                    stdin: None,
+                    timeout: TIMEOUT,
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            format_command_output(DEFAULT_OUTPUT_TEMPLATE, "true", &output),
+            "COMMAND_OUTPUT: (command produced no output, exit code 0)"
+        );
+        assert_eq!(
+            format_command_output("{exit_code}|{stdout}", "true", &output),
+            "0|"
+        );
+    }
+
+    #[test]
+    fn latin1_output_is_transcoded_to_utf8() {
+        let latin1 = output_encoding("latin1").unwrap();
+        assert_eq!(
+            decode_output_as(b"caf\xe9 au lait\n", latin1),
+            "café au lait\n"
+        );
+        assert_eq!(output_encoding(" ISO-8859-1 "), Some(latin1));
+        assert_eq!(output_encoding("utf8"), Some(encoding_rs::UTF_8));
+        assert!(output_encoding("klingon").is_none());
+    }
+
+    #[test]
+    fn output_is_decoded_lossily_and_binary_is_summarized() {
+        assert_eq!(decode_output("héllo ✓\n".as_bytes()), "héllo ✓\n");
+        assert_eq!(decode_output(b"caf\xe9 au lait\n"), "caf\u{FFFD} au lait\n");
+        assert_eq!(
+            decode_output(b"\x1b[32mok\x1b[0m\tdone\r\n"),
+            "\x1b[32mok\x1b[0m\tdone\r\n"
+        );
+
+        let binary: Vec<u8> = (0..=255u8).cycle().take(1000).collect();
+        let summary = decode_output(&binary);
+        assert!(summary.starts_with("[binary output, 1000 bytes, sha256="));
+        assert_eq!(
+            summary.len(),
+            "[binary output, 1000 bytes, sha256=]".len() + 64
+        );
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            sha256_hex(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[test]
+    fn strip_ansi_removes_escape_sequences() {
+        let colored = "\x1b[0m\x1b[01;34msrc\x1b[0m  Cargo.toml\n\x1b]0;title\x07\
+                       \x1b]8;;https://example.com\x1b\\link\x1b]8;;\x1b\\ \x1b[2K\x1b[1Gdone\x1b=";
+
+        assert_eq!(strip_ansi(colored), "src  Cargo.toml\nlink done");
+    }
+
+    #[test]
+    fn strip_ansi_leaves_plain_text_alone() {
+        let plain = "[1/3] building ~ 100% ]done[ caf\u{e9}";
+
+        assert_eq!(strip_ansi(plain), plain);
+    }
+
+    #[test]
+    fn truncate_output_keeps_head_and_tail_within_cap() {
+        let text = "é".repeat(50 * 1024);
+        let truncated = truncate_output(&text, 8192);
+
+        assert!(truncated.len() <= 8192);
+        assert!(truncated.starts_with('é') && truncated.ends_with('é'));
+        assert!(truncated.contains(" bytes truncated] ..."));
+        assert_eq!(truncate_output("short", 8192), "short");
+    }
+
+    #[test]
+    fn truncate_output_cuts_plainly_below_the_marker_size() {
+        let text = "é".repeat(100);
+
+        for cap in [1, 10, 29] {
+            let truncated = truncate_output(&text, cap);
+            assert!(truncated.len() <= cap, "{} > {}", truncated.len(), cap);
+            assert!(text.starts_with(&truncated));
+        }
+        assert_eq!(truncate_output(&text, 10), "é".repeat(5));
+    }
+
+    #[test]
+    fn execute_command_kills_commands_past_the_deadline() {
+        let started = Instant::now();
+        let err = execute_command(
+            "echo started; sleep 5",
+            "",
+            Duration::from_secs(1),
+            &[],
+            &DockerOptions::default(),
+            &default_shell(),
+            &[],
+            None,
+            None,
+            false,
+        )
+        .unwrap_err()
+        .to_string();
+
+        assert!(started.elapsed() < Duration::from_secs(4));
+        assert!(err.starts_with("command timed out after 1s"));
+        assert!(err.contains("started"));
+    }
+
+    fn run_on_host(cmd: &str, timeout: Duration) -> Result<CommandOutput, CrabError> {
+        execute_command(
+            cmd,
+            "",
+            timeout,
+            &[],
+            &DockerOptions::default(),
+            &default_shell(),
+            &[],
+            None,
+            None,
+            false,
+        )
+    }
+
+    #[test]
+    fn reading_stdin_gets_eof_and_a_hint_instead_of_hanging() {
+        let started = Instant::now();
+        let output = run_on_host("read x", Duration::from_secs(10)).unwrap();
+
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_ne!(output.exit_code, 0);
+        assert!(output.stderr.ends_with(&format!("{}\n", INTERACTIVE_HINT)));
+
+        let output = run_on_host("echo y | read x; exit 1", Duration::from_secs(10)).unwrap();
+        assert!(!output.stderr.contains(INTERACTIVE_HINT));
+    }
+
+    #[test]
+    fn a_prompt_left_waiting_at_the_timeout_gets_the_hint() {
+        let err = run_on_host(
+            "printf 'Do you want to continue? [Y/n] '; sleep 5",
+            Duration::from_secs(1),
+        )
+        .unwrap_err();
+        assert!(
+            matches!(
+                err,
+                CrabError::CommandTimeout {
+                    hint: Some(INTERACTIVE_HINT),
+                    ..
+                }
+            ),
+            "{}",
+            err
+        );
+
+        let err = run_on_host("sleep 5", Duration::from_secs(1)).unwrap_err();
+        assert!(!err.to_string().contains("[hint]"));
+    }
+
+    #[test]
+    fn waits_for_input_spots_questions_and_terminal_prompters() {
+        assert!(waits_for_input(
+            "apt install jq",
+            "Do you want to continue? [Y/n] Abort.\n",
+            false
+        ));
+        assert!(waits_for_input(
+            "sudo ls",
+            "[sudo] password for crab: ",
+            false
+        ));
+        assert!(waits_for_input("ssh host uptime", "", true));
+        assert!(!waits_for_input("ssh host uptime", "", false));
+        assert!(!waits_for_input(
+            "while read l; do echo $l; done < list",
+            "",
+            false
+        ));
+        assert!(!waits_for_input("grep -q needle haystack", "", false));
+    }
+
+    #[cfg(feature = "docker")]
+    #[test]
+    fn execute_command_runs_inside_image() {
+        let output = execute_command(
+            "echo hello && cat /etc/alpine-release",
+            "alpine",
+            TIMEOUT,
+            &[],
+            &DockerOptions::default(),
+            &default_shell(),
+            &[],
+            None,
+            None,
+            false,
+        )
+        .unwrap();
+        assert!(output.stdout.starts_with("hello\n"));
+        assert_eq!(output.exit_code, 0);
+    }
+
+    #[test]
+    fn docker_options_validate_and_render_flags() {
+        let options = DockerOptions::default()
+            .limits("512M", "1.5", "64")
+            .unwrap()
+            .network("none")
+            .unwrap()
+            .user("1000:1000")
+            .unwrap();
+        assert_eq!(
+            options.args(),
+            [
+                "--memory",
+                "512M",
+                "--memory-swap",
+                "512M",
+                "--cpus",
+                "1.5",
+                "--pids-limit",
+                "64",
+                "--network",
+                "none",
+                "--user",
+                "1000:1000"
+            ]
+        );
+        let unset = DockerOptions::default().limits("", "", "").unwrap();
+        assert!(unset.network("").unwrap().args().is_empty());
+
+        for (memory, cpus, pids, field) in [
+            ("lots", "", "", "DOCKER_MEMORY"),
+            ("1m", "", "", "DOCKER_MEMORY"),
+            ("", "-2", "", "DOCKER_CPUS"),
+            ("", "", "0", "DOCKER_PIDS_LIMIT"),
+        ] {
+            let err = DockerOptions::default()
+                .limits(memory, cpus, pids)
+                .unwrap_err();
+            assert!(err.to_string().contains(field), "{}", err);
+        }
+        let err = DockerOptions::default()
+            .network("--privileged")
+            .unwrap_err();
+        assert!(err.to_string().contains("DOCKER_NETWORK"), "{}", err);
+        for user in ["1000:", "-u 0", "root:wheel:x", "$(id -u)"] {
+            let err = DockerOptions::default().user(user).unwrap_err();
+            assert!(err.to_string().contains("DOCKER_USER"), "{}", err);
+        }
+    }
+
+    #[cfg(feature = "docker")]
+    #[test]
+    fn missing_image_fails_the_preflight_clearly() {
+        let image = "crabshell/definitely-not-a-real-image:nope";
+        let err = ensure_image(image, false).unwrap_err().to_string();
+        assert!(err.contains(image), "{}", err);
+        assert!(err.contains("not available locally"), "{}", err);
+
+        let err = ensure_image(image, true).unwrap_err().to_string();
+        assert!(err.contains("could not be pulled"), "{}", err);
+    }
+
+    #[cfg(feature = "docker")]
+    #[test]
+    fn docker_user_runs_commands_as_that_uid() {
+        let options = DockerOptions::default().user("1234:1234").unwrap();
+        let session = DockerSession::start("alpine", &[], &options).unwrap();
+        let output = session.run("id -u && id -g", TIMEOUT).unwrap();
+        assert_eq!(output.stdout, "1234\n1234\n");
+    }
+
+    #[cfg(feature = "docker")]
+    #[test]
+    fn docker_memory_limit_constrains_commands() {
+        let options = DockerOptions::default().limits("16m", "", "").unwrap();
+        let session = DockerSession::start("alpine", &[], &options).unwrap();
+        // tail has to buffer the whole newline-free 64MB stream
+        let output = session
+            .run(
+                "head -c 67108864 /dev/zero | tail -c 1 > /dev/null",
+                TIMEOUT,
+            )
+            .unwrap();
+        assert_ne!(output.exit_code, 0);
+    }
+
+    #[test]
+    fn workdir_mount_accepts_project_dirs_only() {
+        let dir = std::env::temp_dir().join(format!("crab-mount-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let host = std::fs::canonicalize(&dir).unwrap();
+
+        let options = DockerOptions::default()
+            .mount(&format!("{}:ro", dir.display()))
+            .unwrap();
+        assert_eq!(
+            options.args(),
+            [
+                "-v".to_string(),
+                format!("{}:/workspace:ro", host.display()),
+                "-w".to_string(),
+                "/workspace".to_string(),
+            ]
+        );
+        let writable = DockerOptions::default()
+            .mount(&dir.display().to_string())
+            .unwrap();
+        assert!(!writable.mount.unwrap().read_only);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        for spec in ["/", "/etc/", "/usr/../:ro", "/definitely/not/here"] {
+            let err = DockerOptions::default().mount(spec).unwrap_err();
+            assert!(err.to_string().contains("WORKDIR_MOUNT"), "{}", err);
+        }
+    }
+
+    #[cfg(feature = "docker")]
+    #[test]
+    fn mounted_workdir_is_readable_inside_the_container() {
+        let dir = std::env::temp_dir().join(format!("crab-mount-docker-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("notes.txt"), "from the host\n").unwrap();
+
+        let options = DockerOptions::default()
+            .mount(&format!("{}:ro", dir.display()))
+            .unwrap();
+        let session = DockerSession::start("alpine", &[], &options).unwrap();
+        let output = session.run("pwd && cat notes.txt", TIMEOUT).unwrap();
+        let write = session.run("touch new.txt", TIMEOUT).unwrap();
+        drop(session);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(output.stdout, "/workspace\nfrom the host\n");
+        assert_ne!(write.exit_code, 0);
+    }
+
+    #[cfg(feature = "docker")]
+    #[test]
+    fn network_none_blocks_outbound_access() {
+        let options = DockerOptions::default().network("none").unwrap();
+        let session = DockerSession::start("alpine", &[], &options).unwrap();
+        let output = session
+            .run("wget -q -T 5 -O /dev/null http://example.com", TIMEOUT)
+            .unwrap();
+        assert_ne!(output.exit_code, 0);
+        let interfaces = session.run("ls /sys/class/net", TIMEOUT).unwrap();
+        assert_eq!(interfaces.stdout.trim(), "lo");
+    }
+
+    #[cfg(feature = "docker")]
+    #[test]
+    fn docker_session_passes_forwarded_env_with_e() {
+        let session = DockerSession::start(
+            "alpine",
+            &["CARGO_PKG_NAME".to_string()],
+            &DockerOptions::default(),
+        )
+        .unwrap();
+        let output = session
+            .run(
+                "echo \"$CARGO_PKG_NAME:${CARGO_MANIFEST_DIR:-unset}\"",
+                TIMEOUT,
+            )
+            .unwrap();
+        assert_eq!(output.stdout.trim(), "hermit-crab:unset");
+    }
+
+    #[cfg(feature = "docker")]
+    #[test]
+    fn docker_session_keeps_state_between_commands() {
+        let session = DockerSession::start("alpine", &[], &DockerOptions::default()).unwrap();
+        session
+            .run("mkdir -p /tmp/crab && touch /tmp/crab/x", TIMEOUT)
+            .unwrap();
+        assert_eq!(session.run("ls /tmp/crab", TIMEOUT).unwrap().stdout, "x\n");
+
+        let id = session.container_id().to_string();
+        drop(session);
+        let inspect = Command::new("docker")
+            .args(["inspect", &id])
+            .output()
+            .unwrap();
+        assert!(!inspect.status.success());
+    }
+
+    #[cfg(feature = "docker")]
+    #[test]
+    fn docker_diff_reports_the_files_a_command_creates() {
+        let session = DockerSession::start("alpine", &[], &DockerOptions::default()).unwrap();
+        let before = session.snapshot().unwrap().unwrap();
+        session
+            .run("echo hi > /tmp/created.txt && rm /etc/motd", TIMEOUT)
+            .unwrap();
+        let after = session.snapshot().unwrap().unwrap();
+
+        let changes = FsChanges::between(&before, &after);
+        assert_eq!(changes.added, vec!["/tmp/created.txt"]);
+        assert_eq!(changes.deleted, vec!["/etc/motd"]);
+        assert!(changes.changed.is_empty(), "{:?}", changes);
+    }
+
+    fn default_denylist() -> Vec<String> {
+        DEFAULT_READONLY_DENYLIST
+            .iter()
+            .map(|s| s.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn privilege_escalation_is_found_past_assignments_and_wrappers() {
+        assert_eq!(
+            privilege_escalation("sudo apt install -y curl"),
+            Some("sudo".to_string())
+        );
+        assert_eq!(
+            privilege_escalation("DEBIAN_FRONTEND=noninteractive /usr/bin/sudo apt install jq"),
+            Some("sudo".to_string())
+        );
+        assert_eq!(
+            privilege_escalation("ls && nohup doas reboot"),
+            Some("doas".to_string())
+        );
+        assert_eq!(
+            privilege_escalation("echo hi | su -c whoami"),
+            Some("su".to_string())
+        );
+        assert_eq!(privilege_escalation("grep sudo /var/log/auth.log"), None);
+        assert_eq!(privilege_escalation("summary --sudo"), None);
+    }
+
+    #[test]
+    fn syntax_check_reports_parse_errors_only_for_shells_with_n() {
+        let shell = default_shell();
+        assert_eq!(syntax_error(&shell, "echo ok && ls | wc -l"), None);
+        let error = syntax_error(&shell, "if true; then echo unfinished").unwrap();
+        assert!(!error.is_empty());
+
+        let python = parse_shell_cmd("python3 -c").unwrap();
+        assert!(!checks_syntax(&python));
+        assert_eq!(syntax_error(&python, "if true; then"), None);
+        let missing = parse_shell_cmd("/no/such/sh -c").unwrap();
+        assert_eq!(syntax_error(&missing, "if true; then"), None);
+    }
+
+    #[test]
+    fn env_overrides_refuse_loader_and_host_variables() {
+        let forward = vec!["HOME".to_string()];
+        assert_eq!(env_override_violation("RUST_LOG", &forward), None);
+        assert_eq!(
+            env_override_violation("LD_PRELOAD", &forward).unwrap(),
+            "'LD_PRELOAD' can make programs run other code"
+        );
+        assert!(env_override_violation("path", &forward).is_some());
+        assert_eq!(
+            env_override_violation("HOME", &forward).unwrap(),
+            "'HOME' comes from the host through FORWARD_ENV"
+        );
+        assert!(env_override_violation("1BAD", &forward).is_some());
+        assert!(env_override_violation("A-B", &forward).is_some());
+    }
+
+    #[test]
+    fn readonly_blocks_mutating_commands() {
+        let denylist = default_denylist();
+        assert_eq!(
+            readonly_violation("rm -rf /", &denylist),
+            Some("rm".to_string())
+        );
+        assert_eq!(
+            readonly_violation("cd /tmp && sudo /bin/rm -f x", &denylist),
+            Some("rm".to_string())
+        );
+        assert_eq!(
+            readonly_violation("echo hi > notes.txt", &denylist),
+            Some(">".to_string())
+        );
+        assert_eq!(
+            readonly_violation("apt-get install -y curl", &denylist),
+            Some("apt-get install".to_string())
+        );
+        assert_eq!(
+            readonly_violation("mkfs.ext4 /dev/sdb1", &denylist),
+            Some("mkfs".to_string())
+        );
+    }
+
+    #[test]
+    fn readonly_allows_inspection_commands() {
+        let denylist = default_denylist();
+        assert_eq!(readonly_violation("ls -la", &denylist), None);
+        assert_eq!(
+            readonly_violation("cat removed.txt | grep mv", &denylist),
+            None
+        );
+        assert_eq!(readonly_violation("grep \">\" log.txt", &denylist), None);
+        assert_eq!(
+            readonly_violation("find / -name x 2>/dev/null", &denylist),
+            None
+        );
+        assert_eq!(readonly_violation("ps aux 2>&1 | head", &denylist), None);
+    }
+
+    fn scratch_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("crab-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn file_scan_reports_added_changed_and_deleted_files() {
+        let dir = scratch_dir("watch");
+        let root = Path::new(&dir);
+        std::fs::write(root.join("kept"), "same").unwrap();
+        std::fs::write(root.join("edited"), "short").unwrap();
+        std::fs::write(root.join("removed"), "bye").unwrap();
+        std::fs::create_dir_all(root.join(".git")).unwrap();
+        let before = scan_files(root).unwrap();
+
+        std::fs::write(root.join("edited"), "a good deal longer").unwrap();
+        std::fs::remove_file(root.join("removed")).unwrap();
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("sub/new"), "hi").unwrap();
+        std::fs::write(root.join(".git/index"), "ignored").unwrap();
+        let after = scan_files(root).unwrap();
+        let _ = std::fs::remove_dir_all(root);
+
+        let changes = FsChanges::between(&before, &after);
+        let under = |name: &str| vec![format!("{}/{}", dir, name)];
+        assert_eq!(changes.added, under("sub/new"));
+        assert_eq!(changes.changed, under("edited"));
+        assert_eq!(changes.deleted, under("removed"));
+        assert_eq!(
+            FsChanges::default().summary(),
+            "",
+            "nothing changed, nothing to report"
+        );
+        assert!(changes
+            .summary()
+            .starts_with(&format!("FILES_CHANGED: added {}/sub/new; changed ", dir)));
+    }
+
+    #[test]
+    fn shell_keeps_cwd_between_commands() {
+        let dir = scratch_dir("shell-cwd");
+        let mut shell = Shell::new();
+
+        let touched = shell
+            .run(
+                &HostRunner::default(),
+                &format!("cd {} && touch x", dir),
+                None,
+                TIMEOUT,
+            )
+            .unwrap();
+        assert_eq!(touched.exit_code, 0);
+
+        let listed = shell
+            .run(&HostRunner::default(), "ls", None, TIMEOUT)
+            .unwrap();
+        assert_eq!(listed.stdout, "x\n");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn shell_handles_relative_cd_dash_and_dir_stack() {
+        let dir = scratch_dir("shell-stack");
+        std::fs::create_dir(format!("{}/sub", dir)).unwrap();
+        let mut shell = Shell::new();
+
+        shell
+            .run(
+                &HostRunner::default(),
+                &format!("cd {}", dir),
+                None,
+                TIMEOUT,
+            )
+            .unwrap();
+        shell
+            .run(&HostRunner::default(), "cd sub", None, TIMEOUT)
+            .unwrap();
+        assert_eq!(shell.cwd(), Some(format!("{}/sub", dir).as_str()));
+
+        let back = shell
+            .run(&HostRunner::default(), "cd -", None, TIMEOUT)
+            .unwrap();
+        assert_eq!(back.stdout, format!("{}\n", dir));
+
+        shell
+            .run(&HostRunner::default(), "pushd /", None, TIMEOUT)
+            .unwrap();
+        assert_eq!(shell.cwd(), Some("/"));
+        shell
+            .run(&HostRunner::default(), "popd", None, TIMEOUT)
+            .unwrap();
+        assert_eq!(shell.cwd(), Some(dir.as_str()));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn shell_reports_missing_directory() {
+        let mut shell = Shell::new();
+        let output = shell
+            .run(
+                &HostRunner::default(),
+                "cd /definitely/not/here && ls",
+                None,
+                TIMEOUT,
+            )
+            .unwrap();
+
+        assert_eq!(output.exit_code, 1);
+        assert!(output.stderr.contains("No such directory"));
+        assert_eq!(shell.cwd(), None);
+    }
+
+    #[test]
+    fn nothing_runs_once_the_remembered_directory_is_gone() {
+        let dir = scratch_dir("shell-gone");
+        let mut shell = Shell::new();
+        shell
+            .run(
+                &HostRunner::default(),
+                &format!("cd {}", dir),
+                None,
+                TIMEOUT,
+            )
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let env = [("A".to_string(), "1".to_string())];
+        for run in [
+            shell.run_with_env(&HostRunner::default(), "echo ran", None, &env, TIMEOUT),
+            shell.run(&HostRunner::default(), "true\necho ran", None, TIMEOUT),
+        ] {
+            let output = run.unwrap();
+            assert_eq!(output.exit_code, 1);
+            assert_eq!(output.stdout, "");
+        }
+    }
+}
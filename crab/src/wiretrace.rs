@@ -0,0 +1,114 @@
+use reqwest::blocking::Request;
+use reqwest::header::HeaderMap;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// TRACE_FILE gets the raw LLM traffic, one JSON line per request body sent and
+// per response body received, for debugging prompts. Unlike AUDIT_LOG it keeps
+// the full text, so credentials are masked before anything is written.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WireEntry {
+    pub at_ms: u64,
+    // "request" or "response"
+    pub direction: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    pub body: String,
+}
+
+// Headers whose whole value is a credential
+const SECRET_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "api-key",
+    "x-api-key",
+    "x-goog-api-key",
+];
+
+pub const MASK: &str = "***";
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+// `secrets` are masked wherever they appear, e.g. a key in Google's query string
+fn mask(text: &str, secrets: &[&str]) -> String {
+    secrets
+        .iter()
+        .filter(|s| !s.is_empty())
+        .fold(text.to_string(), |text, secret| text.replace(secret, MASK))
+}
+
+fn masked_headers(headers: &HeaderMap, secrets: &[&str]) -> BTreeMap<String, String> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if SECRET_HEADERS.contains(&name.as_str()) {
+                MASK.to_string()
+            } else {
+                mask(&String::from_utf8_lossy(value.as_bytes()), secrets)
+            };
+            (name.to_string(), value)
+        })
+        .collect()
+}
+
+pub fn request(path: &str, request: &Request, secrets: &[&str]) {
+    let body = request
+        .body()
+        .and_then(|b| b.as_bytes())
+        .map(|b| String::from_utf8_lossy(b).to_string())
+        .unwrap_or_default();
+    append(
+        path,
+        &WireEntry {
+            at_ms: now_ms(),
+            direction: "request".to_string(),
+            url: mask(request.url().as_str(), secrets),
+            status: None,
+            headers: masked_headers(request.headers(), secrets),
+            body: mask(&body, secrets),
+        },
+    );
+}
+
+pub fn response(path: &str, status: u16, body: &str, secrets: &[&str]) {
+    append(
+        path,
+        &WireEntry {
+            at_ms: now_ms(),
+            direction: "response".to_string(),
+            url: String::new(),
+            status: Some(status),
+            headers: BTreeMap::new(),
+            body: mask(body, secrets),
+        },
+    );
+}
+
+// A failed write is warned about rather than stopping the run, like AUDIT_LOG
+fn append(path: &str, entry: &WireEntry) {
+    let written = serde_json::to_string(entry)
+        .map_err(|e| e.to_string())
+        .and_then(|line| {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{}", line))
+                .map_err(|e| e.to_string())
+        });
+    if let Err(e) = written {
+        eprintln!("Warning: Could not write TRACE_FILE {}: {}", path, e);
+    }
+}
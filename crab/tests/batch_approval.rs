@@ -0,0 +1,101 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+#[test]
+fn declining_the_batch_digest_runs_none_of_it() {
+    let dir = std::env::temp_dir().join(format!("crab-batch-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let script = dir.join("replies.txt");
+    let first = dir.join("first.txt");
+    let second = dir.join("second.txt");
+    std::fs::write(
+        &script,
+        format!(
+            "ACTION: EXECUTE\nCOMMAND: ls {}\nCOMMAND: echo one > {}\nCOMMAND: touch {}\n%%\nNothing was changed.\n",
+            dir.display(),
+            first.display(),
+            second.display()
+        ),
+    )
+    .unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_hermit-crab"))
+        .current_dir(&dir)
+        .env_clear()
+        .env("PATH", std::env::var("PATH").unwrap_or_default())
+        .env("PROVIDER", "mock")
+        .env("MOCK_RESPONSES", &script)
+        .env("DOCKER_IMAGE", "")
+        .env("APPROVAL", "manual")
+        .arg("make two files")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"n\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+    let made_any = first.exists() || second.exists();
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "stderr: {}", stderr);
+    assert!(!made_any, "stderr: {}", stderr);
+    assert!(
+        stderr.contains("Run this batch of 3 commands?"),
+        "stderr: {}",
+        stderr
+    );
+    assert!(stderr.contains(&format!("1. ls {}\n", dir.display())));
+    assert!(stderr.contains(&format!("2. echo one > {}  [mutating: >]", first.display())));
+    assert!(stderr.contains(&format!("3. touch {}  [mutating: touch]", second.display())));
+    assert!(
+        stderr.contains("Risk: 2 of 3 matched the mutating denylist."),
+        "stderr: {}",
+        stderr
+    );
+    assert!(!stderr.contains("Run command?"), "stderr: {}", stderr);
+}
+
+#[test]
+fn an_edited_command_is_held_to_max_command_len() {
+    let dir = std::env::temp_dir().join(format!("crab-edit-len-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let script = dir.join("replies.txt");
+    let made = dir.join("made.txt");
+    std::fs::write(
+        &script,
+        "ACTION: EXECUTE\nCOMMAND: true\n%%\nNothing was changed.\n",
+    )
+    .unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_hermit-crab"))
+        .current_dir(&dir)
+        .env_clear()
+        .env("PATH", std::env::var("PATH").unwrap_or_default())
+        .env("PROVIDER", "mock")
+        .env("MOCK_RESPONSES", &script)
+        .env("DOCKER_IMAGE", "")
+        .env("APPROVAL", "manual")
+        .env("MAX_COMMAND_LEN", "40")
+        .arg("check")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let edited = format!("touch {} # {}", made.display(), "x".repeat(40));
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(format!("e\n{}\n", edited).as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    let ran = made.exists();
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "stderr: {}", stderr);
+    assert!(!ran, "stderr: {}", stderr);
+}
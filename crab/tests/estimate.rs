@@ -0,0 +1,36 @@
+use std::process::{Command, Stdio};
+
+#[test]
+fn estimate_prints_the_projection_without_calling_the_model_or_running_anything() {
+    let dir = std::env::temp_dir().join(format!("crab-estimate-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let marker = dir.join("ran.txt");
+
+    // No API key and nothing listening at the base URL: any call would fail the run
+    let output = Command::new(env!("CARGO_BIN_EXE_hermit-crab"))
+        .current_dir(&dir)
+        .env_clear()
+        .env("PATH", std::env::var("PATH").unwrap_or_default())
+        .env("API_BASE_URL", "http://127.0.0.1:9/v1")
+        .env("DOCKER_IMAGE", "")
+        .env("MODEL", "gpt-4o")
+        .arg("--estimate")
+        .arg(format!("run: touch {}", marker.display()))
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    let ran = marker.exists();
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "stderr: {}", stderr);
+    assert!(
+        stdout.starts_with("Estimate for gpt-4o: ~"),
+        "stdout: {}",
+        stdout
+    );
+    assert!(stdout.contains("Over MAX_ITERATIONS=5: ~$"));
+    assert!(!stdout.contains("COMMAND:"));
+    assert!(!ran);
+}
@@ -0,0 +1,79 @@
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+
+// Runs one mock session with a post-hook appending CRAB_EXIT_CODE to `hooks`
+fn run_with_hooks(dir: &Path, replies: &str, pre_hook: &str) -> (Output, String) {
+    std::fs::create_dir_all(dir).unwrap();
+    let script = dir.join("replies.txt");
+    let hooks = dir.join("hooks.txt");
+    std::fs::write(&script, replies).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_hermit-crab"))
+        .current_dir(dir)
+        .env_clear()
+        .env("PATH", std::env::var("PATH").unwrap_or_default())
+        .env("PROVIDER", "mock")
+        .env("MOCK_RESPONSES", &script)
+        .env("DOCKER_IMAGE", "")
+        .env("PRE_HOOK", pre_hook)
+        .env(
+            "POST_HOOK",
+            format!("echo post $CRAB_EXIT_CODE >> {}", hooks.display()),
+        )
+        .arg("do the task")
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    let hooks = std::fs::read_to_string(&hooks).unwrap_or_default();
+    let _ = std::fs::remove_dir_all(dir);
+    (output, hooks)
+}
+
+#[test]
+fn post_hook_runs_after_a_successful_run() {
+    let dir = std::env::temp_dir().join(format!("crab-hooks-ok-{}", std::process::id()));
+    let marker = dir.join("pre.txt");
+    let (output, hooks) = run_with_hooks(
+        &dir,
+        &format!(
+            "ACTION: EXECUTE\nCOMMAND: cat {}\n%%\nThe pre-hook ran.\n",
+            marker.display()
+        ),
+        &format!("echo seeded > {}", marker.display()),
+    );
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(!stdout.contains("Running PRE_HOOK"), "stdout: {}", stdout);
+    assert_eq!(hooks, "post 0\n");
+}
+
+#[test]
+fn post_hook_runs_after_a_failed_run() {
+    let dir = std::env::temp_dir().join(format!("crab-hooks-abort-{}", std::process::id()));
+    let (output, hooks) = run_with_hooks(&dir, "ABORT: the service never came up\n", "");
+
+    assert_eq!(output.status.code(), Some(6));
+    assert_eq!(hooks, "post 6\n");
+}
+
+#[test]
+fn failing_pre_hook_aborts_before_the_model_is_asked() {
+    let dir = std::env::temp_dir().join(format!("crab-hooks-pre-{}", std::process::id()));
+    let (output, hooks) = run_with_hooks(&dir, "The answer.\n", "exit 3");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "stderr: {}", stderr);
+    assert!(
+        stderr.contains("Error: PRE_HOOK exited with 3"),
+        "stderr: {}",
+        stderr
+    );
+    assert!(!stdout.contains("The answer."), "stdout: {}", stdout);
+    assert_eq!(hooks, "post 1\n");
+}
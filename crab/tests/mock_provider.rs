@@ -0,0 +1,48 @@
+use std::process::{Command, Stdio};
+
+#[test]
+fn mock_provider_runs_the_scripted_session_without_credentials() {
+    let dir = std::env::temp_dir().join(format!("crab-mock-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let script = dir.join("replies.txt");
+    let seen = dir.join("seen.txt");
+    // Commands run in the workspace directory, so the file is named in full
+    let command = format!("echo scripted > {}", seen.display());
+    std::fs::write(
+        &script,
+        format!(
+            "ACTION: EXECUTE\nCOMMAND: {}\n%%\nThe file says scripted.\n",
+            command
+        ),
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_hermit-crab"))
+        .current_dir(&dir)
+        .env_clear()
+        .env("PATH", std::env::var("PATH").unwrap_or_default())
+        .env("PROVIDER", "mock")
+        .env("MOCK_RESPONSES", &script)
+        .env("DOCKER_IMAGE", "")
+        .arg("write a file")
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    let seen = std::fs::read_to_string(&seen).unwrap_or_default();
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "stderr: {}", stderr);
+    assert_eq!(seen, "scripted\n");
+    assert!(
+        stdout.contains(&format!("COMMAND: {}", command)),
+        "stdout: {}",
+        stdout
+    );
+    assert!(
+        stdout.trim_end().ends_with("The file says scripted."),
+        "stdout: {}",
+        stdout
+    );
+}
@@ -0,0 +1,76 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::process::{Command, Output, Stdio};
+use std::thread;
+
+const MODELS: &str = r#"{"object":"list","data":[{"id":"gpt-4o-mini","object":"model"},{"id":"gpt-4o","object":"model"}]}"#;
+
+// Answers one request with MODELS and hands back its request line
+fn models_server() -> (String, thread::JoinHandle<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/v1", listener.local_addr().unwrap());
+    let handle = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).unwrap();
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap() > 2 {
+            line.clear();
+        }
+        let _ = write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            MODELS.len(),
+            MODELS
+        );
+        request_line
+    });
+    (url, handle)
+}
+
+fn crab_models(url: &str, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_hermit-crab"))
+        .env_clear()
+        .env("PATH", std::env::var("PATH").unwrap_or_default())
+        .env("PROVIDER", "openai")
+        .env("OPENAI_API_KEY", "sk-models-test")
+        .env("API_BASE_URL", url)
+        .arg("models")
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn models_lists_the_ids_from_the_models_endpoint() {
+    let (url, server) = models_server();
+    let output = crab_models(&url, &[]);
+    let request_line = server.join().unwrap();
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "stderr: {}", stderr);
+    assert!(
+        request_line.starts_with("GET /v1/models "),
+        "{}",
+        request_line
+    );
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "gpt-4o\ngpt-4o-mini\n"
+    );
+}
+
+#[test]
+fn models_json_prints_an_array_of_ids() {
+    let (url, server) = models_server();
+    let output = crab_models(&url, &["--json"]);
+    server.join().unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "[\"gpt-4o\",\"gpt-4o-mini\"]\n"
+    );
+}
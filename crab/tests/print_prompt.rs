@@ -0,0 +1,55 @@
+use std::io::ErrorKind;
+use std::net::TcpListener;
+use std::process::{Command, Stdio};
+
+#[test]
+fn print_prompt_shows_the_messages_without_calling_the_model() {
+    let dir = std::env::temp_dir().join(format!("crab-print-prompt-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    // Anything reaching the API would show up as a connection here
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    server.set_nonblocking(true).unwrap();
+    // [{"role":"user","content":"what is in /etc/motd?"},
+    //  {"role":"assistant","content":"It welcomes you to the cubicle."}]
+    let history = "W3sicm9sZSI6InVzZXIiLCJjb250ZW50Ijoid2hhdCBpcyBpbiAvZXRjL21vdGQ/In0seyJyb2xlIjoiYXNzaXN0YW50IiwiY29udGVudCI6Ikl0IHdlbGNvbWVzIHlvdSB0byB0aGUgY3ViaWNsZS4ifV0=";
+
+    let output = Command::new(env!("CARGO_BIN_EXE_hermit-crab"))
+        .current_dir(&dir)
+        .env_clear()
+        .env("PATH", std::env::var("PATH").unwrap_or_default())
+        .env(
+            "API_BASE_URL",
+            format!("http://{}/v1", server.local_addr().unwrap()),
+        )
+        .env("OPENAI_API_KEY", "sk-print-prompt-secret")
+        .env("DOCKER_IMAGE", "")
+        .env("HISTORY", history)
+        .arg("--print-prompt")
+        .arg("check it with sk-print-prompt-secret")
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "stderr: {}", stderr);
+    assert!(
+        stdout.starts_with("--- 1 [system] ---\n"),
+        "stdout: {}",
+        stdout
+    );
+    assert!(stdout.contains("WORKSPACE DIRECTORY STRUCTURE"));
+    assert!(stdout.contains("[user] ---\nwhat is in /etc/motd?\n"));
+    assert!(stdout.contains("[assistant] ---\nIt welcomes you to the cubicle.\n"));
+    assert!(stdout.contains("check it with"));
+    assert!(
+        !stdout.contains("sk-print-prompt-secret"),
+        "stdout: {}",
+        stdout
+    );
+    assert_eq!(
+        server.accept().map(|_| ()).unwrap_err().kind(),
+        ErrorKind::WouldBlock
+    );
+}
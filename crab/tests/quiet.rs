@@ -0,0 +1,45 @@
+use std::process::{Command, Stdio};
+
+fn run(dir: &std::path::Path, quiet: bool) -> String {
+    let script = dir.join("replies.txt");
+    std::fs::write(
+        &script,
+        "Let me look first.\nACTION: EXECUTE\nCOMMAND: echo checked\n%%\nEverything checks out.\n",
+    )
+    .unwrap();
+    let mut command = Command::new(env!("CARGO_BIN_EXE_hermit-crab"));
+    command
+        .current_dir(dir)
+        .env_clear()
+        .env("PATH", std::env::var("PATH").unwrap_or_default())
+        .env("PROVIDER", "mock")
+        .env("MOCK_RESPONSES", &script)
+        .env("DOCKER_IMAGE", "")
+        .env("STREAM", "true")
+        .arg("check things")
+        .stdin(Stdio::null());
+    if quiet {
+        command.env("QUIET", "true");
+    }
+    let output = command.output().unwrap();
+    assert!(
+        output.status.success(),
+        "stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+#[test]
+fn quiet_mode_prints_only_the_final_answer() {
+    let dir = std::env::temp_dir().join(format!("crab-quiet-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let loud = run(&dir, false);
+    let quiet = run(&dir, true);
+    let _ = std::fs::remove_dir_all(&dir);
+
+    assert!(loud.contains("Let me look first."), "stdout: {}", loud);
+    assert!(loud.contains("COMMAND: echo checked"), "stdout: {}", loud);
+    assert_eq!(quiet, "Everything checks out.\n");
+}